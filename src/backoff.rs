@@ -0,0 +1,143 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponential backoff with optional jitter, for retrying throttled or
+/// failed requests
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    /// Growth of the delay per attempt
+    factor: f64,
+    /// Fraction of each delay that may be randomly taken off (0 = none)
+    jitter: f64,
+    /// Delay of the last attempt before jitter
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    /// Doubling delays from `initial` up to `max`, without jitter
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            factor: 2.0,
+            jitter: 0.0,
+            current: None,
+        }
+    }
+
+    /// Multiply the delay by `factor` (at least 1) per attempt
+    pub fn with_factor(mut self, factor: f64) -> Self {
+        self.factor = factor.max(1.0);
+        self
+    }
+
+    /// Shorten each delay by a random share of up to `jitter` (0 to 1), so
+    /// devices that failed together do not retry together
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before the next attempt, growing on every call up to the maximum
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = match self.current {
+            Some(previous) => previous.mul_f64(self.factor).min(self.max),
+            None => self.initial.min(self.max),
+        };
+        self.current = Some(delay);
+
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+
+    /// Start over from the initial delay after a successful attempt
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+/// A random number in `[0, 1)`. Jitter only needs to spread devices out, so
+/// the randomly keyed std hasher is enough and saves a dependency.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn test_reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_custom_factor() {
+        let mut backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(10)).with_factor(3.0);
+        let delays: Vec<u128> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![100, 300, 900, 2700]);
+
+        // A factor below 1 would shrink the delay; it is raised to 1
+        let mut flat =
+            Backoff::new(Duration::from_secs(2), Duration::from_secs(10)).with_factor(0.5);
+        assert_eq!(flat.next_delay(), Duration::from_secs(2));
+        assert_eq!(flat.next_delay(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(8)).with_jitter(0.25);
+
+        // Jitter does not compound: each step is bounded by the un-jittered delay
+        for expected_secs in [1.0, 2.0, 4.0, 8.0, 8.0] {
+            let delay = backoff.next_delay().as_secs_f64();
+            assert!(
+                delay <= expected_secs && delay >= expected_secs * 0.75,
+                "{} outside [{}, {}]",
+                delay,
+                expected_secs * 0.75,
+                expected_secs
+            );
+        }
+    }
+
+    #[test]
+    fn test_jitter_varies_delays() {
+        let delays: Vec<Duration> = (0..20)
+            .map(|_| {
+                Backoff::new(Duration::from_secs(10), Duration::from_secs(60))
+                    .with_jitter(1.0)
+                    .next_delay()
+            })
+            .collect();
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(10)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn test_random_fraction_range() {
+        for _ in 0..1000 {
+            let fraction = random_fraction();
+            assert!((0.0..1.0).contains(&fraction));
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::config::DEFAULT_CONFIG_PATH;
+use crate::error::{DeviceOpsError, Result};
+use crate::history::{parse_since, HistoryQuery};
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the config file when `--config` is not given
+pub const CONFIG_ENV_VAR: &str = "DEVICE_OPS_CONFIG";
+/// Environment variable naming the config profile when `--profile` is not given
+pub const PROFILE_ENV_VAR: &str = "DEVICE_OPS_PROFILE";
+
+/// Command line options of the component binary
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    /// Config file named by `--config` or `DEVICE_OPS_CONFIG`; `None` means
+    /// the default path, which may be missing
+    pub config_path: Option<PathBuf>,
+    /// Log configuration problems as a warning instead of refusing to start
+    pub lenient_config: bool,
+    /// Load and validate the config, print it (redacted) and exit
+    pub print_config: bool,
+    /// Config profile named by `--profile` or `DEVICE_OPS_PROFILE`; `None`
+    /// leaves the choice to the config itself
+    pub profile: Option<String>,
+    /// Environment variables that supplied an option, for the startup log
+    pub env_overrides: Vec<&'static str>,
+    /// `history` subcommand: print the job execution history and exit
+    pub history: Option<HistoryQuery>,
+}
+
+impl Options {
+    /// Parse the arguments after the program name. `env` looks up
+    /// `DEVICE_OPS_CONFIG` and `DEVICE_OPS_PROFILE`; `--config` and
+    /// `--profile` win over them. A leading `history` selects the history
+    /// subcommand, which also takes `--since <duration>`, `--failed-only`
+    /// and `--json`.
+    pub fn parse<F>(args: impl IntoIterator<Item = String>, env: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut options = Options::default();
+        let mut args = args.into_iter().peekable();
+        if args.peek().is_some_and(|arg| arg == "history") {
+            args.next();
+            options.history = Some(HistoryQuery::default());
+        }
+
+        while let Some(arg) = args.next() {
+            if let Some(query) = options.history.as_mut() {
+                match arg.as_str() {
+                    "--failed-only" => {
+                        query.failed_only = true;
+                        continue;
+                    }
+                    "--json" => {
+                        query.json = true;
+                        continue;
+                    }
+                    "--since" => {
+                        let since = args.next().ok_or_else(|| {
+                            DeviceOpsError::ConfigError("--since needs a duration".to_string())
+                        })?;
+                        query.since = Some(parse_since(&since)?);
+                        continue;
+                    }
+                    _ => {
+                        if let Some(since) = arg.strip_prefix("--since=") {
+                            query.since = Some(parse_since(since)?);
+                            continue;
+                        }
+                    }
+                }
+            }
+            match arg.as_str() {
+                "--lenient-config" => options.lenient_config = true,
+                "--print-config" => options.print_config = true,
+                "--config" => {
+                    let path = args.next().ok_or_else(|| {
+                        DeviceOpsError::ConfigError("--config needs a path".to_string())
+                    })?;
+                    options.config_path = Some(PathBuf::from(path));
+                }
+                "--profile" => {
+                    let name = args.next().ok_or_else(|| {
+                        DeviceOpsError::ConfigError("--profile needs a name".to_string())
+                    })?;
+                    options.profile = Some(name);
+                }
+                _ => {
+                    if let Some(path) = arg.strip_prefix("--config=") {
+                        options.config_path = Some(PathBuf::from(path));
+                    } else if let Some(name) = arg.strip_prefix("--profile=") {
+                        options.profile = Some(name.to_string());
+                    } else {
+                        return Err(DeviceOpsError::ConfigError(format!(
+                            "Unknown argument: {} (expected --config <path>, --profile <name>, --lenient-config or --print-config; history takes --since <duration>, --failed-only and --json)",
+                            arg
+                        )));
+                    }
+                }
+            }
+        }
+
+        if options.config_path.is_none() {
+            options.config_path = env(CONFIG_ENV_VAR)
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from);
+            if options.config_path.is_some() {
+                options.env_overrides.push(CONFIG_ENV_VAR);
+            }
+        }
+        if options.profile.is_none() {
+            options.profile = env(PROFILE_ENV_VAR).filter(|name| !name.is_empty());
+            if options.profile.is_some() {
+                options.env_overrides.push(PROFILE_ENV_VAR);
+            }
+        }
+        Ok(options)
+    }
+
+    /// The config file that will be read
+    pub fn effective_config_path(&self) -> &Path {
+        self.config_path
+            .as_deref()
+            .unwrap_or(Path::new(DEFAULT_CONFIG_PATH))
+    }
+
+    /// A config file named explicitly must exist; only the default path falls
+    /// back to built-in defaults when missing
+    pub fn check_config_path(&self) -> Result<()> {
+        match &self.config_path {
+            Some(path) if !path.exists() => Err(DeviceOpsError::ConfigError(format!(
+                "Config file {} does not exist",
+                path.display()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    fn config_env(path: &str) -> impl Fn(&str) -> Option<String> + '_ {
+        move |name| (name == CONFIG_ENV_VAR).then(|| path.to_string())
+    }
+
+    #[test]
+    fn test_config_flag_wins_over_env() {
+        let options = Options::parse(
+            args(&["--config", "/data/greengrass/config.json"]),
+            config_env("/etc/device-ops.json"),
+        )
+        .unwrap();
+        assert_eq!(
+            options.config_path,
+            Some(PathBuf::from("/data/greengrass/config.json"))
+        );
+        // The flag won, so the variable overrode nothing
+        assert!(options.env_overrides.is_empty());
+
+        let options =
+            Options::parse(args(&["--config=/tmp/a.yaml", "--lenient-config"]), no_env).unwrap();
+        assert_eq!(options.config_path, Some(PathBuf::from("/tmp/a.yaml")));
+        assert!(options.lenient_config);
+        assert!(!options.print_config);
+
+        let options = Options::parse(args(&["--print-config"]), no_env).unwrap();
+        assert!(options.print_config);
+
+        let options = Options::parse(args(&[]), config_env("/etc/device-ops.json")).unwrap();
+        assert_eq!(
+            options.effective_config_path(),
+            Path::new("/etc/device-ops.json")
+        );
+        assert_eq!(options.env_overrides, vec![CONFIG_ENV_VAR]);
+    }
+
+    #[test]
+    fn test_default_path_when_unset() {
+        let options = Options::parse(args(&[]), no_env).unwrap();
+        assert_eq!(options, Options::default());
+        assert_eq!(
+            options.effective_config_path(),
+            Path::new(DEFAULT_CONFIG_PATH)
+        );
+    }
+
+    #[test]
+    fn test_profile_from_flag_or_env() {
+        let env = |name: &str| (name == PROFILE_ENV_VAR).then(|| "staging".to_string());
+        assert_eq!(
+            Options::parse(args(&[]), env).unwrap().profile.as_deref(),
+            Some("staging")
+        );
+        for flag in [&["--profile", "prod"][..], &["--profile=prod"][..]] {
+            let options = Options::parse(args(flag), env).unwrap();
+            assert_eq!(options.profile.as_deref(), Some("prod"));
+            assert!(options.env_overrides.is_empty());
+        }
+        assert_eq!(Options::parse(args(&[]), no_env).unwrap().profile, None);
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        assert!(Options::parse(args(&["--config"]), no_env).is_err());
+        assert!(Options::parse(args(&["--profile"]), no_env).is_err());
+        assert!(Options::parse(args(&["--verbose"]), no_env).is_err());
+        // History flags only follow the subcommand
+        assert!(Options::parse(args(&["--failed-only"]), no_env).is_err());
+        assert!(Options::parse(args(&["history", "--since"]), no_env).is_err());
+        assert!(Options::parse(args(&["history", "--since", "2 days"]), no_env).is_err());
+    }
+
+    #[test]
+    fn test_history_subcommand() {
+        let options = Options::parse(
+            args(&[
+                "history",
+                "--since",
+                "48h",
+                "--failed-only",
+                "--config",
+                "/tmp/a.json",
+            ]),
+            no_env,
+        )
+        .unwrap();
+        assert_eq!(
+            options.history,
+            Some(HistoryQuery {
+                since: Some(std::time::Duration::from_secs(48 * 60 * 60)),
+                failed_only: true,
+                json: false,
+            })
+        );
+        assert_eq!(options.config_path, Some(PathBuf::from("/tmp/a.json")));
+
+        let options = Options::parse(args(&["history", "--json", "--since=7d"]), no_env).unwrap();
+        let query = options.history.unwrap();
+        assert!(query.json && !query.failed_only);
+
+        assert_eq!(Options::parse(args(&[]), no_env).unwrap().history, None);
+    }
+
+    #[test]
+    fn test_missing_explicit_config_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+
+        let explicit =
+            Options::parse(args(&["--config", missing.to_str().unwrap()]), no_env).unwrap();
+        let err = explicit.check_config_path().unwrap_err().to_string();
+        assert!(err.contains("does not exist"), "{}", err);
+
+        let from_env = Options::parse(args(&[]), config_env(missing.to_str().unwrap())).unwrap();
+        assert!(from_env.check_config_path().is_err());
+
+        // The implicit default path may be missing: built-in defaults apply
+        let implicit = Options::parse(args(&[]), no_env).unwrap();
+        assert!(implicit.check_config_path().is_ok());
+
+        std::fs::write(&missing, "{}").unwrap();
+        assert!(explicit.check_config_path().is_ok());
+    }
+}
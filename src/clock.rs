@@ -0,0 +1,118 @@
+use async_trait::async_trait;
+use std::time::{Duration, Instant};
+
+const PROC_UPTIME: &str = "/proc/uptime";
+
+/// Source of the current time, swappable in tests
+#[async_trait]
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for measuring durations
+    fn now(&self) -> Instant;
+    /// Wall-clock time as milliseconds since the Unix epoch
+    fn epoch_millis(&self) -> i64;
+    /// Wait for `duration` to pass
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Clock backed by the system monotonic clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn epoch_millis(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// Whole seconds since the device booted, from `/proc/uptime`; `None` where
+/// that file does not exist or cannot be parsed
+pub fn system_uptime_secs() -> Option<u64> {
+    let content = std::fs::read_to_string(PROC_UPTIME).ok()?;
+    parse_uptime(&content)
+}
+
+/// First field of `/proc/uptime` ("12345.67 54321.00"), rounded down
+fn parse_uptime(content: &str) -> Option<u64> {
+    let secs: f64 = content.split_whitespace().next()?.parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then_some(secs as u64)
+}
+
+/// Manually advanced clock for tests
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    now: std::sync::Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Wall-clock time the mock starts at (2023-11-14T22:13:20Z)
+    pub const START_EPOCH_MILLIS: i64 = 1_700_000_000_000;
+
+    pub fn new() -> Self {
+        let start = Instant::now();
+        Self {
+            start,
+            now: std::sync::Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn epoch_millis(&self) -> i64 {
+        let elapsed = self.now().duration_since(self.start);
+        Self::START_EPOCH_MILLIS + elapsed.as_millis() as i64
+    }
+
+    /// Returns at once, with the clock moved forward
+    async fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uptime() {
+        assert_eq!(parse_uptime("12345.67 54321.00\n"), Some(12345));
+        assert_eq!(parse_uptime(""), None);
+        assert_eq!(parse_uptime("soon 1.0"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_system_uptime_is_plausible() {
+        let uptime = system_uptime_secs().unwrap();
+        // Booted, and not before the Unix epoch
+        assert!(uptime < chrono::Utc::now().timestamp() as u64);
+    }
+}
@@ -0,0 +1,130 @@
+//! What device and build a job ran on
+//!
+//! Collected once when the job handler starts: the component version, the
+//! hostname, the kernel release and the `ID`/`VERSION_ID` of
+//! `/etc/os-release`. Job results carry the compact form as the `device`
+//! statusDetails field (`v0.3.1 rpi4 debian-12`), history records carry the
+//! same string and the liveness heartbeat carries every field, so all three
+//! agree on which build and OS image a device was on.
+
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    pub version: String,
+    pub hostname: String,
+    /// Kernel release, e.g. `6.1.0-rpi7-rpi-v8`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    /// `ID` of `/etc/os-release`, e.g. `debian`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_id: Option<String>,
+    /// `VERSION_ID` of `/etc/os-release`, e.g. `12`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_version_id: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Read the running system; what cannot be read is left out
+    pub fn collect() -> Self {
+        let read = |path: &str| {
+            std::fs::read_to_string(path)
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        let hostname = read("/proc/sys/kernel/hostname")
+            .or_else(|| read("/etc/hostname"))
+            .unwrap_or_else(|| "unknown".to_string());
+        let (os_id, os_version_id) = std::fs::read_to_string(Path::new("/etc/os-release"))
+            .map(|contents| parse_os_release(&contents))
+            .unwrap_or_default();
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname,
+            kernel: read("/proc/sys/kernel/osrelease"),
+            os_id,
+            os_version_id,
+        }
+    }
+
+    /// `v<version> <hostname> <os id>-<os version>`, e.g. `v0.3.1 rpi4 debian-12`
+    pub fn compact(&self) -> String {
+        let mut compact = format!("v{} {}", self.version, self.hostname);
+        match (&self.os_id, &self.os_version_id) {
+            (Some(id), Some(version)) => compact.push_str(&format!(" {}-{}", id, version)),
+            (Some(id), None) => compact.push_str(&format!(" {}", id)),
+            _ => {}
+        }
+        compact
+    }
+}
+
+/// `ID` and `VERSION_ID` of an os-release file, unquoted
+pub fn parse_os_release(contents: &str) -> (Option<String>, Option<String>) {
+    let mut id = None;
+    let mut version_id = None;
+    for line in contents.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        if value.is_empty() {
+            continue;
+        }
+        match key {
+            "ID" => id = Some(value.to_string()),
+            "VERSION_ID" => version_id = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    (id, version_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_release() {
+        let contents = "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\n\
+                        NAME=\"Debian GNU/Linux\"\n\
+                        VERSION_ID=\"12\"\n\
+                        # comment\n\
+                        ID=debian\n\
+                        ID_LIKE=\n";
+        assert_eq!(
+            parse_os_release(contents),
+            (Some("debian".to_string()), Some("12".to_string()))
+        );
+        // Rolling releases have no VERSION_ID
+        assert_eq!(
+            parse_os_release("ID='arch'\n"),
+            (Some("arch".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut info = DeviceInfo {
+            version: "0.3.1".to_string(),
+            hostname: "rpi4".to_string(),
+            kernel: Some("6.1.0-rpi7-rpi-v8".to_string()),
+            os_id: Some("debian".to_string()),
+            os_version_id: Some("12".to_string()),
+        };
+        assert_eq!(info.compact(), "v0.3.1 rpi4 debian-12");
+        info.os_version_id = None;
+        assert_eq!(info.compact(), "v0.3.1 rpi4 debian");
+        info.os_id = None;
+        assert_eq!(info.compact(), "v0.3.1 rpi4");
+    }
+
+    #[test]
+    fn test_collect_has_version_and_hostname() {
+        let info = DeviceInfo::collect();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.hostname.is_empty());
+    }
+}
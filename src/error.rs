@@ -1,24 +1,649 @@
 use thiserror::Error;
 
+/// Cause of an IPC failure; the SDK's error types are boxed as they are
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The variants with a `source` keep the underlying error for `source()`;
+/// their message is the same `<context>: <cause>` the string variants carry.
 #[derive(Error, Debug)]
 pub enum DeviceOpsError {
     #[error("IPC connection failed: {0}")]
     IpcError(String),
 
+    #[error("IPC connection failed: {context}: {source}")]
+    Ipc {
+        context: String,
+        #[source]
+        source: BoxError,
+    },
+
+    /// Any other execution failure, including those of custom `CommandRunner`s
     #[error("Job execution failed: {0}")]
     ExecutionError(String),
 
+    /// The command (or the sudo, wrapper or systemd-run in front of it) could not be started
+    #[error("Job execution failed: cannot start {path}: {source}")]
+    SpawnFailed {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The command started but its exit could not be collected
+    #[error("Job execution failed: lost track of the running command: {source}")]
+    WaitFailed {
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A runAsUser check could not be run at all (as opposed to finding the
+    /// user unavailable)
+    #[error("Job execution failed: cannot verify runAsUser '{user}': {detail}")]
+    VerificationFailed { user: String, detail: String },
+
+    /// Reading the command's stdout or stderr failed while it ran
+    #[error("Job execution failed: cannot read the command's {stream}: {source}")]
+    OutputCaptureFailed {
+        stream: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Job execution failed: {context}: {source}")]
+    ExecutionIo {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Job execution failed: {context}: {source}")]
+    ExecutionJson {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("command not found: {0}")]
+    CommandNotFound(String),
+
     #[error("Security validation failed: {0}")]
     SecurityError(String),
 
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
-    #[error("Timeout: command exceeded {0} seconds")]
-    TimeoutError(u64),
+    #[error("Configuration error: {context}: {source}")]
+    ConfigIo {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Configuration error: {context}: {source}")]
+    ConfigJson {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// A step ran past its timeout; carries what it wrote before it was stopped
+    #[error("Timeout: command exceeded {timeout_secs} seconds")]
+    TimeoutError {
+        step: String,
+        timeout_secs: u64,
+        elapsed_ms: u64,
+        stdout: String,
+        stderr: String,
+    },
+
+    /// A step went quiet for too long; carries what it wrote before it was stopped
+    #[error("Idle timeout: command produced no output for {idle_secs} seconds")]
+    IdleTimeout {
+        idle_secs: u64,
+        elapsed_ms: u64,
+        stdout: String,
+        stderr: String,
+    },
 
     #[error("Invalid job document: {0}")]
     InvalidJobDocument(String),
+
+    /// A job document field that failed validation, e.g. `steps[2].action.input.timeout`
+    #[error("Invalid job document: {path}: {message}")]
+    InvalidDocumentField { path: String, message: String },
+}
+
+/// Stable name for each kind of error, logged as `error_kind` on error
+/// events and used as the `kind` label of `device_ops_errors_total`. Alerts
+/// can key off these instead of message text, which may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Ipc,
+    Execution,
+    CommandNotFound,
+    Security,
+    Config,
+    Timeout,
+    IdleTimeout,
+    InvalidDocument,
+}
+
+impl ErrorKind {
+    /// Every kind in declaration order, following [`Self::next`] from the first
+    pub const ALL: [Self; Self::COUNT] = {
+        let mut all = [Self::Ipc; Self::COUNT];
+        let mut i = 1;
+        while i < Self::COUNT {
+            all[i] = match all[i - 1].next() {
+                Some(kind) => kind,
+                None => unreachable!(),
+            };
+            // The chain follows the declaration order
+            assert!(all[i] as usize == i);
+            i += 1;
+        }
+        all
+    };
+
+    const COUNT: usize = {
+        let mut count = 1;
+        let mut kind = Self::Ipc;
+        while let Some(next) = kind.next() {
+            kind = next;
+            count += 1;
+        }
+        count
+    };
+
+    /// The kind declared after this one. The match is exhaustive, so a new
+    /// kind does not compile until it is chained in here, which puts it in
+    /// [`Self::ALL`].
+    const fn next(self) -> Option<Self> {
+        match self {
+            Self::Ipc => Some(Self::Execution),
+            Self::Execution => Some(Self::CommandNotFound),
+            Self::CommandNotFound => Some(Self::Security),
+            Self::Security => Some(Self::Config),
+            Self::Config => Some(Self::Timeout),
+            Self::Timeout => Some(Self::IdleTimeout),
+            Self::IdleTimeout => Some(Self::InvalidDocument),
+            Self::InvalidDocument => None,
+        }
+    }
+
+    /// Label value; `timeout` matches the step `reason_code` of the same name
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Ipc => "ipc",
+            Self::Execution => "execution",
+            Self::CommandNotFound => "command_not_found",
+            Self::Security => "security",
+            Self::Config => "config",
+            Self::Timeout => "timeout",
+            Self::IdleTimeout => "idle_timeout",
+            Self::InvalidDocument => "invalid_document",
+        }
+    }
+}
+
+impl DeviceOpsError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::IpcError(_) | Self::Ipc { .. } => ErrorKind::Ipc,
+            Self::ExecutionError(_)
+            | Self::ExecutionIo { .. }
+            | Self::ExecutionJson { .. }
+            | Self::SpawnFailed { .. }
+            | Self::WaitFailed { .. }
+            | Self::VerificationFailed { .. }
+            | Self::OutputCaptureFailed { .. } => ErrorKind::Execution,
+            Self::CommandNotFound(_) => ErrorKind::CommandNotFound,
+            Self::SecurityError(_) => ErrorKind::Security,
+            Self::ConfigError(_) | Self::ConfigIo { .. } | Self::ConfigJson { .. } => {
+                ErrorKind::Config
+            }
+            Self::TimeoutError { .. } => ErrorKind::Timeout,
+            Self::IdleTimeout { .. } => ErrorKind::IdleTimeout,
+            Self::InvalidJobDocument(_) | Self::InvalidDocumentField { .. } => {
+                ErrorKind::InvalidDocument
+            }
+        }
+    }
+
+    /// `reason_code` reported in statusDetails when a job fails with this error
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            Self::IpcError(_) | Self::Ipc { .. } => "IPC_FAILED",
+            Self::ExecutionError(_) | Self::ExecutionIo { .. } | Self::ExecutionJson { .. } => {
+                "EXECUTION_FAILED"
+            }
+            Self::SpawnFailed { .. } => "SPAWN_FAILED",
+            Self::WaitFailed { .. } => "WAIT_FAILED",
+            Self::VerificationFailed { .. } => "VERIFICATION_FAILED",
+            Self::OutputCaptureFailed { .. } => "OUTPUT_CAPTURE_FAILED",
+            Self::CommandNotFound(_) => "COMMAND_NOT_FOUND",
+            Self::SecurityError(_) => "SECURITY_REJECTED",
+            Self::ConfigError(_) | Self::ConfigIo { .. } | Self::ConfigJson { .. } => {
+                "CONFIG_ERROR"
+            }
+            Self::TimeoutError { .. } => "TIMEOUT",
+            Self::IdleTimeout { .. } => "IDLE_TIMEOUT",
+            Self::InvalidJobDocument(_) | Self::InvalidDocumentField { .. } => "INVALID_DOCUMENT",
+        }
+    }
+
+    /// Whether trying again a moment later may succeed: IPC failures, runAsUser
+    /// checks that could not run, and I/O errors that only say the system was
+    /// busy (`EAGAIN`, `EINTR`, `ETXTBSY`, `ENOMEM`, timeouts) before the
+    /// command started. Once it started (`WaitFailed`, `OutputCaptureFailed`)
+    /// running it again could repeat its effects. Rejections, missing commands
+    /// and steps that ran into their timeout are terminal.
+    pub fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind as Io;
+        match self {
+            Self::IpcError(_) | Self::Ipc { .. } | Self::VerificationFailed { .. } => true,
+            Self::ExecutionIo { source, .. } | Self::SpawnFailed { source, .. } => matches!(
+                source.kind(),
+                Io::WouldBlock
+                    | Io::Interrupted
+                    | Io::TimedOut
+                    | Io::ExecutableFileBusy
+                    | Io::OutOfMemory
+            ),
+            _ => false,
+        }
+    }
+
+    /// For `map_err`: an IPC failure caused by `source`
+    pub fn ipc<E>(context: impl Into<String>) -> impl FnOnce(E) -> Self
+    where
+        E: Into<BoxError>,
+    {
+        let context = context.into();
+        move |source| Self::Ipc {
+            context,
+            source: source.into(),
+        }
+    }
+
+    /// For `map_err`: an execution failure caused by an I/O error
+    pub fn execution_io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |source| Self::ExecutionIo { context, source }
+    }
+
+    /// For `map_err`: an execution failure caused by (de)serialization
+    pub fn execution_json(context: impl Into<String>) -> impl FnOnce(serde_json::Error) -> Self {
+        let context = context.into();
+        move |source| Self::ExecutionJson { context, source }
+    }
+
+    /// For `map_err`: a configuration failure caused by an I/O error
+    pub fn config_io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |source| Self::ConfigIo { context, source }
+    }
+
+    /// For `map_err`: a configuration failure caused by (de)serialization
+    pub fn config_json(context: impl Into<String>) -> impl FnOnce(serde_json::Error) -> Self {
+        let context = context.into();
+        move |source| Self::ConfigJson { context, source }
+    }
 }
 
+/// An SDK error that only implements `Debug`, kept as the source of an IPC error
+#[derive(Debug)]
+pub struct SdkError(pub String);
+
+impl SdkError {
+    pub fn from_debug(error: impl std::fmt::Debug) -> Self {
+        Self(format!("{:?}", error))
+    }
+}
+
+impl std::fmt::Display for SdkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SdkError {}
+
 pub type Result<T> = std::result::Result<T, DeviceOpsError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alerts depend on these names: changing one, or adding a kind, must be
+    /// deliberate
+    #[test]
+    fn test_error_kinds_are_stable() {
+        let labels: Vec<&str> = ErrorKind::ALL.iter().map(|kind| kind.label()).collect();
+        assert_eq!(
+            labels,
+            [
+                "ipc",
+                "execution",
+                "command_not_found",
+                "security",
+                "config",
+                "timeout",
+                "idle_timeout",
+                "invalid_document",
+            ]
+        );
+
+        let errors = [
+            DeviceOpsError::IpcError(String::new()),
+            DeviceOpsError::ExecutionError(String::new()),
+            DeviceOpsError::CommandNotFound(String::new()),
+            DeviceOpsError::SecurityError(String::new()),
+            DeviceOpsError::ConfigError(String::new()),
+            DeviceOpsError::TimeoutError {
+                step: String::new(),
+                timeout_secs: 1,
+                elapsed_ms: 1000,
+                stdout: String::new(),
+                stderr: String::new(),
+            },
+            DeviceOpsError::IdleTimeout {
+                idle_secs: 1,
+                elapsed_ms: 1000,
+                stdout: String::new(),
+                stderr: String::new(),
+            },
+            DeviceOpsError::InvalidJobDocument(String::new()),
+        ];
+        let kinds: Vec<ErrorKind> = errors.iter().map(DeviceOpsError::kind).collect();
+        assert_eq!(kinds, ErrorKind::ALL);
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let spawn = |kind: std::io::ErrorKind| DeviceOpsError::SpawnFailed {
+            path: "/opt/a.sh".to_string(),
+            source: kind.into(),
+        };
+        for kind in [
+            std::io::ErrorKind::WouldBlock,
+            std::io::ErrorKind::ExecutableFileBusy,
+            std::io::ErrorKind::OutOfMemory,
+        ] {
+            assert!(spawn(kind).is_retryable(), "{:?}", kind);
+        }
+        assert!(DeviceOpsError::IpcError("Failed to publish".to_string()).is_retryable());
+
+        let terminal = [
+            spawn(std::io::ErrorKind::PermissionDenied),
+            DeviceOpsError::CommandNotFound("/opt/missing.sh".to_string()),
+            DeviceOpsError::SecurityError("not allowed".to_string()),
+            DeviceOpsError::TimeoutError {
+                step: "install".to_string(),
+                timeout_secs: 30,
+                elapsed_ms: 30000,
+                stdout: String::new(),
+                stderr: String::new(),
+            },
+            DeviceOpsError::InvalidJobDocument("no steps".to_string()),
+            DeviceOpsError::config_io("Failed to read config")(
+                std::io::ErrorKind::WouldBlock.into(),
+            ),
+        ];
+        for err in terminal {
+            assert!(!err.is_retryable(), "{}", err);
+        }
+    }
+
+    /// statusDetails consumers key off these codes: changing one must be deliberate
+    #[test]
+    fn test_reason_codes_and_retryability_are_stable() {
+        let io = |kind: std::io::ErrorKind| std::io::Error::from(kind);
+        let bad_json = || serde_json::from_str::<u8>("x").unwrap_err();
+        let cases = [
+            (
+                DeviceOpsError::SpawnFailed {
+                    path: "/opt/a.sh".to_string(),
+                    source: io(std::io::ErrorKind::ExecutableFileBusy),
+                },
+                "SPAWN_FAILED",
+                true,
+                "Job execution failed: cannot start /opt/a.sh: executable file busy",
+            ),
+            (
+                DeviceOpsError::SpawnFailed {
+                    path: "/opt/a.sh".to_string(),
+                    source: io(std::io::ErrorKind::PermissionDenied),
+                },
+                "SPAWN_FAILED",
+                false,
+                "Job execution failed: cannot start /opt/a.sh: permission denied",
+            ),
+            (
+                DeviceOpsError::WaitFailed {
+                    source: io(std::io::ErrorKind::Interrupted),
+                },
+                "WAIT_FAILED",
+                false,
+                "Job execution failed: lost track of the running command: operation interrupted",
+            ),
+            (
+                DeviceOpsError::VerificationFailed {
+                    user: "ops".to_string(),
+                    detail: "cannot run `id ops`: out of memory".to_string(),
+                },
+                "VERIFICATION_FAILED",
+                true,
+                "Job execution failed: cannot verify runAsUser 'ops': cannot run `id ops`: out of memory",
+            ),
+            (
+                DeviceOpsError::OutputCaptureFailed {
+                    stream: "stderr",
+                    source: io(std::io::ErrorKind::BrokenPipe),
+                },
+                "OUTPUT_CAPTURE_FAILED",
+                false,
+                "Job execution failed: cannot read the command's stderr: broken pipe",
+            ),
+            (
+                DeviceOpsError::ExecutionError("custom runner failed".to_string()),
+                "EXECUTION_FAILED",
+                false,
+                "Job execution failed: custom runner failed",
+            ),
+            (
+                DeviceOpsError::CommandNotFound("/opt/missing.sh".to_string()),
+                "COMMAND_NOT_FOUND",
+                false,
+                "command not found: /opt/missing.sh",
+            ),
+            (
+                DeviceOpsError::SecurityError("not allowed".to_string()),
+                "SECURITY_REJECTED",
+                false,
+                "Security validation failed: not allowed",
+            ),
+            (
+                DeviceOpsError::IdleTimeout {
+                    idle_secs: 30,
+                    elapsed_ms: 45_000,
+                    stdout: "starting".to_string(),
+                    stderr: String::new(),
+                },
+                "IDLE_TIMEOUT",
+                false,
+                "Idle timeout: command produced no output for 30 seconds",
+            ),
+            (
+                DeviceOpsError::IpcError("Failed to publish".to_string()),
+                "IPC_FAILED",
+                true,
+                "IPC connection failed: Failed to publish",
+            ),
+            (
+                DeviceOpsError::ipc("Failed to connect")(SdkError("refused".to_string())),
+                "IPC_FAILED",
+                true,
+                "IPC connection failed: Failed to connect: refused",
+            ),
+            (
+                DeviceOpsError::execution_io("Failed to open spool")(io(
+                    std::io::ErrorKind::WouldBlock,
+                )),
+                "EXECUTION_FAILED",
+                true,
+                "Job execution failed: Failed to open spool: operation would block",
+            ),
+            (
+                DeviceOpsError::execution_io("Failed to open spool")(io(
+                    std::io::ErrorKind::NotFound,
+                )),
+                "EXECUTION_FAILED",
+                false,
+                "Job execution failed: Failed to open spool: entity not found",
+            ),
+            (
+                DeviceOpsError::execution_json("Failed to parse output")(bad_json()),
+                "EXECUTION_FAILED",
+                false,
+                "Job execution failed: Failed to parse output: expected value at line 1 column 1",
+            ),
+            (
+                DeviceOpsError::ConfigError("bad profile".to_string()),
+                "CONFIG_ERROR",
+                false,
+                "Configuration error: bad profile",
+            ),
+            (
+                DeviceOpsError::config_io("Failed to read config")(io(
+                    std::io::ErrorKind::Interrupted,
+                )),
+                "CONFIG_ERROR",
+                false,
+                "Configuration error: Failed to read config: operation interrupted",
+            ),
+            (
+                DeviceOpsError::config_json("Failed to parse config")(bad_json()),
+                "CONFIG_ERROR",
+                false,
+                "Configuration error: Failed to parse config: expected value at line 1 column 1",
+            ),
+            (
+                DeviceOpsError::TimeoutError {
+                    step: "Install".to_string(),
+                    timeout_secs: 60,
+                    elapsed_ms: 60_000,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                },
+                "TIMEOUT",
+                false,
+                "Timeout: command exceeded 60 seconds",
+            ),
+            (
+                DeviceOpsError::InvalidJobDocument("no steps".to_string()),
+                "INVALID_DOCUMENT",
+                false,
+                "Invalid job document: no steps",
+            ),
+            (
+                DeviceOpsError::InvalidDocumentField {
+                    path: "steps[0].action.input.timeout".to_string(),
+                    message: "must be at least 1".to_string(),
+                },
+                "INVALID_DOCUMENT",
+                false,
+                "Invalid job document: steps[0].action.input.timeout: must be at least 1",
+            ),
+        ];
+        let mut covered = std::collections::BTreeSet::new();
+        for (err, code, retryable, message) in cases {
+            assert_eq!(err.reason_code(), code, "{}", err);
+            assert_eq!(err.is_retryable(), retryable, "{}", err);
+            assert_eq!(err.to_string(), message);
+            assert!(ErrorKind::ALL.contains(&err.kind()), "{}", err);
+            covered.insert(variant(&err));
+        }
+        assert_eq!(covered.len(), VARIANTS, "{:?}", covered);
+    }
+
+    /// Number of `DeviceOpsError` variants
+    const VARIANTS: usize = 18;
+
+    /// Exhaustive, so a new variant does not compile until it has a case in
+    /// the reason code test
+    fn variant(err: &DeviceOpsError) -> &'static str {
+        match err {
+            DeviceOpsError::IpcError(_) => "IpcError",
+            DeviceOpsError::Ipc { .. } => "Ipc",
+            DeviceOpsError::ExecutionError(_) => "ExecutionError",
+            DeviceOpsError::SpawnFailed { .. } => "SpawnFailed",
+            DeviceOpsError::WaitFailed { .. } => "WaitFailed",
+            DeviceOpsError::VerificationFailed { .. } => "VerificationFailed",
+            DeviceOpsError::OutputCaptureFailed { .. } => "OutputCaptureFailed",
+            DeviceOpsError::ExecutionIo { .. } => "ExecutionIo",
+            DeviceOpsError::ExecutionJson { .. } => "ExecutionJson",
+            DeviceOpsError::CommandNotFound(_) => "CommandNotFound",
+            DeviceOpsError::SecurityError(_) => "SecurityError",
+            DeviceOpsError::ConfigError(_) => "ConfigError",
+            DeviceOpsError::ConfigIo { .. } => "ConfigIo",
+            DeviceOpsError::ConfigJson { .. } => "ConfigJson",
+            DeviceOpsError::TimeoutError { .. } => "TimeoutError",
+            DeviceOpsError::IdleTimeout { .. } => "IdleTimeout",
+            DeviceOpsError::InvalidJobDocument(_) => "InvalidJobDocument",
+            DeviceOpsError::InvalidDocumentField { .. } => "InvalidDocumentField",
+        }
+    }
+
+    #[test]
+    fn test_sourced_variants_keep_message_and_kind() {
+        use std::error::Error;
+
+        let not_found = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        let bad_json = || serde_json::from_str::<u8>("x").unwrap_err();
+        let cases = [
+            (
+                DeviceOpsError::ipc("Failed to connect to IPC")(SdkError("Refused".to_string())),
+                ErrorKind::Ipc,
+                "IPC connection failed: Failed to connect to IPC: Refused".to_string(),
+            ),
+            (
+                DeviceOpsError::execution_io("Failed to spawn command")(not_found()),
+                ErrorKind::Execution,
+                format!(
+                    "Job execution failed: Failed to spawn command: {}",
+                    not_found()
+                ),
+            ),
+            (
+                DeviceOpsError::execution_json("Failed to serialize last run")(bad_json()),
+                ErrorKind::Execution,
+                format!(
+                    "Job execution failed: Failed to serialize last run: {}",
+                    bad_json()
+                ),
+            ),
+            (
+                DeviceOpsError::config_io("Failed to read config")(not_found()),
+                ErrorKind::Config,
+                format!(
+                    "Configuration error: Failed to read config: {}",
+                    not_found()
+                ),
+            ),
+            (
+                DeviceOpsError::config_json("Failed to parse config")(bad_json()),
+                ErrorKind::Config,
+                format!(
+                    "Configuration error: Failed to parse config: {}",
+                    bad_json()
+                ),
+            ),
+        ];
+        for (err, kind, message) in cases {
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.to_string(), message);
+            assert!(err.source().is_some(), "{}", message);
+        }
+    }
+}
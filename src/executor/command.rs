@@ -1,15 +1,39 @@
+use crate::backoff::Backoff;
+use crate::clock::{Clock, SystemClock};
 use crate::config::ExecutionConfig;
-use crate::error::{DeviceOpsError, Result};
-use crate::models::{Command, ExecutionOutput, JobDocument, JobExecutionResult, StepOutput};
+use crate::error::{DeviceOpsError, ErrorKind, Result};
+use crate::executor::env::expand_env_refs;
+use crate::executor::ids;
+use crate::executor::output_log::{LineLogger, LiveOutput};
+use crate::executor::power::{BatteryProbe, SystemBatteryProbe};
+use crate::executor::spawn::{self, Process};
+use crate::metrics::{self, SecurityRule, StepResult};
+use crate::models::{
+    AttemptRecord, Command, ExecutionOutput, FailureClass, GroupFailurePolicy, JobDocument,
+    JobExecutionResult, RunAsUserFallback, StepFailureReason, StepOutput, StepTiming,
+    TerminationSignal,
+};
 use crate::security::SecurityValidator;
 use async_trait::async_trait;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::process::Command as TokioCommand;
-use tokio::time::timeout;
+use tokio::sync::{Mutex as AsyncMutex, Notify, OwnedMutexGuard, Semaphore};
+use tokio::time::{timeout, timeout_at};
+use tracing::Instrument;
 
-const MAX_OUTPUT_LINES: usize = 1000;
-const MAX_OUTPUT_BYTES: usize = 32 * 1024; // 32KB limit for IoT Jobs statusDetails
+/// A stderr line starting with this, followed by a JSON object, is the
+/// script's structured error. It is removed from stderr and does not count
+/// against `allowStdErr`.
+pub const STRUCTURED_ERROR_PREFIX: &str = "DEVICE_OPS_ERROR ";
+
+/// Longest wait between two attempts of a step with `retries`
+const STEP_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
 /// Trait for running commands - allows mocking in tests
 #[async_trait]
@@ -27,116 +51,549 @@ impl CommandRunner for SystemCommandRunner {
             script = %command.script_path,
             args = ?command.args,
             run_as_user = ?command.run_as_user,
+            slice = ?command.slice,
             "Executing command"
         );
 
-        let mut cmd = if let Some(user) = &command.run_as_user {
-            // Build: sudo -u $user -n command args...
-            let mut sudo_cmd = TokioCommand::new("sudo");
-            sudo_cmd.arg("-u").arg(user).arg("-n");
-            sudo_cmd.arg(&command.script_path);
-            sudo_cmd.args(&command.args);
-            sudo_cmd
-        } else {
-            let mut cmd = TokioCommand::new(&command.script_path);
-            cmd.args(&command.args);
-            cmd
-        };
+        let (program, args) = Self::command_line(command);
+        let mut cmd = TokioCommand::new(&program);
+        cmd.args(&args);
+        cmd.envs(command.env.iter().map(|(name, value)| (name, value)));
+        if let Some(path) = &command.sanitize_path {
+            if !command.env.iter().any(|(name, _)| name == "PATH") {
+                cmd.env("PATH", path);
+            }
+        }
+
+        if let Some(dir) = &command.working_dir {
+            cmd.current_dir(dir);
+        }
 
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        // Spawn the process so we can stop it on timeout
+        let spawn_start = std::time::Instant::now();
+        let (child, stdout_pipe, stderr_pipe) = spawn::spawn(&mut cmd, command.spawn_method)
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    // With a slice, wrapper or runAsUser the spawned program is systemd-run, the wrapper or sudo
+                    DeviceOpsError::CommandNotFound(program.clone())
+                } else {
+                    DeviceOpsError::SpawnFailed {
+                        path: program.clone(),
+                        source: e,
+                    }
+                }
+            })?;
 
-        // Spawn the process so we can kill it on timeout
-        let child = cmd.spawn().map_err(|e| {
-            DeviceOpsError::ExecutionError(format!("Failed to spawn command: {}", e))
-        })?;
+        let spawned = std::time::Instant::now();
+        let mut running = RunningChild {
+            child: Some(child),
+            signal: command.stop_signal,
+            grace: command.stop_timeout,
+            escalate_to_kill: command.escalate_to_kill,
+        };
 
-        let output = child.wait_with_output().await.map_err(|e| {
-            DeviceOpsError::ExecutionError(format!("Failed to execute command: {}", e))
-        })?;
+        // Drain both pipes while waiting so a chatty command cannot block on a full pipe;
+        // what was read so far is kept in `captured` for a command that times out
+        let output = Notify::new();
+        let captured = (Mutex::new(Vec::new()), Mutex::new(Vec::new()));
+        let log = |stream| {
+            LineLogger::new(
+                stream,
+                command.log_output.as_ref(),
+                command.live_output.as_ref(),
+            )
+        };
+        let exit = async {
+            let status = running
+                .wait()
+                .await
+                .map_err(|source| DeviceOpsError::WaitFailed { source })?;
+            Ok((status, std::time::Instant::now()))
+        };
+        let capture = |stream| move |source| DeviceOpsError::OutputCaptureFailed { stream, source };
+        let finished = async {
+            tokio::try_join!(
+                exit,
+                async {
+                    read_pipe(stdout_pipe, &captured.0, &output, log("stdout"))
+                        .await
+                        .map_err(capture("stdout"))
+                },
+                async {
+                    read_pipe(stderr_pipe, &captured.1, &output, log("stderr"))
+                        .await
+                        .map_err(capture("stderr"))
+                }
+            )
+        };
+        let idle = async {
+            match command.idle_timeout {
+                Some(idle) => wait_idle(&output, idle).await,
+                None => std::future::pending().await,
+            }
+        };
+        let deadline = async {
+            match command.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        let text =
+            |bytes: &Mutex<Vec<u8>>| String::from_utf8_lossy(&bytes.lock().unwrap()).into_owned();
+        // Dropping `running` on either timeout stops the command
+        let result = tokio::select! {
+            result = finished => result,
+            () = idle => {
+                let idle = command.idle_timeout.unwrap_or_default();
+                tracing::error!(
+                    idle_secs = idle.as_secs(),
+                    error_kind = ErrorKind::IdleTimeout.label(),
+                    "Command produced no output, stopping it"
+                );
+                return Err(DeviceOpsError::IdleTimeout {
+                    idle_secs: idle.as_secs(),
+                    elapsed_ms: spawn_start.elapsed().as_millis() as u64,
+                    stdout: text(&captured.0),
+                    stderr: text(&captured.1),
+                });
+            }
+            () = deadline => {
+                // The executor fills in the step and its timeout
+                return Err(DeviceOpsError::TimeoutError {
+                    step: String::new(),
+                    timeout_secs: 0,
+                    elapsed_ms: spawn_start.elapsed().as_millis() as u64,
+                    stdout: text(&captured.0),
+                    stderr: text(&captured.1),
+                });
+            }
+        };
+        let ((status, exited), (), ()) = result?;
+        let stdout = captured.0.into_inner().unwrap();
+        let stderr = captured.1.into_inner().unwrap();
+        let drained = std::time::Instant::now();
+        let timing = StepTiming {
+            spawn_ms: spawned.duration_since(spawn_start).as_millis() as u64,
+            run_ms: exited.duration_since(spawned).as_millis() as u64,
+            drain_ms: drained.duration_since(exited).as_millis() as u64,
+            ..Default::default()
+        };
 
-        let (stdout, stdout_truncated) = Self::limit_output(&output.stdout);
-        let (stderr, stderr_truncated) = Self::limit_output(&output.stderr);
+        // Output limits are applied by the executor
+        let stdout = String::from_utf8_lossy(&stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&stderr).into_owned();
         let stderr_line_count = stderr.lines().count();
-        let exit_code = output.status.code().unwrap_or(-1);
+        let exit_code = status.code().unwrap_or(-1);
 
         tracing::info!(
             exit_code = exit_code,
             stdout_len = stdout.len(),
             stderr_len = stderr.len(),
             stderr_lines = stderr_line_count,
-            stdout_truncated = stdout_truncated,
-            stderr_truncated = stderr_truncated,
             "Command execution completed"
         );
 
+        // A step run through sudo may never have started
+        let failure_class = match sudo_denial(exit_code, &stderr) {
+            Some(denial) if command.run_as_user.is_some() => {
+                tracing::error!(user = ?command.run_as_user, denial = %denial, "sudo refused to run the command");
+                Some(FailureClass::Privilege)
+            }
+            _ => None,
+        };
+
         Ok(ExecutionOutput {
             stdout,
             stderr,
             exit_code,
             execution_time_ms: 0, // Will be set by caller
             stderr_line_count,
-            stdout_truncated,
-            stderr_truncated,
+            stdout_truncated: false,
+            stderr_truncated: false,
+            started_at: 0, // Will be set by caller
+            finished_at: 0,
+            attempt_history: Vec::new(),
+            structured_error: None,
+            termination_signal: None,
+            failure_class,
+            // Set by `execute_step_with_retries`
+            fail_reason: None,
+            policy_violation: None,
+            // Set by `execute_step`
+            ran_as_uid: None,
+            ran_as_gid: None,
+            timing,
         })
     }
 }
 
+/// sudo's own message when it refused to run a command: sudo exits 1 and,
+/// as the command never started, its message is the first stderr line
+pub fn sudo_denial(exit_code: i32, stderr: &str) -> Option<&str> {
+    const DENIALS: &[&str] = &[
+        "a password is required",
+        "a terminal is required",
+        "no tty present",
+        "unknown user",
+        "is not in the sudoers file",
+        "is not allowed to execute",
+        "is not allowed to run sudo",
+        "account validation failure",
+        "you do not exist in the passwd database",
+    ];
+    if exit_code != 1 {
+        return None;
+    }
+    let first = stderr
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?;
+    let from_sudo = first.starts_with("sudo: ") || first.starts_with("Sorry, user ");
+    (from_sudo && DENIALS.iter().any(|denial| first.contains(denial))).then_some(first)
+}
+
 impl SystemCommandRunner {
-    /// Limit output to MAX_OUTPUT_LINES and MAX_OUTPUT_BYTES
-    fn limit_output(bytes: &[u8]) -> (String, bool) {
-        let full_output = String::from_utf8_lossy(bytes);
-        let lines: Vec<&str> = full_output.lines().collect();
+    /// Program and arguments to spawn: the script, wrapped in
+    /// `sudo -u <user> -n` for runAsUser, then in the configured command
+    /// wrapper and then in `systemd-run --scope --slice=<slice>` for a cgroup
+    /// slice. The wrapper stays outside sudo, so sudoers rules keep naming
+    /// the scripts themselves; CPU affinity and scheduling carry over into
+    /// sudo and the script.
+    fn command_line(command: &Command) -> (String, Vec<String>) {
+        let mut line = Vec::new();
+        if let Some(slice) = &command.slice {
+            line.extend([
+                "systemd-run".to_string(),
+                "--scope".to_string(),
+                "--quiet".to_string(),
+                format!("--slice={}", slice),
+                "--".to_string(),
+            ]);
+        }
+        line.extend(command.wrapper.iter().cloned());
+        if let Some(user) = &command.run_as_user {
+            line.extend([
+                "sudo".to_string(),
+                "-u".to_string(),
+                user.clone(),
+                "-n".to_string(),
+            ]);
+        }
+        line.push(command.script_path.clone());
+        line.extend(command.args.iter().cloned());
+
+        let program = line.remove(0);
+        (program, line)
+    }
+}
 
-        let mut truncated = false;
-        let mut result = String::new();
+/// A spawned command. If it is dropped before exiting (the step timed out),
+/// it is sent `signal` and, with `escalate_to_kill`, killed once `grace`
+/// has passed.
+struct RunningChild {
+    child: Option<Process>,
+    signal: TerminationSignal,
+    grace: Duration,
+    escalate_to_kill: bool,
+}
 
-        // Limit by line count
-        let lines_to_take = if lines.len() > MAX_OUTPUT_LINES {
-            truncated = true;
-            MAX_OUTPUT_LINES
-        } else {
-            lines.len()
+impl RunningChild {
+    async fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        let status = match self.child.as_mut() {
+            Some(child) => child.wait().await?,
+            None => unreachable!("waited on a command twice"),
+        };
+        self.child = None;
+        Ok(status)
+    }
+}
+
+impl Drop for RunningChild {
+    fn drop(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
         };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(stop_child(
+                    child,
+                    self.signal,
+                    self.grace,
+                    self.escalate_to_kill,
+                ));
+            }
+            Err(_) => {
+                let _ = child.start_kill();
+            }
+        }
+    }
+}
 
-        for (idx, line) in lines.iter().take(lines_to_take).enumerate() {
-            if idx > 0 {
-                result.push('\n');
+/// Send `signal` to a timed-out command, then SIGKILL if it is still
+/// running after `grace` and `escalate_to_kill` is set
+async fn stop_child(
+    mut child: Process,
+    signal: TerminationSignal,
+    grace: Duration,
+    escalate_to_kill: bool,
+) {
+    let pid = child.id();
+    if signal != TerminationSignal::Kill {
+        match child.signal(signal) {
+            Ok(()) if !escalate_to_kill => {
+                // Reap it whenever it exits
+                let _ = child.wait().await;
+                return;
+            }
+            Ok(()) => {
+                if timeout(grace, child.wait()).await.is_ok() {
+                    tracing::info!(pid, signal = %signal, "Stopped timed-out command");
+                    return;
+                }
+                tracing::warn!(
+                    pid,
+                    signal = %signal,
+                    grace_secs = grace.as_secs(),
+                    "Timed-out command did not exit, killing it"
+                );
+            }
+            Err(e) => {
+                tracing::warn!(
+                    pid,
+                    signal = %signal,
+                    error = %e,
+                    "Failed to signal timed-out command, killing it"
+                );
             }
-            result.push_str(line);
+        }
+    }
 
-            // Check if we're approaching byte limit
-            if result.len() > MAX_OUTPUT_BYTES - 100 {
-                truncated = true;
+    if let Err(e) = child.kill().await {
+        tracing::error!(error_kind = ErrorKind::Execution.label(), error = %e, "Failed to kill timed-out command");
+    }
+}
+
+/// Read a pipe to its end into `bytes`, signalling `output` on every chunk
+/// read and passing the chunks to `log`
+async fn read_pipe<P: AsyncRead + Unpin>(
+    pipe: Option<P>,
+    bytes: &Mutex<Vec<u8>>,
+    output: &Notify,
+    mut log: Option<LineLogger>,
+) -> std::io::Result<()> {
+    if let Some(mut pipe) = pipe {
+        let mut chunk = [0u8; 8192];
+        loop {
+            let read = pipe.read(&mut chunk).await?;
+            if read == 0 {
                 break;
             }
+            bytes.lock().unwrap().extend_from_slice(&chunk[..read]);
+            if let Some(log) = log.as_mut() {
+                log.feed(&chunk[..read]);
+            }
+            output.notify_one();
+        }
+    }
+    if let Some(log) = log {
+        log.finish();
+    }
+    Ok(())
+}
+
+/// Complete once `output` has not been signalled for `idle`
+async fn wait_idle(output: &Notify, idle: Duration) {
+    while timeout(idle, output.notified()).await.is_ok() {}
+}
+
+/// Keep at most `max_lines` lines and about `max_bytes` bytes of output;
+/// returns the kept text and whether anything was cut
+fn limit_output(text: &str, max_lines: usize, max_bytes: usize) -> (String, bool) {
+    let lines: Vec<&str> = text.lines().collect();
+
+    let mut truncated = false;
+    let mut result = String::new();
+
+    // Limit by line count
+    let lines_to_take = if lines.len() > max_lines {
+        truncated = true;
+        max_lines
+    } else {
+        lines.len()
+    };
+
+    for (idx, line) in lines.iter().take(lines_to_take).enumerate() {
+        if idx > 0 {
+            result.push('\n');
+        }
+        result.push_str(line);
+
+        // Check if we're approaching byte limit
+        if result.len() > max_bytes.saturating_sub(100) {
+            truncated = true;
+            break;
+        }
+    }
+
+    if truncated {
+        result.push_str("\n[Output truncated: exceeded limit]");
+    }
+
+    // Final truncation to ensure we don't exceed byte limit
+    if result.len() > max_bytes {
+        let mut end = max_bytes.saturating_sub(50);
+        while !result.is_char_boundary(end) {
+            end -= 1;
         }
+        result.truncate(end);
+        result.push_str("\n[Output truncated: size limit]");
+    }
+
+    (result, truncated)
+}
+
+/// Outcome of checking whether a step can run as a user
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunAsCheck {
+    Available,
+    /// sudo, the user or passwordless sudo is missing
+    Unavailable,
+    /// The named probe did not answer in time, e.g. `id` on a device whose
+    /// user directory hangs
+    TimedOut(String),
+}
+
+/// Trait for checking whether commands can be run as another user - allows mocking in tests
+#[async_trait]
+pub trait SudoVerifier: Send + Sync {
+    /// Whether sudo exists, the user exists and passwordless sudo to it
+    /// works, giving each probe up to `probe_timeout`
+    async fn verify(&self, user: &str, probe_timeout: Duration) -> Result<RunAsCheck>;
+}
+
+/// Check at startup that `execution.default_run_as_user` can be used, so a
+/// misconfigured device fails before any job arrives
+pub async fn verify_default_run_as_user(
+    config: &ExecutionConfig,
+    verifier: &dyn SudoVerifier,
+) -> Result<()> {
+    let Some(user) = &config.default_run_as_user else {
+        return Ok(());
+    };
+    let check = if user.is_empty() {
+        RunAsCheck::Unavailable
+    } else {
+        verifier
+            .verify(user, Duration::from_secs(config.verification_timeout_secs))
+            .await?
+    };
+    match check {
+        RunAsCheck::Available => Ok(()),
+        RunAsCheck::Unavailable => Err(DeviceOpsError::ConfigError(format!(
+            "execution.default_run_as_user: cannot run as '{}' (sudo, the user or passwordless sudo is unavailable)",
+            user
+        ))),
+        RunAsCheck::TimedOut(probe) => Err(DeviceOpsError::ConfigError(format!(
+            "execution.default_run_as_user: cannot verify '{}' ({} timed out after {}s)",
+            user, probe, config.verification_timeout_secs
+        ))),
+    }
+}
+
+/// Real verifier that probes sudo and the user database
+pub struct SystemSudoVerifier;
 
-        if truncated {
-            result.push_str("\n[Output truncated: exceeded limit]");
+impl SystemSudoVerifier {
+    /// Run a probe, returning whether it succeeded or `None` if it outlived
+    /// `probe_timeout` (the probe is killed then)
+    async fn probe(
+        program: &str,
+        args: &[&str],
+        probe_timeout: Duration,
+    ) -> std::io::Result<Option<bool>> {
+        let output = TokioCommand::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        match timeout(probe_timeout, output).await {
+            Ok(output) => output.map(|output| Some(output.status.success())),
+            Err(_) => Ok(None),
         }
+    }
+}
 
-        // Final truncation to ensure we don't exceed byte limit
-        if result.len() > MAX_OUTPUT_BYTES {
-            result.truncate(MAX_OUTPUT_BYTES - 50);
-            result.push_str("\n[Output truncated: size limit]");
+#[async_trait]
+impl SudoVerifier for SystemSudoVerifier {
+    async fn verify(&self, user: &str, probe_timeout: Duration) -> Result<RunAsCheck> {
+        let probes: [(&str, &[&str], &str); 3] = [
+            ("which", &["sudo"], "sudo command not found"),
+            ("id", &[user], "User does not exist"),
+            // Passwordless sudo: -n fails instead of prompting
+            (
+                "sudo",
+                &["-n", "-u", user, "true"],
+                "Passwordless sudo not configured for user",
+            ),
+        ];
+
+        for (program, args, failure) in probes {
+            let probe = format!("{} {}", program, args.join(" "));
+            let outcome = Self::probe(program, args, probe_timeout)
+                .await
+                .map_err(|e| DeviceOpsError::VerificationFailed {
+                    user: user.to_string(),
+                    detail: format!("cannot run `{}`: {}", probe, e),
+                })?;
+            match outcome {
+                Some(true) => {}
+                Some(false) => {
+                    tracing::warn!(user = %user, "{}", failure);
+                    return Ok(RunAsCheck::Unavailable);
+                }
+                None => {
+                    tracing::warn!(
+                        user = %user,
+                        probe = %probe,
+                        timeout_secs = probe_timeout.as_secs(),
+                        "runAsUser verification timed out"
+                    );
+                    return Ok(RunAsCheck::TimedOut(probe));
+                }
+            }
         }
 
-        (result, truncated)
+        Ok(RunAsCheck::Available)
     }
 }
 
 pub struct CommandExecutor<R: CommandRunner = SystemCommandRunner> {
-    config: ExecutionConfig,
-    security: Option<SecurityValidator>,
+    /// Swapped by `reconfigure`; each step reads a snapshot
+    config: RwLock<Arc<ExecutionConfig>>,
+    security: RwLock<Option<Arc<SecurityValidator>>>,
     runner: R,
+    sudo_verifier: Box<dyn SudoVerifier>,
+    battery_probe: Box<dyn BatteryProbe>,
+    clock: Arc<dyn Clock>,
+    /// One permit per job allowed to run at once (`max_concurrent_jobs`)
+    job_slots: RwLock<Arc<Semaphore>>,
+    /// Per resolved script path, held by `serializeByCommand` steps while they run
+    command_locks: Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>,
 }
 
 impl CommandExecutor<SystemCommandRunner> {
     pub fn new(config: ExecutionConfig, security: Option<SecurityValidator>) -> Self {
         Self {
-            config,
-            security,
+            job_slots: RwLock::new(Arc::new(Semaphore::new(config.max_concurrent_jobs))),
+            config: RwLock::new(Arc::new(config)),
+            security: RwLock::new(security.map(Arc::new)),
             runner: SystemCommandRunner,
+            sudo_verifier: Box::new(SystemSudoVerifier),
+            battery_probe: Box::new(SystemBatteryProbe::default()),
+            clock: Arc::new(SystemClock),
+            command_locks: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -150,40 +607,152 @@ impl<R: CommandRunner> CommandExecutor<R> {
         runner: R,
     ) -> Self {
         Self {
-            config,
-            security,
+            job_slots: RwLock::new(Arc::new(Semaphore::new(config.max_concurrent_jobs))),
+            config: RwLock::new(Arc::new(config)),
+            security: RwLock::new(security.map(Arc::new)),
             runner,
+            sudo_verifier: Box::new(SystemSudoVerifier),
+            battery_probe: Box::new(SystemBatteryProbe::default()),
+            clock: Arc::new(SystemClock),
+            command_locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Use a different sudo verifier (for testing)
+    #[cfg(test)]
+    pub fn with_sudo_verifier(mut self, verifier: impl SudoVerifier + 'static) -> Self {
+        self.sudo_verifier = Box::new(verifier);
+        self
+    }
+
+    /// Use a different battery probe (for testing)
+    #[cfg(test)]
+    pub fn with_battery_probe(mut self, probe: impl BatteryProbe + 'static) -> Self {
+        self.battery_probe = Box::new(probe);
+        self
+    }
+
+    /// Use a different clock for step timing (for testing)
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Apply new execution settings and allowlists; steps already running keep the old ones
+    pub fn reconfigure(&self, config: ExecutionConfig, security: Option<SecurityValidator>) {
+        if config.max_concurrent_jobs != self.config().max_concurrent_jobs {
+            *self.job_slots.write().unwrap() = Arc::new(Semaphore::new(config.max_concurrent_jobs));
         }
+        *self.config.write().unwrap() = Arc::new(config);
+        *self.security.write().unwrap() = security.map(Arc::new);
+    }
+
+    fn config(&self) -> Arc<ExecutionConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    fn security(&self) -> Option<Arc<SecurityValidator>> {
+        self.security.read().unwrap().clone()
     }
 
     /// Execute all steps in the job document sequentially
     pub async fn execute(&self, job_document: &JobDocument) -> Result<JobExecutionResult> {
+        self.execute_reporting(job_document, &|_| {}).await
+    }
+
+    /// Like `execute`, calling `on_step` as each step (including the final
+    /// step) finishes. Skipped steps are not reported.
+    pub async fn execute_reporting(
+        &self,
+        job_document: &JobDocument,
+        on_step: &(dyn Fn(StepReport<'_>) + Send + Sync),
+    ) -> Result<JobExecutionResult> {
+        self.execute_streaming(job_document, on_step, None).await
+    }
+
+    /// Like `execute_reporting`, also passing the steps' output lines to
+    /// `live` as they are read
+    pub async fn execute_streaming(
+        &self,
+        job_document: &JobDocument,
+        on_step: &(dyn Fn(StepReport<'_>) + Send + Sync),
+        live: Option<LiveOutput>,
+    ) -> Result<JobExecutionResult> {
+        let slots = self.job_slots.read().unwrap().clone();
+        let _slot = slots
+            .acquire_owned()
+            .await
+            .map_err(|e| DeviceOpsError::ExecutionError(format!("Job slots unavailable: {}", e)))?;
+
+        if let Some(reason) = self.check_battery(job_document) {
+            tracing::warn!(reason = %reason, "Not starting job");
+            return Ok(JobExecutionResult {
+                outputs: Vec::new(),
+                overall_success: false,
+                failed_step: None,
+                failure_reason: Some(reason),
+                reason_code: None,
+                retryable: false,
+            });
+        }
+
+        let job_document = &*with_document_run_as_user(job_document);
+
         let mut outputs = Vec::new();
         let mut overall_success = true;
         let mut failed_step = None;
+        let mut failure_reason = None;
+        let mut reason_code = None;
+        let mut retryable = false;
+
+        // Group whose remaining steps are skipped after an ignored group failure
+        let mut skipped_group: Option<&str> = None;
 
         // Execute all steps in sequence
         for (idx, step) in job_document.steps.iter().enumerate() {
-            tracing::info!(
-                step_number = idx + 1,
-                step_name = %step.action.name,
-                "Executing step"
-            );
+            let group = step.action.group.as_deref();
+            if group.is_some() && group == skipped_group {
+                tracing::info!(
+                    step_name = %step.action.name,
+                    group = ?group,
+                    "Skipping step, its group already failed under ignoreFailure"
+                );
+                continue;
+            }
 
-            match self.execute_step(&step.action).await {
-                Ok(output) => {
-                    let step_failed = !self.evaluate_step_success(&output, &step.action);
-                    let ignore_failure = step.action.ignore_step_failure.unwrap_or(false);
+            let span = step_span(&step.action, idx);
+            span.in_scope(|| tracing::info!("Executing step"));
+
+            let step_ignores = step.action.ignore_step_failure.unwrap_or(false);
+            let group_ignores =
+                job_document.group_policy(group) == GroupFailurePolicy::IgnoreFailure;
+            let ignore_failure = step_ignores || group_ignores;
+
+            let outcome = self
+                .execute_step_with_retries(&step.action, live.as_ref())
+                .instrument(span.clone())
+                .await;
+            let result = record_step_metric(&outcome);
+            // No more awaits for this step, so its span can stay entered
+            let _step = span.enter();
+            on_step(StepReport::new(&step.action, idx, result, &outcome));
+            match outcome {
+                Ok((output, succeeded)) => {
+                    let step_failed = !succeeded;
 
                     if step_failed && !ignore_failure {
                         tracing::error!(
-                            step_name = %step.action.name,
+                            reason_code = result.label(),
                             exit_code = output.exit_code,
                             stderr_lines = output.stderr_line_count,
                             "Step failed"
                         );
                         overall_success = false;
                         failed_step = Some(step.action.name.clone());
+                        if output.failure_class == Some(FailureClass::Privilege) {
+                            failure_reason = privilege_reason(&step.action, &output);
+                        }
 
                         outputs.push(StepOutput {
                             step_name: step.action.name.clone(),
@@ -193,11 +762,18 @@ impl<R: CommandRunner> CommandExecutor<R> {
                         break;
                     }
 
-                    if step_failed && ignore_failure {
+                    if step_failed && step_ignores {
                         tracing::warn!(
-                            step_name = %step.action.name,
+                            reason_code = result.label(),
                             "Step failed but ignoreStepFailure=true, continuing"
                         );
+                    } else if step_failed {
+                        tracing::warn!(
+                            reason_code = result.label(),
+                            group = ?group,
+                            "Step failed in ignoreFailure group, skipping rest of group"
+                        );
+                        skipped_group = group;
                     }
 
                     outputs.push(StepOutput {
@@ -207,24 +783,44 @@ impl<R: CommandRunner> CommandExecutor<R> {
                     });
                 }
                 Err(e) => {
-                    let ignore_failure = step.action.ignore_step_failure.unwrap_or(false);
+                    if let Some(output) = self.timed_out_output(&step.action, &e) {
+                        outputs.push(StepOutput {
+                            step_name: step.action.name.clone(),
+                            output,
+                            ignored_failure: ignore_failure,
+                        });
+                    }
 
                     if !ignore_failure {
                         tracing::error!(
-                            step_name = %step.action.name,
+                            reason_code = result.label(),
+                            error_kind = e.kind().label(),
                             error = %e,
                             "Step execution failed"
                         );
                         overall_success = false;
                         failed_step = Some(step.action.name.clone());
+                        failure_reason = Some(e.to_string());
+                        reason_code = Some(e.reason_code());
+                        retryable = e.is_retryable();
                         break;
                     }
 
-                    tracing::warn!(
-                        step_name = %step.action.name,
-                        error = %e,
-                        "Step execution failed but ignoreStepFailure=true, continuing"
-                    );
+                    if step_ignores {
+                        tracing::warn!(
+                            reason_code = result.label(),
+                            error = %e,
+                            "Step execution failed but ignoreStepFailure=true, continuing"
+                        );
+                    } else {
+                        tracing::warn!(
+                            reason_code = result.label(),
+                            group = ?group,
+                            error = %e,
+                            "Step execution failed in ignoreFailure group, skipping rest of group"
+                        );
+                        skipped_group = group;
+                    }
                 }
             }
         }
@@ -232,22 +828,32 @@ impl<R: CommandRunner> CommandExecutor<R> {
         // Execute final step if all steps succeeded
         if overall_success {
             if let Some(final_step) = &job_document.final_step {
-                tracing::info!(
-                    step_name = %final_step.action.name,
-                    "Executing final step"
-                );
+                let span = step_span(&final_step.action, job_document.steps.len());
+                span.in_scope(|| tracing::info!("Executing final step"));
 
-                match self.execute_step(&final_step.action).await {
-                    Ok(output) => {
-                        let step_failed = !self.evaluate_step_success(&output, &final_step.action);
+                let outcome = self
+                    .execute_step_with_retries(&final_step.action, live.as_ref())
+                    .instrument(span.clone())
+                    .await;
+                let result = record_step_metric(&outcome);
+                let _step = span.enter();
+                on_step(StepReport::new(
+                    &final_step.action,
+                    job_document.steps.len(),
+                    result,
+                    &outcome,
+                ));
+                match outcome {
+                    Ok((output, succeeded)) => {
+                        let step_failed = !succeeded;
 
                         if step_failed {
-                            tracing::error!(
-                                step_name = %final_step.action.name,
-                                "Final step failed"
-                            );
+                            tracing::error!(reason_code = result.label(), "Final step failed");
                             overall_success = false;
                             failed_step = Some(final_step.action.name.clone());
+                            if output.failure_class == Some(FailureClass::Privilege) {
+                                failure_reason = privilege_reason(&final_step.action, &output);
+                            }
                         }
 
                         outputs.push(StepOutput {
@@ -258,12 +864,23 @@ impl<R: CommandRunner> CommandExecutor<R> {
                     }
                     Err(e) => {
                         tracing::error!(
-                            step_name = %final_step.action.name,
+                            reason_code = result.label(),
+                            error_kind = e.kind().label(),
                             error = %e,
                             "Final step execution failed"
                         );
+                        if let Some(output) = self.timed_out_output(&final_step.action, &e) {
+                            outputs.push(StepOutput {
+                                step_name: final_step.action.name.clone(),
+                                output,
+                                ignored_failure: false,
+                            });
+                        }
                         overall_success = false;
                         failed_step = Some(final_step.action.name.clone());
+                        failure_reason = Some(e.to_string());
+                        reason_code = Some(e.reason_code());
+                        retryable = e.is_retryable();
                     }
                 }
             }
@@ -273,170 +890,741 @@ impl<R: CommandRunner> CommandExecutor<R> {
             outputs,
             overall_success,
             failed_step,
+            failure_reason,
+            reason_code,
+            retryable,
         })
     }
 
-    /// Execute a single step
-    async fn execute_step(&self, action: &crate::models::JobAction) -> Result<ExecutionOutput> {
-        let command = self.build_command(action)?;
-
-        // Security validation (if enabled)
-        if let Some(validator) = &self.security {
-            validator.validate(&command)?;
+    /// Check that the document's steps, run to their timeouts with every
+    /// retry and delay, end `reporting_headroom_secs` before its
+    /// `jobTimeoutSecs`, leaving time to report the result. A tight document
+    /// is logged, or rejected with `reject_tight_timeouts`.
+    pub fn check_timeout_headroom(&self, document: &JobDocument) -> Result<()> {
+        let Some(job_timeout) = document.job_timeout_secs else {
+            return Ok(());
+        };
+        let config = self.config();
+        let document = with_document_run_as_user(document);
+        let worst_case_ms: u64 = document
+            .steps
+            .iter()
+            .chain(document.final_step.as_deref())
+            .map(|step| {
+                let action = &step.action;
+                let retries = action.retries.unwrap_or(0);
+                let mut backoff = self.step_retry_backoff();
+                let retry_delays_ms: u64 = (0..retries)
+                    .map(|_| backoff.next_delay().as_millis() as u64)
+                    .sum();
+                action.delay_before_ms.unwrap_or(0)
+                    + retry_delays_ms
+                    + (1 + u64::from(retries)) * self.step_timeout(action) * 1000
+            })
+            .sum();
+        let worst_case = worst_case_ms.div_ceil(1000);
+        if worst_case + config.reporting_headroom_secs <= job_timeout {
+            return Ok(());
         }
 
-        // Execute with timeout
-        let timeout_duration =
-            Duration::from_secs(action.input.timeout.unwrap_or(self.config.default_timeout));
+        let message = format!(
+            "Steps may run for up to {}s, leaving less than the {}s reporting headroom before jobTimeoutSecs ({}s)",
+            worst_case, config.reporting_headroom_secs, job_timeout
+        );
+        if config.reject_tight_timeouts {
+            return Err(DeviceOpsError::InvalidJobDocument(message));
+        }
+        tracing::warn!(
+            worst_case_secs = worst_case,
+            job_timeout_secs = job_timeout,
+            "{}",
+            message
+        );
+        Ok(())
+    }
 
-        let start = std::time::Instant::now();
+    /// Why the job must not start under `requireMinBatteryPercent`, if it
+    /// must not. Devices without a battery are treated as mains powered; an
+    /// unreadable battery level fails the job.
+    fn check_battery(&self, job_document: &JobDocument) -> Option<String> {
+        let required = job_document.require_min_battery_percent?;
+        let config = self.config();
 
-        let output = match timeout(timeout_duration, self.runner.run(&command)).await {
-            Ok(result) => result?,
-            Err(_) => {
-                tracing::error!(
-                    timeout_secs = timeout_duration.as_secs(),
-                    "Command execution timed out"
+        match self
+            .battery_probe
+            .battery_percent(config.battery_command.as_deref())
+        {
+            Ok(Some(level)) if level < required => Some(format!(
+                "Battery at {}%, job requires at least {}%",
+                level, required
+            )),
+            Ok(Some(level)) => {
+                tracing::info!(
+                    battery_percent = level,
+                    required,
+                    "Battery level sufficient"
                 );
-                return Err(DeviceOpsError::TimeoutError(timeout_duration.as_secs()));
+                None
             }
-        };
-
-        let execution_time_ms = start.elapsed().as_millis() as u64;
-
-        Ok(ExecutionOutput {
-            stdout: output.stdout,
-            stderr: output.stderr,
-            exit_code: output.exit_code,
-            execution_time_ms,
-            stderr_line_count: output.stderr_line_count,
-            stdout_truncated: output.stdout_truncated,
-            stderr_truncated: output.stderr_truncated,
-        })
-    }
-
-    /// Build command with sudo support if runAsUser is specified
-    fn build_command(&self, action: &crate::models::JobAction) -> Result<Command> {
-        let run_as_user = if let Some(user) = &action.run_as_user {
-            if self.verify_sudo_and_user(user)? {
-                Some(user.clone())
-            } else {
-                tracing::warn!(
-                    user = %user,
-                    "sudo or user not found, running as current user"
-                );
+            Ok(None) => {
+                tracing::info!("No battery found, assuming mains power");
                 None
             }
-        } else {
-            None
-        };
+            Err(e) => Some(format!("Cannot read battery level: {}", e)),
+        }
+    }
 
-        Ok(Command {
-            script_path: action.input.command.clone(),
-            args: action.input.args.clone().unwrap_or_default(),
-            run_as_user,
-        })
+    /// Delays between the attempts of a step with `retries`
+    fn step_retry_backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_millis(self.config().step_retry_backoff_ms),
+            STEP_RETRY_BACKOFF_MAX,
+        )
     }
 
-    /// Verify that sudo and the specified user exist
-    fn verify_sudo_and_user(&self, user: &str) -> Result<bool> {
-        // Check if sudo exists
-        let sudo_check = std::process::Command::new("which")
-            .arg("sudo")
-            .output()
-            .map_err(|e| {
-                DeviceOpsError::ExecutionError(format!("Failed to check for sudo: {}", e))
-            })?;
+    /// Execute a step, retrying failed attempts up to the step's `retries`
+    /// with a growing delay between them. Returns the last attempt's output
+    /// and whether it succeeded.
+    async fn execute_step_with_retries(
+        &self,
+        action: &crate::models::JobAction,
+        live: Option<&LiveOutput>,
+    ) -> Result<(ExecutionOutput, bool)> {
+        let retries = action.retries.unwrap_or(0);
+        let mut history = Vec::new();
+        let mut attempt = 1;
+        let mut backoff = self.step_retry_backoff();
 
-        if !sudo_check.status.success() {
-            tracing::warn!("sudo command not found");
-            return Ok(false);
+        if let Some(delay_ms) = action.delay_before_ms.filter(|ms| *ms > 0) {
+            tracing::debug!(delay_ms = delay_ms, "Delaying step");
+            self.clock.sleep(Duration::from_millis(delay_ms)).await;
         }
 
-        // Check if user exists
-        let user_check = std::process::Command::new("id")
-            .arg(user)
-            .output()
-            .map_err(|e| {
-                DeviceOpsError::ExecutionError(format!("Failed to check for user: {}", e))
-            })?;
+        loop {
+            let mut output = self.execute_step(action, live).await?;
+            output.fail_reason = self.evaluate_step_success(&output, action);
+            let succeeded = output.fail_reason.is_none();
 
-        if !user_check.status.success() {
-            tracing::warn!(user = %user, "User does not exist");
-            return Ok(false);
-        }
+            if retries == 0 {
+                return Ok((output, succeeded));
+            }
 
-        // Verify passwordless sudo is configured by testing with -n flag
-        let sudo_test = std::process::Command::new("sudo")
-            .arg("-n")
-            .arg("-u")
-            .arg(user)
-            .arg("true")
-            .output()
-            .map_err(|e| {
-                DeviceOpsError::ExecutionError(format!("Failed to test sudo access: {}", e))
-            })?;
+            history.push(AttemptRecord {
+                attempt,
+                exit_code: output.exit_code,
+                duration_ms: output.execution_time_ms,
+            });
 
-        if !sudo_test.status.success() {
+            if succeeded || attempt > retries {
+                output.attempt_history = history;
+                return Ok((output, succeeded));
+            }
+
+            let delay = backoff.next_delay();
             tracing::warn!(
-                user = %user,
-                "Passwordless sudo not configured for user"
+                attempt = attempt,
+                retries = retries,
+                exit_code = output.exit_code,
+                delay_ms = delay.as_millis() as u64,
+                "Step attempt failed, retrying"
             );
-            return Ok(false);
+            self.clock.sleep(delay).await;
+            attempt += 1;
         }
-
-        Ok(true)
     }
 
-    /// Evaluate if a step succeeded based on exit code and stderr
-    fn evaluate_step_success(
+    /// Execute a single step
+    async fn execute_step(
         &self,
-        output: &ExecutionOutput,
         action: &crate::models::JobAction,
-    ) -> bool {
-        // Check exit code
-        if output.exit_code != 0 {
-            return false;
-        }
+        live: Option<&LiveOutput>,
+    ) -> Result<ExecutionOutput> {
+        let verify_start = self.clock.now();
+        let mut command = self.build_command(action).await?;
+        command.live_output = live.map(|live| live.for_step(&action.name));
+        let (ran_as_uid, ran_as_gid) = ids::resolve(command.run_as_user.as_deref()).unzip();
 
-        // Check stderr line count against allowStdErr
-        let allowed_stderr = action.allow_std_err.unwrap_or(0);
-        if output.stderr_line_count > allowed_stderr as usize {
-            tracing::warn!(
-                stderr_lines = output.stderr_line_count,
-                allowed = allowed_stderr,
-                "Step produced more stderr lines than allowed"
+        // Security validation (if enabled)
+        let policy_violation = match self.security() {
+            Some(validator) => validator.validate(&command)?,
+            None => None,
+        };
+        let verified = self.clock.now();
+
+        // Waiting for another run of the same script does not count against the timeout
+        let _command_lock = if action.serialize_by_command.unwrap_or(false) {
+            Some(self.lock_command(&command.script_path).await)
+        } else {
+            None
+        };
+        let queue_ms = self.clock.now().duration_since(verified).as_millis() as u64;
+
+        // Execute with timeout
+        let timeout_duration = Duration::from_secs(self.step_timeout(action));
+
+        let start = self.clock.now();
+        let started_at = self.clock.epoch_millis();
+
+        // The runner stops the command at the deadline and reports its output so far;
+        // the same deadline here covers a runner that does not
+        let deadline = tokio::time::Instant::now() + timeout_duration;
+        command.deadline = Some(deadline);
+        let timed_out = |elapsed_ms: u64, stdout: String, stderr: String| {
+            tracing::error!(
+                step = %action.name,
+                timeout_secs = timeout_duration.as_secs(),
+                elapsed_ms,
+                stdout_len = stdout.len(),
+                stderr_len = stderr.len(),
+                error_kind = ErrorKind::Timeout.label(),
+                "Command execution timed out"
             );
-            return false;
-        }
+            DeviceOpsError::TimeoutError {
+                step: action.name.clone(),
+                timeout_secs: timeout_duration.as_secs(),
+                elapsed_ms,
+                stdout,
+                stderr,
+            }
+        };
+        let output = match timeout_at(deadline, self.runner.run(&command)).await {
+            Ok(Err(DeviceOpsError::TimeoutError {
+                elapsed_ms,
+                stdout,
+                stderr,
+                ..
+            })) => return Err(timed_out(elapsed_ms, stdout, stderr)),
+            Ok(result) => result?,
+            Err(_) => {
+                let elapsed_ms = self.clock.now().duration_since(start).as_millis() as u64;
+                return Err(timed_out(elapsed_ms, String::new(), String::new()));
+            }
+        };
 
-        true
-    }
-}
+        let execution_time_ms = self.clock.now().duration_since(start).as_millis() as u64;
+        let finished_at = self.clock.epoch_millis();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::models::{JobAction, JobInput, JobStep};
-    use std::collections::VecDeque;
-    use std::sync::{Arc, Mutex};
+        let (stderr, structured_error, removed_lines) = take_structured_error(&output.stderr);
 
-    /// Mock command runner for unit tests
-    struct MockCommandRunner {
-        responses: Arc<Mutex<VecDeque<Result<ExecutionOutput>>>>,
-    }
+        let config = self.config();
+        let (stdout, stdout_cut) = limit_output(
+            &output.stdout,
+            config.max_output_lines,
+            config.max_output_bytes,
+        );
+        let (mut stderr_kept, stderr_cut) =
+            limit_output(&stderr, config.max_output_lines, config.max_output_bytes);
+        let mut stdout_kept = stdout;
 
-    impl MockCommandRunner {
-        fn new(responses: Vec<Result<ExecutionOutput>>) -> Self {
-            Self {
-                responses: Arc::new(Mutex::new(responses.into())),
+        if let Some(dir) = &config.output_spool_dir {
+            let spool = |stream: &str, full: &str, kept: &mut String| {
+                if let Some(path) = spool_output(dir, &action.name, started_at, stream, full) {
+                    kept.push_str(&format!("\n[Full output: {}]", path.display()));
+                }
+            };
+            if stdout_cut {
+                spool("stdout", &output.stdout, &mut stdout_kept);
+            }
+            if stderr_cut {
+                spool("stderr", &stderr, &mut stderr_kept);
             }
         }
-    }
+        // Not counted against allowStdErr: the script did not write it
+        if let Some(note) = &command.run_as_note {
+            if !stderr_kept.is_empty() {
+                stderr_kept.push('\n');
+            }
+            stderr_kept.push_str(&format!("[{}]", note));
+        }
+
+        Ok(ExecutionOutput {
+            stdout: stdout_kept,
+            stderr: stderr_kept,
+            exit_code: output.exit_code,
+            execution_time_ms,
+            stderr_line_count: output.stderr_line_count.saturating_sub(removed_lines),
+            stdout_truncated: output.stdout_truncated || stdout_cut,
+            stderr_truncated: output.stderr_truncated || stderr_cut,
+            started_at,
+            finished_at,
+            attempt_history: Vec::new(),
+            structured_error,
+            termination_signal: None,
+            failure_class: output.failure_class,
+            fail_reason: None,
+            policy_violation,
+            ran_as_uid,
+            ran_as_gid,
+            timing: StepTiming {
+                queue_ms,
+                verify_ms: verified.duration_since(verify_start).as_millis() as u64,
+                ..runner_timing(&output, execution_time_ms)
+            },
+        })
+    }
+
+    /// Seconds a step may run: its own timeout, else its user's default, else
+    /// the global default
+    fn step_timeout(&self, action: &crate::models::JobAction) -> u64 {
+        let config = self.config();
+        action.input.timeout.unwrap_or_else(|| {
+            action
+                .run_as_user
+                .as_ref()
+                .and_then(|user| config.user_timeouts.get(user).copied())
+                .unwrap_or(config.default_timeout)
+        })
+    }
+
+    /// Take the lock for a script, keyed by its canonical path so different
+    /// spellings or symlinks of one script share it
+    async fn lock_command(&self, script_path: &str) -> OwnedMutexGuard<()> {
+        let key = std::fs::canonicalize(script_path).unwrap_or_else(|_| PathBuf::from(script_path));
+        let lock = self
+            .command_locks
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone();
+        if lock.try_lock().is_err() {
+            tracing::info!(script = %script_path, "Waiting for another run of the same command");
+        }
+        lock.lock_owned().await
+    }
+
+    /// Signal that stops the step on timeout: the step's own, else the configured one
+    fn termination_signal(&self, action: &crate::models::JobAction) -> TerminationSignal {
+        action
+            .termination_signal
+            .unwrap_or(self.config().termination.signal)
+    }
+
+    /// Output recorded for a step that was stopped by its timeout or idle timeout
+    fn timed_out_output(
+        &self,
+        action: &crate::models::JobAction,
+        error: &DeviceOpsError,
+    ) -> Option<ExecutionOutput> {
+        let (execution_time_ms, stdout, stderr) = match error {
+            DeviceOpsError::TimeoutError {
+                elapsed_ms,
+                stdout,
+                stderr,
+                ..
+            }
+            | DeviceOpsError::IdleTimeout {
+                elapsed_ms,
+                stdout,
+                stderr,
+                ..
+            } => (*elapsed_ms, stdout.as_str(), stderr.as_str()),
+            _ => return None,
+        };
+        // What the step wrote before it was stopped, under the usual limits
+        let config = self.config();
+        let (stdout, stdout_truncated) =
+            limit_output(stdout, config.max_output_lines, config.max_output_bytes);
+        let (stderr, stderr_truncated) =
+            limit_output(stderr, config.max_output_lines, config.max_output_bytes);
+        Some(ExecutionOutput {
+            stderr_line_count: stderr.lines().count(),
+            stdout,
+            stderr,
+            stdout_truncated,
+            stderr_truncated,
+            exit_code: -1,
+            execution_time_ms,
+            termination_signal: Some(self.termination_signal(action)),
+            ..Default::default()
+        })
+    }
+
+    /// Build command with sudo support if runAsUser is specified
+    async fn build_command(&self, action: &crate::models::JobAction) -> Result<Command> {
+        let config = self.config();
+        // An empty runAsUser asks for the component's own user
+        let requested = match action.run_as_user.as_deref() {
+            Some("") if config.allow_component_user => None,
+            Some("") => {
+                metrics::global().record_security_denial(SecurityRule::RunAsUser);
+                return Err(DeviceOpsError::SecurityError(
+                    "runAsUser \"\" runs the step as the component's own user, which needs execution.allow_component_user".to_string(),
+                ));
+            }
+            Some(user) => Some(user),
+            None => config.default_run_as_user.as_deref(),
+        };
+        let from_config_default = action.run_as_user.is_none() && requested.is_some();
+
+        let check = match requested {
+            Some(user) => Some(
+                self.sudo_verifier
+                    .verify(user, Duration::from_secs(config.verification_timeout_secs))
+                    .await?,
+            ),
+            None => None,
+        };
+        let mut run_as_note = None;
+        let run_as_user = match (requested, check) {
+            (Some(user), Some(RunAsCheck::Available)) => Some(user.to_string()),
+            (Some(user), Some(check)) => {
+                // A hung probe says nothing about the user; say so instead
+                // of reporting the user as missing
+                let unavailable = match &check {
+                    RunAsCheck::TimedOut(probe) => format!(
+                        "verification timed out ({} took over {}s)",
+                        probe, config.verification_timeout_secs
+                    ),
+                    _ => "sudo, the user or passwordless sudo is unavailable".to_string(),
+                };
+                // A document may tighten the device's fallback, never loosen it
+                let fallback = if config.strict_run_as_user
+                    || (from_config_default && !config.allow_component_user)
+                    || config.run_as_user_fallback == RunAsUserFallback::Fail
+                {
+                    RunAsUserFallback::Fail
+                } else {
+                    action
+                        .run_as_user_fallback
+                        .unwrap_or(config.run_as_user_fallback)
+                };
+                match fallback {
+                    RunAsUserFallback::CurrentUser => {
+                        tracing::warn!(
+                            user = %user,
+                            reason = %unavailable,
+                            "Cannot run as user, running as current user"
+                        );
+                        if matches!(check, RunAsCheck::TimedOut(_)) {
+                            run_as_note = Some(format!(
+                                "runAsUser '{}' {}; ran as the current user",
+                                user, unavailable
+                            ));
+                        }
+                        None
+                    }
+                    RunAsUserFallback::Fail => {
+                        let why = if config.strict_run_as_user {
+                            "strict_run_as_user is set"
+                        } else if from_config_default && !config.allow_component_user {
+                            "default_run_as_user is set and allow_component_user is not"
+                        } else if config.run_as_user_fallback == RunAsUserFallback::Fail {
+                            "execution.run_as_user_fallback is fail"
+                        } else {
+                            "runAsUserFallback is fail"
+                        };
+                        metrics::global().record_security_denial(SecurityRule::RunAsUser);
+                        return Err(DeviceOpsError::SecurityError(format!(
+                            "Cannot run as user '{}': {} ({})",
+                            user, unavailable, why
+                        )));
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let mut args = action.input.args.clone().unwrap_or_default();
+        if action.expand_env_in_args.unwrap_or(false) {
+            args = args
+                .iter()
+                .map(|arg| {
+                    expand_env_refs(
+                        arg,
+                        &config.env_allowlist,
+                        config.undefined_env_var,
+                        |name| std::env::var(name).ok(),
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?;
+        }
+
+        Ok(Command {
+            script_path: action.input.command.clone(),
+            args,
+            run_as_user,
+            working_dir: action.input.working_directory.clone(),
+            stop_signal: self.termination_signal(action),
+            stop_timeout: Duration::from_secs(
+                config
+                    .termination
+                    .grace_secs
+                    .unwrap_or(config.default_stop_timeout),
+            ),
+            escalate_to_kill: config.termination.escalate_to_kill,
+            slice: action.cgroup.clone(),
+            wrapper: config.command_wrapper.clone().unwrap_or_default(),
+            idle_timeout: action.idle_timeout.map(Duration::from_secs),
+            // Set by `execute_step` once the command is about to run
+            deadline: None,
+            run_as_note,
+            env: Vec::new(),
+            sanitize_path: config.sanitize_path.clone(),
+            spawn_method: config.spawn_method,
+            log_output: config.log_output.enabled.then(|| config.log_output.clone()),
+            // Set per job by `execute_step`
+            live_output: None,
+        })
+    }
+
+    /// Evaluate if a step succeeded based on exit code and stderr; `None`
+    /// when it did, otherwise why it failed
+    fn evaluate_step_success(
+        &self,
+        output: &ExecutionOutput,
+        action: &crate::models::JobAction,
+    ) -> Option<StepFailureReason> {
+        // Check exit code, or the one the command printed last
+        let exit_code = if action.exit_code_from_output.unwrap_or(false) {
+            match exit_code_from_output(&output.stdout) {
+                Some(code) => code,
+                None => {
+                    tracing::warn!(
+                        step = %action.name,
+                        "exitCodeFromOutput set but the last stdout line is not an exit code"
+                    );
+                    return Some(StepFailureReason::NoExitCodeInOutput);
+                }
+            }
+        } else {
+            output.exit_code
+        };
+
+        // All of the step's own criteria, instead of the checks below
+        if let Some(criteria) = &action.success_criteria {
+            let unmet = criteria
+                .iter()
+                .find(|criterion| !criterion.holds(exit_code, output))?;
+            tracing::warn!(criterion = ?unmet, exit_code, "Step success criterion not met");
+            return Some(StepFailureReason::Criterion(unmet.clone()));
+        }
+
+        if exit_code != 0 {
+            return Some(StepFailureReason::ExitCode(exit_code));
+        }
+
+        // Check stderr line count against allowStdErr, else the configured default
+        let allowed_stderr = action
+            .allow_std_err
+            .unwrap_or(self.config().default_allow_std_err);
+        if output.stderr_line_count > allowed_stderr as usize {
+            tracing::warn!(
+                stderr_lines = output.stderr_line_count,
+                allowed = allowed_stderr,
+                "Step produced more stderr lines than allowed"
+            );
+            return Some(StepFailureReason::StderrLines {
+                lines: output.stderr_line_count,
+                allowed: allowed_stderr,
+            });
+        }
+
+        None
+    }
+}
+
+/// The document with its `runAsUser` copied to every step that sets none
+fn with_document_run_as_user(document: &JobDocument) -> Cow<'_, JobDocument> {
+    let Some(user) = &document.run_as_user else {
+        return Cow::Borrowed(document);
+    };
+
+    let mut document = document.clone();
+    let steps = document
+        .steps
+        .iter_mut()
+        .chain(document.final_step.as_deref_mut());
+    for step in steps {
+        step.action.run_as_user.get_or_insert_with(|| user.clone());
+    }
+    Cow::Owned(document)
+}
+
+/// Parse the last non-empty stdout line as an exit code
+fn exit_code_from_output(stdout: &str) -> Option<i32> {
+    stdout
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?
+        .parse()
+        .ok()
+}
+
+/// Write a step's full output to the spool directory, returning the file
+fn spool_output(
+    dir: &Path,
+    step_name: &str,
+    started_at: i64,
+    stream: &str,
+    full: &str,
+) -> Option<PathBuf> {
+    let safe_name: String = step_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let path = dir.join(format!("{}-{}.{}", started_at, safe_name, stream));
+
+    match std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, full)) {
+        Ok(()) => Some(path),
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to spool step output");
+            None
+        }
+    }
+}
+
+/// Split structured error lines out of stderr. Returns the remaining
+/// stderr, the last valid error object and how many lines were removed.
+/// Lines whose JSON does not parse stay in stderr.
+fn take_structured_error(stderr: &str) -> (String, Option<serde_json::Value>, usize) {
+    let mut kept = Vec::new();
+    let mut error = None;
+    let mut removed = 0;
+
+    for line in stderr.lines() {
+        let parsed = line
+            .strip_prefix(STRUCTURED_ERROR_PREFIX)
+            .map(|json| serde_json::from_str::<serde_json::Value>(json.trim()));
+        match parsed {
+            Some(Ok(value)) if value.is_object() => {
+                error = Some(value);
+                removed += 1;
+            }
+            Some(_) => {
+                tracing::warn!("Ignoring structured error that is not a JSON object");
+                kept.push(line);
+            }
+            None => kept.push(line),
+        }
+    }
+
+    if removed == 0 {
+        return (stderr.to_string(), None, 0);
+    }
+    (kept.join("\n"), error, removed)
+}
+
+/// A step that finished, as passed to the callback of `execute_reporting`
+#[derive(Debug)]
+pub struct StepReport<'a> {
+    pub name: &'a str,
+    /// Position in the document; the final step comes after the last step
+    pub index: usize,
+    pub result: StepResult,
+    /// Output of a step that ran to its end
+    pub output: Option<&'a ExecutionOutput>,
+    /// Why the step could not run or was stopped
+    pub error: Option<&'a DeviceOpsError>,
+}
+
+impl<'a> StepReport<'a> {
+    fn new(
+        action: &'a crate::models::JobAction,
+        index: usize,
+        result: StepResult,
+        outcome: &'a Result<(ExecutionOutput, bool)>,
+    ) -> Self {
+        Self {
+            name: &action.name,
+            index,
+            result,
+            output: outcome.as_ref().ok().map(|(output, _)| output),
+            error: outcome.as_ref().err(),
+        }
+    }
+}
+
+/// Span around one step; events logged while it runs carry its name and its
+/// index in the document (the final step comes after the last step)
+fn step_span(action: &crate::models::JobAction, index: usize) -> tracing::Span {
+    tracing::info_span!("step", name = %action.name, index)
+}
+
+/// The spawn, run and drain phases the runner measured. A runner that
+/// measures none (a custom `CommandRunner`) gets all of the step's time as
+/// run time, so the phases always add up to `execution_time_ms`.
+fn runner_timing(output: &ExecutionOutput, execution_time_ms: u64) -> StepTiming {
+    let mut timing = output.timing;
+    let measured = timing.spawn_ms + timing.run_ms + timing.drain_ms;
+    if measured == 0 {
+        timing.run_ms = execution_time_ms;
+    }
+    timing
+}
+
+/// The failure reason of a step sudo refused to run, which says that the
+/// command itself never started
+fn privilege_reason(action: &crate::models::JobAction, output: &ExecutionOutput) -> Option<String> {
+    let denial = sudo_denial(output.exit_code, &output.stderr)?;
+    let user = match action.run_as_user.as_deref() {
+        Some(user) => format!(" '{}'", user),
+        None => String::new(),
+    };
+    Some(format!(
+        "Step '{}' did not run: sudo refused to run it as user{} ({}); check the sudoers rules for the component's user",
+        action.name, user, denial
+    ))
+}
+
+/// Count a step's outcome, returning it for the `reason_code` of failure events
+fn record_step_metric(outcome: &Result<(ExecutionOutput, bool)>) -> StepResult {
+    let result = match outcome {
+        Ok((_, true)) => StepResult::Success,
+        Ok((_, false)) => StepResult::Failure,
+        Err(DeviceOpsError::TimeoutError { .. } | DeviceOpsError::IdleTimeout { .. }) => {
+            StepResult::Timeout
+        }
+        Err(_) => StepResult::Error,
+    };
+    metrics::global().record_step(result);
+    if let Err(e) = outcome {
+        metrics::global().record_error(e.kind());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::{
+        AllowlistViolationAction, IncludeStdoutPolicy, OutputLogConfig, SecurityConfig, SpawnMethod,
+    };
+    use crate::models::{
+        format_status_details, Criterion, JobAction, JobInput, JobStatus, JobStep,
+        StatusDetailsOptions,
+    };
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// Mock command runner for unit tests
+    struct MockCommandRunner {
+        responses: Arc<Mutex<VecDeque<Result<ExecutionOutput>>>>,
+        /// Commands seen, in order
+        commands: Arc<Mutex<Vec<Command>>>,
+    }
+
+    impl MockCommandRunner {
+        fn new(responses: Vec<Result<ExecutionOutput>>) -> Self {
+            Self {
+                responses: Arc::new(Mutex::new(responses.into())),
+                commands: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
 
     #[async_trait]
     impl CommandRunner for MockCommandRunner {
-        async fn run(&self, _command: &Command) -> Result<ExecutionOutput> {
+        async fn run(&self, command: &Command) -> Result<ExecutionOutput> {
+            self.commands.lock().unwrap().push(command.clone());
             self.responses
                 .lock()
                 .unwrap()
@@ -457,6 +1645,7 @@ mod tests {
     async fn test_single_step_execution_logic() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![Ok(ExecutionOutput {
@@ -467,6 +1656,7 @@ mod tests {
             stderr_line_count: 0,
             stdout_truncated: false,
             stderr_truncated: false,
+            ..Default::default()
         })]);
 
         let executor = CommandExecutor::new_with_runner(config, None, mock);
@@ -481,14 +1671,17 @@ mod tests {
                         command: "echo".to_string(),
                         args: Some(vec!["hello".to_string()]),
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
@@ -502,6 +1695,7 @@ mod tests {
     async fn test_multi_step_execution_logic() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![
@@ -513,6 +1707,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
             Ok(ExecutionOutput {
                 stdout: "step2".to_string(),
@@ -522,6 +1717,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
         ]);
 
@@ -538,10 +1734,12 @@ mod tests {
                             command: "echo".to_string(),
                             args: Some(vec!["step1".to_string()]),
                             timeout: None,
+                            ..Default::default()
                         },
                         run_as_user: None,
                         ignore_step_failure: None,
                         allow_std_err: None,
+                        ..Default::default()
                     },
                 },
                 JobStep {
@@ -552,15 +1750,18 @@ mod tests {
                             command: "echo".to_string(),
                             args: Some(vec!["step2".to_string()]),
                             timeout: None,
+                            ..Default::default()
                         },
                         run_as_user: None,
                         ignore_step_failure: None,
                         allow_std_err: None,
+                        ..Default::default()
                     },
                 },
             ],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
@@ -574,6 +1775,7 @@ mod tests {
     async fn test_ignore_step_failure_logic() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![
@@ -585,6 +1787,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
             Ok(ExecutionOutput {
                 stdout: "success".to_string(),
@@ -594,6 +1797,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
         ]);
 
@@ -610,10 +1814,12 @@ mod tests {
                             command: "false".to_string(),
                             args: None,
                             timeout: None,
+                            ..Default::default()
                         },
                         run_as_user: None,
                         ignore_step_failure: Some(true),
                         allow_std_err: None,
+                        ..Default::default()
                     },
                 },
                 JobStep {
@@ -624,15 +1830,18 @@ mod tests {
                             command: "echo".to_string(),
                             args: Some(vec!["success".to_string()]),
                             timeout: None,
+                            ..Default::default()
                         },
                         run_as_user: None,
                         ignore_step_failure: None,
                         allow_std_err: None,
+                        ..Default::default()
                     },
                 },
             ],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
@@ -646,6 +1855,7 @@ mod tests {
     async fn test_final_step_execution_logic() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![
@@ -657,6 +1867,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
             Ok(ExecutionOutput {
                 stdout: "final".to_string(),
@@ -666,6 +1877,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
         ]);
 
@@ -681,10 +1893,12 @@ mod tests {
                         command: "echo".to_string(),
                         args: Some(vec!["main".to_string()]),
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: Some(Box::new(JobStep {
@@ -695,13 +1909,16 @@ mod tests {
                         command: "echo".to_string(),
                         args: Some(vec!["final".to_string()]),
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             })),
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
@@ -715,6 +1932,7 @@ mod tests {
     async fn test_allow_std_err_logic() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![Ok(ExecutionOutput {
@@ -725,6 +1943,7 @@ mod tests {
             stderr_line_count: 1,
             stdout_truncated: false,
             stderr_truncated: false,
+            ..Default::default()
         })]);
 
         let executor = CommandExecutor::new_with_runner(config, None, mock);
@@ -739,14 +1958,17 @@ mod tests {
                         command: "sh".to_string(),
                         args: Some(vec!["-c".to_string(), "echo error >&2".to_string()]),
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: Some(1), // Allow 1 line of stderr
+                    ..Default::default()
                 },
             }],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
@@ -754,10 +1976,295 @@ mod tests {
         assert_eq!(result.outputs[0].output.stderr_line_count, 1);
     }
 
+    fn attempt_output(exit_code: i32) -> Result<ExecutionOutput> {
+        Ok(ExecutionOutput {
+            exit_code,
+            ..Default::default()
+        })
+    }
+
+    fn retried_step_document(retries: u32) -> JobDocument {
+        JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "Flaky".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: "/opt/device-scripts/flaky.sh".to_string(),
+                        ..Default::default()
+                    },
+                    retries: Some(retries),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_history_records_each_attempt() {
+        let mock = MockCommandRunner::new(vec![
+            attempt_output(1),
+            attempt_output(2),
+            attempt_output(0),
+        ]);
+        let clock = Arc::new(MockClock::new());
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock)
+            .with_clock(clock);
+
+        let result = executor.execute(&retried_step_document(3)).await.unwrap();
+        assert!(result.overall_success);
+        // The last attempt started after waits of 1s and 2s
+        assert_eq!(
+            result.outputs[0].output.started_at,
+            MockClock::START_EPOCH_MILLIS + 3000
+        );
+
+        let history = &result.outputs[0].output.attempt_history;
+        let exit_codes: Vec<i32> = history.iter().map(|a| a.exit_code).collect();
+        assert_eq!(exit_codes, vec![1, 2, 0]);
+        assert_eq!(history[2].attempt, 3);
+
+        let status = JobStatus::from_success(&result, &StatusDetailsOptions::default());
+        let attempts = status.to_json()["statusDetails"]["attempts"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let exits: Vec<&str> = attempts
+            .split(", ")
+            .map(|a| a.split(" (").next().unwrap())
+            .collect();
+        assert_eq!(exits, vec!["exit 1", "exit 2", "exit 0"]);
+    }
+
+    #[tokio::test]
+    async fn test_retries_exhausted_fails_step() {
+        let mock = MockCommandRunner::new(vec![attempt_output(1), attempt_output(1)]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock)
+            .with_clock(Arc::new(MockClock::new()));
+
+        let result = executor.execute(&retried_step_document(1)).await.unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(result.outputs[0].output.attempt_history.len(), 2);
+    }
+
+    fn exit_code_from_output_document() -> JobDocument {
+        JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "LegacyWrapper".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: "/opt/device-scripts/legacy.sh".to_string(),
+                        ..Default::default()
+                    },
+                    exit_code_from_output: Some(true),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_from_output_parsed() {
+        let mock = MockCommandRunner::new(vec![Ok(ExecutionOutput {
+            stdout: "doing work\n3\n\n".to_string(),
+            exit_code: 0,
+            ..Default::default()
+        })]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+
+        let result = executor
+            .execute(&exit_code_from_output_document())
+            .await
+            .unwrap();
+        assert!(!result.overall_success);
+        // The printed code is the one reported, not the process's 0
+        assert_eq!(
+            result.outputs[0].output.fail_reason,
+            Some(StepFailureReason::ExitCode(3))
+        );
+
+        assert_eq!(exit_code_from_output("ok\n 0 \n"), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_from_output_unparseable_fails() {
+        let mock = MockCommandRunner::new(vec![Ok(ExecutionOutput {
+            stdout: "done\n".to_string(),
+            exit_code: 0,
+            ..Default::default()
+        })]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+
+        let result = executor
+            .execute(&exit_code_from_output_document())
+            .await
+            .unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(
+            result.outputs[0].output.fail_reason,
+            Some(StepFailureReason::NoExitCodeInOutput)
+        );
+        assert_eq!(exit_code_from_output(""), None);
+    }
+
+    fn stderr_step(allow_std_err: Option<i32>) -> JobAction {
+        JobAction {
+            name: "Warns".to_string(),
+            action_type: "runCommand".to_string(),
+            allow_std_err,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_default_allow_std_err_precedence() {
+        let output = ExecutionOutput {
+            stderr: "warning\nwarning\n".to_string(),
+            stderr_line_count: 2,
+            ..Default::default()
+        };
+
+        // No step value and no config default: no stderr allowed
+        let executor = CommandExecutor::new(ExecutionConfig::default(), None);
+        assert_eq!(
+            executor.evaluate_step_success(&output, &stderr_step(None)),
+            Some(StepFailureReason::StderrLines {
+                lines: 2,
+                allowed: 0
+            })
+        );
+
+        // Config default applies when the step does not set allowStdErr
+        let config = ExecutionConfig {
+            default_allow_std_err: 2,
+            ..Default::default()
+        };
+        let executor = CommandExecutor::new(config, None);
+        assert_eq!(
+            executor.evaluate_step_success(&output, &stderr_step(None)),
+            None
+        );
+
+        // The step's own value wins over the default
+        assert_eq!(
+            executor.evaluate_step_success(&output, &stderr_step(Some(1))),
+            Some(StepFailureReason::StderrLines {
+                lines: 2,
+                allowed: 1
+            })
+        );
+        assert_eq!(
+            executor.evaluate_step_success(&output, &stderr_step(Some(5))),
+            None
+        );
+
+        // A failing exit code is reported before the stderr lines
+        let output = ExecutionOutput {
+            exit_code: 2,
+            ..output
+        };
+        let reason = executor.evaluate_step_success(&output, &stderr_step(Some(1)));
+        assert_eq!(reason, Some(StepFailureReason::ExitCode(2)));
+        assert_eq!(reason.unwrap().to_string(), "exit_code=2");
+    }
+
+    #[test]
+    fn test_success_criteria_must_all_hold() {
+        let step = JobAction {
+            name: "Install".to_string(),
+            action_type: "runCommand".to_string(),
+            success_criteria: Some(vec![
+                Criterion::ExitCode(0),
+                Criterion::StdoutContains("installed".to_string()),
+                Criterion::StderrLineMax(2),
+            ]),
+            ..Default::default()
+        };
+        let passing = ExecutionOutput {
+            stdout: "package installed\n".to_string(),
+            stderr: "warning\nwarning\n".to_string(),
+            stderr_line_count: 2,
+            ..Default::default()
+        };
+        // Two stderr lines pass although allowStdErr defaults to none
+        let executor = CommandExecutor::new(ExecutionConfig::default(), None);
+        assert_eq!(executor.evaluate_step_success(&passing, &step), None);
+
+        // The first criterion that does not hold is the reason
+        let failing = [
+            (
+                ExecutionOutput {
+                    exit_code: 1,
+                    ..passing.clone()
+                },
+                Criterion::ExitCode(0),
+            ),
+            (
+                ExecutionOutput {
+                    stdout: "package skipped\n".to_string(),
+                    ..passing.clone()
+                },
+                Criterion::StdoutContains("installed".to_string()),
+            ),
+            (
+                ExecutionOutput {
+                    stderr_line_count: 3,
+                    ..passing.clone()
+                },
+                Criterion::StderrLineMax(2),
+            ),
+        ];
+        for (output, unmet) in &failing {
+            assert_eq!(
+                executor.evaluate_step_success(output, &step),
+                Some(StepFailureReason::Criterion(unmet.clone())),
+                "{:?}",
+                output
+            );
+        }
+        assert_eq!(
+            StepFailureReason::Criterion(Criterion::StdoutContains("installed".to_string()))
+                .to_string(),
+            r#"criterion={"stdoutContains":"installed"}"#
+        );
+
+        // Without an exit code criterion any exit code will do
+        let step = JobAction {
+            success_criteria: Some(vec![
+                Criterion::ExitCodeIn(vec![0, 3]),
+                Criterion::StdoutNotContains("error".to_string()),
+                Criterion::StderrContains("warning".to_string()),
+            ]),
+            ..step
+        };
+        let output = ExecutionOutput {
+            exit_code: 3,
+            ..passing.clone()
+        };
+        assert_eq!(executor.evaluate_step_success(&output, &step), None);
+        let output = ExecutionOutput {
+            stdout: "error: disk full".to_string(),
+            ..output
+        };
+        assert!(executor.evaluate_step_success(&output, &step).is_some());
+        let output = ExecutionOutput {
+            stderr: String::new(),
+            stderr_line_count: 0,
+            ..passing
+        };
+        assert!(executor.evaluate_step_success(&output, &step).is_some());
+    }
+
     #[tokio::test]
     async fn test_step_failure_stops_execution() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![
@@ -769,6 +2276,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
             // Second step should not be called
         ]);
@@ -786,10 +2294,12 @@ mod tests {
                             command: "false".to_string(),
                             args: None,
                             timeout: None,
+                            ..Default::default()
                         },
                         run_as_user: None,
                         ignore_step_failure: None,
                         allow_std_err: None,
+                        ..Default::default()
                     },
                 },
                 JobStep {
@@ -800,15 +2310,18 @@ mod tests {
                             command: "echo".to_string(),
                             args: Some(vec!["should not run".to_string()]),
                             timeout: None,
+                            ..Default::default()
                         },
                         run_as_user: None,
                         ignore_step_failure: None,
                         allow_std_err: None,
+                        ..Default::default()
                     },
                 },
             ],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
@@ -821,6 +2334,7 @@ mod tests {
     async fn test_final_step_not_run_on_failure() {
         let config = ExecutionConfig {
             default_timeout: 300,
+            ..Default::default()
         };
 
         let mock = MockCommandRunner::new(vec![
@@ -832,6 +2346,7 @@ mod tests {
                 stderr_line_count: 0,
                 stdout_truncated: false,
                 stderr_truncated: false,
+                ..Default::default()
             }),
             // Final step should not be called
         ]);
@@ -848,10 +2363,12 @@ mod tests {
                         command: "false".to_string(),
                         args: None,
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: Some(Box::new(JobStep {
@@ -862,17 +2379,1723 @@ mod tests {
                         command: "echo".to_string(),
                         args: Some(vec!["cleanup".to_string()]),
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             })),
             include_std_out: None,
+            ..Default::default()
         };
 
         let result = executor.execute(&document).await.unwrap();
         assert!(!result.overall_success);
         assert_eq!(result.outputs.len(), 1); // Only failing step, no final step
     }
+
+    /// Runner that takes a fixed amount of (mock) time per command
+    struct SlowRunner {
+        clock: Arc<MockClock>,
+        duration: Duration,
+    }
+
+    #[async_trait]
+    impl CommandRunner for SlowRunner {
+        async fn run(&self, _command: &Command) -> Result<ExecutionOutput> {
+            self.clock.advance(self.duration);
+            Ok(ExecutionOutput::default())
+        }
+    }
+
+    fn sleep_action() -> JobAction {
+        JobAction {
+            name: "Timed".to_string(),
+            action_type: "runCommand".to_string(),
+            input: JobInput {
+                command: "/bin/sleep".to_string(),
+                args: Some(vec!["0.05".to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_step_timestamps_from_clock() {
+        let clock = Arc::new(MockClock::new());
+        let runner = SlowRunner {
+            clock: clock.clone(),
+            duration: Duration::from_millis(1500),
+        };
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, runner)
+            .with_clock(clock);
+
+        let output = executor.execute_step(&sleep_action(), None).await.unwrap();
+        assert_eq!(output.started_at, MockClock::START_EPOCH_MILLIS);
+        assert_eq!(output.finished_at, MockClock::START_EPOCH_MILLIS + 1500);
+        assert_eq!(output.execution_time_ms, 1500);
+    }
+
+    #[tokio::test]
+    async fn test_delay_before_step() {
+        let clock = Arc::new(MockClock::new());
+        let runner = SlowRunner {
+            clock: clock.clone(),
+            duration: Duration::from_millis(1500),
+        };
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, runner)
+            .with_clock(clock);
+        let action = JobAction {
+            delay_before_ms: Some(500),
+            ..sleep_action()
+        };
+
+        // The command starts after the delay, which is not part of its execution time
+        let (output, succeeded) = executor
+            .execute_step_with_retries(&action, None)
+            .await
+            .unwrap();
+        assert!(succeeded);
+        assert_eq!(output.started_at, MockClock::START_EPOCH_MILLIS + 500);
+        assert_eq!(output.execution_time_ms, 1500);
+    }
+
+    #[tokio::test]
+    async fn test_step_timestamps_match_execution_time() {
+        let executor = CommandExecutor::new(ExecutionConfig::default(), None);
+
+        let output = executor.execute_step(&sleep_action(), None).await.unwrap();
+        let wall_ms = output.finished_at - output.started_at;
+        assert!(output.execution_time_ms >= 50);
+        assert!(
+            (wall_ms - output.execution_time_ms as i64).abs() <= 5,
+            "wall {}ms vs measured {}ms",
+            wall_ms,
+            output.execution_time_ms
+        );
+    }
+
+    #[test]
+    fn test_take_structured_error() {
+        let stderr = "warning: disk low\nDEVICE_OPS_ERROR {\"code\": \"E_DISK\", \"free_mb\": 12}\nDEVICE_OPS_ERROR not json";
+        let (rest, error, removed) = take_structured_error(stderr);
+
+        assert_eq!(rest, "warning: disk low\nDEVICE_OPS_ERROR not json");
+        assert_eq!(
+            error,
+            Some(serde_json::json!({"code": "E_DISK", "free_mb": 12}))
+        );
+        assert_eq!(removed, 1);
+
+        let (rest, error, removed) = take_structured_error("plain\n");
+        assert_eq!((rest.as_str(), error, removed), ("plain\n", None, 0));
+    }
+
+    #[tokio::test]
+    async fn test_structured_error_surfaces_in_status_details() {
+        let mock = MockCommandRunner::new(vec![Ok(ExecutionOutput {
+            stderr: "DEVICE_OPS_ERROR {\"code\":\"E_CONN\",\"retryable\":true}".to_string(),
+            stderr_line_count: 1,
+            exit_code: 3,
+            ..Default::default()
+        })]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "Connect".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: "/opt/device-scripts/connect.sh".to_string(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        };
+
+        let result = executor.execute(&document).await.unwrap();
+        let output = &result.outputs[0].output;
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stderr_line_count, 0);
+        assert_eq!(output.structured_error.as_ref().unwrap()["code"], "E_CONN");
+
+        let status = JobStatus::from_failure(&result, &StatusDetailsOptions::default());
+        let error = status.to_json()["statusDetails"]["error"].clone();
+        assert_eq!(error, r#"{"code":"E_CONN","retryable":true}"#);
+    }
+
+    fn single_step_document(command: &str) -> JobDocument {
+        JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "Step".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: command.to_string(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_step_events_carry_the_step_span() {
+        use crate::job_log::{job_log_path, JobLogLayer};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(JobLogLayer::new(dir.path().to_path_buf()));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let mock = MockCommandRunner::new(vec![
+            Ok(ExecutionOutput::default()),
+            Ok(ExecutionOutput {
+                exit_code: 3,
+                ..Default::default()
+            }),
+        ]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+        let mut document = single_step_document("/opt/first.sh");
+        let mut second = document.steps[0].clone();
+        second.action.name = "Second".to_string();
+        document.steps.push(second);
+
+        let span = tracing::info_span!("job", job_id = "job-7");
+        let result = executor.execute(&document).instrument(span).await.unwrap();
+        assert!(!result.overall_success);
+
+        let log = std::fs::read_to_string(job_log_path(dir.path(), "job-7")).unwrap();
+        assert!(
+            log.contains("step{name=Step index=0}: Executing step"),
+            "{}",
+            log
+        );
+        assert!(
+            log.contains("step{name=Second index=1}: Step failed reason_code=failure exit_code=3")
+        );
+    }
+
+    #[test]
+    fn test_timeout_headroom_threshold() {
+        let strict = ExecutionConfig {
+            reject_tight_timeouts: true,
+            ..Default::default()
+        };
+        let executor = CommandExecutor::new(strict, None);
+
+        let mut document = single_step_document("/opt/device-scripts/update.sh");
+        document.steps[0].action.input.timeout = Some(100);
+        // No job timeout given: nothing to check
+        assert!(executor.check_timeout_headroom(&document).is_ok());
+
+        // 100s of steps + 30s headroom fit exactly into 130s
+        document.job_timeout_secs = Some(130);
+        assert!(executor.check_timeout_headroom(&document).is_ok());
+
+        document.job_timeout_secs = Some(129);
+        let err = executor
+            .check_timeout_headroom(&document)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("up to 100s"), "{}", err);
+        assert!(err.contains("30s reporting headroom"), "{}", err);
+
+        // Retries, the wait before each and delays count toward the worst case
+        document.job_timeout_secs = Some(241);
+        document.steps[0].action.retries = Some(1);
+        document.steps[0].action.delay_before_ms = Some(10_500);
+        assert!(executor.check_timeout_headroom(&document).is_err());
+        document.job_timeout_secs = Some(242);
+        assert!(executor.check_timeout_headroom(&document).is_ok());
+
+        // Steps without a timeout use their user's default
+        let mut config = ExecutionConfig {
+            reject_tight_timeouts: true,
+            ..Default::default()
+        };
+        config.user_timeouts.insert("backup".to_string(), 3600);
+        let executor = CommandExecutor::new(config, None);
+        let mut document = single_step_document("/opt/device-scripts/backup.sh");
+        document.run_as_user = Some("backup".to_string());
+        document.job_timeout_secs = Some(3600);
+        assert!(executor.check_timeout_headroom(&document).is_err());
+
+        // Without reject_tight_timeouts a tight document only warns
+        let lenient = CommandExecutor::new(ExecutionConfig::default(), None);
+        assert!(lenient.check_timeout_headroom(&document).is_ok());
+    }
+
+    fn stdout_output(stdout: &str) -> Result<ExecutionOutput> {
+        Ok(ExecutionOutput {
+            stdout: stdout.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_output_limits_from_config() {
+        let config = ExecutionConfig {
+            max_output_lines: 2,
+            ..Default::default()
+        };
+        let mock = MockCommandRunner::new(vec![stdout_output("one\ntwo\nthree")]);
+        let executor = CommandExecutor::new_with_runner(config, None, mock);
+
+        let result = executor
+            .execute(&single_step_document("/opt/a.sh"))
+            .await
+            .unwrap();
+        let output = &result.outputs[0].output;
+        assert_eq!(
+            output.stdout,
+            "one\ntwo\n[Output truncated: exceeded limit]"
+        );
+        assert!(output.stdout_truncated);
+
+        // Default limits keep the whole output
+        let mock = MockCommandRunner::new(vec![stdout_output("one\ntwo\nthree")]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+        let result = executor
+            .execute(&single_step_document("/opt/a.sh"))
+            .await
+            .unwrap();
+        assert_eq!(result.outputs[0].output.stdout, "one\ntwo\nthree");
+    }
+
+    #[tokio::test]
+    async fn test_output_spool_dir_keeps_full_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ExecutionConfig {
+            max_output_lines: 1,
+            output_spool_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mock = MockCommandRunner::new(vec![stdout_output("one\ntwo")]);
+        let executor = CommandExecutor::new_with_runner(config, None, mock);
+
+        let result = executor
+            .execute(&single_step_document("/opt/a.sh"))
+            .await
+            .unwrap();
+        let stdout = &result.outputs[0].output.stdout;
+        let path = stdout
+            .lines()
+            .last()
+            .and_then(|line| line.strip_prefix("[Full output: "))
+            .and_then(|line| line.strip_suffix(']'))
+            .unwrap();
+        assert!(path.ends_with("-Step.stdout"), "{}", path);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "one\ntwo");
+    }
+
+    #[tokio::test]
+    async fn test_stop_timeout_reaches_runner() {
+        let config = ExecutionConfig {
+            default_stop_timeout: 3,
+            ..Default::default()
+        };
+        let mock = MockCommandRunner::new(vec![stdout_output("")]);
+        let commands = mock.commands.clone();
+        let executor = CommandExecutor::new_with_runner(config, None, mock);
+
+        executor
+            .execute(&single_step_document("/opt/a.sh"))
+            .await
+            .unwrap();
+        assert_eq!(
+            commands.lock().unwrap()[0].stop_timeout,
+            Duration::from_secs(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_command_is_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!("echo $$ > {}; exec sleep 30", pid_file.display()),
+            ],
+            stop_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+
+        let run = timeout(
+            Duration::from_millis(500),
+            SystemCommandRunner.run(&command),
+        )
+        .await;
+        assert!(run.is_err());
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid.trim()));
+        for _ in 0..50 {
+            if !proc_dir.exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed-out command {} is still running", pid.trim());
+    }
+
+    #[tokio::test]
+    async fn test_step_termination_signal_overrides_config() {
+        let mut config = ExecutionConfig::default();
+        config.termination.signal = TerminationSignal::Int;
+        config.termination.grace_secs = Some(2);
+
+        let mut document = single_step_document("/opt/a.sh");
+        document.steps.push(document.steps[0].clone());
+        document.steps[1].action.termination_signal = Some(TerminationSignal::Kill);
+
+        let mock = MockCommandRunner::new(vec![stdout_output(""), stdout_output("")]);
+        let commands = mock.commands.clone();
+        let executor = CommandExecutor::new_with_runner(config, None, mock);
+        executor.execute(&document).await.unwrap();
+
+        let commands = commands.lock().unwrap();
+        assert_eq!(commands[0].stop_signal, TerminationSignal::Int);
+        assert_eq!(commands[1].stop_signal, TerminationSignal::Kill);
+        // grace_secs wins over default_stop_timeout
+        assert_eq!(commands[0].stop_timeout, Duration::from_secs(2));
+    }
+
+    /// Runner that never finishes within a step timeout
+    struct HangingRunner;
+
+    #[async_trait]
+    impl CommandRunner for HangingRunner {
+        async fn run(&self, _command: &Command) -> Result<ExecutionOutput> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(ExecutionOutput::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_step_records_termination_signal() {
+        let mut config = ExecutionConfig::default();
+        config.termination.signal = TerminationSignal::Hup;
+        let executor = CommandExecutor::new_with_runner(config, None, HangingRunner);
+
+        let mut document = single_step_document("/opt/hangs.sh");
+        document.steps[0].action.input.timeout = Some(1);
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(
+            result.failure_reason.as_deref(),
+            Some("Timeout: command exceeded 1 seconds")
+        );
+        assert!(result.timed_out());
+        let output = &result.outputs[0].output;
+        assert_eq!(output.termination_signal, Some(TerminationSignal::Hup));
+
+        let status = JobStatus::from_failure(&result, &StatusDetailsOptions::default());
+        assert_eq!(
+            status.to_json()["statusDetails"]["termination_signal"],
+            "SIGHUP"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_violation_action() {
+        let security = |action| {
+            Some(SecurityValidator::new(SecurityConfig {
+                enabled: true,
+                command_allowlist: vec!["/opt/device-scripts/allowed.sh".to_string()],
+                allowlist_violation_action: action,
+                ..Default::default()
+            }))
+        };
+        let document = single_step_document("/opt/other.sh");
+
+        let mock = MockCommandRunner::new(vec![stdout_output("done")]);
+        let commands = mock.commands.clone();
+        let executor = CommandExecutor::new_with_runner(
+            ExecutionConfig::default(),
+            security(AllowlistViolationAction::Reject),
+            mock,
+        );
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(
+            result.failure_reason.as_deref(),
+            Some("Security validation failed: Command not in allowlist: /opt/other.sh")
+        );
+        assert!(commands.lock().unwrap().is_empty());
+
+        let mock = MockCommandRunner::new(vec![stdout_output("done")]);
+        let executor = CommandExecutor::new_with_runner(
+            ExecutionConfig::default(),
+            security(AllowlistViolationAction::Flag),
+            mock,
+        );
+        let result = executor.execute(&document).await.unwrap();
+        assert!(result.overall_success);
+        assert_eq!(
+            result.outputs[0].output.policy_violation.as_deref(),
+            Some("Command not in allowlist: /opt/other.sh")
+        );
+        let status = JobStatus::from_success(&result, &StatusDetailsOptions::default());
+        assert_eq!(
+            status.to_json()["statusDetails"]["policy_violation"],
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_step_reports_partial_output() {
+        let mock = MockCommandRunner::new(vec![Err(DeviceOpsError::TimeoutError {
+            step: String::new(),
+            timeout_secs: 0,
+            elapsed_ms: 1200,
+            stdout: "Downloading firmware\n40%".to_string(),
+            stderr: "slow mirror".to_string(),
+        })]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+        let mut document = single_step_document("/opt/update.sh");
+        document.steps[0].action.input.timeout = Some(1);
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(result.timed_out());
+        let output = &result.outputs[0].output;
+        assert_eq!(output.stdout, "Downloading firmware\n40%");
+        assert_eq!(output.execution_time_ms, 1200);
+
+        let options = StatusDetailsOptions {
+            include_stdout: true,
+            ..Default::default()
+        };
+        let status = JobStatus::from_failure(&result, &options).to_json();
+        let details = &status["statusDetails"];
+        assert_eq!(details["reason"], "Timeout: command exceeded 1 seconds");
+        assert_eq!(details["stdout"], "Downloading firmware\n40%");
+        assert_eq!(details["stderr"], "slow mirror");
+        assert_eq!(details["execution_time_ms"], "1200");
+    }
+
+    #[tokio::test]
+    async fn test_runner_keeps_output_written_before_deadline() {
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "echo started; echo warming up >&2; sleep 30".to_string(),
+            ],
+            deadline: Some(tokio::time::Instant::now() + Duration::from_millis(500)),
+            ..Default::default()
+        };
+
+        match SystemCommandRunner.run(&command).await {
+            Err(DeviceOpsError::TimeoutError {
+                elapsed_ms,
+                stdout,
+                stderr,
+                ..
+            }) => {
+                assert_eq!(stdout, "started\n");
+                assert_eq!(stderr, "warming up\n");
+                assert!(elapsed_ms >= 400, "{}", elapsed_ms);
+            }
+            other => panic!("expected a timeout, got {:?}", other.map(|o| o.exit_code)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_command_gets_configured_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("signal");
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!(
+                    "trap 'echo INT > {}; exit 0' INT; while :; do sleep 0.1; done",
+                    marker.display()
+                ),
+            ],
+            stop_signal: TerminationSignal::Int,
+            stop_timeout: Duration::from_secs(5),
+            escalate_to_kill: true,
+            ..Default::default()
+        };
+
+        let run = timeout(
+            Duration::from_millis(300),
+            SystemCommandRunner.run(&command),
+        )
+        .await;
+        assert!(run.is_err());
+
+        for _ in 0..50 {
+            if let Ok(content) = std::fs::read_to_string(&marker) {
+                assert_eq!(content.trim(), "INT");
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("command did not receive SIGINT");
+    }
+
+    #[tokio::test]
+    async fn test_idle_step_is_stopped() {
+        let executor = CommandExecutor::new(ExecutionConfig::default(), None);
+        let mut document = single_step_document("/bin/sh");
+        document.steps[0].action.input.args = Some(vec![
+            "-c".to_string(),
+            "echo starting; sleep 30; echo done".to_string(),
+        ]);
+        document.steps[0].action.idle_timeout = Some(1);
+
+        let started = std::time::Instant::now();
+        let result = executor.execute(&document).await.unwrap();
+        // Well before the 300s step timeout or the script's own end
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!result.overall_success);
+        assert_eq!(
+            result.failure_reason.as_deref(),
+            Some("Idle timeout: command produced no output for 1 seconds")
+        );
+        let output = &result.outputs[0].output;
+        assert_eq!(output.exit_code, -1);
+        assert_eq!(output.termination_signal, Some(TerminationSignal::Term));
+        // What it printed before going quiet, and how long it ran
+        assert_eq!(output.stdout.trim(), "starting");
+        assert!(
+            output.execution_time_ms >= 1000,
+            "{}",
+            output.execution_time_ms
+        );
+    }
+
+    #[tokio::test]
+    async fn test_steady_output_resets_idle_timeout() {
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "for i in 1 2 3 4 5 6; do echo $i; sleep 0.3; done".to_string(),
+            ],
+            idle_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        // Runs about 1.8s in all, longer than the idle timeout
+        let output = SystemCommandRunner.run(&command).await.unwrap();
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout.lines().count(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_timing_phases_add_up() {
+        let executor = CommandExecutor::new(ExecutionConfig::default(), None);
+        let mut document = single_step_document("/bin/sh");
+        document.steps[0].action.input.args = Some(vec![
+            "-c".to_string(),
+            // Exits after 0.2s, while a child keeps stdout open for 0.3s more
+            "(sleep 0.5; echo late) & sleep 0.2; echo early".to_string(),
+        ]);
+
+        let result = executor.execute(&document).await.unwrap();
+        let output = &result.outputs[0].output;
+        assert_eq!(output.stdout, "early\nlate");
+        let timing = output.timing;
+        assert!(timing.run_ms >= 200, "{:?}", timing);
+        assert!(timing.drain_ms >= 250, "{:?}", timing);
+        let phases = timing.spawn_ms + timing.run_ms + timing.drain_ms;
+        assert!(
+            output.execution_time_ms.abs_diff(phases) <= 50,
+            "{:?} vs {}ms",
+            timing,
+            output.execution_time_ms
+        );
+        assert!(timing.total_ms() >= phases);
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_path_replaces_inherited_path() {
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), "echo $PATH".to_string()],
+            sanitize_path: Some("/usr/bin:/bin".to_string()),
+            ..Default::default()
+        };
+        let output = SystemCommandRunner.run(&command).await.unwrap();
+        assert_eq!(output.stdout.trim(), "/usr/bin:/bin");
+
+        // A PATH set explicitly for the command wins
+        let command = Command {
+            env: vec![("PATH".to_string(), "/opt/tools/bin".to_string())],
+            ..command
+        };
+        let output = SystemCommandRunner.run(&command).await.unwrap();
+        assert_eq!(output.stdout.trim(), "/opt/tools/bin");
+    }
+
+    #[tokio::test]
+    async fn test_logged_output_is_captured_in_full() {
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), "seq 1 500; printf tail".to_string()],
+            log_output: Some(OutputLogConfig {
+                enabled: true,
+                every_nth_line: 7,
+                max_lines_per_sec: 3,
+            }),
+            ..Default::default()
+        };
+        let output = SystemCommandRunner.run(&command).await.unwrap();
+        let lines: Vec<&str> = output.stdout.lines().collect();
+        assert_eq!(lines.len(), 501);
+        assert_eq!(lines[499], "500");
+        assert_eq!(lines[500], "tail");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_posix_spawn_runs_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Command {
+            // Found through the command's PATH, not the component's
+            script_path: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "echo \"$PATH $GREETING $(pwd)\"; echo oops >&2; exit 3".to_string(),
+            ],
+            working_dir: Some(dir.path().display().to_string()),
+            env: vec![("GREETING".to_string(), "hello".to_string())],
+            sanitize_path: Some("/nonexistent:/bin:/usr/bin".to_string()),
+            spawn_method: SpawnMethod::PosixSpawn,
+            ..Default::default()
+        };
+        let output = SystemCommandRunner.run(&command).await.unwrap();
+        assert_eq!(output.exit_code, 3);
+        assert_eq!(
+            output.stdout.trim(),
+            format!(
+                "/nonexistent:/bin:/usr/bin hello {}",
+                dir.path().canonicalize().unwrap().display()
+            )
+        );
+        assert_eq!(output.stderr.trim(), "oops");
+
+        let missing = Command {
+            script_path: "/nonexistent/device-ops/missing.sh".to_string(),
+            spawn_method: SpawnMethod::PosixSpawn,
+            ..Default::default()
+        };
+        assert!(matches!(
+            SystemCommandRunner.run(&missing).await.unwrap_err(),
+            DeviceOpsError::CommandNotFound(_)
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_posix_spawned_command_is_stopped_on_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let command = Command {
+            script_path: "/bin/sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!("echo $$ > {}; exec sleep 30", pid_file.display()),
+            ],
+            stop_timeout: Duration::from_secs(1),
+            spawn_method: SpawnMethod::PosixSpawn,
+            ..Default::default()
+        };
+
+        let run = timeout(
+            Duration::from_millis(500),
+            SystemCommandRunner.run(&command),
+        )
+        .await;
+        assert!(run.is_err());
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid.trim()));
+        for _ in 0..50 {
+            if !proc_dir.exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed-out command {} is still running", pid.trim());
+    }
+
+    /// Runner that holds each command for a while and tracks how many overlap
+    #[derive(Default)]
+    struct OverlapRunner {
+        running: std::sync::atomic::AtomicUsize,
+        peak: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl CommandRunner for OverlapRunner {
+        async fn run(&self, _command: &Command) -> Result<ExecutionOutput> {
+            use std::sync::atomic::Ordering;
+            let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(ExecutionOutput::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_jobs() {
+        for (limit, expected_peak) in [(1, 1), (2, 2)] {
+            let config = ExecutionConfig {
+                max_concurrent_jobs: limit,
+                ..Default::default()
+            };
+            let executor = CommandExecutor::new_with_runner(config, None, OverlapRunner::default());
+            let document = single_step_document("/opt/a.sh");
+
+            let (a, b) = tokio::join!(executor.execute(&document), executor.execute(&document));
+            assert!(a.unwrap().overall_success && b.unwrap().overall_success);
+            assert_eq!(
+                executor
+                    .runner
+                    .peak
+                    .load(std::sync::atomic::Ordering::SeqCst),
+                expected_peak
+            );
+        }
+    }
+
+    #[test]
+    fn test_step_timeout_precedence() {
+        let config = ExecutionConfig {
+            default_timeout: 300,
+            user_timeouts: [("backupuser".to_string(), 7200)].into(),
+            ..Default::default()
+        };
+        let executor =
+            CommandExecutor::new_with_runner(config, None, MockCommandRunner::new(vec![]));
+        let action = |user: Option<&str>, timeout: Option<u64>| JobAction {
+            run_as_user: user.map(str::to_string),
+            input: JobInput {
+                command: "/opt/a.sh".to_string(),
+                timeout,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Step timeout > user default > global default
+        assert_eq!(
+            executor.step_timeout(&action(Some("backupuser"), Some(60))),
+            60
+        );
+        assert_eq!(
+            executor.step_timeout(&action(Some("backupuser"), None)),
+            7200
+        );
+        assert_eq!(executor.step_timeout(&action(Some("ggc_user"), None)), 300);
+        assert_eq!(executor.step_timeout(&action(None, None)), 300);
+        assert_eq!(executor.step_timeout(&action(None, Some(5))), 5);
+    }
+
+    fn serialized_document(command: &str) -> JobDocument {
+        let mut document = single_step_document(command);
+        document.steps[0].action.serialize_by_command = Some(true);
+        document
+    }
+
+    #[tokio::test]
+    async fn test_serialize_by_command() {
+        let config = ExecutionConfig {
+            max_concurrent_jobs: 2,
+            ..Default::default()
+        };
+        let peak = |executor: &CommandExecutor<OverlapRunner>| {
+            executor
+                .runner
+                .peak
+                .load(std::sync::atomic::Ordering::SeqCst)
+        };
+
+        // The same command waits for the run in progress
+        let executor =
+            CommandExecutor::new_with_runner(config.clone(), None, OverlapRunner::default());
+        let document = serialized_document("/opt/a.sh");
+        let (a, b) = tokio::join!(executor.execute(&document), executor.execute(&document));
+        assert!(a.unwrap().overall_success && b.unwrap().overall_success);
+        assert_eq!(peak(&executor), 1);
+
+        // Different commands still run in parallel
+        let executor = CommandExecutor::new_with_runner(config, None, OverlapRunner::default());
+        let (first, second) = (
+            serialized_document("/opt/a.sh"),
+            serialized_document("/opt/b.sh"),
+        );
+        let (a, b) = tokio::join!(executor.execute(&first), executor.execute(&second));
+        assert!(a.unwrap().overall_success && b.unwrap().overall_success);
+        assert_eq!(peak(&executor), 2);
+    }
+
+    #[tokio::test]
+    async fn test_include_stdout_policy_overrides_document() {
+        let mock = MockCommandRunner::new(vec![stdout_output("store-42")]);
+        let config = ExecutionConfig {
+            include_stdout_policy: IncludeStdoutPolicy::Always,
+            ..Default::default()
+        };
+        let executor = CommandExecutor::new_with_runner(config.clone(), None, mock);
+        let document = single_step_document("/opt/a.sh");
+
+        let result = executor.execute(&document).await.unwrap();
+        let options = StatusDetailsOptions {
+            include_stdout: config
+                .include_stdout_policy
+                .include_stdout(document.include_std_out),
+            ..Default::default()
+        };
+        let status = JobStatus::from_success(&result, &options);
+        assert_eq!(status.to_json()["statusDetails"]["stdout"], "store-42");
+    }
+
+    #[tokio::test]
+    async fn test_include_stdout_on_failed_multi_step_job() {
+        let step = |name: &str| JobStep {
+            action: JobAction {
+                name: name.to_string(),
+                action_type: "runCommand".to_string(),
+                input: JobInput {
+                    command: format!("/opt/device-scripts/{}.sh", name),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        };
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![step("download"), step("install")],
+            include_std_out: Some(true),
+            ..Default::default()
+        };
+        let mock = MockCommandRunner::new(vec![
+            stdout_output("fetched 3 files"),
+            Ok(ExecutionOutput {
+                stdout: "unpacked 2 of 3".to_string(),
+                exit_code: 2,
+                ..Default::default()
+            }),
+        ]);
+        let config = ExecutionConfig::default();
+        let executor = CommandExecutor::new_with_runner(config.clone(), None, mock);
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+
+        let steps = |include_std_out: Option<bool>| -> Vec<serde_json::Value> {
+            let options = StatusDetailsOptions {
+                include_stdout: config.include_stdout_policy.include_stdout(include_std_out),
+                ..Default::default()
+            };
+            let details = format_status_details(&result, &options);
+            assert_eq!(details["overall_success"], "false");
+            serde_json::from_str(details["steps"].as_str().unwrap()).unwrap()
+        };
+
+        let included = steps(document.include_std_out);
+        assert_eq!(included[0]["stdout"], "fetched 3 files");
+        assert_eq!(included[1]["stdout"], "unpacked 2 of 3");
+        assert_eq!(included[1]["exit_code"], 2);
+
+        for step in steps(None) {
+            assert!(step.get("stdout").is_none(), "{}", step);
+        }
+    }
+
+    /// Probe reporting a fixed battery level
+    struct FixedBattery(Option<u8>);
+
+    impl BatteryProbe for FixedBattery {
+        fn battery_percent(&self, _command: Option<&str>) -> Result<Option<u8>> {
+            Ok(self.0)
+        }
+    }
+
+    fn battery_gated_document(min_percent: u8) -> JobDocument {
+        JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "Firmware".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: "/opt/device-scripts/update-firmware.sh".to_string(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            }],
+            require_min_battery_percent: Some(min_percent),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_battery_above_threshold_runs_job() {
+        for level in [Some(80), None] {
+            let mock = MockCommandRunner::new(vec![attempt_output(0)]);
+            let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock)
+                .with_battery_probe(FixedBattery(level));
+
+            let result = executor.execute(&battery_gated_document(50)).await.unwrap();
+            assert!(result.overall_success, "battery {:?}", level);
+            assert_eq!(result.outputs.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_low_battery_aborts_before_first_step() {
+        // No mock responses: running any step would fail differently
+        let executor = CommandExecutor::new_with_runner(
+            ExecutionConfig::default(),
+            None,
+            MockCommandRunner::new(vec![]),
+        )
+        .with_battery_probe(FixedBattery(Some(20)));
+
+        let result = executor.execute(&battery_gated_document(50)).await.unwrap();
+        assert!(!result.overall_success);
+        assert!(result.outputs.is_empty());
+        assert_eq!(
+            result.failure_reason.as_deref(),
+            Some("Battery at 20%, job requires at least 50%")
+        );
+    }
+
+    /// Verifier standing in for a device without usable sudo
+    struct NoSudo;
+
+    #[async_trait]
+    impl SudoVerifier for NoSudo {
+        async fn verify(&self, _user: &str, _probe_timeout: Duration) -> Result<RunAsCheck> {
+            Ok(RunAsCheck::Unavailable)
+        }
+    }
+
+    fn action_as_user(fallback: Option<RunAsUserFallback>) -> JobAction {
+        JobAction {
+            name: "AsUser".to_string(),
+            action_type: "runCommand".to_string(),
+            input: JobInput {
+                command: "/opt/test.sh".to_string(),
+                ..Default::default()
+            },
+            run_as_user: Some("ggc_user".to_string()),
+            run_as_user_fallback: fallback,
+            ..Default::default()
+        }
+    }
+
+    fn executor_without_sudo(fallback: RunAsUserFallback) -> CommandExecutor<MockCommandRunner> {
+        let config = ExecutionConfig {
+            run_as_user_fallback: fallback,
+            ..Default::default()
+        };
+        CommandExecutor::new_with_runner(config, None, MockCommandRunner::new(vec![]))
+            .with_sudo_verifier(NoSudo)
+    }
+
+    #[tokio::test]
+    async fn test_run_as_user_falls_back_to_current_user() {
+        let executor = executor_without_sudo(RunAsUserFallback::CurrentUser);
+
+        let command = executor.build_command(&action_as_user(None)).await.unwrap();
+        assert_eq!(command.run_as_user, None);
+    }
+
+    #[tokio::test]
+    async fn test_run_as_user_fallback_fail() {
+        let executor = executor_without_sudo(RunAsUserFallback::Fail);
+
+        let err = executor
+            .build_command(&action_as_user(None))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeviceOpsError::SecurityError(_)));
+        assert!(err.to_string().contains("ggc_user"));
+    }
+
+    /// Verifier for a device where sudo to any user works
+    struct AnySudo;
+
+    #[async_trait]
+    impl SudoVerifier for AnySudo {
+        async fn verify(&self, _user: &str, _probe_timeout: Duration) -> Result<RunAsCheck> {
+            Ok(RunAsCheck::Available)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_ran_as_ids_are_recorded() {
+        let executor = CommandExecutor::new_with_runner(
+            ExecutionConfig::default(),
+            None,
+            MockCommandRunner::new(vec![stdout_output(""), stdout_output("")]),
+        )
+        .with_sudo_verifier(AnySudo);
+
+        // Without runAsUser the command runs as the component
+        let result = executor
+            .execute(&single_step_document("/opt/a.sh"))
+            .await
+            .unwrap();
+        let (uid, gid) = ids::resolve(None).unwrap();
+        assert_eq!(result.outputs[0].output.ran_as_uid, Some(uid));
+        assert_eq!(result.outputs[0].output.ran_as_gid, Some(gid));
+
+        // With it, as the user sudo switches to
+        let mut document = single_step_document("/opt/a.sh");
+        document.steps[0].action.run_as_user = Some("root".to_string());
+        let result = executor.execute(&document).await.unwrap();
+        assert_eq!(result.outputs[0].output.ran_as_uid, Some(0));
+        assert_eq!(result.outputs[0].output.ran_as_gid, Some(0));
+
+        let options = StatusDetailsOptions {
+            include_ran_as: true,
+            ..Default::default()
+        };
+        let details = &JobStatus::from_success(&result, &options).to_json()["statusDetails"];
+        assert_eq!(details["ran_as_uid"], "0");
+        assert_eq!(details["ran_as_gid"], "0");
+        let details = &JobStatus::from_success(&result, &StatusDetailsOptions::default()).to_json()
+            ["statusDetails"];
+        assert!(details.get("ran_as_uid").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_as_user_precedence() {
+        let config = ExecutionConfig {
+            default_run_as_user: Some("opsuser".to_string()),
+            ..Default::default()
+        };
+        let executor = CommandExecutor::new_with_runner(
+            config,
+            None,
+            MockCommandRunner::new(vec![
+                stdout_output(""),
+                stdout_output(""),
+                stdout_output(""),
+            ]),
+        )
+        .with_sudo_verifier(AnySudo);
+        let users = |executor: &CommandExecutor<MockCommandRunner>| -> Vec<Option<String>> {
+            let commands = executor.runner.commands.lock().unwrap();
+            commands.iter().map(|c| c.run_as_user.clone()).collect()
+        };
+
+        // Config default when neither the step nor the document names a user
+        executor
+            .execute(&single_step_document("/opt/a.sh"))
+            .await
+            .unwrap();
+        // Document default over the config default
+        let mut document = single_step_document("/opt/a.sh");
+        document.run_as_user = Some("docuser".to_string());
+        executor.execute(&document).await.unwrap();
+        // Step over the document default
+        document.steps[0].action.run_as_user = Some("stepuser".to_string());
+        executor.execute(&document).await.unwrap();
+
+        assert_eq!(
+            users(&executor),
+            vec![
+                Some("opsuser".to_string()),
+                Some("docuser".to_string()),
+                Some("stepuser".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_component_user_opt_out() {
+        let mut action = action_as_user(None);
+        action.run_as_user = Some(String::new());
+
+        let config = ExecutionConfig {
+            default_run_as_user: Some("opsuser".to_string()),
+            ..Default::default()
+        };
+        let executor =
+            CommandExecutor::new_with_runner(config.clone(), None, MockCommandRunner::new(vec![]))
+                .with_sudo_verifier(AnySudo);
+        let err = executor.build_command(&action).await.unwrap_err();
+        assert!(err.to_string().contains("allow_component_user"));
+
+        let config = ExecutionConfig {
+            allow_component_user: true,
+            ..config
+        };
+        let executor =
+            CommandExecutor::new_with_runner(config, None, MockCommandRunner::new(vec![]))
+                .with_sudo_verifier(AnySudo);
+        assert_eq!(
+            executor.build_command(&action).await.unwrap().run_as_user,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_default_user_never_falls_back_silently() {
+        let config = ExecutionConfig {
+            default_run_as_user: Some("opsuser".to_string()),
+            ..Default::default()
+        };
+        let mut action = action_as_user(None);
+        action.run_as_user = None;
+
+        // The default fallback is the current user, but not for the config default
+        let executor =
+            CommandExecutor::new_with_runner(config.clone(), None, MockCommandRunner::new(vec![]))
+                .with_sudo_verifier(NoSudo);
+        let err = executor.build_command(&action).await.unwrap_err();
+        assert!(err.to_string().contains("opsuser"));
+
+        assert!(verify_default_run_as_user(&config, &NoSudo).await.is_err());
+        assert!(verify_default_run_as_user(&config, &AnySudo).await.is_ok());
+        assert!(
+            verify_default_run_as_user(&ExecutionConfig::default(), &NoSudo)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_sudo_denial_detection() {
+        for stderr in [
+            "sudo: a password is required\n",
+            "sudo: unknown user ops\nsudo: error initializing audit plugin sudoers_audit\n",
+            "\nsudo: ggc_user is not in the sudoers file.  This incident will be reported.\n",
+            "Sorry, user ggc_user is not allowed to execute '/opt/a.sh' as ops on core-1.\n",
+            "sudo: account validation failure, is your account locked?\n",
+        ] {
+            assert!(sudo_denial(1, stderr).is_some(), "{}", stderr);
+        }
+        assert_eq!(
+            sudo_denial(1, "sudo: a password is required\n"),
+            Some("sudo: a password is required")
+        );
+        // The command ran: other exit codes, or its own output first
+        assert_eq!(sudo_denial(2, "sudo: a password is required\n"), None);
+        assert_eq!(
+            sudo_denial(1, "Installing...\nsudo: a password is required\n"),
+            None
+        );
+        assert_eq!(sudo_denial(1, "sudo: apt-get: command not found\n"), None);
+        assert_eq!(sudo_denial(1, ""), None);
+    }
+
+    #[tokio::test]
+    async fn test_sudo_denial_is_a_privilege_failure() {
+        let denied = ExecutionOutput {
+            stderr: "sudo: a password is required\n".to_string(),
+            exit_code: 1,
+            stderr_line_count: 1,
+            failure_class: Some(FailureClass::Privilege),
+            ..Default::default()
+        };
+        let executor = CommandExecutor::new_with_runner(
+            ExecutionConfig::default(),
+            None,
+            MockCommandRunner::new(vec![Ok(denied)]),
+        )
+        .with_sudo_verifier(AnySudo);
+        let mut document = single_step_document("/opt/a.sh");
+        document.steps[0].action.run_as_user = Some("ops".to_string());
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(
+            result.failure_reason.as_deref(),
+            Some("Step 'Step' did not run: sudo refused to run it as user 'ops' (sudo: a password is required); check the sudoers rules for the component's user")
+        );
+        let details = format_status_details(&result, &StatusDetailsOptions::default());
+        assert_eq!(details["failure_class"], "privilege");
+        assert!(details["reason"].as_str().unwrap().contains("did not run"));
+    }
+
+    #[tokio::test]
+    async fn test_strict_run_as_user_ignores_fallbacks() {
+        let config = ExecutionConfig {
+            strict_run_as_user: true,
+            ..Default::default()
+        };
+        let executor =
+            CommandExecutor::new_with_runner(config, None, MockCommandRunner::new(vec![]))
+                .with_sudo_verifier(NoSudo);
+
+        let err = executor
+            .build_command(&action_as_user(Some(RunAsUserFallback::CurrentUser)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("strict_run_as_user"));
+    }
+
+    #[tokio::test]
+    async fn test_run_as_user_fallback_step_override() {
+        // A step may tighten the configured fallback
+        let lenient = executor_without_sudo(RunAsUserFallback::CurrentUser);
+        assert!(lenient
+            .build_command(&action_as_user(Some(RunAsUserFallback::Fail)))
+            .await
+            .is_err());
+
+        // But a document cannot loosen a configured `fail`
+        let strict = executor_without_sudo(RunAsUserFallback::Fail);
+        let err = strict
+            .build_command(&action_as_user(Some(RunAsUserFallback::CurrentUser)))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("execution.run_as_user_fallback is fail"),
+            "{}",
+            err
+        );
+    }
+
+    /// Verifier for a device whose user directory hangs
+    struct HungUserLookup;
+
+    #[async_trait]
+    impl SudoVerifier for HungUserLookup {
+        async fn verify(&self, user: &str, _probe_timeout: Duration) -> Result<RunAsCheck> {
+            Ok(RunAsCheck::TimedOut(format!("id {}", user)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_as_user_verification_timeout() {
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: action_as_user(None),
+            }],
+            ..Default::default()
+        };
+
+        // Lenient: the step runs as the current user and its stderr says why
+        let executor = CommandExecutor::new_with_runner(
+            ExecutionConfig::default(),
+            None,
+            MockCommandRunner::new(vec![stdout_output("done")]),
+        )
+        .with_sudo_verifier(HungUserLookup);
+        let result = executor.execute(&document).await.unwrap();
+        assert!(result.overall_success);
+        let output = &result.outputs[0].output;
+        assert_eq!(
+            output.stderr,
+            "[runAsUser 'ggc_user' verification timed out (id ggc_user took over 5s); ran as the current user]"
+        );
+        assert_eq!(output.stderr_line_count, 0);
+        assert_eq!(
+            executor.runner.commands.lock().unwrap()[0].run_as_user,
+            None
+        );
+
+        // Strict: the step fails, naming the timeout rather than a missing user
+        let config = ExecutionConfig {
+            strict_run_as_user: true,
+            ..Default::default()
+        };
+        let executor =
+            CommandExecutor::new_with_runner(config.clone(), None, MockCommandRunner::new(vec![]))
+                .with_sudo_verifier(HungUserLookup);
+        let err = executor
+            .build_command(&action_as_user(None))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("verification timed out"), "{}", err);
+        assert!(!err.contains("unavailable"), "{}", err);
+
+        let config = ExecutionConfig {
+            default_run_as_user: Some("opsuser".to_string()),
+            ..config
+        };
+        let err = verify_default_run_as_user(&config, &HungUserLookup)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("id opsuser timed out after 5s"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_hung_probe_is_killed_after_timeout() {
+        let started = std::time::Instant::now();
+        let outcome = SystemSudoVerifier::probe("sleep", &["10"], Duration::from_millis(100)).await;
+        assert_eq!(outcome.unwrap(), None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let outcome = SystemSudoVerifier::probe("true", &[], Duration::from_secs(5)).await;
+        assert_eq!(outcome.unwrap(), Some(true));
+        let outcome = SystemSudoVerifier::probe("false", &[], Duration::from_secs(5)).await;
+        assert_eq!(outcome.unwrap(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_run_as_user_fallback_fail_fails_job() {
+        let executor = executor_without_sudo(RunAsUserFallback::Fail);
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: action_as_user(None),
+            }],
+            ..Default::default()
+        };
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+        assert!(result
+            .failure_reason
+            .unwrap_or_default()
+            .contains("execution.run_as_user_fallback is fail"));
+    }
+
+    #[tokio::test]
+    async fn test_command_runs_in_working_dir() {
+        let command = Command {
+            script_path: "/bin/pwd".to_string(),
+            args: vec![],
+            run_as_user: None,
+            working_dir: Some("/".to_string()),
+            ..Default::default()
+        };
+
+        let output = SystemCommandRunner.run(&command).await.unwrap();
+        assert_eq!(output.stdout.trim(), "/");
+    }
+
+    #[test]
+    fn test_command_line_wrapping() {
+        let mut command = Command {
+            script_path: "/opt/device-scripts/update.sh".to_string(),
+            args: vec!["--fast".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            SystemCommandRunner::command_line(&command),
+            (command.script_path.clone(), vec!["--fast".to_string()])
+        );
+
+        command.slice = Some("device-ops.slice".to_string());
+        command.run_as_user = Some("ggc_user".to_string());
+        let (program, args) = SystemCommandRunner::command_line(&command);
+        assert_eq!(program, "systemd-run");
+        assert_eq!(
+            args,
+            vec![
+                "--scope",
+                "--quiet",
+                "--slice=device-ops.slice",
+                "--",
+                "sudo",
+                "-u",
+                "ggc_user",
+                "-n",
+                "/opt/device-scripts/update.sh",
+                "--fast",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_line_with_wrapper() {
+        let taskset = vec![
+            "/usr/bin/taskset".to_string(),
+            "-c".to_string(),
+            "3".to_string(),
+        ];
+        let mut command = Command {
+            script_path: "/opt/device-scripts/update.sh".to_string(),
+            args: vec!["--fast".to_string()],
+            wrapper: taskset.clone(),
+            ..Default::default()
+        };
+        let (program, args) = SystemCommandRunner::command_line(&command);
+        assert_eq!(program, "/usr/bin/taskset");
+        assert_eq!(
+            args,
+            vec!["-c", "3", "/opt/device-scripts/update.sh", "--fast"]
+        );
+
+        // The wrapper goes around sudo, and inside systemd-run
+        command.run_as_user = Some("ggc_user".to_string());
+        let (program, args) = SystemCommandRunner::command_line(&command);
+        assert_eq!(program, "/usr/bin/taskset");
+        assert_eq!(
+            args,
+            vec![
+                "-c",
+                "3",
+                "sudo",
+                "-u",
+                "ggc_user",
+                "-n",
+                "/opt/device-scripts/update.sh",
+                "--fast",
+            ]
+        );
+
+        command.slice = Some("device-ops.slice".to_string());
+        let (program, args) = SystemCommandRunner::command_line(&command);
+        assert_eq!(program, "systemd-run");
+        assert_eq!(
+            args[..7],
+            [
+                "--scope",
+                "--quiet",
+                "--slice=device-ops.slice",
+                "--",
+                "/usr/bin/taskset",
+                "-c",
+                "3"
+            ]
+        );
+        assert_eq!(args[7], "sudo");
+    }
+
+    #[tokio::test]
+    async fn test_steps_run_through_configured_wrapper() {
+        let config = ExecutionConfig {
+            command_wrapper: Some(vec!["/usr/bin/env".to_string(), "WRAPPED=yes".to_string()]),
+            ..Default::default()
+        };
+        let executor = CommandExecutor::new(config, None);
+
+        let mut document = single_step_document("/usr/bin/printenv");
+        document.steps[0].action.input.args = Some(vec!["WRAPPED".to_string()]);
+        let result = executor.execute(&document).await.unwrap();
+        assert!(result.overall_success);
+        assert_eq!(result.outputs[0].output.stdout.trim(), "yes");
+    }
+
+    #[tokio::test]
+    async fn test_missing_script_reports_command_not_found() {
+        let command = Command {
+            script_path: "/nonexistent/device-ops/missing.sh".to_string(),
+            args: vec![],
+            run_as_user: None,
+            working_dir: None,
+            ..Default::default()
+        };
+
+        let err = SystemCommandRunner.run(&command).await.unwrap_err();
+        assert!(
+            matches!(err, DeviceOpsError::CommandNotFound(ref path) if path == &command.script_path)
+        );
+        assert_eq!(
+            err.to_string(),
+            "command not found: /nonexistent/device-ops/missing.sh"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spawn_failure_keeps_io_error() {
+        use std::error::Error;
+
+        // Without any execute bit even root cannot run it
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("not-executable.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        let command = Command {
+            script_path: script.display().to_string(),
+            ..Default::default()
+        };
+
+        let err = SystemCommandRunner.run(&command).await.unwrap_err();
+        let io = err
+            .source()
+            .and_then(|source| source.downcast_ref::<std::io::Error>())
+            .unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(err.kind(), crate::error::ErrorKind::Execution);
+        assert_eq!(err.reason_code(), "SPAWN_FAILED");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Job execution failed: cannot start {}: {}",
+                script.display(),
+                io
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_not_found_reason_in_result() {
+        let config = ExecutionConfig {
+            default_timeout: 300,
+            ..Default::default()
+        };
+
+        let mock = MockCommandRunner::new(vec![Err(DeviceOpsError::CommandNotFound(
+            "/opt/missing.sh".to_string(),
+        ))]);
+
+        let executor = CommandExecutor::new_with_runner(config, None, mock);
+
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "MissingStep".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: "/opt/missing.sh".to_string(),
+                        args: None,
+                        timeout: None,
+                        ..Default::default()
+                    },
+                    run_as_user: None,
+                    ignore_step_failure: None,
+                    allow_std_err: None,
+                    ..Default::default()
+                },
+            }],
+            final_step: None,
+            include_std_out: None,
+            ..Default::default()
+        };
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(result.failed_step, Some("MissingStep".to_string()));
+        assert_eq!(
+            result.failure_reason,
+            Some("command not found: /opt/missing.sh".to_string())
+        );
+        assert_eq!(result.reason_code, Some("COMMAND_NOT_FOUND"));
+        let status = JobStatus::from_failure(&result, &StatusDetailsOptions::default());
+        assert_eq!(
+            status.to_json()["statusDetails"]["reason_code"],
+            "COMMAND_NOT_FOUND"
+        );
+    }
+
+    fn grouped_step(name: &str, group: &str) -> JobStep {
+        JobStep {
+            action: JobAction {
+                name: name.to_string(),
+                action_type: "runCommand".to_string(),
+                input: JobInput {
+                    command: "/opt/test.sh".to_string(),
+                    ..Default::default()
+                },
+                group: Some(group.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ignored_group_failure_runs_later_group() {
+        let ok = || {
+            Ok(ExecutionOutput {
+                stdout: String::new(),
+                stderr: String::new(),
+                exit_code: 0,
+                execution_time_ms: 0,
+                stderr_line_count: 0,
+                stdout_truncated: false,
+                stderr_truncated: false,
+                ..Default::default()
+            })
+        };
+        let failed = Ok(ExecutionOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: 1,
+            execution_time_ms: 0,
+            stderr_line_count: 0,
+            stdout_truncated: false,
+            stderr_truncated: false,
+            ..Default::default()
+        });
+
+        // verify-1 fails, verify-2 is skipped, cleanup still runs
+        let mock = MockCommandRunner::new(vec![ok(), failed, ok()]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![
+                grouped_step("install", "install"),
+                grouped_step("verify-1", "verify"),
+                grouped_step("verify-2", "verify"),
+                grouped_step("cleanup", "cleanup"),
+            ],
+            group_policies: Some(
+                [("verify".to_string(), GroupFailurePolicy::IgnoreFailure)]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(result.overall_success);
+        let names: Vec<&str> = result
+            .outputs
+            .iter()
+            .map(|o| o.step_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["install", "verify-1", "cleanup"]);
+        assert!(result.outputs[1].ignored_failure);
+    }
+
+    #[tokio::test]
+    async fn test_stop_on_failure_group_fails_job() {
+        let mock = MockCommandRunner::new(vec![Ok(ExecutionOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: 1,
+            execution_time_ms: 0,
+            stderr_line_count: 0,
+            stdout_truncated: false,
+            stderr_truncated: false,
+            ..Default::default()
+        })]);
+        let executor = CommandExecutor::new_with_runner(ExecutionConfig::default(), None, mock);
+
+        let document = JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![
+                grouped_step("install", "install"),
+                grouped_step("cleanup", "cleanup"),
+            ],
+            group_policies: Some(
+                [("install".to_string(), GroupFailurePolicy::StopOnFailure)]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        let result = executor.execute(&document).await.unwrap();
+        assert!(!result.overall_success);
+        assert_eq!(result.failed_step, Some("install".to_string()));
+        assert_eq!(result.outputs.len(), 1);
+    }
 }
@@ -0,0 +1,123 @@
+use crate::config::UndefinedEnvPolicy;
+use crate::error::{DeviceOpsError, Result};
+
+/// Expand `${VAR}` references in an argument.
+///
+/// Only variables named in `allowlist` may be referenced; anything else is a
+/// security error. Undefined (but allowed) variables either fail or expand to
+/// an empty string depending on `policy`. A `$` not followed by `{` is kept
+/// literally.
+pub fn expand_env_refs<F>(
+    arg: &str,
+    allowlist: &[String],
+    policy: UndefinedEnvPolicy,
+    lookup: F,
+) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(arg.len());
+    let mut rest = arg;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+
+        let end = after.find('}').ok_or_else(|| {
+            DeviceOpsError::ExecutionError(format!(
+                "Unterminated variable reference in arg: {}",
+                arg
+            ))
+        })?;
+        let name = &after[..end];
+
+        if !allowlist.iter().any(|allowed| allowed == name) {
+            return Err(DeviceOpsError::SecurityError(format!(
+                "Environment variable not allowed in args: {}",
+                name
+            )));
+        }
+
+        match lookup(name) {
+            Some(value) => result.push_str(&value),
+            None => match policy {
+                UndefinedEnvPolicy::Empty => {}
+                UndefinedEnvPolicy::Error => {
+                    return Err(DeviceOpsError::ExecutionError(format!(
+                        "Environment variable not set: {}",
+                        name
+                    )));
+                }
+            },
+        }
+
+        rest = &after[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOSTNAME" => Some("edge-01".to_string()),
+            "SECRET_TOKEN" => Some("hunter2".to_string()),
+            _ => None,
+        }
+    }
+
+    fn allowlist() -> Vec<String> {
+        vec!["HOSTNAME".to_string(), "STORE_ID".to_string()]
+    }
+
+    #[test]
+    fn test_expands_allowed_variable() {
+        let expanded = expand_env_refs(
+            "--host=${HOSTNAME}/x",
+            &allowlist(),
+            UndefinedEnvPolicy::Error,
+            lookup,
+        )
+        .unwrap();
+        assert_eq!(expanded, "--host=edge-01/x");
+
+        // Plain `$` is not a reference
+        let literal = expand_env_refs("cost $5", &allowlist(), UndefinedEnvPolicy::Error, lookup);
+        assert_eq!(literal.unwrap(), "cost $5");
+    }
+
+    #[test]
+    fn test_rejects_disallowed_variable() {
+        let err = expand_env_refs(
+            "${SECRET_TOKEN}",
+            &allowlist(),
+            UndefinedEnvPolicy::Empty,
+            lookup,
+        )
+        .unwrap_err();
+        assert!(matches!(err, DeviceOpsError::SecurityError(_)));
+    }
+
+    #[test]
+    fn test_undefined_variable_policy() {
+        let err = expand_env_refs(
+            "${STORE_ID}",
+            &allowlist(),
+            UndefinedEnvPolicy::Error,
+            lookup,
+        );
+        assert!(err.is_err());
+
+        let empty = expand_env_refs(
+            "id=${STORE_ID}",
+            &allowlist(),
+            UndefinedEnvPolicy::Empty,
+            lookup,
+        );
+        assert_eq!(empty.unwrap(), "id=");
+    }
+}
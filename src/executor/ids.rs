@@ -0,0 +1,80 @@
+//! The uid and gid a command runs as
+//!
+//! Recorded as `ran_as_uid`/`ran_as_gid` for audit. A step with runAsUser
+//! runs through `sudo -u <user>`, which takes the user's uid and primary
+//! gid from the passwd database; any other step runs as the component's
+//! effective uid and gid. A setuid script or a command wrapper that switches
+//! users is not seen here. Only resolved on Linux; elsewhere nothing is
+//! recorded.
+
+/// uid and primary gid of `user`, or of the component when `None`
+pub fn resolve(user: Option<&str>) -> Option<(u32, u32)> {
+    #[cfg(target_os = "linux")]
+    {
+        match user {
+            Some(user) => linux::lookup(user),
+            None => Some(linux::effective()),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = user;
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CString;
+
+    pub fn effective() -> (u32, u32) {
+        // SAFETY: plain syscalls without arguments; they cannot fail
+        unsafe { (libc::geteuid(), libc::getegid()) }
+    }
+
+    pub fn lookup(user: &str) -> Option<(u32, u32)> {
+        let name = CString::new(user).ok()?;
+        let mut buffer = vec![0 as libc::c_char; 1024];
+        loop {
+            // SAFETY: all-zero is a valid `passwd` (null pointers and zero ids)
+            let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut found: *mut libc::passwd = std::ptr::null_mut();
+            // SAFETY: `entry`, `buffer` and `found` outlive the call and
+            // `buffer`'s length is passed along with it
+            let code = unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            };
+            match code {
+                // Entries with long fields need a larger buffer
+                libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+                0 if !found.is_null() => return Some((entry.pw_uid, entry.pw_gid)),
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_ids_are_its_effective_ids() {
+        // SAFETY: plain syscalls without arguments
+        let expected = unsafe { (libc::geteuid(), libc::getegid()) };
+        assert_eq!(resolve(None), Some(expected));
+    }
+
+    #[test]
+    fn test_user_ids_come_from_the_passwd_database() {
+        assert_eq!(resolve(Some("root")), Some((0, 0)));
+        assert_eq!(resolve(Some("no-such-user-for-ids")), None);
+        assert_eq!(resolve(Some("bad\0name")), None);
+    }
+}
@@ -1,3 +1,8 @@
 pub mod command;
+pub mod env;
+pub mod ids;
+pub mod output_log;
+pub mod power;
+pub mod spawn;
 
 pub use command::CommandExecutor;
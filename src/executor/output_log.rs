@@ -0,0 +1,332 @@
+//! Sampled logging of step output
+//!
+//! With `execution.log_output` on, the lines a step writes are logged as
+//! they arrive, so a hanging step shows how far it got. A chatty command
+//! could flood the logs that way, so each stream logs only every Nth line
+//! and at most `max_lines_per_sec` lines in any second; the rest are counted
+//! and reported when the stream ends. Sampling only affects the log: the
+//! step's output is captured in full either way.
+//!
+//! With `execution.live_output` on, the same lines also go to a
+//! [`LiveOutput`] channel, at most `live_output_lines_per_sec` per stream,
+//! for the job handler to publish on local pub/sub.
+
+use crate::config::OutputLogConfig;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Lines waiting to be published live; more are dropped
+pub const LIVE_OUTPUT_QUEUE_CAPACITY: usize = 256;
+
+/// One output line of a running step
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveLine {
+    pub step_name: String,
+    pub stream: &'static str,
+    /// 1-based, counting every line of the stream, published or not
+    pub line_number: u64,
+    pub line: String,
+}
+
+/// Where a job's output lines go as they are read
+#[derive(Debug, Clone)]
+pub struct LiveOutput {
+    lines: mpsc::Sender<LiveLine>,
+    max_lines_per_sec: u32,
+    step_name: String,
+}
+
+impl LiveOutput {
+    /// A channel taking at most `max_lines_per_sec` lines per stream and its receiver
+    pub fn channel(max_lines_per_sec: u32) -> (Self, mpsc::Receiver<LiveLine>) {
+        let (lines, receiver) = mpsc::channel(LIVE_OUTPUT_QUEUE_CAPACITY);
+        let live = Self {
+            lines,
+            max_lines_per_sec,
+            step_name: String::new(),
+        };
+        (live, receiver)
+    }
+
+    /// The same channel, for the lines of step `step_name`
+    pub fn for_step(&self, step_name: &str) -> Self {
+        Self {
+            step_name: step_name.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Longest line logged; the rest of a longer line is cut
+pub const MAX_LOGGED_LINE_BYTES: usize = 1024;
+
+/// Decides which lines of one stream are logged
+#[derive(Debug)]
+pub struct LineSampler {
+    every_nth_line: u64,
+    /// 0 for no cap
+    max_lines_per_sec: u32,
+    /// Lines seen so far
+    lines: u64,
+    /// Lines left out of the log so far
+    skipped: u64,
+    window_start: Option<Instant>,
+    logged_in_window: u32,
+}
+
+impl LineSampler {
+    pub fn new(config: &OutputLogConfig) -> Self {
+        Self {
+            every_nth_line: config.every_nth_line.max(1),
+            max_lines_per_sec: config.max_lines_per_sec,
+            lines: 0,
+            skipped: 0,
+            window_start: None,
+            logged_in_window: 0,
+        }
+    }
+
+    /// Whether the next line, seen at `now`, is logged
+    pub fn sample(&mut self, now: Instant) -> bool {
+        self.lines += 1;
+        let logged = (self.lines - 1).is_multiple_of(self.every_nth_line) && self.within_rate(now);
+        if !logged {
+            self.skipped += 1;
+        }
+        logged
+    }
+
+    fn within_rate(&mut self, now: Instant) -> bool {
+        if self.max_lines_per_sec == 0 {
+            return true;
+        }
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {}
+            _ => {
+                self.window_start = Some(now);
+                self.logged_in_window = 0;
+            }
+        }
+        if self.logged_in_window >= self.max_lines_per_sec {
+            return false;
+        }
+        self.logged_in_window += 1;
+        true
+    }
+
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+/// Splits one stream's chunks into lines, logs the sampled ones and passes
+/// them on live
+#[derive(Debug)]
+pub struct LineLogger {
+    stream: &'static str,
+    /// Decides which lines are logged, or none with logging off
+    sampler: Option<LineSampler>,
+    live: Option<(LiveOutput, LineSampler)>,
+    /// Lines seen so far
+    lines: u64,
+    /// Start of the line not yet ended by a newline, up to `MAX_LOGGED_LINE_BYTES`
+    partial: Vec<u8>,
+    /// Whether part of a line has been read, but not its newline
+    in_line: bool,
+}
+
+impl LineLogger {
+    /// A logger for `stream`, or none when its lines go neither to the log
+    /// nor live
+    pub fn new(
+        stream: &'static str,
+        log: Option<&OutputLogConfig>,
+        live: Option<&LiveOutput>,
+    ) -> Option<Self> {
+        if log.is_none() && live.is_none() {
+            return None;
+        }
+        let live = live.map(|live| {
+            let rate = OutputLogConfig {
+                enabled: true,
+                every_nth_line: 1,
+                max_lines_per_sec: live.max_lines_per_sec,
+            };
+            (live.clone(), LineSampler::new(&rate))
+        });
+        Some(Self {
+            stream,
+            sampler: log.map(LineSampler::new),
+            live,
+            lines: 0,
+            partial: Vec::new(),
+            in_line: false,
+        })
+    }
+
+    /// Log the sampled lines completed by `chunk`
+    pub fn feed(&mut self, chunk: &[u8]) {
+        let now = Instant::now();
+        let mut rest = chunk;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            self.append(&rest[..end]);
+            self.end_line(now);
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            self.append(rest);
+            self.in_line = true;
+        }
+    }
+
+    /// Log a last line without a newline and how many lines were left out
+    pub fn finish(mut self) {
+        if self.in_line {
+            self.end_line(Instant::now());
+        }
+        if let Some(sampler) = self.sampler.as_ref().filter(|s| s.skipped() > 0) {
+            tracing::info!(
+                stream = self.stream,
+                lines = sampler.lines(),
+                not_logged = sampler.skipped(),
+                "Some output lines were not logged"
+            );
+        }
+        if let Some((_, sampler)) = self.live.as_ref().filter(|(_, s)| s.skipped() > 0) {
+            tracing::debug!(
+                stream = self.stream,
+                lines = sampler.lines(),
+                not_published = sampler.skipped(),
+                "Some output lines were not published live"
+            );
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        let room = MAX_LOGGED_LINE_BYTES.saturating_sub(self.partial.len());
+        self.partial
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    fn end_line(&mut self, now: Instant) {
+        self.lines += 1;
+        let line = String::from_utf8_lossy(&self.partial);
+        let line = line.trim_end_matches('\r');
+        if self.sampler.as_mut().is_some_and(|s| s.sample(now)) {
+            tracing::info!(stream = self.stream, line_number = self.lines, "{}", line);
+        }
+        if let Some((live, sampler)) = self.live.as_mut() {
+            // A full queue drops the line; live output never holds up the step
+            if sampler.sample(now) {
+                let _ = live.lines.try_send(LiveLine {
+                    step_name: live.step_name.clone(),
+                    stream: self.stream,
+                    line_number: self.lines,
+                    line: line.to_string(),
+                });
+            }
+        }
+        self.partial.clear();
+        self.in_line = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(every_nth_line: u64, max_lines_per_sec: u32) -> OutputLogConfig {
+        OutputLogConfig {
+            enabled: true,
+            every_nth_line,
+            max_lines_per_sec,
+        }
+    }
+
+    fn sampled(sampler: &mut LineSampler, now: Instant, lines: usize) -> usize {
+        (0..lines).filter(|_| sampler.sample(now)).count()
+    }
+
+    #[test]
+    fn test_every_nth_line() {
+        let mut sampler = LineSampler::new(&config(3, 0));
+        let now = Instant::now();
+        let logged: Vec<bool> = (0..7).map(|_| sampler.sample(now)).collect();
+        assert_eq!(logged, [true, false, false, true, false, false, true]);
+        assert_eq!(sampler.lines(), 7);
+        assert_eq!(sampler.skipped(), 4);
+    }
+
+    #[test]
+    fn test_lines_per_second_cap() {
+        let mut sampler = LineSampler::new(&config(1, 5));
+        let start = Instant::now();
+        assert_eq!(sampled(&mut sampler, start, 100), 5);
+        assert_eq!(
+            sampled(&mut sampler, start + Duration::from_millis(999), 10),
+            0
+        );
+        // A new second starts a new window
+        assert_eq!(
+            sampled(&mut sampler, start + Duration::from_millis(1000), 10),
+            5
+        );
+        assert_eq!(sampler.skipped(), 120 - 10);
+    }
+
+    #[test]
+    fn test_cap_applies_after_every_nth() {
+        let mut sampler = LineSampler::new(&config(10, 2));
+        // Lines 1, 11 and 21 are candidates; only two fit in the second
+        assert_eq!(sampled(&mut sampler, Instant::now(), 30), 2);
+    }
+
+    #[test]
+    fn test_logger_splits_lines_across_chunks() {
+        let mut logger = LineLogger::new("stdout", Some(&config(1, 0)), None).unwrap();
+        logger.feed(b"first\nsec");
+        assert_eq!(logger.lines, 1);
+        logger.feed(b"ond\r\n");
+        assert_eq!(logger.lines, 2);
+        logger.feed(&[b'x'; 3 * MAX_LOGGED_LINE_BYTES]);
+        assert_eq!(logger.partial.len(), MAX_LOGGED_LINE_BYTES);
+        logger.feed(b"\nlast");
+        assert_eq!(logger.lines, 3);
+        assert!(logger.in_line);
+        logger.finish();
+    }
+
+    #[test]
+    fn test_no_logger_without_log_or_live_output() {
+        assert!(LineLogger::new("stdout", None, None).is_none());
+    }
+
+    #[test]
+    fn test_live_lines_are_rate_limited() {
+        let (live, mut lines) = LiveOutput::channel(3);
+        let mut logger = LineLogger::new("stderr", None, Some(&live.for_step("Install"))).unwrap();
+        logger.feed(b"one\r\ntwo\nthree\nfour\nfive");
+        logger.finish();
+        drop(live);
+
+        let first = lines.try_recv().unwrap();
+        assert_eq!(
+            first,
+            LiveLine {
+                step_name: "Install".to_string(),
+                stream: "stderr",
+                line_number: 1,
+                line: "one".to_string(),
+            }
+        );
+        let rest: Vec<String> = std::iter::from_fn(|| lines.try_recv().ok())
+            .map(|line| line.line)
+            .collect();
+        // Three lines fit in the second
+        assert_eq!(rest, ["two", "three"]);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::error::{DeviceOpsError, Result};
+use std::path::PathBuf;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Trait for reading the device's battery level - allows mocking in tests
+pub trait BatteryProbe: Send + Sync {
+    /// Battery charge in percent, or `None` when the device has no battery.
+    /// `command` (from `execution.battery_command`) replaces the sysfs lookup.
+    fn battery_percent(&self, command: Option<&str>) -> Result<Option<u8>>;
+}
+
+/// Real probe reading sysfs or running the configured command
+pub struct SystemBatteryProbe {
+    power_supply_dir: PathBuf,
+}
+
+impl Default for SystemBatteryProbe {
+    fn default() -> Self {
+        Self {
+            power_supply_dir: PathBuf::from(POWER_SUPPLY_DIR),
+        }
+    }
+}
+
+impl SystemBatteryProbe {
+    /// First supply of type `Battery` under the power supply directory
+    fn read_sysfs(&self) -> Result<Option<u8>> {
+        let entries = match std::fs::read_dir(&self.power_supply_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(DeviceOpsError::execution_io(format!(
+                    "Failed to list {}",
+                    self.power_supply_dir.display()
+                ))(e))
+            }
+        };
+
+        let mut supplies: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        supplies.sort();
+
+        for supply in supplies {
+            let kind = std::fs::read_to_string(supply.join("type")).unwrap_or_default();
+            if kind.trim() != "Battery" {
+                continue;
+            }
+            let capacity = std::fs::read_to_string(supply.join("capacity")).map_err(
+                DeviceOpsError::execution_io(format!(
+                    "Failed to read battery capacity from {}",
+                    supply.display()
+                )),
+            )?;
+            return parse_percent(&capacity).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn run_command(command: &str) -> Result<Option<u8>> {
+        let output =
+            std::process::Command::new(command)
+                .output()
+                .map_err(DeviceOpsError::execution_io(format!(
+                    "Failed to run battery command {}",
+                    command
+                )))?;
+
+        if !output.status.success() {
+            return Err(DeviceOpsError::ExecutionError(format!(
+                "Battery command {} exited with {}",
+                command, output.status
+            )));
+        }
+
+        parse_percent(&String::from_utf8_lossy(&output.stdout)).map(Some)
+    }
+}
+
+impl BatteryProbe for SystemBatteryProbe {
+    fn battery_percent(&self, command: Option<&str>) -> Result<Option<u8>> {
+        match command {
+            Some(command) => Self::run_command(command),
+            None => self.read_sysfs(),
+        }
+    }
+}
+
+/// Parse "85", "85%" or "85\n" into a percentage
+fn parse_percent(text: &str) -> Result<u8> {
+    let trimmed = text.trim();
+    trimmed
+        .trim_end_matches('%')
+        .parse::<u8>()
+        .ok()
+        .filter(|percent| *percent <= 100)
+        .ok_or_else(|| {
+            DeviceOpsError::ExecutionError(format!("Invalid battery level: {:?}", trimmed))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(dir: &std::path::Path, name: &str, kind: &str, capacity: Option<&str>) {
+        let path = dir.join(name);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("type"), kind).unwrap();
+        if let Some(capacity) = capacity {
+            std::fs::write(path.join("capacity"), capacity).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reads_battery_from_sysfs() {
+        let dir = tempfile::tempdir().unwrap();
+        supply(dir.path(), "AC", "Mains\n", None);
+        supply(dir.path(), "BAT0", "Battery\n", Some("42\n"));
+
+        let probe = SystemBatteryProbe {
+            power_supply_dir: dir.path().to_path_buf(),
+        };
+        assert_eq!(probe.battery_percent(None).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn test_no_battery_on_mains_powered_device() {
+        let dir = tempfile::tempdir().unwrap();
+        supply(dir.path(), "AC", "Mains\n", None);
+
+        let probe = SystemBatteryProbe {
+            power_supply_dir: dir.path().join("missing"),
+        };
+        assert_eq!(probe.battery_percent(None).unwrap(), None);
+
+        let probe = SystemBatteryProbe {
+            power_supply_dir: dir.path().to_path_buf(),
+        };
+        assert_eq!(probe.battery_percent(None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("85%\n").unwrap(), 85);
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("full").is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! Starting commands
+//!
+//! The default spawn goes through the async runtime, which may `fork` the
+//! component. A fork has to account for the component's whole address space
+//! and can fail with `ENOMEM` on small devices under memory pressure, even
+//! for a tiny command. With `execution.spawn_method = "posix_spawn"`,
+//! commands are started through the C library's `posix_spawn`, which on
+//! Linux shares the address space until the command is exec'd. That method
+//! is only implemented on Linux; elsewhere the default spawn is used.
+
+use crate::config::SpawnMethod;
+use crate::models::TerminationSignal;
+use std::process::ExitStatus;
+use tokio::io::AsyncRead;
+use tokio::process::{Child, Command as TokioCommand};
+
+/// Read side of a command's stdout or stderr
+pub type OutputPipe = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The method commands are started with on this target
+pub fn effective_method(requested: SpawnMethod) -> SpawnMethod {
+    if cfg!(target_os = "linux") {
+        requested
+    } else {
+        if requested == SpawnMethod::PosixSpawn {
+            static WARNED: std::sync::Once = std::sync::Once::new();
+            WARNED.call_once(|| {
+                tracing::warn!("posix_spawn is only supported on Linux, using the default spawn")
+            });
+        }
+        SpawnMethod::Fork
+    }
+}
+
+/// Start `command` with `method`, stdout and stderr piped and stdin inherited
+pub fn spawn(
+    command: &mut TokioCommand,
+    method: SpawnMethod,
+) -> std::io::Result<(Process, Option<OutputPipe>, Option<OutputPipe>)> {
+    match effective_method(method) {
+        SpawnMethod::Fork => {
+            command
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped());
+            let mut child = command.spawn()?;
+            let stdout = child.stdout.take().map(|pipe| Box::new(pipe) as OutputPipe);
+            let stderr = child.stderr.take().map(|pipe| Box::new(pipe) as OutputPipe);
+            Ok((Process::Tokio(child), stdout, stderr))
+        }
+        #[cfg(target_os = "linux")]
+        SpawnMethod::PosixSpawn => {
+            let (child, stdout, stderr) = posix::spawn(command.as_std())?;
+            Ok((
+                Process::Posix(child),
+                Some(Box::new(stdout)),
+                Some(Box::new(stderr)),
+            ))
+        }
+        #[cfg(not(target_os = "linux"))]
+        SpawnMethod::PosixSpawn => unreachable!("posix_spawn is only used on Linux"),
+    }
+}
+
+/// A started command
+pub enum Process {
+    Tokio(Child),
+    #[cfg(target_os = "linux")]
+    Posix(posix::PosixChild),
+}
+
+impl Process {
+    /// Process ID, until the command has been reaped
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            Self::Tokio(child) => child.id(),
+            #[cfg(target_os = "linux")]
+            Self::Posix(child) => child.id(),
+        }
+    }
+
+    pub async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        match self {
+            Self::Tokio(child) => child.wait().await,
+            #[cfg(target_os = "linux")]
+            Self::Posix(child) => child.wait().await,
+        }
+    }
+
+    /// SIGKILL the command and wait for it
+    pub async fn kill(&mut self) -> std::io::Result<()> {
+        self.start_kill()?;
+        self.wait().await.map(|_| ())
+    }
+
+    /// SIGKILL the command without waiting
+    pub fn start_kill(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tokio(child) => child.start_kill(),
+            #[cfg(target_os = "linux")]
+            Self::Posix(child) => child.start_kill(),
+        }
+    }
+
+    /// Send `signal` to the command without waiting; nothing once it has
+    /// been reaped. Signals other than KILL are only sent on Linux
+    pub fn signal(&mut self, signal: TerminationSignal) -> std::io::Result<()> {
+        if signal == TerminationSignal::Kill {
+            return self.start_kill();
+        }
+        let Some(pid) = self.id() else {
+            return Ok(());
+        };
+        #[cfg(target_os = "linux")]
+        {
+            let signo = match signal {
+                TerminationSignal::Term => libc::SIGTERM,
+                TerminationSignal::Int => libc::SIGINT,
+                TerminationSignal::Hup => libc::SIGHUP,
+                TerminationSignal::Kill => libc::SIGKILL,
+            };
+            // SAFETY: plain syscall on a PID this process has not reaped
+            if unsafe { libc::kill(pid as libc::pid_t, signo) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = pid;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("sending {} is only supported on Linux", signal),
+            ))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod posix {
+    use std::collections::BTreeMap;
+    use std::ffi::{CString, OsStr, OsString};
+    use std::io::{Error, ErrorKind};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, ExitStatus};
+    use tokio::net::unix::pipe;
+    use tokio::sync::watch;
+
+    /// A command started with `posix_spawn`. A thread blocks in `waitpid`
+    /// for it, since the runtime only reaps children it started itself.
+    pub struct PosixChild {
+        pid: libc::pid_t,
+        exited: watch::Receiver<Option<ExitStatus>>,
+    }
+
+    impl PosixChild {
+        pub fn id(&self) -> Option<u32> {
+            match *self.exited.borrow() {
+                Some(_) => None,
+                None => Some(self.pid as u32),
+            }
+        }
+
+        pub async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+            match *self
+                .exited
+                .wait_for(Option::is_some)
+                .await
+                .map_err(|_| Error::other("lost track of the command"))?
+            {
+                Some(status) => Ok(status),
+                None => unreachable!("waited for an exit status"),
+            }
+        }
+
+        pub fn start_kill(&mut self) -> std::io::Result<()> {
+            // Once reaped the PID may belong to another process
+            if self.exited.borrow().is_some() {
+                return Ok(());
+            }
+            // SAFETY: plain syscall on a PID this process has not reaped
+            if unsafe { libc::kill(self.pid, libc::SIGKILL) } == -1 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    /// Turn a `posix_spawn*` return code into a result
+    fn check(code: libc::c_int) -> std::io::Result<()> {
+        match code {
+            0 => Ok(()),
+            code => Err(Error::from_raw_os_error(code)),
+        }
+    }
+
+    fn c_string(bytes: &[u8]) -> std::io::Result<CString> {
+        CString::new(bytes).map_err(|_| Error::new(ErrorKind::InvalidInput, "nul byte in command"))
+    }
+
+    /// Find `program` the way the default spawn does: as given when it
+    /// contains a `/`, otherwise in the command's `PATH`
+    pub(super) fn resolve(program: &OsStr, path: Option<&OsStr>) -> std::io::Result<PathBuf> {
+        if program.as_bytes().contains(&b'/') {
+            return Ok(PathBuf::from(program));
+        }
+        let path = path.unwrap_or_default();
+        std::env::split_paths(path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| is_executable(candidate))
+            .ok_or_else(|| Error::from(ErrorKind::NotFound))
+    }
+
+    fn is_executable(path: &Path) -> bool {
+        std::fs::metadata(path)
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    /// Pipe for one output stream: the read end for us, the write end for
+    /// the command; both close on exec
+    fn output_pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just opened and are owned by nobody else
+        Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+    }
+
+    /// `posix_spawn` attributes and file actions, destroyed on drop
+    struct SpawnSetup {
+        attr: libc::posix_spawnattr_t,
+        actions: libc::posix_spawn_file_actions_t,
+    }
+
+    impl SpawnSetup {
+        fn new() -> std::io::Result<Self> {
+            // SAFETY: both are plain C structs, initialised before use and
+            // only destroyed once initialised
+            unsafe {
+                let mut attr = std::mem::zeroed();
+                check(libc::posix_spawnattr_init(&mut attr))?;
+                let mut actions = std::mem::zeroed();
+                if let Err(e) = check(libc::posix_spawn_file_actions_init(&mut actions)) {
+                    libc::posix_spawnattr_destroy(&mut attr);
+                    return Err(e);
+                }
+                Ok(Self { attr, actions })
+            }
+        }
+    }
+
+    impl Drop for SpawnSetup {
+        fn drop(&mut self) {
+            // SAFETY: both were initialised in `new`
+            unsafe {
+                libc::posix_spawn_file_actions_destroy(&mut self.actions);
+                libc::posix_spawnattr_destroy(&mut self.attr);
+            }
+        }
+    }
+
+    /// Start `command` (program, arguments, environment changes and working
+    /// directory) with its stdout and stderr piped
+    pub fn spawn(
+        command: &Command,
+    ) -> std::io::Result<(PosixChild, pipe::Receiver, pipe::Receiver)> {
+        let mut env: BTreeMap<OsString, OsString> = std::env::vars_os().collect();
+        for (name, value) in command.get_envs() {
+            match value {
+                Some(value) => env.insert(name.to_owned(), value.to_owned()),
+                None => env.remove(name),
+            };
+        }
+        let program = resolve(
+            command.get_program(),
+            env.get(OsStr::new("PATH")).map(OsString::as_os_str),
+        )?;
+
+        let program = c_string(program.as_os_str().as_bytes())?;
+        let args = std::iter::once(Ok(c_string(command.get_program().as_bytes())?))
+            .chain(command.get_args().map(|arg| c_string(arg.as_bytes())))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let env = env
+            .iter()
+            .map(|(name, value)| {
+                let mut entry = name.as_bytes().to_vec();
+                entry.push(b'=');
+                entry.extend_from_slice(value.as_bytes());
+                c_string(&entry)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let dir = command
+            .get_current_dir()
+            .map(|dir| c_string(dir.as_os_str().as_bytes()))
+            .transpose()?;
+
+        let mut argv: Vec<*mut libc::c_char> =
+            args.iter().map(|arg| arg.as_ptr() as *mut _).collect();
+        argv.push(std::ptr::null_mut());
+        let mut envp: Vec<*mut libc::c_char> =
+            env.iter().map(|entry| entry.as_ptr() as *mut _).collect();
+        envp.push(std::ptr::null_mut());
+
+        let (stdout_read, stdout_write) = output_pipe()?;
+        let (stderr_read, stderr_write) = output_pipe()?;
+
+        let mut setup = SpawnSetup::new()?;
+        let mut pid: libc::pid_t = 0;
+        // SAFETY: the attributes and file actions are initialised, and every
+        // pointer handed over stays alive until posix_spawn returns
+        unsafe {
+            let actions = &mut setup.actions;
+            check(libc::posix_spawn_file_actions_adddup2(
+                actions,
+                stdout_write.as_raw_fd(),
+                libc::STDOUT_FILENO,
+            ))?;
+            check(libc::posix_spawn_file_actions_adddup2(
+                actions,
+                stderr_write.as_raw_fd(),
+                libc::STDERR_FILENO,
+            ))?;
+            if let Some(dir) = &dir {
+                check(libc::posix_spawn_file_actions_addchdir_np(
+                    actions,
+                    dir.as_ptr(),
+                ))?;
+            }
+
+            // Like the default spawn: no blocked signals, SIGPIPE back to default
+            let attr = &mut setup.attr;
+            let mut signals: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            check(libc::posix_spawnattr_setsigmask(attr, &signals))?;
+            libc::sigaddset(&mut signals, libc::SIGPIPE);
+            check(libc::posix_spawnattr_setsigdefault(attr, &signals))?;
+            check(libc::posix_spawnattr_setflags(
+                attr,
+                (libc::POSIX_SPAWN_SETSIGMASK | libc::POSIX_SPAWN_SETSIGDEF) as libc::c_short,
+            ))?;
+
+            check(libc::posix_spawn(
+                &mut pid,
+                program.as_ptr(),
+                &setup.actions,
+                &setup.attr,
+                argv.as_ptr(),
+                envp.as_ptr(),
+            ))?;
+        }
+        // Only the command keeps the write ends, so the pipes end when it exits
+        drop((stdout_write, stderr_write));
+
+        let (status_tx, exited) = watch::channel(None);
+        std::thread::Builder::new()
+            .name(format!("waitpid-{}", pid))
+            .spawn(move || {
+                let mut status = 0;
+                loop {
+                    // SAFETY: `status` is a valid out pointer
+                    let reaped = unsafe { libc::waitpid(pid, &mut status, 0) };
+                    if reaped == pid {
+                        let _ = status_tx.send(Some(ExitStatus::from_raw(status)));
+                        return;
+                    }
+                    if reaped == -1 && Error::last_os_error().kind() != ErrorKind::Interrupted {
+                        tracing::error!(
+                            pid,
+                            error_kind = crate::error::ErrorKind::Execution.label(),
+                            error = %Error::last_os_error(),
+                            "Failed to wait for command"
+                        );
+                        return;
+                    }
+                }
+            })?;
+
+        Ok((
+            PosixChild { pid, exited },
+            pipe::Receiver::from_owned_fd(stdout_read)?,
+            pipe::Receiver::from_owned_fd(stderr_read)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_effective_method() {
+        assert_eq!(effective_method(SpawnMethod::Fork), SpawnMethod::Fork);
+        let expected = if cfg!(target_os = "linux") {
+            SpawnMethod::PosixSpawn
+        } else {
+            SpawnMethod::Fork
+        };
+        assert_eq!(effective_method(SpawnMethod::PosixSpawn), expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resolve_uses_command_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("probe");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+
+        let path = std::env::join_paths(["/nonexistent", dir.path().to_str().unwrap()]).unwrap();
+        assert_eq!(
+            posix::resolve("probe".as_ref(), Some(&path)).unwrap(),
+            script
+        );
+        assert_eq!(
+            posix::resolve("probe".as_ref(), Some("/usr/bin".as_ref()))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            posix::resolve("./probe".as_ref(), None).unwrap(),
+            PathBuf::from("./probe")
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! Local execution history
+//!
+//! One JSON line per finished job in `<storage.history_dir>/history.jsonl`,
+//! so "what ran on this device lately" can be answered on the device itself
+//! (`device-ops history`) after the Greengrass logs have rotated.
+
+use crate::config::fnv1a_hex;
+use crate::error::{DeviceOpsError, Result};
+use crate::models::{JobDocument, JobExecutionResult, StepTiming};
+use serde::{Deserialize, Serialize};
+use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Name of the history file in `storage.history_dir`
+pub const HISTORY_FILE_NAME: &str = "history.jsonl";
+
+/// Serializes appends and pruning within the component, so a prune cannot
+/// drop a record appended while it rewrites the file
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// One finished job
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub job_id: String,
+    pub thing_name: String,
+    pub execution_id: String,
+    /// Hash of the job document, to spot runs of the same document
+    pub document_hash: String,
+    /// Final job status, e.g. `SUCCEEDED` or `FAILED`
+    pub status: String,
+    /// Epoch millis
+    pub started_at: i64,
+    /// Epoch millis
+    pub finished_at: i64,
+    #[serde(default)]
+    pub steps: Vec<StepRecord>,
+    /// Why the job failed without a failing step (rejected document, execution error)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Compact device info, as in the job's `device` statusDetails field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+/// One executed step of a `HistoryRecord`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub name: String,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+    /// Where the time went; missing in records written before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<StepTiming>,
+}
+
+impl HistoryRecord {
+    pub fn is_failure(&self) -> bool {
+        self.status != "SUCCEEDED"
+    }
+}
+
+/// Hash of a job document's JSON form
+pub fn document_hash(document: &JobDocument) -> String {
+    fnv1a_hex(&serde_json::to_string(document).unwrap_or_default())
+}
+
+/// The steps of an execution result, in order
+pub fn step_records(result: &JobExecutionResult) -> Vec<StepRecord> {
+    result
+        .outputs
+        .iter()
+        .map(|step| StepRecord {
+            name: step.step_name.clone(),
+            exit_code: step.output.exit_code,
+            duration_ms: step.output.execution_time_ms,
+            timing: Some(step.output.timing),
+        })
+        .collect()
+}
+
+/// Records read back from a history file
+#[derive(Debug, Default)]
+pub struct History {
+    pub records: Vec<HistoryRecord>,
+    /// Lines that are not a record (e.g. cut short by a power loss); skipped
+    pub corrupt_lines: usize,
+}
+
+/// Append one record, creating the file and its directory if needed
+pub fn append(path: &Path, record: &HistoryRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record).map_err(DeviceOpsError::execution_json(
+        "Failed to serialize history record",
+    ))?;
+    line.push('\n');
+
+    let _guard = HISTORY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| history_error(path, e))?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| history_error(path, e))
+}
+
+/// Read every record; a missing file is an empty history
+pub fn read(path: &Path) -> Result<History> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(History::default()),
+        Err(e) => return Err(history_error(path, e)),
+    };
+
+    let mut history = History::default();
+    for (idx, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(record) => history.records.push(record),
+            Err(e) => {
+                tracing::warn!(path = %path.display(), line = idx + 1, error = %e, "Skipping corrupt history line");
+                history.corrupt_lines += 1;
+            }
+        }
+    }
+    Ok(history)
+}
+
+/// Bytes read at a time from the end of the file by [`read_tail`]
+const TAIL_CHUNK: u64 = 16 * 1024;
+
+/// Read the newest `count` records, oldest first, reading back from the end
+/// of the file only as far as needed; corrupt lines are skipped and a
+/// missing file is an empty history
+pub fn read_tail(path: &Path, count: usize) -> Result<Vec<HistoryRecord>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(history_error(path, e)),
+    };
+    let mut start = file.metadata().map_err(|e| history_error(path, e))?.len();
+    let mut tail: Vec<u8> = Vec::new();
+    // The file ends in a newline, so one more than `count` of them means
+    // `count` whole lines after a possibly cut first one
+    while start > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= count {
+        let chunk = TAIL_CHUNK.min(start);
+        start -= chunk;
+        let mut buffer = vec![0; chunk as usize];
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.read_exact(&mut buffer))
+            .map_err(|e| history_error(path, e))?;
+        buffer.extend_from_slice(&tail);
+        tail = buffer;
+    }
+
+    let text = String::from_utf8_lossy(&tail);
+    let mut lines = text.lines();
+    if start > 0 {
+        lines.next();
+    }
+    let mut records: Vec<HistoryRecord> = lines
+        .rev()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .take(count)
+        .collect();
+    records.reverse();
+    Ok(records)
+}
+
+/// Drop records that finished more than `retention_days` before `now_ms`
+/// (0 keeps everything), along with corrupt lines. Returns how many records
+/// were dropped.
+pub fn prune(path: &Path, retention_days: u64, now_ms: i64) -> Result<usize> {
+    if retention_days == 0 {
+        return Ok(0);
+    }
+    let max_age_ms = i64::try_from(retention_days * 24 * 60 * 60 * 1000).unwrap_or(i64::MAX);
+    let cutoff = now_ms.saturating_sub(max_age_ms);
+
+    let _guard = HISTORY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let history = read(path)?;
+    let total = history.records.len();
+    let kept: Vec<&HistoryRecord> = history
+        .records
+        .iter()
+        .filter(|record| record.finished_at >= cutoff)
+        .collect();
+    if kept.len() == total && history.corrupt_lines == 0 {
+        return Ok(0);
+    }
+
+    let mut content = String::new();
+    for record in &kept {
+        if let Ok(line) = serde_json::to_string(record) {
+            content.push_str(&line);
+            content.push('\n');
+        }
+    }
+    // Temp file + rename, like the state file, so a crash leaves either version
+    let tmp_path = path.with_extension("jsonl.tmp");
+    std::fs::write(&tmp_path, content)
+        .and_then(|_| std::fs::rename(&tmp_path, path))
+        .map_err(|e| history_error(path, e))?;
+    Ok(total - kept.len())
+}
+
+fn history_error(path: &Path, e: std::io::Error) -> DeviceOpsError {
+    DeviceOpsError::execution_io(format!("Failed to access history file {}", path.display()))(e)
+}
+
+/// Which records `device-ops history` shows
+#[derive(Debug, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// Only jobs that finished within this long before now
+    pub since: Option<Duration>,
+    pub failed_only: bool,
+    /// Print JSON lines instead of a table
+    pub json: bool,
+}
+
+impl HistoryQuery {
+    /// The matching records, oldest first
+    pub fn select<'a>(&self, records: &'a [HistoryRecord], now_ms: i64) -> Vec<&'a HistoryRecord> {
+        let cutoff = self.since.map(|since| {
+            now_ms.saturating_sub(i64::try_from(since.as_millis()).unwrap_or(i64::MAX))
+        });
+        let mut selected: Vec<&HistoryRecord> = records
+            .iter()
+            .filter(|record| cutoff.is_none_or(|cutoff| record.finished_at >= cutoff))
+            .filter(|record| !self.failed_only || record.is_failure())
+            .collect();
+        selected.sort_by_key(|record| record.finished_at);
+        selected
+    }
+
+    /// Render the records as a table or as JSON lines
+    pub fn render(&self, records: &[&HistoryRecord]) -> String {
+        if self.json {
+            return records
+                .iter()
+                .filter_map(|record| serde_json::to_string(record).ok())
+                .map(|line| line + "\n")
+                .collect();
+        }
+        format_table(records)
+    }
+}
+
+/// Parse a duration such as `48h`, `30m`, `7d` or `90s`
+pub fn parse_since(text: &str) -> Result<Duration> {
+    let invalid = || {
+        DeviceOpsError::ConfigError(format!(
+            "Invalid --since {:?} (expected a number with s, m, h or d, e.g. 48h)",
+            text
+        ))
+    };
+    let split = text.char_indices().last().map_or(0, |(idx, _)| idx);
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(number.saturating_mul(unit_secs)))
+}
+
+/// One line per job: when it finished, its status, IDs and each step with
+/// its exit code and duration
+fn format_table(records: &[&HistoryRecord]) -> String {
+    let job_width = records
+        .iter()
+        .map(|record| record.job_id.len())
+        .chain(["JOB ID".len()])
+        .max()
+        .unwrap_or_default();
+    let mut table = format!(
+        "{:<20}  {:<9}  {:<job_width$}  {:<8}  STEPS\n",
+        "FINISHED", "STATUS", "JOB ID", "DURATION"
+    );
+    for record in records {
+        let finished = chrono::DateTime::from_timestamp_millis(record.finished_at)
+            .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|| record.finished_at.to_string());
+        let duration_ms = u64::try_from(record.finished_at - record.started_at).unwrap_or(0);
+        let mut steps = record
+            .steps
+            .iter()
+            .map(|step| {
+                format!(
+                    "{} (exit {}, {})",
+                    step.name,
+                    step.exit_code,
+                    format_duration(step.duration_ms)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Some(error) = &record.error {
+            if !steps.is_empty() {
+                steps.push_str("; ");
+            }
+            steps.push_str(error);
+        }
+        let line = format!(
+            "{:<20}  {:<9}  {:<job_width$}  {:<8}  {}",
+            finished,
+            record.status,
+            record.job_id,
+            format_duration(duration_ms),
+            steps
+        );
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+fn format_duration(ms: u64) -> String {
+    if ms < 1000 {
+        format!("{}ms", ms)
+    } else {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+    const NOW_MS: i64 = 1_790_000_000_000;
+
+    fn record(job_id: &str, status: &str, finished_at: i64) -> HistoryRecord {
+        HistoryRecord {
+            job_id: job_id.to_string(),
+            thing_name: "device-1".to_string(),
+            execution_id: format!("{}-run", job_id),
+            document_hash: "0123456789abcdef".to_string(),
+            status: status.to_string(),
+            started_at: finished_at - 1500,
+            finished_at,
+            steps: vec![StepRecord {
+                name: "Check".to_string(),
+                exit_code: if status == "SUCCEEDED" { 0 } else { 1 },
+                duration_ms: 1500,
+                timing: None,
+            }],
+            error: None,
+            device: None,
+        }
+    }
+
+    #[test]
+    fn test_append_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history").join(HISTORY_FILE_NAME);
+        assert!(read(&path).unwrap().records.is_empty());
+
+        let first = record("job-1", "SUCCEEDED", NOW_MS - HOUR_MS);
+        let second = HistoryRecord {
+            steps: vec![],
+            error: Some("Invalid job document: no steps".to_string()),
+            device: Some("v0.3.1 rpi4 debian-12".to_string()),
+            ..record("job-2", "FAILED", NOW_MS)
+        };
+        append(&path, &first).unwrap();
+        append(&path, &second).unwrap();
+
+        let history = read(&path).unwrap();
+        assert_eq!(history.records, vec![first, second]);
+        assert_eq!(history.corrupt_lines, 0);
+    }
+
+    #[test]
+    fn test_corrupt_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE_NAME);
+        append(&path, &record("job-1", "SUCCEEDED", NOW_MS)).unwrap();
+        // A line cut short by a power loss, then more records
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"{\"job_id\":\"job-2\",\"thi\n").unwrap();
+        append(&path, &record("job-3", "FAILED", NOW_MS)).unwrap();
+
+        let history = read(&path).unwrap();
+        let ids: Vec<&str> = history.records.iter().map(|r| r.job_id.as_str()).collect();
+        assert_eq!(ids, vec!["job-1", "job-3"]);
+        assert_eq!(history.corrupt_lines, 1);
+
+        // Pruning also drops the corrupt line
+        assert_eq!(prune(&path, 30, NOW_MS).unwrap(), 0);
+        assert_eq!(read(&path).unwrap().corrupt_lines, 0);
+    }
+
+    #[test]
+    fn test_tail_holds_the_newest_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE_NAME);
+        assert!(read_tail(&path, 5).unwrap().is_empty());
+
+        // Enough records to span several chunks
+        for i in 0..300 {
+            append(&path, &record(&format!("job-{}", i), "SUCCEEDED", NOW_MS)).unwrap();
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() > 2 * TAIL_CHUNK);
+
+        let tail = read_tail(&path, 3).unwrap();
+        let ids: Vec<&str> = tail.iter().map(|r| r.job_id.as_str()).collect();
+        assert_eq!(ids, vec!["job-297", "job-298", "job-299"]);
+        assert_eq!(
+            read_tail(&path, 1000).unwrap(),
+            read(&path).unwrap().records
+        );
+        assert!(read_tail(&path, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_drops_records_past_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE_NAME);
+        append(
+            &path,
+            &record("old", "SUCCEEDED", NOW_MS - 3 * 24 * HOUR_MS),
+        )
+        .unwrap();
+        append(&path, &record("recent", "FAILED", NOW_MS - HOUR_MS)).unwrap();
+
+        assert_eq!(prune(&path, 0, NOW_MS).unwrap(), 0);
+        assert_eq!(read(&path).unwrap().records.len(), 2);
+
+        assert_eq!(prune(&path, 2, NOW_MS).unwrap(), 1);
+        let history = read(&path).unwrap();
+        assert_eq!(
+            history.records,
+            vec![record("recent", "FAILED", NOW_MS - HOUR_MS)]
+        );
+        assert_eq!(prune(&path, 2, NOW_MS).unwrap(), 0);
+
+        // No history yet is nothing to prune
+        assert_eq!(
+            prune(&dir.path().join("missing.jsonl"), 2, NOW_MS).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_query_filters_and_renders() {
+        let records = vec![
+            record("job-3", "FAILED", NOW_MS - HOUR_MS),
+            record("job-1", "FAILED", NOW_MS - 72 * HOUR_MS),
+            record("job-2", "SUCCEEDED", NOW_MS - 2 * HOUR_MS),
+        ];
+        let query = HistoryQuery {
+            since: Some(parse_since("48h").unwrap()),
+            ..Default::default()
+        };
+        let ids = |selected: Vec<&HistoryRecord>| -> Vec<String> {
+            selected.iter().map(|r| r.job_id.clone()).collect()
+        };
+        assert_eq!(ids(query.select(&records, NOW_MS)), vec!["job-2", "job-3"]);
+
+        let failed = HistoryQuery {
+            failed_only: true,
+            ..query
+        };
+        let selected = failed.select(&records, NOW_MS);
+        assert_eq!(ids(selected.clone()), vec!["job-3"]);
+
+        let table = failed.render(&selected);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("FINISHED"));
+        assert!(lines[1].contains("FAILED"), "{}", lines[1]);
+        assert!(lines[1].contains("job-3"), "{}", lines[1]);
+        assert!(lines[1].contains("Check (exit 1, 1.5s)"), "{}", lines[1]);
+
+        let json = HistoryQuery {
+            json: true,
+            ..failed
+        };
+        let line = json.render(&selected);
+        let parsed: HistoryRecord = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(&parsed, selected[0]);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("48h").unwrap(), Duration::from_secs(48 * 3600));
+        assert_eq!(parse_since("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_since("7d").unwrap(), Duration::from_secs(7 * 86400));
+        assert_eq!(parse_since("90s").unwrap(), Duration::from_secs(90));
+        for bad in ["", "h", "48", "48w", "-1h", "1.5h"] {
+            assert!(parse_since(bad).is_err(), "{}", bad);
+        }
+    }
+}
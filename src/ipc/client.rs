@@ -1,64 +1,463 @@
-use crate::error::{DeviceOpsError, Result};
-use crate::models::{Job, JobNotification, JobOrError, JobStatus};
-use gg_sdk::{Qos, Sdk};
+use crate::config::{IpcConfig, TopicTemplates};
+use crate::device_info::DeviceInfo;
+use crate::error::{DeviceOpsError, ErrorKind, Result};
+use crate::ipc::echo::UpdateOrigins;
+use crate::ipc::liveness::LivenessProbes;
+use crate::ipc::stats::{self, STATS_QUEUE_CAPACITY};
+use crate::ipc::throttle::StatusThrottle;
+use crate::ipc::topics::{
+    heartbeat_topic, liveness_probe_topic, reconnect_topic, stats_request_topic,
+    stats_response_topic, JobsTopics,
+};
+use crate::ipc::transport::{GreengrassTransport, IotCoreTransport, MessageHandler};
+use crate::metrics::{self, IpcRetry};
+use crate::models::{
+    job_key, Job, JobNotification, JobOrError, JobStatus, JobsErrorResponse, PendingJobExecutions,
+};
+use gg_sdk::Qos;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
 
+/// Receivers fed by the IoT Jobs subscriptions
+pub struct JobStreams {
+    pub jobs: mpsc::Receiver<JobOrError>,
+    pub reconnects: mpsc::Receiver<()>,
+    /// GetPendingJobExecutions responses, tagged with the thing they are for
+    pub pending: mpsc::Receiver<(String, PendingJobExecutions)>,
+    /// Rejections of our `$next/get` requests, tagged with the thing they are for
+    pub next_rejected: mpsc::Receiver<(String, JobsErrorResponse)>,
+}
+
+const UNKNOWN_THING_NAME: &str = "unknown-thing";
+
+/// Identifies this process in clientTokens (pid plus start time)
+fn instance_id() -> String {
+    format!(
+        "{}-{:x}",
+        std::process::id(),
+        chrono::Utc::now().timestamp_millis()
+    )
+}
+
+/// Resolve the thing name from config, then the environment Greengrass provides.
+/// An unresolvable name is an error unless `ipc.allow_unknown_thing` is set.
+pub fn resolve_thing_name<F>(config: &IpcConfig, env: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let configured = config
+        .thing_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+    if let Some(name) = configured {
+        tracing::debug!(thing_name = %name, "Thing name from ipc.thing_name");
+        return Ok(name.to_string());
+    }
+
+    if let Some(name) = env("AWS_IOT_THING_NAME").filter(|name| !name.trim().is_empty()) {
+        tracing::debug!(thing_name = %name, "Thing name from AWS_IOT_THING_NAME");
+        return Ok(name.trim().to_string());
+    }
+
+    if config.allow_unknown_thing {
+        tracing::warn!(
+            thing_name = UNKNOWN_THING_NAME,
+            "Could not determine thing name, continuing because ipc.allow_unknown_thing is set"
+        );
+        return Ok(UNKNOWN_THING_NAME.to_string());
+    }
+
+    Err(DeviceOpsError::ConfigError(
+        "Could not determine thing name (tried: ipc.thing_name in config, \
+         AWS_IOT_THING_NAME environment variable). Set one of them, or set \
+         ipc.allow_unknown_thing for bench setups"
+            .to_string(),
+    ))
+}
+
+/// Things to serve: `ipc.thing_names` if set, otherwise just the core device
+fn served_thing_names(config: &IpcConfig, core_thing: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in config.thing_names.iter().map(|name| name.trim()) {
+        if !name.is_empty() && !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
+        }
+    }
+
+    if names.is_empty() {
+        names.push(core_thing.to_string());
+    }
+    names
+}
+
+/// A subscription `IpcClient` re-establishes after reconnecting
+struct ManagedSubscription {
+    topic: String,
+    handler: MessageHandler,
+    /// On local pub/sub rather than IoT Core
+    local: bool,
+}
+
+impl std::fmt::Debug for ManagedSubscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.topic)
+    }
+}
+
+/// What a heartbeat reports besides the instance
+#[derive(Debug, Serialize)]
+pub struct Heartbeat<'a> {
+    /// Hash of the active settings
+    pub config_hash: &'a str,
+    pub config_profile: Option<&'a str>,
+    /// Config updates applied since startup
+    pub config_reloads: u64,
+    pub device: &'a DeviceInfo,
+    /// Jobs AWS last reported as queued, over every served thing
+    pub queued_in_aws: usize,
+    /// Jobs waiting in the on-device queue
+    pub queued_on_device: usize,
+}
+
 /// Greengrass IPC client using the official AWS SDK
 #[derive(Debug)]
 pub struct IpcClient {
-    sdk: Sdk,
+    /// Shared with the blocking tasks that publish under a timeout
+    transport: Arc<dyn IotCoreTransport>,
+    /// The core device's own thing name
     thing_name: String,
+    /// Every thing whose jobs this component executes
+    thing_names: Vec<String>,
+    topics: HashMap<String, JobsTopics>,
+    topic_templates: TopicTemplates,
+    status_throttle: StatusThrottle,
+    /// Identifies this process in clientTokens and liveness probes
+    instance: String,
+    update_origins: Arc<UpdateOrigins>,
+    /// Every subscription made, so they can be re-made after a reconnect
+    subscriptions: Mutex<Vec<ManagedSubscription>>,
+    liveness: LivenessProbes,
+    /// Epoch millis of the last message on a Jobs topic (0 before the first)
+    last_jobs_contact: Arc<AtomicI64>,
+    /// `ipc.publish_timeout_secs`; `None` publishes inline without a limit
+    publish_timeout: Option<Duration>,
+}
+
+/// `handler`, also recording when it was called in `contact`
+fn noting_contact(contact: &Arc<AtomicI64>, handler: MessageHandler) -> MessageHandler {
+    let contact = contact.clone();
+    Arc::new(move |topic: &str, payload: &[u8]| {
+        contact.store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+        handler(topic, payload)
+    })
 }
 
 impl IpcClient {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(config: &IpcConfig) -> Result<Self> {
         // Initialize the Greengrass SDK
-        let sdk = Sdk::init();
+        Self::with_transport(Box::new(GreengrassTransport::new()), config).await
+    }
 
+    /// Connect over the given transport instead of the Greengrass SDK
+    pub async fn with_transport(
+        transport: Box<dyn IotCoreTransport>,
+        config: &IpcConfig,
+    ) -> Result<Self> {
         // Connect to Greengrass IPC
-        sdk.connect()
-            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to connect to IPC: {:?}", e)))?;
-
-        // Get thing name from environment or configuration
-        let thing_name = std::env::var("AWS_IOT_THING_NAME")
-            .or_else(|_| Self::get_thing_name_from_config())
-            .unwrap_or_else(|_| {
-                tracing::warn!("Could not determine thing name, using default");
-                "unknown-thing".to_string()
+        transport
+            .connect()
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to connect to IPC: {}", e)))?;
+
+        // Resolve the thing name before subscribing to anything
+        let thing_name = resolve_thing_name(config, |name| std::env::var(name).ok())?;
+
+        let thing_names = served_thing_names(config, &thing_name);
+        let topics = thing_names
+            .iter()
+            .map(|thing| {
+                let topics = JobsTopics::from_templates(
+                    thing,
+                    config.jobs_namespace.as_deref(),
+                    &config.topics,
+                );
+                (thing.clone(), topics)
+            })
+            .collect();
+
+        tracing::info!(
+            thing_name = %thing_name,
+            thing_names = ?thing_names,
+            jobs_namespace = ?config.jobs_namespace,
+            "Connected to Greengrass IPC"
+        );
+
+        let instance = instance_id();
+
+        Ok(Self {
+            transport: Arc::from(transport),
+            thing_name,
+            thing_names,
+            topics,
+            topic_templates: config.topics.clone(),
+            status_throttle: StatusThrottle::new(Duration::from_millis(
+                config.min_status_update_interval_ms,
+            )),
+            update_origins: Arc::new(UpdateOrigins::new(&instance)),
+            instance,
+            subscriptions: Mutex::new(Vec::new()),
+            liveness: LivenessProbes::new(),
+            last_jobs_contact: Arc::new(AtomicI64::new(0)),
+            publish_timeout: (config.publish_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.publish_timeout_secs)),
+        })
+    }
+
+    pub fn thing_name(&self) -> &str {
+        &self.thing_name
+    }
+
+    /// Things whose jobs this client subscribes to
+    pub fn thing_names(&self) -> &[String] {
+        &self.thing_names
+    }
+
+    fn topics(&self, thing_name: &str) -> Result<&JobsTopics> {
+        self.topics.get(thing_name).ok_or_else(|| {
+            DeviceOpsError::IpcError(format!("Not subscribed to jobs for thing: {}", thing_name))
+        })
+    }
+
+    /// Update responses seen on the wildcard subscriptions that we did not originate.
+    /// A steadily growing count suggests another instance is updating this thing's jobs.
+    pub fn foreign_updates_observed(&self) -> u64 {
+        self.update_origins.foreign_updates()
+    }
+
+    /// Subscribe through the transport and remember the subscription for reconnects
+    fn subscribe_managed(
+        &self,
+        topic: &str,
+        handler: MessageHandler,
+    ) -> std::result::Result<(), String> {
+        self.transport
+            .subscribe(topic, Qos::AtLeastOnce, handler.clone())?;
+        self.remember(topic, handler, false);
+        Ok(())
+    }
+
+    /// Like `subscribe_managed`, on local pub/sub
+    fn subscribe_managed_local(
+        &self,
+        topic: &str,
+        handler: MessageHandler,
+    ) -> std::result::Result<(), String> {
+        self.transport.subscribe_local(topic, handler.clone())?;
+        self.remember(topic, handler, true);
+        Ok(())
+    }
+
+    fn remember(&self, topic: &str, handler: MessageHandler, local: bool) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .push(ManagedSubscription {
+                topic: topic.to_string(),
+                handler,
+                local,
             });
+    }
 
-        tracing::info!(thing_name = %thing_name, "Connected to Greengrass IPC");
+    /// Tear down the session, open a new one and re-make every subscription
+    pub async fn reconnect(&self) -> Result<()> {
+        metrics::global().record_ipc_retry(IpcRetry::Reconnect);
+        self.transport
+            .reconnect()
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to reconnect to IPC: {}", e)))?;
+
+        let subscriptions: Vec<(String, MessageHandler, bool)> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| (s.topic.clone(), s.handler.clone(), s.local))
+            .collect();
+
+        for (topic, handler, local) in subscriptions {
+            let result = if local {
+                self.transport.subscribe_local(&topic, handler)
+            } else {
+                self.transport.subscribe(&topic, Qos::AtLeastOnce, handler)
+            };
+            result.map_err(|e| {
+                DeviceOpsError::IpcError(format!("Failed to resubscribe to {}: {}", topic, e))
+            })?;
+        }
 
-        Ok(Self { sdk, thing_name })
+        tracing::info!(thing_name = %self.thing_name, "Reconnected to Greengrass IPC");
+        Ok(())
     }
 
-    fn get_thing_name_from_config() -> std::result::Result<String, String> {
-        // Try to get thing name from Greengrass configuration
-        // This would use GetConfiguration IPC call in production
-        Err("Not implemented".to_string())
+    /// Publish through the transport, counting the outcome in
+    /// `device_ops_ipc_publishes_total`. A publish that is not acknowledged
+    /// within `ipc.publish_timeout_secs` fails instead of holding up the
+    /// handler; the SDK call cannot be cancelled and finishes on its
+    /// blocking thread.
+    async fn publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: Qos,
+    ) -> std::result::Result<(), String> {
+        publish_with_timeout(&self.transport, self.publish_timeout, topic, payload, qos).await
     }
 
-    pub fn thing_name(&self) -> &str {
-        &self.thing_name
+    /// Subscribe to our own loopback liveness topic on local pub/sub
+    fn subscribe_to_liveness(&self) -> Result<()> {
+        let topic = liveness_probe_topic(&self.thing_name);
+        let instance = self.instance.clone();
+        let received = self.liveness.receiver();
+
+        let callback: MessageHandler = Arc::new(move |_topic: &str, payload: &[u8]| {
+            let Ok(probe) = serde_json::from_slice::<serde_json::Value>(payload) else {
+                return;
+            };
+            // Other instances on the same thing probe the same topic
+            if probe["instance"] != instance.as_str() {
+                return;
+            }
+            if let Some(seq) = probe["seq"].as_u64() {
+                received.fetch_max(seq, Ordering::SeqCst);
+            }
+        });
+
+        self.subscribe_managed_local(&topic, callback).map_err(|e| {
+            DeviceOpsError::IpcError(format!("Failed to subscribe to liveness topic: {}", e))
+        })
+    }
+
+    /// Publish a liveness probe that should come back on our own local
+    /// subscription. It never leaves the core, so an IoT Core outage does not
+    /// make the IPC session look dead.
+    pub fn send_liveness_probe(&self) -> Result<()> {
+        let seq = self.liveness.next_seq();
+        let payload = serde_json::to_vec(&serde_json::json!({
+            "instance": self.instance,
+            "seq": seq,
+        }))
+        .map_err(DeviceOpsError::ipc("Failed to serialize probe"))?;
+
+        tracing::trace!(seq = seq, "Sending liveness probe");
+        self.transport
+            .publish_local(&liveness_probe_topic(&self.thing_name), &payload)
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to publish probe: {}", e)))
+    }
+
+    /// Publish a heartbeat to IoT Core
+    pub async fn send_heartbeat(&self, heartbeat: &Heartbeat<'_>) -> Result<()> {
+        let mut payload = serde_json::to_value(heartbeat)
+            .map_err(DeviceOpsError::ipc("Failed to serialize heartbeat"))?;
+        payload["instance"] = serde_json::Value::String(self.instance.clone());
+        self.publish_json(&self.heartbeat_topic(), &payload).await
+    }
+
+    /// Publish the startup self-test report on the heartbeat topic
+    pub async fn publish_self_test(&self, report: &serde_json::Value) -> Result<()> {
+        self.publish_json(&self.heartbeat_topic(), report).await
+    }
+
+    fn heartbeat_topic(&self) -> String {
+        heartbeat_topic(&self.topic_templates, &self.thing_name)
+    }
+
+    /// Epoch millis of the last message received on any Jobs topic
+    pub fn last_jobs_api_contact(&self) -> Option<i64> {
+        Some(self.last_jobs_contact.load(Ordering::Relaxed)).filter(|at| *at > 0)
+    }
+
+    /// Subscribe to the core's stats request topic. Yields the request ID of
+    /// each valid request; malformed ones, and any beyond a small backlog,
+    /// are dropped.
+    pub fn subscribe_to_stats_requests(&self) -> Result<mpsc::Receiver<String>> {
+        let (request_tx, request_rx) = mpsc::channel(STATS_QUEUE_CAPACITY);
+        let topic = stats_request_topic(&self.topic_templates, &self.thing_name);
+        tracing::info!(topic = %topic, "Subscribing to stats requests");
+
+        let callback: MessageHandler = Arc::new(move |topic: &str, payload: &[u8]| {
+            match stats::request_id(payload) {
+                Ok(request_id) => {
+                    if request_tx.try_send(request_id).is_err() {
+                        tracing::warn!(topic = %topic, "Too many stats requests waiting, dropping one");
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(topic = %topic, error = %e, "Ignoring invalid stats request")
+                }
+            }
+        });
+
+        self.subscribe_managed(&topic, callback).map_err(|e| {
+            DeviceOpsError::IpcError(format!("Failed to subscribe to stats requests: {}", e))
+        })?;
+        Ok(request_rx)
+    }
+
+    /// Reply to stats request `request_id` from a task of its own, so a slow
+    /// broker holds up neither the caller nor the job it is running. The
+    /// outcome is only logged.
+    pub fn spawn_stats_response(&self, request_id: &str, response: &serde_json::Value) {
+        let payload = match serde_json::to_vec(response) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!(request_id = %request_id, error = %e, "Failed to serialize stats response");
+                return;
+            }
+        };
+        let topic = stats_response_topic(&self.topic_templates, &self.thing_name, request_id);
+        let transport = self.transport.clone();
+        let timeout = self.publish_timeout;
+        let request_id = request_id.to_string();
+        tokio::spawn(async move {
+            match publish_with_timeout(&transport, timeout, &topic, &payload, Qos::AtLeastOnce)
+                .await
+            {
+                Ok(()) => tracing::info!(request_id = %request_id, "Answered stats request"),
+                Err(e) => {
+                    tracing::warn!(request_id = %request_id, error = %e, "Failed to answer stats request")
+                }
+            }
+        });
+    }
+
+    /// Whether the last liveness probe came back (true before the first one)
+    pub fn liveness_probe_answered(&self) -> bool {
+        self.liveness.last_answered()
     }
 
     /// Parse job notification and extract job or error
-    fn parse_job_notification(payload: &[u8]) -> Option<JobOrError> {
-        match serde_json::from_slice::<JobNotification>(payload) {
-            Ok(notification) => {
-                if let Some(job) = Option::<Job>::from(notification) {
-                    tracing::debug!(job_id = %job.job_id, "Received job notification");
+    fn parse_job_notification(thing_name: &str, payload: &[u8]) -> Option<JobOrError> {
+        match JobNotification::parse(payload) {
+            Ok((notification, unknown_fields)) => {
+                if let Some(mut job) = Option::<Job>::from(notification) {
+                    job.thing_name = thing_name.to_string();
+                    job.unknown_fields = unknown_fields;
+                    tracing::debug!(thing_name = %thing_name, job_id = %job.job_id, "Received job notification");
                     Some(JobOrError::Valid(job))
                 } else {
                     tracing::debug!("Received notification without execution details");
                     None
                 }
             }
-            Err(e) => {
+            Err((error_msg, path)) => {
                 let payload_str = String::from_utf8_lossy(payload);
-                let error_msg = e.to_string();
                 tracing::error!(
+                    error_kind = ErrorKind::InvalidDocument.label(),
                     error = %error_msg,
+                    path = ?path,
                     payload = %payload_str,
                     "Failed to parse job notification - job document format is invalid"
                 );
@@ -69,8 +468,10 @@ impl IpcClient {
                         if let Some(job_id) = execution.get("jobId").and_then(|id| id.as_str()) {
                             tracing::warn!(job_id = %job_id, "Sending parse error for malformed job");
                             return Some(JobOrError::ParseError {
+                                thing_name: thing_name.to_string(),
                                 job_id: job_id.to_string(),
                                 error: error_msg,
+                                path,
                             });
                         }
                     }
@@ -80,92 +481,189 @@ impl IpcClient {
         }
     }
 
-    pub async fn subscribe_to_jobs(
-        &mut self,
-    ) -> Result<(mpsc::Receiver<JobOrError>, mpsc::Receiver<()>)> {
-        // Subscribe to IoT Jobs notification topic
-        let notify_topic = format!("$aws/things/{}/jobs/notify-next", self.thing_name);
-        let qos = Qos::AtLeastOnce;
-
-        tracing::info!(topic = %notify_topic, "Subscribing to IoT Jobs notifications");
-
+    pub async fn subscribe_to_jobs(&mut self) -> Result<JobStreams> {
         let (job_tx, job_rx) = mpsc::channel(100);
         let (reconnect_tx, reconnect_rx) = mpsc::channel(100);
+        let (pending_tx, pending_rx) = mpsc::channel(10);
+        let (next_rejected_tx, next_rejected_rx) = mpsc::channel(10);
+
+        for thing_name in &self.thing_names {
+            self.subscribe_thing_jobs(
+                thing_name,
+                job_tx.clone(),
+                pending_tx.clone(),
+                next_rejected_tx.clone(),
+            )?;
+        }
+
+        // Subscribe to reconnection signal topic (zdb11 pattern); only the core connects
+        let reconnect_topic = reconnect_topic(&self.topic_templates, &self.thing_name);
+        tracing::info!(topic = %reconnect_topic, "Subscribing to reconnection signals");
+
+        let reconnect_callback: MessageHandler = Arc::new(move |topic: &str, payload: &[u8]| {
+            tracing::info!(
+                topic = %topic,
+                payload = ?String::from_utf8_lossy(payload),
+                "Reconnection detected - will query pending jobs"
+            );
+            if let Err(e) = reconnect_tx.blocking_send(()) {
+                tracing::error!(error_kind = ErrorKind::Ipc.label(), error = %e, "Failed to send reconnection signal");
+            }
+        });
 
-        // Create callback for job notifications
-        // Note: Box::leak is intentional - callbacks must live for program lifetime
-        let job_callback = Box::leak(Box::new(move |_topic: &str, payload: &[u8]| {
-            if let Some(job_or_error) = Self::parse_job_notification(payload) {
+        self.subscribe_managed(&reconnect_topic, reconnect_callback)
+            .map_err(|e| {
+                DeviceOpsError::IpcError(format!("Failed to subscribe to reconnect topic: {}", e))
+            })?;
+
+        self.subscribe_to_liveness()?;
+
+        Ok(JobStreams {
+            jobs: job_rx,
+            reconnects: reconnect_rx,
+            pending: pending_rx,
+            next_rejected: next_rejected_rx,
+        })
+    }
+
+    /// Subscribe to the IoT Jobs topics of one thing
+    fn subscribe_thing_jobs(
+        &self,
+        thing_name: &str,
+        job_tx: mpsc::Sender<JobOrError>,
+        pending_tx: mpsc::Sender<(String, PendingJobExecutions)>,
+        next_rejected_tx: mpsc::Sender<(String, JobsErrorResponse)>,
+    ) -> Result<()> {
+        let topics = self.topics(thing_name)?;
+
+        // Subscribe to IoT Jobs notification topic
+        let notify_topic = topics.notify_next();
+        tracing::info!(topic = %notify_topic, "Subscribing to IoT Jobs notifications");
+
+        // Create callback for job notifications; every Jobs callback notes
+        // when the Jobs API was last heard from
+        let thing = thing_name.to_string();
+        let job_callback: MessageHandler = Arc::new(move |_topic: &str, payload: &[u8]| {
+            if let Some(job_or_error) = Self::parse_job_notification(&thing, payload) {
                 if let Err(e) = job_tx.blocking_send(job_or_error) {
-                    tracing::error!(error = %e, "Failed to send job to channel");
+                    tracing::error!(error_kind = ErrorKind::Ipc.label(), error = %e, "Failed to send job to channel");
                 }
             }
-        }));
+        });
+        let job_callback = noting_contact(&self.last_jobs_contact, job_callback);
 
         // Subscribe to notify-next topic
-        let subscription = self
-            .sdk
-            .subscribe_to_iot_core(&notify_topic, qos, job_callback)
-            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to subscribe: {:?}", e)))?;
-
-        // Keep subscription alive by leaking it (intentional for program lifetime)
-        std::mem::forget(subscription);
+        self.subscribe_managed(&notify_topic, job_callback.clone())
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to subscribe: {}", e)))?;
 
         // Subscribe to $next/get/accepted for job request responses
-        let next_topic = format!("$aws/things/{}/jobs/$next/get/accepted", self.thing_name);
+        let next_topic = topics.next_get_accepted();
         tracing::info!(topic = %next_topic, "Subscribing to job request responses");
 
-        let next_subscription = self
-            .sdk
-            .subscribe_to_iot_core(&next_topic, qos, job_callback)
+        self.subscribe_managed(&next_topic, job_callback)
             .map_err(|e| {
                 DeviceOpsError::IpcError(format!(
-                    "Failed to subscribe to $next/get/accepted: {:?}",
+                    "Failed to subscribe to $next/get/accepted: {}",
                     e
                 ))
             })?;
 
-        std::mem::forget(next_subscription);
+        // Subscribe to $next/get/rejected so a failed request isn't mistaken for "no jobs"
+        let next_rejected_topic = topics.next_get_rejected();
+        tracing::info!(topic = %next_rejected_topic, "Subscribing to job request rejections");
+
+        let thing = thing_name.to_string();
+        let next_rejected_callback: MessageHandler = Arc::new(
+            move |topic: &str, payload: &[u8]| match serde_json::from_slice::<JobsErrorResponse>(
+                payload,
+            ) {
+                Ok(error) => {
+                    tracing::error!(
+                        topic = %topic,
+                        error_kind = ErrorKind::Ipc.label(),
+                        code = %error.code,
+                        message = ?error.message,
+                        client_token = ?error.client_token,
+                        "AWS REJECTED next job request"
+                    );
+                    if let Err(e) = next_rejected_tx.blocking_send((thing.clone(), error)) {
+                        tracing::error!(error_kind = ErrorKind::Ipc.label(), error = %e, "Failed to send job request rejection to channel");
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(
+                        topic = %topic,
+                        error_kind = ErrorKind::Ipc.label(),
+                        error = %e,
+                        payload = %String::from_utf8_lossy(payload),
+                        "AWS REJECTED next job request with an unparseable error"
+                    );
+                }
+            },
+        );
 
-        // Subscribe to reconnection signal topic (zdb11 pattern)
-        let reconnect_topic = format!("reconnect/{}", self.thing_name);
-        tracing::info!(topic = %reconnect_topic, "Subscribing to reconnection signals");
+        let next_rejected_callback =
+            noting_contact(&self.last_jobs_contact, next_rejected_callback);
+        self.subscribe_managed(&next_rejected_topic, next_rejected_callback)
+            .map_err(|e| {
+                DeviceOpsError::IpcError(format!(
+                    "Failed to subscribe to $next/get/rejected: {}",
+                    e
+                ))
+            })?;
 
-        // Note: Box::leak is intentional - callbacks must live for program lifetime
-        let reconnect_callback = Box::leak(Box::new(move |topic: &str, payload: &[u8]| {
-            tracing::info!(
-                topic = %topic,
-                payload = ?String::from_utf8_lossy(payload),
-                "Reconnection detected - will query pending jobs"
-            );
-            if let Err(e) = reconnect_tx.blocking_send(()) {
-                tracing::error!(error = %e, "Failed to send reconnection signal");
+        // Subscribe to get/accepted for GetPendingJobExecutions responses
+        let pending_topic = topics.pending_get_accepted();
+        tracing::info!(topic = %pending_topic, "Subscribing to pending job executions");
+
+        let thing = thing_name.to_string();
+        let pending_callback: MessageHandler = Arc::new(move |_topic: &str, payload: &[u8]| {
+            match serde_json::from_slice::<PendingJobExecutions>(payload) {
+                Ok(pending) => {
+                    if let Err(e) = pending_tx.blocking_send((thing.clone(), pending)) {
+                        tracing::error!(error_kind = ErrorKind::Ipc.label(), error = %e, "Failed to send pending jobs to channel");
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to parse pending job executions");
+                }
             }
-        }));
+        });
 
-        let reconnect_subscription = self
-            .sdk
-            .subscribe_to_iot_core(&reconnect_topic, qos, reconnect_callback)
+        let pending_callback = noting_contact(&self.last_jobs_contact, pending_callback);
+        self.subscribe_managed(&pending_topic, pending_callback)
             .map_err(|e| {
-                DeviceOpsError::IpcError(format!("Failed to subscribe to reconnect topic: {:?}", e))
+                DeviceOpsError::IpcError(format!("Failed to subscribe to get/accepted: {}", e))
             })?;
 
-        std::mem::forget(reconnect_subscription);
-
         // Subscribe to update response topics to see AWS's actual response
-        let update_accepted_topic =
-            format!("$aws/things/{}/jobs/+/update/accepted", self.thing_name);
-        let update_rejected_topic =
-            format!("$aws/things/{}/jobs/+/update/rejected", self.thing_name);
+        let update_accepted_topic = topics.update_accepted("+");
+        let update_rejected_topic = topics.update_rejected("+");
 
         tracing::info!(topic = %update_accepted_topic, "Subscribing to update accepted responses");
         tracing::info!(topic = %update_rejected_topic, "Subscribing to update rejected responses");
 
-        // Create debug callback for update responses
-        // Note: Box::leak is intentional - callbacks must live for program lifetime
-        let debug_callback = Box::leak(Box::new(move |topic: &str, payload: &[u8]| {
+        // Create debug callback for update responses; responses to updates we did
+        // not publish (other components, consoles) are only traced
+        let origins = self.update_origins.clone();
+        let debug_callback: MessageHandler = Arc::new(move |topic: &str, payload: &[u8]| {
             let payload_str = String::from_utf8_lossy(payload);
-            if topic.contains("/update/accepted") {
+            if !origins.is_own_response(payload) {
+                metrics::global().record_foreign_update();
+                let foreign_updates = origins.foreign_updates();
+                if foreign_updates == 1 {
+                    tracing::warn!(
+                        topic = %topic,
+                        "Observed a job status update from another client for this thing"
+                    );
+                }
+                tracing::trace!(
+                    topic = %topic,
+                    payload = %payload_str,
+                    foreign_updates = foreign_updates,
+                    "Ignoring foreign job status update response"
+                );
+            } else if topic.contains("/update/accepted") {
                 tracing::info!(
                     topic = %topic,
                     payload = %payload_str,
@@ -174,72 +672,240 @@ impl IpcClient {
             } else if topic.contains("/update/rejected") {
                 tracing::error!(
                     topic = %topic,
+                    error_kind = ErrorKind::Ipc.label(),
                     payload = %payload_str,
                     "AWS REJECTED job status update"
                 );
             }
-        }));
+        });
 
-        let update_accepted_sub = self
-            .sdk
-            .subscribe_to_iot_core(&update_accepted_topic, qos, debug_callback)
+        let debug_callback = noting_contact(&self.last_jobs_contact, debug_callback);
+        self.subscribe_managed(&update_accepted_topic, debug_callback.clone())
             .map_err(|e| {
-                DeviceOpsError::IpcError(format!("Failed to subscribe to update/accepted: {:?}", e))
+                DeviceOpsError::IpcError(format!("Failed to subscribe to update/accepted: {}", e))
             })?;
 
-        let update_rejected_sub = self
-            .sdk
-            .subscribe_to_iot_core(&update_rejected_topic, qos, debug_callback)
+        self.subscribe_managed(&update_rejected_topic, debug_callback)
             .map_err(|e| {
-                DeviceOpsError::IpcError(format!("Failed to subscribe to update/rejected: {:?}", e))
+                DeviceOpsError::IpcError(format!("Failed to subscribe to update/rejected: {}", e))
             })?;
 
-        std::mem::forget(update_accepted_sub);
-        std::mem::forget(update_rejected_sub);
-
-        Ok((job_rx, reconnect_rx))
+        Ok(())
     }
 
-    pub async fn update_job_status(&self, job_id: &str, status: JobStatus) -> Result<()> {
+    pub async fn update_job_status(
+        &self,
+        thing_name: &str,
+        job_id: &str,
+        status: JobStatus,
+    ) -> Result<()> {
         // Publish job status update to IoT Core
-        let topic = format!("$aws/things/{}/jobs/{}/update", self.thing_name, job_id);
+        let topic = self.topics(thing_name)?.update(job_id);
         let qos = Qos::AtLeastOnce;
 
-        let status_json = status.to_json();
+        // Space updates out so the terminal status can't overtake a progress update
+        self.status_throttle
+            .wait(&job_key(thing_name, job_id), status.is_terminal())
+            .await;
+
+        let mut status_json = status.to_json();
+        status_json["clientToken"] = serde_json::Value::String(self.update_origins.next_token());
         let payload = serde_json::to_vec(&status_json)
-            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to serialize status: {}", e)))?;
+            .map_err(DeviceOpsError::ipc("Failed to serialize status"))?;
 
         tracing::info!(
+            thing_name = %thing_name,
             job_id = %job_id,
             topic = %topic,
             payload = ?String::from_utf8_lossy(&payload),
             "Updating job status"
         );
 
-        self.sdk
-            .publish_to_iot_core(&topic, &payload, qos)
-            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to publish: {:?}", e)))?;
+        self.publish(&topic, &payload, qos)
+            .await
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to publish: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Publish an arbitrary JSON payload to an IoT Core topic
+    pub async fn publish_json(&self, topic: &str, payload: &serde_json::Value) -> Result<()> {
+        let payload = serde_json::to_vec(payload)
+            .map_err(DeviceOpsError::ipc("Failed to serialize payload"))?;
+
+        tracing::debug!(topic = %topic, bytes = payload.len(), "Publishing to IoT Core");
+
+        self.publish(topic, &payload, Qos::AtLeastOnce)
+            .await
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to publish: {}", e)))?;
 
         Ok(())
     }
 
-    pub async fn request_next_job(&self) -> Result<()> {
+    /// Publish a JSON payload on local pub/sub, for other components on the core
+    pub fn publish_local_json(&self, topic: &str, payload: &serde_json::Value) -> Result<()> {
+        let payload = serde_json::to_vec(payload)
+            .map_err(DeviceOpsError::ipc("Failed to serialize payload"))?;
+
+        tracing::debug!(topic = %topic, bytes = payload.len(), "Publishing to local pub/sub");
+
+        self.transport
+            .publish_local(topic, &payload)
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to publish locally: {}", e)))
+    }
+
+    pub async fn request_next_job(&self, thing_name: &str) -> Result<()> {
         // Publish to $next/get to request pending jobs
-        let topic = format!("$aws/things/{}/jobs/$next/get", self.thing_name);
+        let topic = self.topics(thing_name)?.next_get();
         let qos = Qos::AtLeastOnce;
-        let payload = b"{}"; // Empty JSON object
+        // The clientToken is echoed in a rejection, tying it back to this request
+        let client_token = self.update_origins.next_token();
+        let payload = serde_json::to_vec(&serde_json::json!({ "clientToken": client_token }))
+            .map_err(DeviceOpsError::ipc("Failed to serialize request"))?;
 
-        tracing::debug!(topic = %topic, "Requesting next pending job");
+        tracing::debug!(topic = %topic, client_token = %client_token, "Requesting next pending job");
 
-        self.sdk
-            .publish_to_iot_core(&topic, payload, qos)
-            .map_err(|e| {
-                DeviceOpsError::IpcError(format!("Failed to request next job: {:?}", e))
-            })?;
+        self.publish(&topic, &payload, qos)
+            .await
+            .map_err(|e| DeviceOpsError::IpcError(format!("Failed to request next job: {}", e)))?;
+
+        Ok(())
+    }
+
+    pub async fn request_pending_jobs(&self, thing_name: &str) -> Result<()> {
+        // Publish to jobs/get (GetPendingJobExecutions) to learn the queue depth
+        let topic = self.topics(thing_name)?.pending_get();
+        let qos = Qos::AtLeastOnce;
+        let payload = b"{}";
+
+        tracing::debug!(topic = %topic, "Requesting pending job executions");
+
+        self.publish(&topic, payload, qos).await.map_err(|e| {
+            DeviceOpsError::IpcError(format!("Failed to request pending jobs: {}", e))
+        })?;
 
         Ok(())
     }
 }
 
-// Note: Tests removed as they require a real Greengrass environment
-// Integration tests should be run on actual devices
+// End-to-end flows run against `ipc::fake::FakeSdk` in tests/ipc_flow.rs
+
+/// [`IpcClient::publish`] without the client, for publishes from a task of their own
+async fn publish_with_timeout(
+    transport: &Arc<dyn IotCoreTransport>,
+    timeout: Option<Duration>,
+    topic: &str,
+    payload: &[u8],
+    qos: Qos,
+) -> std::result::Result<(), String> {
+    let result = match timeout {
+        None => transport.publish(topic, payload, qos),
+        Some(limit) => {
+            let transport = transport.clone();
+            let (owned_topic, owned_payload) = (topic.to_string(), payload.to_vec());
+            let publish = tokio::task::spawn_blocking(move || {
+                transport.publish(&owned_topic, &owned_payload, qos)
+            });
+            match tokio::time::timeout(limit, publish).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => Err(format!("publish task failed: {}", e)),
+                Err(_) => {
+                    tracing::warn!(
+                        topic = %topic,
+                        timeout_secs = limit.as_secs(),
+                        error_kind = ErrorKind::Ipc.label(),
+                        "Publish not acknowledged in time"
+                    );
+                    Err(format!("not acknowledged within {}s", limit.as_secs()))
+                }
+            }
+        }
+    };
+    metrics::global().record_publish(result.is_ok());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env_with_thing(name: &str) -> Option<String> {
+        (name == "AWS_IOT_THING_NAME").then(|| "env-thing".to_string())
+    }
+
+    #[test]
+    fn test_thing_name_precedence() {
+        let config = IpcConfig {
+            thing_name: Some("config-thing".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_thing_name(&config, env_with_thing).unwrap(),
+            "config-thing"
+        );
+
+        let config = IpcConfig::default();
+        assert_eq!(
+            resolve_thing_name(&config, env_with_thing).unwrap(),
+            "env-thing"
+        );
+    }
+
+    #[test]
+    fn test_unresolvable_thing_name_fails() {
+        let err = resolve_thing_name(&IpcConfig::default(), |_| None).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("ipc.thing_name"));
+        assert!(message.contains("AWS_IOT_THING_NAME"));
+
+        // Blank values count as missing
+        let blank = IpcConfig {
+            thing_name: Some("  ".to_string()),
+            ..Default::default()
+        };
+        assert!(resolve_thing_name(&blank, |_| Some(String::new())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_foreign_update_responses_are_counted() {
+        use crate::ipc::fake::FakeSdk;
+
+        let fake = FakeSdk::new();
+        let config = IpcConfig {
+            thing_name: Some("test-thing".to_string()),
+            min_status_update_interval_ms: 0,
+            ..Default::default()
+        };
+        let mut client = IpcClient::with_transport(Box::new(fake.clone()), &config)
+            .await
+            .unwrap();
+        let _streams = client.subscribe_to_jobs().await.unwrap();
+
+        // Our own update comes back with our clientToken
+        fake.reject_updates(true);
+        client
+            .update_job_status("test-thing", "job-1", JobStatus::queued(1))
+            .await
+            .unwrap();
+        assert_eq!(client.foreign_updates_observed(), 0);
+
+        // Another client's update on the same thing
+        fake.inject(
+            "$aws/things/test-thing/jobs/job-9/update/accepted",
+            br#"{"clientToken":"console-1","timestamp":1}"#,
+        );
+        assert_eq!(client.foreign_updates_observed(), 1);
+    }
+
+    #[test]
+    fn test_allow_unknown_thing() {
+        let config = IpcConfig {
+            allow_unknown_thing: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_thing_name(&config, |_| None).unwrap(),
+            UNKNOWN_THING_NAME
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use crate::config::{Config, ConfigLoadReport, DEFAULT_CONFIG_PATH};
+use crate::error::{DeviceOpsError, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+
+/// Handler invoked whenever the component configuration changes
+pub type UpdateHandler = Arc<dyn Fn() + Send + Sync>;
+
+/// Where the Greengrass component configuration comes from: over IPC, the
+/// client's own session (`GreengrassTransport`). Errors carry the underlying
+/// cause; callers add context.
+pub trait ComponentConfigSource: Send + Sync {
+    /// The component's whole configuration tree
+    fn get_configuration(&self) -> std::result::Result<Value, String>;
+    /// Call `on_update` after every change to the component configuration
+    fn subscribe_to_updates(&self, on_update: UpdateHandler) -> std::result::Result<(), String>;
+}
+
+/// Load the config with the component configuration layered over the file
+/// (see `Config::load_layered`), falling back to the file alone when the
+/// component configuration cannot be read
+pub fn load_config(
+    source: &dyn ComponentConfigSource,
+    path: Option<PathBuf>,
+    profile: Option<&str>,
+) -> Result<(Config, ConfigLoadReport)> {
+    match source.get_configuration() {
+        Ok(component) => Config::load_layered_with_report(path, Some(&component), profile),
+        Err(e) => {
+            tracing::warn!(error = %e, "Cannot read component configuration, using config file only");
+            Config::load_layered_with_report(path, None, profile)
+        }
+    }
+}
+
+/// Forward every SIGHUP as a reload request for `watch_config`, the way
+/// device-management agents ask services to re-read rewritten config files
+pub fn sighup_reload_requests() -> Result<mpsc::UnboundedReceiver<()>> {
+    let mut hangups =
+        signal(SignalKind::hangup()).map_err(DeviceOpsError::config_io("Cannot handle SIGHUP"))?;
+    let (request_tx, request_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if request_tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(request_rx)
+}
+
+/// Rebuild the config after every component configuration update, every
+/// message on `reload_requests` and, with `file_poll` set, whenever the
+/// modification time or size of the config file or a list file it references
+/// changes. `profile` is the profile selected on the command line, if any.
+/// The receiver always holds the latest valid config; updates that fail to
+/// load or validate are logged and the previous config stays active.
+pub fn watch_config(
+    source: Option<Arc<dyn ComponentConfigSource>>,
+    path: Option<PathBuf>,
+    profile: Option<String>,
+    initial: Config,
+    file_poll: Option<Duration>,
+    reload_requests: Option<mpsc::UnboundedReceiver<()>>,
+) -> Result<watch::Receiver<Config>> {
+    let (notify_tx, mut notify_rx) = mpsc::unbounded_channel();
+    let mut subscribed = false;
+    if let Some(source) = &source {
+        source
+            .subscribe_to_updates(Arc::new(move || {
+                let _ = notify_tx.send(());
+            }))
+            .map_err(|e| {
+                DeviceOpsError::IpcError(format!(
+                    "Failed to subscribe to configuration updates: {}",
+                    e
+                ))
+            })?;
+        subscribed = true;
+    }
+
+    let file_path = path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+    let polling = file_poll.is_some();
+    let mut poll_tick = tokio::time::interval(file_poll.unwrap_or(Duration::from_secs(3600)));
+    poll_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_stamp = files_stamp(&file_path, &initial.security.list_files);
+    let mut requestable = reload_requests.is_some();
+    let mut reload_requests = reload_requests.unwrap_or_else(|| mpsc::unbounded_channel().1);
+
+    let (config_tx, config_rx) = watch::channel(initial);
+    tokio::spawn(async move {
+        loop {
+            let reason;
+            tokio::select! {
+                notified = notify_rx.recv(), if subscribed => {
+                    if notified.is_none() {
+                        subscribed = false;
+                        continue;
+                    }
+                    // Several quick updates only need one reload
+                    while notify_rx.try_recv().is_ok() {}
+                    reason = "component configuration update";
+                }
+                requested = reload_requests.recv(), if requestable => {
+                    if requested.is_none() {
+                        requestable = false;
+                        continue;
+                    }
+                    while reload_requests.try_recv().is_ok() {}
+                    tracing::info!("Reload requested");
+                    reason = "reload request";
+                }
+                _ = poll_tick.tick(), if polling => {
+                    let stamp = files_stamp(&file_path, &config_tx.borrow().security.list_files);
+                    if stamp == last_stamp {
+                        continue;
+                    }
+                    last_stamp = stamp;
+                    tracing::info!(path = %file_path.display(), "Config file changed");
+                    reason = "config file change";
+                }
+                else => break,
+            }
+
+            let component = match &source {
+                Some(source) => match source.get_configuration() {
+                    Ok(component) => Some(component),
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Cannot read updated component configuration");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let reloaded =
+                Config::load_layered(path.clone(), component.as_ref(), profile.as_deref())
+                    .and_then(|config| config.validate().map(|()| config));
+            match reloaded {
+                Ok(config) => {
+                    config.log_effective(reason);
+                    if config_tx.send(config).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(reason, error_kind = e.kind().label(), error = %e, "Rejected configuration update, keeping the current configuration");
+                }
+            }
+        }
+    });
+
+    Ok(config_rx)
+}
+
+/// Modification time and size of the config file and the list files the
+/// active config was built from; `None` for a missing file
+fn files_stamp(config_file: &Path, list_files: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    std::iter::once(config_file)
+        .chain(list_files.iter().map(PathBuf::as_path))
+        .map(|path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct FakeConfigSource {
+        config: Mutex<Option<Value>>,
+        handlers: Mutex<Vec<UpdateHandler>>,
+    }
+
+    impl FakeConfigSource {
+        fn update(&self, config: Value) {
+            *self.config.lock().unwrap() = Some(config);
+            for handler in self.handlers.lock().unwrap().iter() {
+                handler();
+            }
+        }
+    }
+
+    impl ComponentConfigSource for FakeConfigSource {
+        fn get_configuration(&self) -> std::result::Result<Value, String> {
+            self.config
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| "no configuration".to_string())
+        }
+
+        fn subscribe_to_updates(
+            &self,
+            on_update: UpdateHandler,
+        ) -> std::result::Result<(), String> {
+            self.handlers.lock().unwrap().push(on_update);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_load_falls_back_to_file_without_component_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 42 } }"#).unwrap();
+
+        let (config, _) = load_config(&FakeConfigSource::default(), Some(path), None).unwrap();
+        assert_eq!(config.execution.default_timeout, 42);
+    }
+
+    #[tokio::test]
+    async fn test_updates_reach_the_receiver() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Some(dir.path().join("missing.json"));
+        let source = Arc::new(FakeConfigSource::default());
+
+        let mut updates = watch_config(
+            Some(source.clone()),
+            path,
+            None,
+            Config::default(),
+            None,
+            None,
+        )
+        .unwrap();
+
+        source.update(serde_json::json!({
+            "security": { "enabled": true, "commandAllowlist": ["/opt/a.sh"] }
+        }));
+        tokio::time::timeout(Duration::from_secs(5), updates.changed())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let config = updates.borrow_and_update().clone();
+        assert!(config.security.enabled);
+        assert_eq!(config.security.command_allowlist, vec!["/opt/a.sh"]);
+    }
+
+    #[tokio::test]
+    async fn test_file_edits_are_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 42 } }"#).unwrap();
+        let initial = Config::load(Some(path.clone())).unwrap();
+
+        let mut updates = watch_config(
+            None,
+            Some(path.clone()),
+            None,
+            initial,
+            Some(Duration::from_millis(20)),
+            None,
+        )
+        .unwrap();
+
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 120 } }"#).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), updates.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updates.borrow_and_update().execution.default_timeout, 120);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_file_edit_keeps_current_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 42 } }"#).unwrap();
+        let initial = Config::load(Some(path.clone())).unwrap();
+
+        let mut updates = watch_config(
+            None,
+            Some(path.clone()),
+            None,
+            initial,
+            Some(Duration::from_millis(20)),
+            None,
+        )
+        .unwrap();
+
+        // Parses, but fails validation
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 0 } }"#).unwrap();
+        let changed = tokio::time::timeout(Duration::from_millis(300), updates.changed()).await;
+        assert!(changed.is_err());
+        assert_eq!(updates.borrow().execution.default_timeout, 42);
+    }
+
+    #[tokio::test]
+    async fn test_list_file_edits_are_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("commands.json");
+        std::fs::write(&list, r#"["/opt/a.sh"]"#).unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(
+            &path,
+            r#"{ "security": { "command_allowlist": { "file": "commands.json" } } }"#,
+        )
+        .unwrap();
+        let initial = Config::load(Some(path.clone())).unwrap();
+
+        let mut updates = watch_config(
+            None,
+            Some(path),
+            None,
+            initial,
+            Some(Duration::from_millis(20)),
+            None,
+        )
+        .unwrap();
+
+        std::fs::write(&list, r#"["/opt/a.sh", "/opt/b.sh"]"#).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), updates.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            updates.borrow_and_update().security.command_allowlist,
+            vec!["/opt/a.sh", "/opt/b.sh"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_requests_reread_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 42 } }"#).unwrap();
+        let initial = Config::load(Some(path.clone())).unwrap();
+
+        // No file polling: only the request makes the edit visible
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        let mut updates = watch_config(
+            None,
+            Some(path.clone()),
+            None,
+            initial,
+            None,
+            Some(request_rx),
+        )
+        .unwrap();
+
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 0 } }"#).unwrap();
+        request_tx.send(()).unwrap();
+        let changed = tokio::time::timeout(Duration::from_millis(300), updates.changed()).await;
+        assert!(changed.is_err(), "invalid config must not be applied");
+        assert_eq!(updates.borrow().execution.default_timeout, 42);
+
+        std::fs::write(&path, r#"{ "execution": { "default_timeout": 120 } }"#).unwrap();
+        request_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), updates.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updates.borrow_and_update().execution.default_timeout, 120);
+    }
+}
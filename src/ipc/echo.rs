@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// How many of our own clientTokens to remember
+const MAX_TRACKED_TOKENS: usize = 256;
+
+/// Tracks the clientTokens on status updates we published, so responses on the
+/// wildcard `update/accepted|rejected` subscriptions can be told apart from
+/// updates made by other components, consoles or a second device-ops instance
+#[derive(Debug)]
+pub struct UpdateOrigins {
+    prefix: String,
+    next_seq: AtomicU64,
+    tokens: Mutex<VecDeque<String>>,
+    foreign_updates: AtomicU64,
+}
+
+impl UpdateOrigins {
+    /// `instance` distinguishes this process's tokens from any other instance's
+    pub fn new(instance: &str) -> Self {
+        Self {
+            prefix: format!("dops-{}", instance),
+            next_seq: AtomicU64::new(0),
+            tokens: Mutex::new(VecDeque::with_capacity(MAX_TRACKED_TOKENS)),
+            foreign_updates: AtomicU64::new(0),
+        }
+    }
+
+    /// Allocate and remember a clientToken for an outgoing status update
+    pub fn next_token(&self) -> String {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let token = format!("{}-{}", self.prefix, seq);
+
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.push_back(token.clone());
+        if tokens.len() > MAX_TRACKED_TOKENS {
+            tokens.pop_front();
+        }
+
+        token
+    }
+
+    /// Whether an update response payload echoes one of our clientTokens.
+    /// Anything else is counted as a foreign update.
+    pub fn is_own_response(&self, payload: &[u8]) -> bool {
+        let token = serde_json::from_slice::<serde_json::Value>(payload)
+            .ok()
+            .and_then(|json| json.get("clientToken")?.as_str().map(str::to_string));
+
+        let own = token.is_some_and(|token| self.tokens.lock().unwrap().contains(&token));
+        if !own {
+            self.foreign_updates.fetch_add(1, Ordering::Relaxed);
+        }
+        own
+    }
+
+    /// Number of update responses observed that we did not originate
+    pub fn foreign_updates(&self) -> u64 {
+        self.foreign_updates.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(token: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "clientToken": token, "timestamp": 1 })).unwrap()
+    }
+
+    #[test]
+    fn test_own_tokens_are_recognized() {
+        let origins = UpdateOrigins::new("a");
+        let token = origins.next_token();
+
+        assert!(origins.is_own_response(&response(&token)));
+        assert_eq!(origins.foreign_updates(), 0);
+    }
+
+    #[test]
+    fn test_foreign_responses_are_counted() {
+        let origins = UpdateOrigins::new("a");
+        origins.next_token();
+
+        // Another instance's token, and a response without any token
+        assert!(!origins.is_own_response(&response(&UpdateOrigins::new("b").next_token())));
+        assert!(!origins.is_own_response(b"{\"timestamp\":1}"));
+        assert!(!origins.is_own_response(b"not json"));
+        assert_eq!(origins.foreign_updates(), 3);
+    }
+
+    #[test]
+    fn test_oldest_tokens_are_forgotten() {
+        let origins = UpdateOrigins::new("a");
+        let first = origins.next_token();
+        for _ in 0..MAX_TRACKED_TOKENS {
+            origins.next_token();
+        }
+
+        assert!(!origins.is_own_response(&response(&first)));
+    }
+}
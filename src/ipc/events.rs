@@ -0,0 +1,276 @@
+//! Lifecycle events on local pub/sub: `jobStarted`, `stepCompleted`,
+//! `jobCompleted` and `securityDenied`, as small JSON messages on
+//! `events.topic`, for other components on the core that want to react to
+//! jobs without polling the cloud.
+//!
+//! Events are fire-and-forget. Emitting only queues the event for the job
+//! handler's loop to publish; when the queue is full or the event type is
+//! switched off the event is dropped, and a failed publish is only logged.
+
+use crate::config::EventsConfig;
+use crate::executor::command::StepReport;
+use crate::metrics::JobOutcome;
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc;
+
+/// Events waiting to be published; more are dropped
+pub const EVENT_QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    JobStarted,
+    StepCompleted,
+    JobCompleted,
+    SecurityDenied,
+}
+
+impl EventKind {
+    /// Value of the `event` field
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::JobStarted => "jobStarted",
+            Self::StepCompleted => "stepCompleted",
+            Self::JobCompleted => "jobCompleted",
+            Self::SecurityDenied => "securityDenied",
+        }
+    }
+
+    /// Whether `config` publishes events of this type
+    pub fn enabled_in(self, config: &EventsConfig) -> bool {
+        config.enabled
+            && match self {
+                Self::JobStarted => config.job_started,
+                Self::StepCompleted => config.step_completed,
+                Self::JobCompleted => config.job_completed,
+                Self::SecurityDenied => config.security_denied,
+            }
+    }
+}
+
+/// The run an event belongs to
+#[derive(Debug, Clone)]
+pub struct EventJob {
+    pub thing_name: String,
+    pub job_id: String,
+    pub execution_id: String,
+}
+
+/// One event, ready to publish
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifecycleEvent {
+    pub kind: EventKind,
+    pub payload: Value,
+}
+
+impl LifecycleEvent {
+    /// `event`, `timestamp` (epoch millis) and the run's IDs, plus `fields`
+    fn new(kind: EventKind, job: &EventJob, fields: Value) -> Self {
+        let mut payload = Map::new();
+        payload.insert("event".to_string(), kind.name().into());
+        payload.insert(
+            "timestamp".to_string(),
+            chrono::Utc::now().timestamp_millis().into(),
+        );
+        payload.insert("thingName".to_string(), job.thing_name.clone().into());
+        payload.insert("jobId".to_string(), job.job_id.clone().into());
+        payload.insert("executionId".to_string(), job.execution_id.clone().into());
+        if let Value::Object(fields) = fields {
+            payload.extend(fields);
+        }
+        Self {
+            kind,
+            payload: Value::Object(payload),
+        }
+    }
+
+    /// The job's steps are about to run
+    pub fn job_started(job: &EventJob, steps: usize) -> Self {
+        Self::new(EventKind::JobStarted, job, json!({ "steps": steps }))
+    }
+
+    /// A step finished; `status` is its result as in `device_ops_steps_total`
+    pub fn step_completed(job: &EventJob, step: &StepReport<'_>) -> Self {
+        let mut fields = json!({
+            "stepName": step.name,
+            "stepIndex": step.index,
+            "status": step.result.label(),
+        });
+        if let Some(output) = step.output {
+            fields["exitCode"] = output.exit_code.into();
+            fields["startedAt"] = output.started_at.into();
+            fields["finishedAt"] = output.finished_at.into();
+        }
+        if let Some(error) = step.error {
+            fields["error"] = error.to_string().into();
+        }
+        Self::new(EventKind::StepCompleted, job, fields)
+    }
+
+    /// The job's final status is being reported. `status` is `SUCCEEDED` or
+    /// `FAILED`, and `outcome` the job outcome as in `device_ops_jobs_total`.
+    pub fn job_completed(
+        job: &EventJob,
+        outcome: JobOutcome,
+        failed_step: Option<&str>,
+        started_at: i64,
+    ) -> Self {
+        let status = if outcome == JobOutcome::Succeeded {
+            "SUCCEEDED"
+        } else {
+            "FAILED"
+        };
+        let mut fields = json!({
+            "status": status,
+            "outcome": outcome.label(),
+            "startedAt": started_at,
+            "finishedAt": chrono::Utc::now().timestamp_millis(),
+        });
+        if let Some(step) = failed_step {
+            fields["failedStep"] = step.into();
+        }
+        Self::new(EventKind::JobCompleted, job, fields)
+    }
+
+    /// A security check refused the document or one of its steps
+    pub fn security_denied(job: &EventJob, step_name: Option<&str>, reason: &str) -> Self {
+        let mut fields = json!({ "reason": reason });
+        if let Some(step) = step_name {
+            fields["stepName"] = step.into();
+        }
+        Self::new(EventKind::SecurityDenied, job, fields)
+    }
+}
+
+/// Queues events for publishing without ever waiting
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    queue: mpsc::Sender<LifecycleEvent>,
+}
+
+impl EventSender {
+    /// A sender and the receiver its events are published from
+    pub fn channel() -> (Self, mpsc::Receiver<LifecycleEvent>) {
+        let (queue, receiver) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        (Self { queue }, receiver)
+    }
+
+    /// Queue `event` if `config` publishes its type
+    pub fn emit(&self, config: &EventsConfig, event: LifecycleEvent) {
+        if !event.kind.enabled_in(config) {
+            return;
+        }
+        if let Err(e) = self.queue.try_send(event) {
+            tracing::debug!(error = %e, "Dropping lifecycle event");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DeviceOpsError;
+    use crate::metrics::StepResult;
+    use crate::models::ExecutionOutput;
+
+    fn job() -> EventJob {
+        EventJob {
+            thing_name: "core-1".to_string(),
+            job_id: "job-1".to_string(),
+            execution_id: "run-1".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_event_payloads() {
+        let started = LifecycleEvent::job_started(&job(), 2).payload;
+        assert_eq!(started["event"], "jobStarted");
+        assert_eq!(started["thingName"], "core-1");
+        assert_eq!(started["jobId"], "job-1");
+        assert_eq!(started["executionId"], "run-1");
+        assert_eq!(started["steps"], 2);
+        assert!(started["timestamp"].as_i64().unwrap() > 0);
+
+        let output = ExecutionOutput {
+            exit_code: 3,
+            started_at: 1_700_000_000_000,
+            finished_at: 1_700_000_000_250,
+            ..Default::default()
+        };
+        let step = StepReport {
+            name: "Install",
+            index: 1,
+            result: StepResult::Failure,
+            output: Some(&output),
+            error: None,
+        };
+        let completed = LifecycleEvent::step_completed(&job(), &step).payload;
+        assert_eq!(completed["event"], "stepCompleted");
+        assert_eq!(completed["stepName"], "Install");
+        assert_eq!(completed["stepIndex"], 1);
+        assert_eq!(completed["status"], "failure");
+        assert_eq!(completed["exitCode"], 3);
+        assert_eq!(completed["finishedAt"], 1_700_000_000_250_i64);
+        assert!(completed.get("error").is_none());
+
+        let error = DeviceOpsError::TimeoutError {
+            step: "Install".to_string(),
+            timeout_secs: 30,
+            elapsed_ms: 30000,
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        let timed_out = StepReport {
+            result: StepResult::Timeout,
+            output: None,
+            error: Some(&error),
+            ..step
+        };
+        let completed = LifecycleEvent::step_completed(&job(), &timed_out).payload;
+        assert_eq!(completed["status"], "timeout");
+        assert_eq!(completed["error"], error.to_string());
+        assert!(completed.get("exitCode").is_none());
+
+        let failed =
+            LifecycleEvent::job_completed(&job(), JobOutcome::TimedOut, Some("Install"), 1).payload;
+        assert_eq!(failed["status"], "FAILED");
+        assert_eq!(failed["outcome"], "timed_out");
+        assert_eq!(failed["failedStep"], "Install");
+        assert_eq!(failed["startedAt"], 1);
+
+        let denied = LifecycleEvent::security_denied(&job(), None, "not allowlisted").payload;
+        assert_eq!(denied["event"], "securityDenied");
+        assert_eq!(denied["reason"], "not allowlisted");
+        assert!(denied.get("stepName").is_none());
+    }
+
+    #[test]
+    fn test_emit_honors_toggles_and_never_blocks() {
+        let (sender, mut receiver) = EventSender::channel();
+        let mut config = EventsConfig::default();
+        sender.emit(&config, LifecycleEvent::job_started(&job(), 1));
+        assert!(receiver.try_recv().is_err());
+
+        config.enabled = true;
+        config.job_started = false;
+        sender.emit(&config, LifecycleEvent::job_started(&job(), 1));
+        sender.emit(
+            &config,
+            LifecycleEvent::security_denied(&job(), None, "denied"),
+        );
+        assert_eq!(receiver.try_recv().unwrap().kind, EventKind::SecurityDenied);
+        assert!(receiver.try_recv().is_err());
+
+        // Nobody draining the queue: the overflow is dropped
+        for _ in 0..EVENT_QUEUE_CAPACITY + 5 {
+            sender.emit(
+                &config,
+                LifecycleEvent::security_denied(&job(), None, "denied"),
+            );
+        }
+        let mut queued = 0;
+        while receiver.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, EVENT_QUEUE_CAPACITY);
+    }
+}
@@ -0,0 +1,392 @@
+//! In-memory stand-in for Greengrass IPC, for tests that drive `IpcClient`
+//! and `JobHandler` without a nucleus. Enabled by the `fake-ipc` feature.
+
+use crate::ipc::transport::{IotCoreTransport, MessageHandler};
+use gg_sdk::Qos;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A message published through the fake
+#[derive(Debug, Clone)]
+pub struct PublishedMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl PublishedMessage {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.payload).unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Default)]
+struct FakeState {
+    connected: bool,
+    refuse_connect: bool,
+    offline: bool,
+    stalled: bool,
+    session_dead: bool,
+    reconnects: usize,
+    reject_updates: bool,
+    subscriptions: Vec<(String, MessageHandler)>,
+    local_subscriptions: Vec<(String, MessageHandler)>,
+    published: Vec<PublishedMessage>,
+    published_local: Vec<PublishedMessage>,
+}
+
+/// Fake IoT Core transport with in-memory topic routing. Clones share state,
+/// so a test keeps one handle while `IpcClient` owns another.
+#[derive(Clone, Default)]
+pub struct FakeSdk {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl std::fmt::Debug for FakeSdk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("FakeSdk")
+            .field("connected", &state.connected)
+            .field("subscriptions", &state.subscriptions.len())
+            .field("published", &state.published.len())
+            .finish()
+    }
+}
+
+impl FakeSdk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `connect` fail, as when the nucleus is not running
+    pub fn refuse_connect(&self) {
+        self.state.lock().unwrap().refuse_connect = true;
+    }
+
+    /// While offline, IoT Core publishes fail; local pub/sub keeps working
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().unwrap().offline = offline;
+    }
+
+    /// While stalled, IoT Core publishes block as with an unresponsive broker;
+    /// they go through once the stall ends
+    pub fn stall_publishes(&self, stalled: bool) {
+        self.state.lock().unwrap().stalled = stalled;
+    }
+
+    /// Kill the session without telling anyone: subscriptions stop receiving
+    /// and publishes vanish, but every call still succeeds until `reconnect`
+    pub fn kill_session(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.session_dead = true;
+        state.subscriptions.clear();
+        state.local_subscriptions.clear();
+    }
+
+    /// Number of times the client reconnected
+    pub fn reconnects(&self) -> usize {
+        self.state.lock().unwrap().reconnects
+    }
+
+    /// Answer every job status update on `.../update/rejected` instead of accepting it
+    pub fn reject_updates(&self, reject: bool) {
+        self.state.lock().unwrap().reject_updates = reject;
+    }
+
+    /// Topic filters subscribed so far
+    pub fn subscriptions(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.subscriptions.iter().map(|(t, _)| t.clone()).collect()
+    }
+
+    /// Everything published so far, in order
+    pub fn published(&self) -> Vec<PublishedMessage> {
+        self.state.lock().unwrap().published.clone()
+    }
+
+    /// JSON payloads published to exactly this topic, in order
+    pub fn published_to(&self, topic: &str) -> Vec<Value> {
+        self.published()
+            .iter()
+            .filter(|m| m.topic == topic)
+            .map(PublishedMessage::json)
+            .collect()
+    }
+
+    /// JSON payloads published on local pub/sub to exactly this topic, in order
+    pub fn published_local_to(&self, topic: &str) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .published_local
+            .iter()
+            .filter(|m| m.topic == topic)
+            .map(PublishedMessage::json)
+            .collect()
+    }
+
+    /// Deliver a message to every matching subscription; returns how many matched
+    pub fn inject(&self, topic: &str, payload: &[u8]) -> usize {
+        let handlers = matching(&self.state.lock().unwrap().subscriptions, topic);
+        deliver(&handlers, topic, payload)
+    }
+
+    pub fn inject_json(&self, topic: &str, payload: &Value) -> usize {
+        self.inject(topic, &serde_json::to_vec(payload).unwrap())
+    }
+
+    /// Poll until a message matching `predicate` has been published
+    pub async fn wait_for<F>(&self, timeout: Duration, predicate: F) -> Option<PublishedMessage>
+    where
+        F: Fn(&PublishedMessage) -> bool,
+    {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(message) = self.published().into_iter().find(|m| predicate(m)) {
+                return Some(message);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Handlers of the subscriptions whose filter matches `topic`
+fn matching(subscriptions: &[(String, MessageHandler)], topic: &str) -> Vec<MessageHandler> {
+    subscriptions
+        .iter()
+        .filter(|(filter, _)| topic_matches(filter, topic))
+        .map(|(_, handler)| handler.clone())
+        .collect()
+}
+
+/// Run each handler on the message; returns how many there were
+fn deliver(handlers: &[MessageHandler], topic: &str, payload: &[u8]) -> usize {
+    // Handlers block on channel sends, so run them off the async runtime
+    // the way the SDK's own callback thread would
+    std::thread::scope(|scope| {
+        for handler in handlers {
+            scope.spawn(|| handler(topic, payload));
+        }
+    });
+
+    handlers.len()
+}
+
+impl IotCoreTransport for FakeSdk {
+    fn connect(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.refuse_connect {
+            return Err("connection refused".to_string());
+        }
+        state.connected = true;
+        Ok(())
+    }
+
+    fn reconnect(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.refuse_connect {
+            return Err("connection refused".to_string());
+        }
+        state.connected = true;
+        state.session_dead = false;
+        state.subscriptions.clear();
+        state.local_subscriptions.clear();
+        state.reconnects += 1;
+        Ok(())
+    }
+
+    fn subscribe(&self, topic: &str, _qos: Qos, handler: MessageHandler) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err("not connected".to_string());
+        }
+        if !state.session_dead {
+            state.subscriptions.push((topic.to_string(), handler));
+        }
+        Ok(())
+    }
+
+    fn publish(&self, topic: &str, payload: &[u8], _qos: Qos) -> Result<(), String> {
+        while self.state.lock().unwrap().stalled {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let reject = {
+            let mut state = self.state.lock().unwrap();
+            if !state.connected || state.offline {
+                return Err("not connected".to_string());
+            }
+            if state.session_dead {
+                return Ok(());
+            }
+            state.published.push(PublishedMessage {
+                topic: topic.to_string(),
+                payload: payload.to_vec(),
+            });
+            state.reject_updates && topic.ends_with("/update")
+        };
+
+        // Like the broker, deliver to our own matching subscriptions
+        self.inject(topic, payload);
+
+        if reject {
+            // Like IoT Jobs, echo the request's clientToken in the response
+            let client_token = serde_json::from_slice::<Value>(payload)
+                .ok()
+                .and_then(|request| request.get("clientToken").cloned());
+            let response = serde_json::json!({
+                "code": "InvalidStateTransition",
+                "message": "Rejected by FakeSdk",
+                "clientToken": client_token,
+            });
+            self.inject_json(&format!("{}/rejected", topic), &response);
+        }
+
+        Ok(())
+    }
+
+    fn publish_local(&self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        let handlers = {
+            let mut state = self.state.lock().unwrap();
+            if !state.connected {
+                return Err("not connected".to_string());
+            }
+            if state.session_dead {
+                return Ok(());
+            }
+            state.published_local.push(PublishedMessage {
+                topic: topic.to_string(),
+                payload: payload.to_vec(),
+            });
+            matching(&state.local_subscriptions, topic)
+        };
+        deliver(&handlers, topic, payload);
+        Ok(())
+    }
+
+    fn subscribe_local(&self, topic: &str, handler: MessageHandler) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err("not connected".to_string());
+        }
+        if !state.session_dead {
+            state.local_subscriptions.push((topic.to_string(), handler));
+        }
+        Ok(())
+    }
+}
+
+/// MQTT topic filter matching with `+` and `#` wildcards
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn test_inject_routes_to_matching_subscriptions() {
+        let fake = FakeSdk::new();
+        fake.connect().unwrap();
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        fake.subscribe(
+            "jobs/+/update/accepted",
+            Qos::AtLeastOnce,
+            Arc::new(move |topic: &str, _payload: &[u8]| {
+                sink.lock().unwrap().push(topic.to_string())
+            }),
+        )
+        .unwrap();
+
+        assert_eq!(fake.inject("jobs/job-1/update/accepted", b"{}"), 1);
+        assert_eq!(fake.inject("jobs/job-1/update/rejected", b"{}"), 0);
+        assert_eq!(*seen.lock().unwrap(), vec!["jobs/job-1/update/accepted"]);
+    }
+
+    #[test]
+    fn test_dead_session_swallows_until_reconnect() {
+        let fake = FakeSdk::new();
+        fake.connect().unwrap();
+        let seen = Arc::new(Mutex::new(0));
+        let sink = seen.clone();
+        let handler: MessageHandler =
+            Arc::new(move |_topic: &str, _payload: &[u8]| *sink.lock().unwrap() += 1);
+        fake.subscribe("loop", Qos::AtLeastOnce, handler.clone())
+            .unwrap();
+
+        fake.publish("loop", b"{}", Qos::AtLeastOnce).unwrap();
+        assert_eq!(*seen.lock().unwrap(), 1);
+
+        fake.kill_session();
+        fake.publish("loop", b"{}", Qos::AtLeastOnce).unwrap();
+        assert_eq!(*seen.lock().unwrap(), 1);
+
+        fake.reconnect().unwrap();
+        fake.subscribe("loop", Qos::AtLeastOnce, handler).unwrap();
+        fake.publish("loop", b"{}", Qos::AtLeastOnce).unwrap();
+        assert_eq!(*seen.lock().unwrap(), 2);
+        assert_eq!(fake.reconnects(), 1);
+    }
+
+    #[test]
+    fn test_local_publish_reaches_local_subscriptions_only() {
+        let fake = FakeSdk::new();
+        fake.connect().unwrap();
+        let seen = Arc::new(Mutex::new(0));
+        let sink = seen.clone();
+        fake.subscribe_local(
+            "probe",
+            Arc::new(move |_topic: &str, _payload: &[u8]| *sink.lock().unwrap() += 1),
+        )
+        .unwrap();
+
+        fake.publish_local("probe", b"{}").unwrap();
+        assert_eq!(*seen.lock().unwrap(), 1);
+        assert_eq!(fake.inject("probe", b"{}"), 0);
+
+        // A cloud outage leaves local pub/sub alone
+        fake.set_offline(true);
+        fake.publish_local("probe", b"{}").unwrap();
+        assert_eq!(*seen.lock().unwrap(), 2);
+
+        fake.kill_session();
+        fake.publish_local("probe", b"{}").unwrap();
+        assert_eq!(*seen.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_offline_publish_fails() {
+        let fake = FakeSdk::new();
+        fake.connect().unwrap();
+        fake.set_offline(true);
+        assert!(fake.publish("t", b"{}", Qos::AtLeastOnce).is_err());
+
+        fake.set_offline(false);
+        fake.publish("t", b"{}", Qos::AtLeastOnce).unwrap();
+        assert_eq!(fake.published_to("t").len(), 1);
+    }
+}
@@ -1,18 +1,102 @@
-use crate::config::Config;
-use crate::error::Result;
+use crate::backoff::Backoff;
+use crate::clock::system_uptime_secs;
+use crate::config::{Config, MAX_STATS_RECENT_JOBS};
+use crate::device_info::DeviceInfo;
+use crate::error::{DeviceOpsError, ErrorKind, Result};
+use crate::executor::command::{CommandRunner, StepReport, SystemCommandRunner};
+use crate::executor::output_log::{LiveLine, LiveOutput};
 use crate::executor::CommandExecutor;
+use crate::history::{self, document_hash, step_records, HistoryRecord, StepRecord};
+use crate::ipc::client::{Heartbeat, JobStreams};
+use crate::ipc::events::{EventJob, EventSender, LifecycleEvent};
+use crate::ipc::liveness::LivenessMonitor;
+use crate::ipc::queue::JobQueue;
+use crate::ipc::state::PersistedState;
+use crate::ipc::stats::{self, HandlerStats};
+use crate::ipc::telemetry::{metric_request, TelemetryEmitter, TelemetryMetric};
+use crate::ipc::throttle::PerMinuteLimiter;
+use crate::ipc::topics::{live_output_topic, results_topic};
 use crate::ipc::IpcClient;
-use crate::models::{Job, JobOrError, JobStatus};
-use crate::security::{validate_job_document, SecurityValidator};
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use crate::job_log::cleanup_job_logs;
+use crate::last_run::{self, LastRun};
+use crate::metrics::{self, IpcRetry, JobOutcome};
+use crate::models::{
+    job_key, Command, Job, JobExecutionResult, JobMetrics, JobOrError, JobStatus,
+    PendingJobExecutions, StatusDetailsOptions,
+};
+use crate::security::{check_unknown_fields, validate_job_document, SecurityValidator};
+use crate::slow_steps::{self, SlowStep, SlowStepRules};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, Instant};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// First retry delay after a throttled `$next/get` request
+const NEXT_JOB_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+/// Upper bound on the retry delay for throttled `$next/get` requests
+const NEXT_JOB_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// First retry delay after a failed reconnect of a dead IPC session
+const RECONNECT_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+/// Upper bound on the retry delay between reconnect attempts
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Upper bound on the delay between runs of a job that failed for a transient reason
+const TRANSIENT_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Share of each retry delay taken off at random, so devices that lost
+/// their session or were throttled together do not retry in lockstep
+const BACKOFF_JITTER: f64 = 0.2;
+
+/// A running job, resolving to the thing it belonged to and its outcome
+type JobRun<'a> = Pin<Box<dyn Future<Output = (String, Result<()>)> + 'a>>;
 
 pub struct JobHandler {
     ipc_client: IpcClient,
     executor: CommandExecutor,
     processed_jobs: Arc<Mutex<VecDeque<String>>>,
+    /// Jobs received but not started yet, persisted with the dedup state
+    queue: Mutex<JobQueue>,
+    /// Job IDs AWS last reported as queued, per thing
+    queued_jobs: Mutex<HashMap<String, Vec<String>>>,
+    /// Replaced on component configuration updates
+    config: RwLock<Arc<Config>>,
+    /// Component configuration and config file updates, consumed by `run`
+    config_updates: Option<watch::Receiver<Config>>,
+    /// Config updates applied since startup, reported in the liveness heartbeat
+    config_reloads: AtomicU64,
+    /// Runs `execution.on_validation_failure_command`
+    hook_runner: Arc<dyn CommandRunner>,
+    /// Lifecycle events for local pub/sub
+    events: EventSender,
+    /// Events waiting to be published, consumed by `run`
+    event_queue: Option<mpsc::Receiver<LifecycleEvent>>,
+    /// When the handler was created, for the uptime in stats responses
+    started: Instant,
+    /// `(thing name, job ID)` of the job being handled
+    running_job: Mutex<Option<(String, String)>>,
+    /// Newest history records, for stats responses; seeded from the end of
+    /// the history file
+    recent_jobs: Mutex<VecDeque<HistoryRecord>>,
+    /// Whether the running job's terminal status went out; a panic after
+    /// that must not report the job a second time
+    terminal_reported: AtomicBool,
+    /// Collected at startup for job results, history and the heartbeat
+    device_info: DeviceInfo,
 }
 
+/// `reason_code` of a job whose handling panicked
+const INTERNAL_PANIC: &str = "INTERNAL_PANIC";
+
+/// Time `execution.on_validation_failure_command` gets before it is stopped
+const VALIDATION_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl JobHandler {
     pub fn new(ipc_client: IpcClient, config: Config) -> Self {
         let security = if config.security.enabled {
@@ -21,63 +105,442 @@ impl JobHandler {
             None
         };
 
-        let executor = CommandExecutor::new(config.execution, security);
+        let executor = CommandExecutor::new(config.execution.clone(), security);
+        let (events, event_queue) = EventSender::channel();
 
         Self {
             ipc_client,
             executor,
-            processed_jobs: Arc::new(Mutex::new(VecDeque::with_capacity(100))),
+            processed_jobs: Arc::new(Mutex::new(VecDeque::with_capacity(
+                config.execution.max_processed_jobs,
+            ))),
+            queue: Mutex::new(JobQueue::new(config.execution.max_queued_jobs)),
+            queued_jobs: Mutex::new(HashMap::new()),
+            config: RwLock::new(Arc::new(config)),
+            config_updates: None,
+            config_reloads: AtomicU64::new(0),
+            hook_runner: Arc::new(SystemCommandRunner),
+            events,
+            event_queue: Some(event_queue),
+            started: Instant::now(),
+            running_job: Mutex::new(None),
+            recent_jobs: Mutex::new(VecDeque::new()),
+            terminal_reported: AtomicBool::new(false),
+            device_info: DeviceInfo::collect(),
+        }
+    }
+
+    /// Apply config updates from the component configuration or config file while running
+    pub fn with_config_updates(mut self, updates: watch::Receiver<Config>) -> Self {
+        self.config_updates = Some(updates);
+        self
+    }
+
+    /// Report `device_info` instead of the collected one (for testing)
+    pub fn with_device_info(mut self, device_info: DeviceInfo) -> Self {
+        self.device_info = device_info;
+        self
+    }
+
+    /// Run hook commands through `runner` instead of spawning them (for testing)
+    pub fn with_hook_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.hook_runner = runner;
+        self
+    }
+
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Switch to an updated config. Only called between jobs, so a job never
+    /// sees two sets of security allowlists or execution settings; IPC, logging
+    /// and reload settings need a restart.
+    async fn apply_config(&self, config: Config) {
+        let security = if config.security.enabled {
+            Some(SecurityValidator::new(config.security.clone()))
+        } else {
+            None
+        };
+        self.executor
+            .reconfigure(config.execution.clone(), security);
+
+        let evicted = self
+            .queue
+            .lock()
+            .unwrap()
+            .set_capacity(config.execution.max_queued_jobs);
+
+        let reloads = self.config_reloads.fetch_add(1, Ordering::SeqCst) + 1;
+        tracing::info!(
+            security_enabled = config.security.enabled,
+            default_timeout = config.execution.default_timeout,
+            config_hash = %config.fingerprint(),
+            config_reloads = reloads,
+            "Configuration reloaded"
+        );
+        *self.config.write().unwrap() = Arc::new(config);
+
+        for job in evicted {
+            self.fail_evicted_job(&job.thing_name, &job.job_id).await;
+        }
+        self.persist_state();
+    }
+
+    /// Record the latest GetPendingJobExecutions response, logging when the depth changes
+    fn update_queue_depth(&self, thing_name: &str, pending: PendingJobExecutions) {
+        let queued: Vec<String> = pending.queued_jobs.into_iter().map(|j| j.job_id).collect();
+        let mut queued_jobs = self.queued_jobs.lock().unwrap();
+        let known = queued_jobs.entry(thing_name.to_string()).or_default();
+
+        if queued.len() != known.len() {
+            tracing::info!(
+                thing_name = %thing_name,
+                queue_depth = queued.len(),
+                previous = known.len(),
+                "Pending job queue depth changed"
+            );
         }
+
+        let threshold = self.config().execution.queue_depth_warn_threshold;
+        if queued.len() > threshold {
+            tracing::warn!(
+                thing_name = %thing_name,
+                queue_depth = queued.len(),
+                threshold = threshold,
+                "Device is falling behind on queued jobs"
+            );
+        }
+
+        *known = queued;
+        let depth = queued_jobs.values().map(Vec::len).sum::<usize>();
+        metrics::global().set_queue_depth(depth as u64);
+    }
+
+    /// Jobs AWS last reported as queued, over every served thing
+    fn queued_in_aws(&self) -> usize {
+        self.queued_jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// Number of known queued jobs of the thing other than the given one
+    fn queued_behind(&self, thing_name: &str, job_id: &str) -> usize {
+        self.queued_jobs
+            .lock()
+            .unwrap()
+            .get(thing_name)
+            .map_or(0, |queued| queued.iter().filter(|id| *id != job_id).count())
     }
 
     /// Check if job was already processed and mark it as processed if not.
     /// Returns true if this is a new job that should be handled.
-    fn mark_job_processed(&self, job_id: &str) -> bool {
+    ///
+    /// The check and the reservation happen under one lock, and callers
+    /// reserve before their first await: of two deliveries of the same job
+    /// racing each other, only one ever gets to queue or run it.
+    ///
+    /// `key` is the job's dedup key, see [`Job::key`].
+    fn mark_job_processed(&self, key: String) -> bool {
         let mut processed = self.processed_jobs.lock().unwrap();
 
         // Check if already processed
-        if processed.contains(&job_id.to_string()) {
+        if processed.contains(&key) {
             return false;
         }
 
         // Mark as processed
-        processed.push_back(job_id.to_string());
+        processed.push_back(key);
 
-        // Keep only the last 100 job IDs (FIFO eviction)
-        if processed.len() > 100 {
+        // Keep only the last `max_processed_jobs` keys (FIFO eviction)
+        if processed.len() > self.config().execution.max_processed_jobs {
             processed.pop_front();
         }
 
         true
     }
 
+    /// Seed the stats responses' recent jobs from the end of the history file
+    async fn load_recent_jobs(&self) {
+        let Some(path) = self.config().execution.history_file.clone() else {
+            return;
+        };
+        match tokio::task::spawn_blocking(move || history::read_tail(&path, MAX_STATS_RECENT_JOBS))
+            .await
+        {
+            Ok(Ok(records)) => *self.recent_jobs.lock().unwrap() = records.into(),
+            Ok(Err(e)) => tracing::warn!(error = %e, "Cannot read job history for stats"),
+            Err(e) => tracing::warn!(error = %e, "Cannot read job history for stats"),
+        }
+    }
+
+    /// Restore dedup state and the local queue from the state file, failing
+    /// any restored jobs that no longer fit in the queue
+    async fn restore_state(&self) {
+        let config = self.config();
+        let Some(path) = &config.execution.state_file else {
+            return;
+        };
+
+        let state = match PersistedState::load(path) {
+            Ok(state) => state,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Ignoring unreadable state file");
+                return;
+            }
+        };
+
+        // State written before multi-thing support holds bare job IDs of the core device
+        let core_thing = self.ipc_client.thing_name().to_string();
+        {
+            let mut processed = self.processed_jobs.lock().unwrap();
+            processed.extend(state.processed_jobs.into_iter().map(|key| {
+                if key.contains('/') {
+                    key
+                } else {
+                    job_key(&core_thing, &key)
+                }
+            }));
+            while processed.len() > self.config().execution.max_processed_jobs {
+                processed.pop_front();
+            }
+        }
+
+        let jobs = state
+            .queued_jobs
+            .into_iter()
+            .map(|mut job| {
+                if job.thing_name.is_empty() {
+                    job.thing_name = core_thing.clone();
+                }
+                job
+            })
+            .collect();
+        let (queue, evicted) = JobQueue::from_jobs(jobs, self.config().execution.max_queued_jobs);
+        tracing::info!(queued = queue.len(), "Restored on-device job queue");
+        *self.queue.lock().unwrap() = queue;
+
+        for job in evicted {
+            self.fail_evicted_job(&job.thing_name, &job.job_id).await;
+        }
+        self.persist_state();
+    }
+
+    /// Write dedup state and the local queue to the state file, if configured
+    fn persist_state(&self) {
+        let config = self.config();
+        let Some(path) = &config.execution.state_file else {
+            return;
+        };
+
+        let state = PersistedState {
+            processed_jobs: self
+                .processed_jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+            queued_jobs: self.queue.lock().unwrap().jobs().cloned().collect(),
+        };
+
+        if let Err(e) = state.save(path, config.execution.compress_state) {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to persist handler state");
+        }
+    }
+
+    /// Add a new job to the local queue, reporting it as queued when it has to wait
+    async fn enqueue_job(&self, job: Job, busy: bool) {
+        if !self.mark_job_processed(job.key()) {
+            tracing::debug!(
+                thing_name = %job.thing_name,
+                job_id = %job.job_id,
+                "Job already processed, skipping duplicate"
+            );
+            return;
+        }
+
+        let thing_name = job.thing_name.clone();
+        let job_id = job.job_id.clone();
+        let (evicted, position) = {
+            let mut queue = self.queue.lock().unwrap();
+            let evicted = queue.push(job);
+            (evicted, queue.position(&thing_name, &job_id))
+        };
+        self.persist_state();
+
+        if let Some(evicted) = evicted {
+            self.fail_evicted_job(&evicted.thing_name, &evicted.job_id)
+                .await;
+        }
+
+        // Only report jobs that actually have to wait
+        let Some(position) = position else {
+            return;
+        };
+        let behind = position + usize::from(busy);
+        if behind == 0 {
+            return;
+        }
+
+        tracing::info!(thing_name = %thing_name, job_id = %job_id, behind = behind, "Job queued on device");
+        if let Err(e) = self
+            .ipc_client
+            .update_job_status(&thing_name, &job_id, JobStatus::queued(behind))
+            .await
+        {
+            tracing::warn!(job_id = %job_id, error = %e, "Failed to report queued job");
+        }
+    }
+
+    async fn fail_evicted_job(&self, thing_name: &str, job_id: &str) {
+        let capacity = self.config().execution.max_queued_jobs;
+        tracing::error!(
+            thing_name = %thing_name,
+            job_id = %job_id,
+            capacity = capacity,
+            "On-device queue full, failing oldest job"
+        );
+
+        let status = JobStatus::failed(
+            format!(
+                "Dropped from on-device queue: queue full ({} jobs)",
+                capacity
+            ),
+            None,
+            None,
+        );
+        if let Err(e) = self
+            .ipc_client
+            .update_job_status(thing_name, job_id, status)
+            .await
+        {
+            tracing::error!(job_id = %job_id, error_kind = e.kind().label(), error = %e, "Failed to report dropped job");
+        }
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         tracing::info!("Job handler starting");
 
+        self.restore_state().await;
+        self.load_recent_jobs().await;
+
         // Request any pending jobs on startup
-        if let Err(e) = self.ipc_client.request_next_job().await {
-            tracing::warn!(error = %e, "Failed to request pending jobs on startup, will retry on next event");
+        for thing_name in self.ipc_client.thing_names() {
+            if let Err(e) = self.ipc_client.request_next_job(thing_name).await {
+                tracing::warn!(thing_name = %thing_name, error = %e, "Failed to request pending jobs on startup, will retry on next event");
+            }
         }
 
         // Subscribe to job notifications and reconnection signals
-        let (mut job_stream, mut reconnect_stream) = self.ipc_client.subscribe_to_jobs().await?;
+        let streams = self.ipc_client.subscribe_to_jobs().await?;
+
+        for thing_name in self.ipc_client.thing_names() {
+            if let Err(e) = self.ipc_client.request_pending_jobs(thing_name).await {
+                tracing::warn!(thing_name = %thing_name, error = %e, "Failed to query pending job queue depth");
+            }
+        }
+
+        // Read once: switching stats on or off takes a restart
+        let stats_requests = if self.config().stats.enabled {
+            match self.ipc_client.subscribe_to_stats_requests() {
+                Ok(requests) => Some(requests),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Stats requests will not be answered");
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
         tracing::info!("Listening for job notifications and reconnection signals");
+        let config_updates = self.config_updates.take();
+        let event_queue = self.event_queue.take();
+        self.process_streams(streams, config_updates, event_queue, stats_requests)
+            .await;
+
+        Ok(())
+    }
+
+    /// Run queued jobs one at a time while draining notifications into the queue
+    async fn process_streams(
+        &self,
+        mut streams: JobStreams,
+        mut config_updates: Option<watch::Receiver<Config>>,
+        mut event_queue: Option<mpsc::Receiver<LifecycleEvent>>,
+        mut stats_requests: Option<mpsc::Receiver<String>>,
+    ) {
+        // The running job; notifications keep being drained into the queue meanwhile
+        let mut current: Option<JobRun<'_>> = None;
+
+        // Throttled $next/get requests are retried per thing after an increasing delay
+        let mut next_job_backoff: HashMap<String, Backoff> = HashMap::new();
+        let mut next_job_retry: HashMap<String, Instant> = HashMap::new();
+
+        // Loopback probes catch an IPC session that died without any callback firing
+        let probe_interval = Duration::from_millis(self.config().ipc.liveness_probe_interval_ms);
+        let probing = !probe_interval.is_zero();
+        let mut probe_tick = tokio::time::interval(if probing {
+            probe_interval
+        } else {
+            Duration::from_secs(3600)
+        });
+        probe_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut liveness = LivenessMonitor::new(self.config().ipc.liveness_failure_threshold);
+        let mut reconnect_backoff = Backoff::new(RECONNECT_BACKOFF_INITIAL, RECONNECT_BACKOFF_MAX)
+            .with_jitter(BACKOFF_JITTER);
+        let mut reconnect_at: Option<Instant> = None;
+        let mut reconnect_queries =
+            PerMinuteLimiter::new(self.config().ipc.max_reconnect_queries_per_minute);
+        let mut stats_answers = PerMinuteLimiter::new(self.config().stats.max_requests_per_minute);
+
+        // Job counters for the CloudWatch metrics component, held back while the session is down
+        let telemetry_enabled = self.config().telemetry.enabled;
+        let mut telemetry_tick = tokio::time::interval(Duration::from_secs(
+            self.config().telemetry.interval_secs.max(1),
+        ));
+        telemetry_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick fires at once; the first flush is one interval in
+        telemetry_tick.reset();
+        let mut telemetry = TelemetryEmitter::new();
 
-        // Process jobs and reconnection signals as they arrive
         loop {
+            if current.is_none() {
+                let next = self.queue.lock().unwrap().pop();
+                if let Some(job) = next {
+                    self.persist_state();
+                    current = Some(Box::pin(self.handle_job(job)));
+                }
+            }
+
             tokio::select! {
-                Some(job_or_error) = job_stream.recv() => {
+                result = async { current.as_mut().unwrap().await }, if current.is_some() => {
+                    current = None;
+                    let (thing_name, result) = result;
+                    if let Err(e) = result {
+                        metrics::global().record_error(e.kind());
+                        tracing::error!(error_kind = e.kind().label(), error = %e, "Failed to handle job");
+                    }
+                    if let Err(e) = self.ipc_client.request_pending_jobs(&thing_name).await {
+                        tracing::warn!(error = %e, "Failed to refresh pending job queue depth");
+                    }
+                }
+                Some(job_or_error) = streams.jobs.recv() => {
                     match job_or_error {
                         JobOrError::Valid(job) => {
-                            if let Err(e) = self.handle_job(job).await {
-                                tracing::error!(error = %e, "Failed to handle job");
+                            if let Some(backoff) = next_job_backoff.get_mut(&job.thing_name) {
+                                backoff.reset();
                             }
+                            self.enqueue_job(job, current.is_some()).await;
                         }
-                        JobOrError::ParseError { job_id, error } => {
-                            if self.mark_job_processed(&job_id) {
-                                if let Err(e) = self.handle_parse_error(&job_id, &error).await {
-                                    tracing::error!(error = %e, "Failed to handle parse error");
+                        JobOrError::ParseError { thing_name, job_id, error, path } => {
+                            if self.mark_job_processed(job_key(&thing_name, &job_id)) {
+                                self.persist_state();
+                                if let Err(e) = self.handle_parse_error(&thing_name, &job_id, &error, path).await {
+                                    tracing::error!(error_kind = e.kind().label(), error = %e, "Failed to handle parse error");
                                 }
                             } else {
                                 tracing::debug!(job_id = %job_id, "Parse error already processed, skipping duplicate");
@@ -85,10 +548,115 @@ impl JobHandler {
                         }
                     }
                 }
-                Some(()) = reconnect_stream.recv() => {
+                Some(()) = streams.reconnects.recv() => {
+                    if !reconnect_queries.allow() {
+                        tracing::warn!(
+                            max_per_minute = self.config().ipc.max_reconnect_queries_per_minute,
+                            "Too many reconnection events, not querying jobs for this one"
+                        );
+                        continue;
+                    }
                     tracing::info!("Handling reconnection event - querying pending jobs");
-                    if let Err(e) = self.ipc_client.request_next_job().await {
-                        tracing::error!(error = %e, "Failed to query jobs after reconnection");
+                    for thing_name in self.ipc_client.thing_names() {
+                        if let Err(e) = self.ipc_client.request_next_job(thing_name).await {
+                            tracing::error!(thing_name = %thing_name, error_kind = e.kind().label(), error = %e, "Failed to query jobs after reconnection");
+                        }
+                        if let Err(e) = self.ipc_client.request_pending_jobs(thing_name).await {
+                            tracing::warn!(thing_name = %thing_name, error = %e, "Failed to refresh pending job queue depth");
+                        }
+                    }
+                }
+                Some((thing_name, pending)) = streams.pending.recv() => {
+                    self.update_queue_depth(&thing_name, pending);
+                }
+                Some((thing_name, rejection)) = streams.next_rejected.recv() => {
+                    if rejection.is_throttled() {
+                        let delay = next_job_backoff
+                            .entry(thing_name.clone())
+                            .or_insert_with(|| {
+                                Backoff::new(NEXT_JOB_BACKOFF_INITIAL, NEXT_JOB_BACKOFF_MAX)
+                                    .with_jitter(BACKOFF_JITTER)
+                            })
+                            .next_delay();
+                        tracing::warn!(
+                            thing_name = %thing_name,
+                            code = %rejection.code,
+                            retry_in_ms = delay.as_millis() as u64,
+                            "Next job request throttled, backing off"
+                        );
+                        next_job_retry.insert(thing_name, Instant::now() + delay);
+                    } else {
+                        tracing::error!(
+                            thing_name = %thing_name,
+                            error_kind = ErrorKind::Ipc.label(),
+                            code = %rejection.code,
+                            message = ?rejection.message,
+                            "Next job request rejected, waiting for the next notification or reconnect"
+                        );
+                    }
+                }
+                _ = sleep_until(earliest_retry(&next_job_retry).unwrap_or_else(Instant::now)), if !next_job_retry.is_empty() => {
+                    let now = Instant::now();
+                    let due: Vec<String> = next_job_retry
+                        .iter()
+                        .filter(|(_, at)| **at <= now)
+                        .map(|(thing_name, _)| thing_name.clone())
+                        .collect();
+                    for thing_name in due {
+                        next_job_retry.remove(&thing_name);
+                        tracing::info!(thing_name = %thing_name, "Retrying throttled next job request");
+                        metrics::global().record_ipc_retry(IpcRetry::NextJob);
+                        if let Err(e) = self.ipc_client.request_next_job(&thing_name).await {
+                            tracing::error!(thing_name = %thing_name, error_kind = e.kind().label(), error = %e, "Failed to retry next job request");
+                        }
+                    }
+                }
+                // Held back while a job runs; the receiver keeps only the latest
+                Some(config) = next_config(&mut config_updates), if current.is_none() => {
+                    self.apply_config(config).await;
+                }
+                _ = probe_tick.tick(), if probing && reconnect_at.is_none() => {
+                    if liveness.record(self.ipc_client.liveness_probe_answered()) {
+                        reconnect_at = Some(Instant::now());
+                    } else {
+                        self.probe_liveness().await;
+                    }
+                }
+                _ = telemetry_tick.tick(), if telemetry_enabled && reconnect_at.is_none() => {
+                    self.publish_telemetry(&mut telemetry).await;
+                }
+                // Published alongside the running job, which never waits for them
+                Some(event) = next_queued(&mut event_queue) => {
+                    self.publish_event(&event);
+                }
+                // Answered while a job runs, from state the job does not hold
+                Some(request_id) = next_queued(&mut stats_requests) => {
+                    if !stats_answers.allow() {
+                        tracing::warn!(
+                            request_id = %request_id,
+                            max_per_minute = self.config().stats.max_requests_per_minute,
+                            "Too many stats requests, not answering this one"
+                        );
+                        continue;
+                    }
+                    self.answer_stats_request(&request_id);
+                }
+                _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)), if reconnect_at.is_some() => {
+                    match self.reconnect_session().await {
+                        Ok(()) => {
+                            reconnect_at = None;
+                            reconnect_backoff.reset();
+                            liveness.reconnected();
+                        }
+                        Err(e) => {
+                            let delay = reconnect_backoff.next_delay();
+                            tracing::warn!(
+                                error = %e,
+                                retry_in_ms = delay.as_millis() as u64,
+                                "Failed to reconnect IPC session, backing off"
+                            );
+                            reconnect_at = Some(Instant::now() + delay);
+                        }
                     }
                 }
                 else => {
@@ -97,89 +665,1753 @@ impl JobHandler {
                 }
             }
         }
+    }
+
+    /// Send a liveness probe over local pub/sub and a heartbeat to IoT Core.
+    /// A failed heartbeat says nothing about the IPC session, so it is only logged.
+    async fn probe_liveness(&self) {
+        if let Err(e) = self.ipc_client.send_liveness_probe() {
+            tracing::debug!(error = %e, "Failed to send liveness probe");
+        }
+        if let Err(e) = self.send_heartbeat().await {
+            tracing::debug!(error = %e, "Failed to send heartbeat");
+        }
+    }
+
+    async fn send_heartbeat(&self) -> Result<()> {
+        let config = self.config();
+        let heartbeat = Heartbeat {
+            config_hash: &config.fingerprint(),
+            config_profile: config.profile.as_deref(),
+            config_reloads: self.config_reloads.load(Ordering::SeqCst),
+            device: &self.device_info,
+            queued_in_aws: self.queued_in_aws(),
+            queued_on_device: self.queue.lock().unwrap().len(),
+        };
+        self.ipc_client.send_heartbeat(&heartbeat).await
+    }
+
+    /// Publish each telemetry metric's change since it was last published.
+    /// Stops at the first failed publish; what was not sent goes out with the
+    /// next flush.
+    async fn publish_telemetry(&self, emitter: &mut TelemetryEmitter) {
+        let config = self.config();
+        let current = metrics::global().snapshot();
+        let timestamp = chrono::Utc::now().timestamp();
+        for metric in TelemetryMetric::ALL {
+            let Some(value) = emitter.value(metric, &current) else {
+                continue;
+            };
+            let request = metric_request(
+                &config.telemetry.namespace,
+                self.ipc_client.thing_name(),
+                metric,
+                value,
+                timestamp,
+            );
+            if let Err(e) = self
+                .ipc_client
+                .publish_json(&config.telemetry.topic, &request)
+                .await
+            {
+                tracing::debug!(error = %e, "Failed to publish telemetry, will retry with the next flush");
+                return;
+            }
+            emitter.sent(metric, &current);
+        }
+    }
+
+    /// Publish one lifecycle event on local pub/sub; a failure is only logged
+    fn publish_event(&self, event: &LifecycleEvent) {
+        let config = self.config();
+        if let Err(e) = self
+            .ipc_client
+            .publish_local_json(&config.events.topic, &event.payload)
+        {
+            tracing::debug!(event = event.kind.name(), error = %e, "Failed to publish lifecycle event");
+        }
+    }
+
+    /// Publish the stats snapshot for `request_id`, built from state held in
+    /// memory; the publish runs on its own and a failure is only logged
+    fn answer_stats_request(&self, request_id: &str) {
+        let config = self.config();
+        let recent_jobs = if config.execution.history_file.is_some() {
+            let recent = self.recent_jobs.lock().unwrap();
+            let skip = recent.len().saturating_sub(config.stats.recent_jobs);
+            recent.iter().skip(skip).cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let stats = HandlerStats {
+            thing_name: self.ipc_client.thing_name().to_string(),
+            config_hash: config.fingerprint(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            system_uptime_secs: system_uptime_secs(),
+            running_job: self.running_job.lock().unwrap().clone(),
+            queued_on_device: self
+                .queue
+                .lock()
+                .unwrap()
+                .jobs()
+                .map(|job| job.job_id.clone())
+                .collect(),
+            queued_in_aws: self.queued_in_aws(),
+            last_jobs_api_contact: self.ipc_client.last_jobs_api_contact(),
+            foreign_updates: self.ipc_client.foreign_updates_observed(),
+            recent_jobs,
+        };
+        let response = stats::response(request_id, &stats, metrics::global().to_json());
+        self.ipc_client.spawn_stats_response(request_id, &response);
+    }
+
+    /// Replace a dead IPC session and ask for any jobs missed while deaf
+    async fn reconnect_session(&self) -> Result<()> {
+        self.ipc_client.reconnect().await?;
+
+        for thing_name in self.ipc_client.thing_names() {
+            if let Err(e) = self.ipc_client.request_next_job(thing_name).await {
+                tracing::warn!(thing_name = %thing_name, error = %e, "Failed to query jobs after reconnect");
+            }
+            if let Err(e) = self.ipc_client.request_pending_jobs(thing_name).await {
+                tracing::warn!(thing_name = %thing_name, error = %e, "Failed to refresh pending job queue depth");
+            }
+        }
 
+        self.probe_liveness().await;
         Ok(())
     }
 
-    async fn handle_parse_error(&self, job_id: &str, error: &str) -> Result<()> {
-        tracing::error!(job_id = %job_id, error = %error, "Marking malformed job as FAILED");
+    async fn handle_parse_error(
+        &self,
+        thing_name: &str,
+        job_id: &str,
+        error: &str,
+        path: Option<String>,
+    ) -> Result<()> {
+        tracing::error!(
+            thing_name = %thing_name,
+            job_id = %job_id,
+            error_kind = ErrorKind::InvalidDocument.label(),
+            error = %error,
+            "Marking malformed job as FAILED"
+        );
 
-        let status = JobStatus::failed(
+        let mut status = JobStatus::failed(
             format!("Job document parsing failed: {}", error),
             None,
             None,
-        );
+        )
+        .with_detail("thing_name", thing_name.to_string());
+        if let Some(path) = path {
+            status = status.with_detail("path", path);
+        }
 
-        self.ipc_client.update_job_status(job_id, status).await?;
+        self.ipc_client
+            .update_job_status(thing_name, job_id, status)
+            .await?;
 
         // Request next job
-        self.ipc_client.request_next_job().await?;
+        self.ipc_client.request_next_job(thing_name).await?;
 
         Ok(())
     }
 
-    async fn handle_job(&self, job: Job) -> Result<()> {
-        // Check if we've already processed this job
-        if !self.mark_job_processed(&job.job_id) {
-            tracing::debug!(job_id = %job.job_id, "Job already processed, skipping duplicate");
+    /// Run one job, returning the thing it belonged to along with the outcome
+    async fn handle_job(&self, job: Job) -> (String, Result<()>) {
+        // A fresh ID per attempt tells apart runs of a re-delivered job ID
+        let execution_id = Uuid::new_v4().to_string();
+        // Everything logged while handling the job is tagged with its IDs
+        // (and captured to the per-job log file when enabled), so events
+        // inside need not repeat them
+        let span = tracing::info_span!(
+            "job",
+            thing_name = %job.thing_name,
+            job_id = %job.job_id,
+            execution_id = %execution_id
+        );
+        let thing_name = job.thing_name.clone();
+        let job_id = job.job_id.clone();
+        *self.running_job.lock().unwrap() = Some((thing_name.clone(), job_id.clone()));
+        self.terminal_reported.store(false, Ordering::SeqCst);
+
+        // A panic fails this job rather than unwinding out of `run`; the job
+        // was marked processed when it was queued, so it is not run into the
+        // same panic again
+        let mut run = std::pin::pin!(self.process_job(job, &execution_id).instrument(span));
+        let outcome = std::future::poll_fn(|cx| {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| run.as_mut().poll(cx))) {
+                Ok(Poll::Ready(result)) => Poll::Ready(Ok(result)),
+                Ok(Poll::Pending) => Poll::Pending,
+                Err(panic) => Poll::Ready(Err(panic)),
+            }
+        })
+        .await;
+        *self.running_job.lock().unwrap() = None;
+        let result = match outcome {
+            Ok(result) => result,
+            Err(panic) => {
+                self.fail_panicked_job(&thing_name, &job_id, &execution_id, panic.as_ref())
+                    .await
+            }
+        };
+
+        let config = self.config();
+        if let Some(dir) = &config.logging.job_log_dir {
+            if let Err(e) = cleanup_job_logs(dir, config.logging.max_job_logs) {
+                tracing::warn!(error = %e, "Failed to clean up old job logs");
+            }
+        }
+
+        (thing_name, result)
+    }
+
+    /// Report a job whose handling panicked as FAILED with `reason_code: INTERNAL_PANIC`,
+    /// unless its terminal status already went out
+    async fn fail_panicked_job(
+        &self,
+        thing_name: &str,
+        job_id: &str,
+        execution_id: &str,
+        panic: &(dyn Any + Send),
+    ) -> Result<()> {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        if self.terminal_reported.load(Ordering::SeqCst) {
+            tracing::error!(
+                thing_name = %thing_name,
+                job_id = %job_id,
+                execution_id = %execution_id,
+                reason_code = INTERNAL_PANIC,
+                panic = %message,
+                "Job handling panicked after its final status was reported"
+            );
+            self.ipc_client.request_next_job(thing_name).await?;
             return Ok(());
         }
+        tracing::error!(
+            thing_name = %thing_name,
+            job_id = %job_id,
+            execution_id = %execution_id,
+            reason_code = INTERNAL_PANIC,
+            panic = %message,
+            "Job handling panicked, failing the job"
+        );
+        metrics::global().record_job(JobOutcome::Failed, None);
+
+        let status = JobStatus::failed(
+            format!("Internal error while handling the job: {}", message),
+            None,
+            None,
+        )
+        .with_detail("reason_code", INTERNAL_PANIC.to_string())
+        .with_detail("execution_id", execution_id.to_string())
+        .with_detail("thing_name", thing_name.to_string());
+        self.ipc_client
+            .update_job_status(thing_name, job_id, status)
+            .await?;
+        self.ipc_client.request_next_job(thing_name).await?;
+        Ok(())
+    }
+
+    async fn report_terminal_status(&self, job: &Job, status: JobStatus) -> Result<()> {
+        self.ipc_client
+            .update_job_status(&job.thing_name, &job.job_id, status)
+            .await?;
+        self.terminal_reported.store(true, Ordering::SeqCst);
+        Ok(())
+    }
 
-        tracing::info!(job_id = %job.job_id, "Received job");
+    async fn process_job(&self, mut job: Job, execution_id: &str) -> Result<()> {
+        // Duplicates were already filtered when the job was queued
+        tracing::info!("Received job");
+        let started_at = chrono::Utc::now().timestamp_millis();
+        let event_job = EventJob {
+            thing_name: job.thing_name.clone(),
+            job_id: job.job_id.clone(),
+            execution_id: execution_id.to_string(),
+        };
 
         // Validate job document
-        if let Err(e) = validate_job_document(&job.document) {
-            tracing::error!(job_id = %job.job_id, error = %e, "Invalid job document");
-            let status = JobStatus::failed(e.to_string(), None, None);
-            self.ipc_client
-                .update_job_status(&job.job_id, status)
-                .await?;
-            self.ipc_client.request_next_job().await?;
+        let config = self.config();
+        let validation = check_unknown_fields(&job.unknown_fields, &config.document_policy)
+            .and_then(|()| {
+                validate_job_document(&mut job.document, &config.security, &config.document_policy)
+            })
+            .and_then(|()| self.executor.check_timeout_headroom(&job.document));
+        if let Err(e) = validation {
+            metrics::global().record_error(e.kind());
+            tracing::error!(
+                reason_code = JobOutcome::Rejected.label(),
+                error_kind = e.kind().label(),
+                error = %e,
+                "Invalid job document"
+            );
+            if let DeviceOpsError::SecurityError(reason) = &e {
+                self.events.emit(
+                    &config.events,
+                    LifecycleEvent::security_denied(&event_job, None, reason),
+                );
+            }
+            self.events.emit(
+                &config.events,
+                LifecycleEvent::job_completed(&event_job, JobOutcome::Rejected, None, started_at),
+            );
+            let mut status = JobStatus::failed(e.to_string(), None, None)
+                .with_detail("execution_id", execution_id.to_string())
+                .with_detail("thing_name", job.thing_name.clone());
+            if let DeviceOpsError::InvalidDocumentField { path, .. } = &e {
+                status = status.with_detail("path", path.clone());
+            }
+            metrics::global().record_job(JobOutcome::Rejected, None);
+            let record = HistoryRecord {
+                error: Some(e.to_string()),
+                ..self.history_record(&job, execution_id, started_at, &status)
+            };
+            self.record_last_run(&record, JobOutcome::Rejected, None);
+            self.record_history(record);
+            self.report_terminal_status(&job, status).await?;
+            self.run_validation_failure_hook(&job, execution_id, &e.to_string())
+                .await;
+            self.ipc_client.request_next_job(&job.thing_name).await?;
             return Ok(());
         }
 
         // Execute all steps in the job document
-        // AWS rejects IN_PROGRESS with empty statusDetails, so we skip it
-        let result = self.executor.execute(&job.document).await;
+        // AWS rejects IN_PROGRESS with empty statusDetails, so there is no
+        // initial one; heartbeats (if enabled) carry the running time
+        self.events.emit(
+            &config.events,
+            LifecycleEvent::job_started(&event_job, job.document.steps.len()),
+        );
+        let events = &self.events;
+        let on_step = |step: StepReport<'_>| {
+            events.emit(
+                &config.events,
+                LifecycleEvent::step_completed(&event_job, &step),
+            );
+            if let Some(DeviceOpsError::SecurityError(reason)) = step.error {
+                events.emit(
+                    &config.events,
+                    LifecycleEvent::security_denied(&event_job, Some(step.name), reason),
+                );
+            }
+        };
+        let run_started = Instant::now();
+        let (result, attempts) = self
+            .execute_with_transient_retries(&job, execution_id, &on_step)
+            .await;
+        let outcome = match &result {
+            Ok(execution_result) if execution_result.overall_success => JobOutcome::Succeeded,
+            Ok(execution_result) if execution_result.timed_out() => JobOutcome::TimedOut,
+            _ => JobOutcome::Failed,
+        };
+        metrics::global().record_job(outcome, Some(run_started.elapsed()));
 
-        // Determine whether to include stdout based on job document
-        let include_stdout = job.document.include_std_out.unwrap_or(false);
+        // Determine statusDetails formatting from the job document and config
+        let options = StatusDetailsOptions {
+            include_stdout: self
+                .config()
+                .execution
+                .include_stdout_policy
+                .include_stdout(job.document.include_std_out),
+            numbers_as_strings: job
+                .document
+                .status_details_numbers_as_strings
+                .unwrap_or(self.config().execution.status_details_numbers_as_strings),
+            include_timestamps: self.config().execution.verbose_status_details,
+            human_summary_job_id: self
+                .config()
+                .execution
+                .human_summary
+                .then(|| job.job_id.clone()),
+            uptime_secs: if job.document.include_uptime.unwrap_or(false) {
+                system_uptime_secs()
+            } else {
+                None
+            },
+            format: job.document.status_details_format.unwrap_or_default(),
+            device: Some(self.device_info.compact()),
+            // Which user a step ran as matters for jobs that change users or
+            // run under the allowlists
+            include_ran_as: self.config().security.enabled
+                || self.config().execution.default_run_as_user.is_some()
+                || job.document.sets_run_as_user(),
+            // Set once the slow steps are known
+            reserved_keys: 0,
+        };
+
+        // Full result for the document's resultTopic, if any
+        let result_json = match &result {
+            Ok(execution_result) => serde_json::to_value(execution_result).ok(),
+            Err(_) => None,
+        };
+
+        if let Ok(execution_result) = &result {
+            let metrics = JobMetrics::from_result(execution_result);
+            tracing::info!(
+                job_id = %job.job_id,
+                execution_id = %execution_id,
+                steps_executed = metrics.steps_executed,
+                execution_time_ms = metrics.execution_time_ms,
+                total_stdout_bytes = metrics.total_stdout_bytes,
+                total_stderr_bytes = metrics.total_stderr_bytes,
+                output_truncated = metrics.output_truncated,
+                "Job metrics"
+            );
+        }
+
+        let (steps, error) = match &result {
+            Ok(execution_result) => (
+                step_records(execution_result),
+                execution_result.failure_reason.clone(),
+            ),
+            Err(e) => (Vec::new(), Some(e.to_string())),
+        };
+        let slow_steps = self.slow_steps(&job, &steps).await;
+        // Room for the details added to the status below that must not be
+        // crowded out: `execution_id`, `thing_name`, `job_attempts` and
+        // `slow_steps`. `queued_behind` and `config_hash` take what is left
+        let retries = self.config().execution.transient_error_retries > 0;
+        let options = StatusDetailsOptions {
+            reserved_keys: 2 + usize::from(retries) + usize::from(!slow_steps.is_empty()),
+            ..options
+        };
+        let failed_step = result
+            .as_ref()
+            .ok()
+            .and_then(|execution_result| execution_result.failed_step.clone());
+        self.events.emit(
+            &config.events,
+            LifecycleEvent::job_completed(&event_job, outcome, failed_step.as_deref(), started_at),
+        );
 
         // Update final status using new JobExecutionResult
         let status = match result {
             Ok(execution_result) => {
                 if execution_result.overall_success {
                     tracing::info!(
-                        job_id = %job.job_id,
                         steps_executed = execution_result.outputs.len(),
                         "Job succeeded"
                     );
-                    JobStatus::from_success(&execution_result, include_stdout)
+                    JobStatus::from_success(&execution_result, &options)
                 } else {
                     tracing::error!(
-                        job_id = %job.job_id,
+                        reason_code = outcome.label(),
                         failed_step = ?execution_result.failed_step,
                         "Job failed"
                     );
-                    JobStatus::from_failure(&execution_result, include_stdout)
+                    JobStatus::from_failure(&execution_result, &options)
                 }
             }
             Err(e) => {
-                tracing::error!(job_id = %job.job_id, error = %e, "Job execution error");
+                metrics::global().record_error(e.kind());
+                tracing::error!(
+                    reason_code = outcome.label(),
+                    error_kind = e.kind().label(),
+                    error = %e,
+                    "Job execution error"
+                );
                 JobStatus::failed(e.to_string(), None, None)
+                    .with_detail("reason_code", e.reason_code().to_string())
             }
         };
 
-        self.ipc_client
-            .update_job_status(&job.job_id, status)
-            .await?;
+        let mut status = status
+            .with_detail("execution_id", execution_id.to_string())
+            .with_detail("thing_name", job.thing_name.clone());
+        if retries {
+            status = status.with_detail("job_attempts", attempts.to_string());
+        }
+        if !slow_steps.is_empty() {
+            status = status.with_detail("slow_steps", slow_steps::summary(&slow_steps));
+        }
+        let status = status
+            .with_detail(
+                "queued_behind",
+                self.queued_behind(&job.thing_name, &job.job_id).to_string(),
+            )
+            .with_detail("config_hash", self.config().fingerprint());
+        let status_json = status.to_json();
+        let record = HistoryRecord {
+            steps,
+            error,
+            ..self.history_record(&job, execution_id, started_at, &status)
+        };
+        self.record_last_run(&record, outcome, failed_step);
+        self.record_history(record);
+
+        self.report_terminal_status(&job, status).await?;
+
+        let result_topic = job
+            .document
+            .result_topic
+            .clone()
+            .or_else(|| results_topic(&self.config().ipc.topics, &job.thing_name, &job.job_id));
+        if let Some(topic) = &result_topic {
+            let payload = serde_json::json!({
+                "jobId": job.job_id,
+                "executionId": execution_id,
+                "thingName": job.thing_name,
+                "status": status_json["status"],
+                "statusDetails": status_json["statusDetails"],
+                "result": result_json,
+            });
+            // The standard status update already went out; a failed extra publish is only logged
+            if let Err(e) = self.ipc_client.publish_json(topic, &payload).await {
+                tracing::warn!(topic = %topic, error = %e, "Failed to publish job result");
+            }
+        }
+
+        // Let the device's own workload catch up before the next job
+        if let Some(delay) = self.delay_after(&job) {
+            tracing::info!(
+                delay_secs = delay.as_secs(),
+                "Cooling down before the next job"
+            );
+            tokio::time::sleep(delay).await;
+        }
 
         // Request next job
-        self.ipc_client.request_next_job().await?;
+        self.ipc_client.request_next_job(&job.thing_name).await?;
 
         Ok(())
     }
+
+    /// Run the job, and run it again up to `execution.transient_error_retries`
+    /// times, with backoff, while it fails only because a step could not run
+    /// for a transient reason. Returns the last result and the number of runs.
+    /// The runs happen here rather than through the queue, so dedup does not
+    /// see them.
+    async fn execute_with_transient_retries(
+        &self,
+        job: &Job,
+        execution_id: &str,
+        on_step: &(dyn Fn(StepReport<'_>) + Send + Sync),
+    ) -> (Result<JobExecutionResult>, u32) {
+        let execution = self.config().execution.clone();
+        let mut backoff = Backoff::new(
+            Duration::from_millis(execution.transient_error_backoff_ms),
+            TRANSIENT_RETRY_BACKOFF_MAX,
+        )
+        .with_jitter(BACKOFF_JITTER);
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .execute_with_heartbeat(job, execution_id, on_step)
+                .await;
+            let reason = match &result {
+                Ok(result) if !result.overall_success && result.retryable => {
+                    result.failure_reason.clone()
+                }
+                Err(e) if e.is_retryable() => Some(e.to_string()),
+                _ => None,
+            };
+            let Some(reason) = reason else {
+                return (result, attempt);
+            };
+            if attempt > execution.transient_error_retries {
+                return (result, attempt);
+            }
+
+            let delay = backoff.next_delay();
+            tracing::warn!(
+                attempt = attempt,
+                retries = execution.transient_error_retries,
+                delay_ms = delay.as_millis() as u64,
+                reason = %reason,
+                "Job failed for a transient reason, running it again"
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Run the job, sending an IN_PROGRESS update every `progress_heartbeat_secs`
+    /// and, with `execution.live_output`, publishing its output lines
+    async fn execute_with_heartbeat(
+        &self,
+        job: &Job,
+        execution_id: &str,
+        on_step: &(dyn Fn(StepReport<'_>) + Send + Sync),
+    ) -> Result<JobExecutionResult> {
+        let config = self.config();
+        let (live, mut live_lines) = if config.execution.live_output {
+            let (live, lines) = LiveOutput::channel(config.execution.live_output_lines_per_sec);
+            (Some(live), Some(lines))
+        } else {
+            (None, None)
+        };
+        let execution = self
+            .executor
+            .execute_streaming(&job.document, on_step, live);
+        let period = Duration::from_secs(config.execution.progress_heartbeat_secs);
+        if period.is_zero() && live_lines.is_none() {
+            return execution.await;
+        }
+
+        tokio::pin!(execution);
+        let started = Instant::now();
+        // Never ticks without progress heartbeats
+        let tick = period.max(Duration::from_secs(1));
+        let mut heartbeat = tokio::time::interval_at(started + tick, tick);
+        loop {
+            tokio::select! {
+                result = &mut execution => {
+                    // Lines read before the job ended go out before its status
+                    if let Some(lines) = live_lines.as_mut() {
+                        while let Ok(line) = lines.try_recv() {
+                            self.publish_live_line(job, execution_id, &line);
+                        }
+                    }
+                    return result;
+                }
+                Some(line) = next_queued(&mut live_lines) => {
+                    self.publish_live_line(job, execution_id, &line);
+                }
+                _ = heartbeat.tick(), if !period.is_zero() => {
+                    let status = JobStatus::running(started.elapsed())
+                        .with_detail("execution_id", execution_id.to_string())
+                        .with_detail("thing_name", job.thing_name.clone());
+                    if let Err(e) = self
+                        .ipc_client
+                        .update_job_status(&job.thing_name, &job.job_id, status)
+                        .await
+                    {
+                        tracing::warn!(error = %e, "Failed to send progress heartbeat");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publish one output line of a running job on its live output topic;
+    /// a failed publish is only logged
+    fn publish_live_line(&self, job: &Job, execution_id: &str, line: &LiveLine) {
+        let payload = serde_json::json!({
+            "jobId": job.job_id,
+            "executionId": execution_id,
+            "thingName": job.thing_name,
+            "stepName": line.step_name,
+            "stream": line.stream,
+            "lineNumber": line.line_number,
+            "line": line.line,
+            "timestamp": chrono::Utc::now().timestamp_millis(),
+        });
+        let topic = live_output_topic(&job.job_id);
+        if let Err(e) = self.ipc_client.publish_local_json(&topic, &payload) {
+            tracing::debug!(topic = %topic, error = %e, "Failed to publish output line");
+        }
+    }
+
+    /// The steps of this run past a slow-step limit, each logged and counted.
+    /// Runs before the run itself is added to the history.
+    async fn slow_steps(&self, job: &Job, steps: &[StepRecord]) -> Vec<SlowStep> {
+        let config = self.config();
+        let rules = SlowStepRules {
+            warn_secs: config.execution.slow_step_warn_secs,
+            factor: config.execution.slow_step_factor,
+            min_runs: config.execution.slow_step_min_runs,
+        };
+        if !rules.enabled() || steps.is_empty() {
+            return Vec::new();
+        }
+        let history = match config.execution.history_file.clone() {
+            Some(path) if rules.relative() => {
+                let tail = tokio::task::spawn_blocking(move || {
+                    history::read_tail(&path, slow_steps::HISTORY_WINDOW)
+                });
+                match tail.await {
+                    Ok(Ok(records)) => records,
+                    Ok(Err(e)) => {
+                        tracing::warn!(error = %e, "Cannot read job history for slow-step checks");
+                        Vec::new()
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Cannot read job history for slow-step checks");
+                        Vec::new()
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
+
+        let slow = slow_steps::find(steps, &document_hash(&job.document), &history, &rules);
+        for step in &slow {
+            tracing::warn!(
+                step_name = %step.name,
+                duration_ms = step.duration_ms,
+                limit = step.limit.kind().label(),
+                "Slow step: {}",
+                step.describe()
+            );
+            metrics::global().record_slow_step(step.limit.kind());
+        }
+        slow
+    }
+
+    /// The history record of a finished job, without its steps
+    fn history_record(
+        &self,
+        job: &Job,
+        execution_id: &str,
+        started_at: i64,
+        status: &JobStatus,
+    ) -> HistoryRecord {
+        HistoryRecord {
+            job_id: job.job_id.clone(),
+            thing_name: job.thing_name.clone(),
+            execution_id: execution_id.to_string(),
+            document_hash: document_hash(&job.document),
+            status: status.to_json()["status"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            started_at,
+            finished_at: chrono::Utc::now().timestamp_millis(),
+            steps: Vec::new(),
+            error: None,
+            device: Some(self.device_info.compact()),
+        }
+    }
+
+    /// Append to the execution history when `execution.history_file` is set;
+    /// a failed write is only logged
+    fn record_history(&self, record: HistoryRecord) {
+        let Some(path) = self.config().execution.history_file.clone() else {
+            return;
+        };
+        if let Err(e) = history::append(&path, &record) {
+            tracing::warn!(error = %e, "Failed to record job history");
+        }
+        let mut recent = self.recent_jobs.lock().unwrap();
+        if recent.len() == MAX_STATS_RECENT_JOBS {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// Write the last-run status files when `execution.write_last_run` is
+    /// on; a failed write is only logged
+    fn record_last_run(
+        &self,
+        record: &HistoryRecord,
+        outcome: JobOutcome,
+        failed_step: Option<String>,
+    ) {
+        let config = self.config();
+        let (Some(last_run), Some(last_success)) = (
+            &config.execution.last_run_file,
+            &config.execution.last_success_file,
+        ) else {
+            return;
+        };
+        let last = LastRun {
+            job_id: record.job_id.clone(),
+            thing_name: record.thing_name.clone(),
+            execution_id: record.execution_id.clone(),
+            status: record.status.clone(),
+            failed_step,
+            reason_code: outcome.label().to_string(),
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+            component_version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        if let Err(e) = last_run::write(last_run, last_success, &last) {
+            tracing::warn!(error = %e, "Failed to write last-run status");
+        }
+    }
+
+    /// Run `execution.on_validation_failure_command`, if set, for a rejected
+    /// document. The job, thing and execution IDs and the reason are passed in
+    /// `DEVICE_OPS_*` variables; the output is ignored and a failure only logged.
+    async fn run_validation_failure_hook(&self, job: &Job, execution_id: &str, reason: &str) {
+        let config = self.config();
+        let Some(program) = config.execution.on_validation_failure_command.clone() else {
+            return;
+        };
+        let command = Command {
+            script_path: program.clone(),
+            stop_timeout: Duration::from_secs(5),
+            escalate_to_kill: true,
+            env: vec![
+                ("DEVICE_OPS_JOB_ID".to_string(), job.job_id.clone()),
+                ("DEVICE_OPS_THING_NAME".to_string(), job.thing_name.clone()),
+                (
+                    "DEVICE_OPS_EXECUTION_ID".to_string(),
+                    execution_id.to_string(),
+                ),
+                (
+                    "DEVICE_OPS_VALIDATION_ERROR".to_string(),
+                    reason.to_string(),
+                ),
+            ],
+            sanitize_path: config.execution.sanitize_path.clone(),
+            spawn_method: config.execution.spawn_method,
+            ..Default::default()
+        };
+        // Dropping the run on timeout stops the command
+        match tokio::time::timeout(VALIDATION_HOOK_TIMEOUT, self.hook_runner.run(&command)).await {
+            Ok(Ok(output)) if output.exit_code == 0 => {}
+            Ok(Ok(output)) => tracing::warn!(
+                command = %program,
+                exit_code = output.exit_code,
+                "Validation failure hook failed"
+            ),
+            Ok(Err(e)) => {
+                tracing::warn!(command = %program, error = %e, "Validation failure hook failed")
+            }
+            Err(_) => tracing::warn!(
+                command = %program,
+                timeout_secs = VALIDATION_HOOK_TIMEOUT.as_secs(),
+                "Validation failure hook timed out"
+            ),
+        }
+    }
+
+    /// Cool-down to wait after a finished job, unless its document opts out
+    fn delay_after(&self, job: &Job) -> Option<Duration> {
+        let delay = Duration::from_secs(self.config().execution.inter_job_delay_secs);
+        if delay.is_zero() || job.document.no_delay_after.unwrap_or(false) {
+            return None;
+        }
+        Some(delay)
+    }
+}
+
+/// Wait for the next item on an optional queue; `None` without a queue or
+/// once its senders are gone
+async fn next_queued<T>(queue: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    queue.as_mut()?.recv().await
+}
+
+/// Wait for the next config update; `None` once there are no more
+async fn next_config(updates: &mut Option<watch::Receiver<Config>>) -> Option<Config> {
+    let receiver = updates.as_mut()?;
+    if receiver.changed().await.is_err() {
+        *updates = None;
+        return None;
+    }
+    let config = receiver.borrow_and_update().clone();
+    Some(config)
 }
 
-// Note: Tests removed as they require a real Greengrass environment
-// Integration tests should be run on actual devices with Greengrass installed
+/// The soonest pending retry of a throttled `$next/get` request
+fn earliest_retry(retries: &HashMap<String, Instant>) -> Option<Instant> {
+    retries.values().min().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IpcConfig;
+    use crate::ipc::fake::FakeSdk;
+    use crate::metrics::SecurityRule;
+    use crate::models::{ExecutionOutput, JobDocument, JobExecutionSummary, MAX_STATUS_DETAILS};
+
+    const UPDATE_TOPIC: &str = "$aws/things/test-thing/jobs/job-2/update";
+
+    async fn handler_with(config: Config) -> (JobHandler, FakeSdk) {
+        let fake = FakeSdk::new();
+        let config = Config {
+            ipc: IpcConfig {
+                thing_name: Some("test-thing".to_string()),
+                min_status_update_interval_ms: 0,
+                ..Default::default()
+            },
+            ..config
+        };
+        let client = IpcClient::with_transport(Box::new(fake.clone()), &config.ipc)
+            .await
+            .unwrap();
+        (JobHandler::new(client, config), fake)
+    }
+
+    fn job(job_id: &str, queued_at: i64) -> Job {
+        Job {
+            thing_name: "test-thing".to_string(),
+            job_id: job_id.to_string(),
+            queued_at: Some(queued_at),
+            document: JobDocument::default(),
+            unknown_fields: Vec::new(),
+        }
+    }
+
+    fn summary(job_id: &str) -> JobExecutionSummary {
+        serde_json::from_value(serde_json::json!({ "jobId": job_id })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mark_job_processed_dedups_and_evicts() {
+        let (handler, _fake) = handler_with(Config::default()).await;
+
+        assert!(handler.mark_job_processed(job_key("test-thing", "job-1")));
+        assert!(!handler.mark_job_processed(job_key("test-thing", "job-1")));
+        // The same job ID on another thing is a different execution
+        assert!(handler.mark_job_processed(job_key("other-thing", "job-1")));
+
+        for i in 0..100 {
+            handler.mark_job_processed(job_key("test-thing", &format!("filler-{}", i)));
+        }
+        // The oldest ID fell out of the window
+        assert!(handler.mark_job_processed(job_key("test-thing", "job-1")));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_duplicate_delivery_runs_once() {
+        let (handler, fake) = handler_with(Config::default()).await;
+
+        // Both deliveries are in flight at once, each awaiting its queued report
+        tokio::join!(
+            handler.enqueue_job(job("job-2", 2), true),
+            handler.enqueue_job(job("job-2", 2), true),
+        );
+        assert_eq!(handler.queue.lock().unwrap().len(), 1);
+        assert_eq!(fake.published_to(UPDATE_TOPIC).len(), 1);
+
+        // Reservations race from several threads: exactly one wins
+        let winners = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| handler.mark_job_processed(job_key("test-thing", "job-3"))))
+                .collect();
+            racers
+                .into_iter()
+                .map(|racer| racer.join().unwrap())
+                .filter(|won| *won)
+                .count()
+        });
+        assert_eq!(winners, 1);
+    }
+
+    #[tokio::test]
+    async fn test_queued_behind_excludes_current_job() {
+        let (handler, _fake) = handler_with(Config::default()).await;
+        handler.update_queue_depth(
+            "test-thing",
+            PendingJobExecutions {
+                in_progress_jobs: vec![],
+                queued_jobs: vec![summary("job-1"), summary("job-2"), summary("job-3")],
+            },
+        );
+
+        assert_eq!(handler.queued_behind("test-thing", "job-1"), 2);
+        assert_eq!(handler.queued_behind("test-thing", "other"), 3);
+        assert_eq!(handler.queued_behind("other-thing", "job-1"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_reports_queued_while_busy() {
+        let (handler, fake) = handler_with(Config::default()).await;
+
+        handler.enqueue_job(job("job-1", 1), false).await;
+        handler.enqueue_job(job("job-2", 2), true).await;
+        // Duplicates are not queued twice
+        handler.enqueue_job(job("job-2", 2), true).await;
+
+        assert_eq!(handler.queue.lock().unwrap().len(), 2);
+        let updates = fake.published_to(UPDATE_TOPIC);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0]["status"], "IN_PROGRESS");
+        assert_eq!(updates[0]["statusDetails"]["queued_behind"], "2");
+        assert!(fake
+            .published_to("$aws/things/test-thing/jobs/job-1/update")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_fails_oldest_job() {
+        let mut config = Config::default();
+        config.execution.max_queued_jobs = 1;
+        let (handler, fake) = handler_with(config).await;
+
+        handler.enqueue_job(job("job-1", 1), true).await;
+        handler.enqueue_job(job("job-2", 2), true).await;
+
+        let failed = fake.published_to("$aws/things/test-thing/jobs/job-1/update");
+        assert_eq!(failed.last().unwrap()["status"], "FAILED");
+        assert_eq!(
+            handler
+                .queue
+                .lock()
+                .unwrap()
+                .position("test-thing", "job-2"),
+            Some(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_and_dedup_state_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.execution.state_file = Some(dir.path().join("state.json"));
+
+        let (handler, _fake) = handler_with(config.clone()).await;
+        handler.enqueue_job(job("job-1", 1), true).await;
+        handler.mark_job_processed(job_key("test-thing", "done-job"));
+
+        let (restarted, _fake) = handler_with(config).await;
+        restarted.restore_state().await;
+        assert_eq!(
+            restarted
+                .queue
+                .lock()
+                .unwrap()
+                .position("test-thing", "job-1"),
+            Some(0)
+        );
+        assert!(!restarted.mark_job_processed(job_key("test-thing", "job-1")));
+    }
+
+    #[tokio::test]
+    async fn test_config_update_applies_without_restart() {
+        let (handler, fake) = handler_with(Config::default()).await;
+        handler.enqueue_job(job("job-1", 1), true).await;
+        handler.enqueue_job(job("job-2", 2), true).await;
+
+        let mut updated = (*handler.config()).clone();
+        updated.security.enabled = true;
+        updated.security.command_allowlist = vec!["/opt/device-scripts/*".to_string()];
+        updated.execution.max_queued_jobs = 1;
+        handler.apply_config(updated).await;
+
+        assert!(handler.config().security.enabled);
+        // The smaller queue fails the oldest job
+        let failed = fake.published_to("$aws/things/test-thing/jobs/job-1/update");
+        assert_eq!(failed.last().unwrap()["status"], "FAILED");
+        assert_eq!(handler.queue.lock().unwrap().len(), 1);
+
+        // The heartbeat reports the reload
+        handler.send_heartbeat().await.unwrap();
+        let heartbeats = fake.published_to("device-ops/test-thing/liveness");
+        let heartbeat = heartbeats.last().unwrap();
+        assert_eq!(heartbeat["config_reloads"], 1);
+        assert_eq!(heartbeat["config_hash"], handler.config().fingerprint());
+        assert!(heartbeat["config_profile"].is_null());
+        assert_eq!(heartbeat["device"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            heartbeat["device"]["hostname"],
+            handler.device_info.hostname
+        );
+        assert_eq!(heartbeat["queued_on_device"], 1);
+        assert_eq!(heartbeat["queued_in_aws"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_inter_job_delay_unless_opted_out() {
+        let (handler, _fake) = handler_with(Config::default()).await;
+        assert_eq!(handler.delay_after(&job("job-1", 1)), None);
+
+        let mut config = Config::default();
+        config.execution.inter_job_delay_secs = 30;
+        let (handler, _fake) = handler_with(config).await;
+        assert_eq!(
+            handler.delay_after(&job("job-1", 1)),
+            Some(Duration::from_secs(30))
+        );
+
+        let mut opted_out = job("job-2", 2);
+        opted_out.document.no_delay_after = Some(true);
+        assert_eq!(handler.delay_after(&opted_out), None);
+    }
+
+    #[tokio::test]
+    async fn test_progress_heartbeat_while_job_runs() {
+        let mut config = Config::default();
+        config.execution.progress_heartbeat_secs = 1;
+        let (handler, fake) = handler_with(config).await;
+
+        let mut sleeper = job("job-2", 2);
+        sleeper.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Sleep",
+                "type": "runCommand",
+                "input": { "command": "/bin/sleep", "args": ["1.5"] }
+            }}]
+        }))
+        .unwrap();
+        handler.process_job(sleeper, "execution-1").await.unwrap();
+
+        let updates = fake.published_to(UPDATE_TOPIC);
+        let statuses: Vec<&str> = updates
+            .iter()
+            .map(|u| u["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, vec!["IN_PROGRESS", "SUCCEEDED"]);
+        assert_eq!(updates[0]["statusDetails"]["running_secs"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_compressed_state_keeps_capped_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.execution.state_file = Some(dir.path().join("state.json.gz"));
+        config.execution.compress_state = true;
+        config.execution.max_processed_jobs = 2;
+
+        let (handler, _fake) = handler_with(config.clone()).await;
+        for job_id in ["job-1", "job-2", "job-3"] {
+            handler.mark_job_processed(job_key("test-thing", job_id));
+        }
+        handler.persist_state();
+
+        let (restarted, _fake) = handler_with(config).await;
+        restarted.restore_state().await;
+        // Only the newest two keys were kept
+        assert!(restarted.mark_job_processed(job_key("test-thing", "job-1")));
+        assert!(!restarted.mark_job_processed(job_key("test-thing", "job-3")));
+    }
+
+    #[tokio::test]
+    async fn test_each_run_gets_its_own_execution_id() {
+        let (handler, fake) = handler_with(Config::default()).await;
+
+        let mut echo = job("job-2", 2);
+        echo.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Echo",
+                "type": "runCommand",
+                "input": { "command": "/bin/echo", "args": ["hello"] }
+            }}]
+        }))
+        .unwrap();
+        // The same job ID delivered again, e.g. after a retry in IoT Jobs
+        for _ in 0..2 {
+            let (_, result) = handler.handle_job(echo.clone()).await;
+            result.unwrap();
+        }
+
+        let updates = fake.published_to(UPDATE_TOPIC);
+        let ids: Vec<&str> = updates
+            .iter()
+            .map(|u| u["statusDetails"]["execution_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids.len(), 2);
+        for id in &ids {
+            assert!(Uuid::parse_str(id).is_ok(), "{}", id);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn test_finished_jobs_write_last_run_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let last_run_path = dir.path().join("last-run.json");
+        let last_success_path = dir.path().join("last-success.json");
+        let mut config = Config::default();
+        config.execution.last_run_file = Some(last_run_path.clone());
+        config.execution.last_success_file = Some(last_success_path.clone());
+        let (handler, _fake) = handler_with(config).await;
+
+        let mut echo = job("job-2", 2);
+        echo.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Echo",
+                "type": "runCommand",
+                "input": { "command": "/bin/echo", "args": ["hello"] }
+            }}]
+        }))
+        .unwrap();
+        handler.handle_job(echo).await.1.unwrap();
+        let success = last_run::read(&last_success_path).unwrap();
+        assert_eq!(success.job_id, "job-2");
+        assert_eq!(success.reason_code, "succeeded");
+        assert_eq!(success.component_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(last_run::read(&last_run_path).unwrap(), success);
+
+        // A document without steps is rejected before running
+        handler.handle_job(job("job-3", 3)).await.1.unwrap();
+        let last = last_run::read(&last_run_path).unwrap();
+        assert_eq!(last.job_id, "job-3");
+        assert_eq!(last.status, "FAILED");
+        assert_eq!(last.reason_code, "rejected");
+        assert_eq!(last.failed_step, None);
+        assert_eq!(last_run::read(&last_success_path).unwrap().job_id, "job-2");
+    }
+
+    #[tokio::test]
+    async fn test_finished_jobs_are_recorded_in_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history").join("history.jsonl");
+        let mut config = Config::default();
+        config.execution.history_file = Some(path.clone());
+        let (handler, fake) = handler_with(config).await;
+
+        let mut echo = job("job-2", 2);
+        echo.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Echo",
+                "type": "runCommand",
+                "input": { "command": "/bin/echo", "args": ["hello"] }
+            }}]
+        }))
+        .unwrap();
+        handler.handle_job(echo).await.1.unwrap();
+        // A document without steps is rejected before running
+        handler.handle_job(job("job-3", 3)).await.1.unwrap();
+
+        let records = history::read(&path).unwrap().records;
+        assert_eq!(records.len(), 2);
+        let update = &fake.published_to(UPDATE_TOPIC)[0];
+        assert_eq!(
+            records[0].execution_id,
+            update["statusDetails"]["execution_id"]
+        );
+        assert_eq!(records[0].status, "SUCCEEDED");
+        assert_eq!(records[0].steps.len(), 1);
+        assert_eq!(records[0].steps[0].name, "Echo");
+        assert_eq!(records[0].steps[0].exit_code, 0);
+        assert!(records[0].finished_at >= records[0].started_at);
+
+        assert_eq!(records[1].job_id, "job-3");
+        assert_eq!(records[1].status, "FAILED");
+        assert!(records[1].steps.is_empty());
+        assert!(records[1].error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_stats_answer_does_not_wait_for_the_broker() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.execution.history_file = Some(dir.path().join("history.jsonl"));
+        let (earlier, _fake) = handler_with(config.clone()).await;
+        earlier.handle_job(job("job-3", 3)).await.1.unwrap();
+
+        // Recent jobs come from the end of the history file and then from memory
+        let (handler, fake) = handler_with(config).await;
+        handler.load_recent_jobs().await;
+        handler.handle_job(job("job-4", 4)).await.1.unwrap();
+
+        fake.stall_publishes(true);
+        handler.answer_stats_request("req-1");
+        fake.stall_publishes(false);
+
+        let response = fake
+            .wait_for(Duration::from_secs(5), |m| {
+                m.topic == "device-ops/test-thing/stats/response/req-1"
+            })
+            .await
+            .expect("stats response")
+            .json();
+        let ids: Vec<_> = response["recentJobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|job| job["jobId"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["job-4", "job-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_output_lines_are_published_live() {
+        let mut config = Config::default();
+        config.execution.live_output = true;
+        config.execution.live_output_lines_per_sec = 5;
+        let (handler, fake) = handler_with(config).await;
+
+        let mut chatty = job("job-2", 2);
+        chatty.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [
+                { "action": {
+                    "name": "Greet",
+                    "type": "runCommand",
+                    "input": { "command": "/bin/sh", "args": ["-c", "echo one; echo two >&2"] },
+                    "allowStdErr": 1
+                }},
+                { "action": {
+                    "name": "Count",
+                    "type": "runCommand",
+                    "input": { "command": "/usr/bin/seq", "args": ["1", "50"] }
+                }}
+            ]
+        }))
+        .unwrap();
+        handler.handle_job(chatty).await.1.unwrap();
+
+        let lines = fake.published_local_to("device-ops/live/job-2");
+        let greet: Vec<(String, String)> = lines
+            .iter()
+            .filter(|line| line["stepName"] == "Greet")
+            .map(|line| {
+                assert_eq!(line["executionId"], lines[0]["executionId"]);
+                assert_eq!(line["lineNumber"], 1);
+                (
+                    line["stream"].as_str().unwrap().to_string(),
+                    line["line"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(greet.len(), 2);
+        assert!(greet.contains(&("stdout".to_string(), "one".to_string())));
+        assert!(greet.contains(&("stderr".to_string(), "two".to_string())));
+
+        // At most five lines of a stream in a second
+        let counted: Vec<&serde_json::Value> = lines
+            .iter()
+            .filter(|line| line["stepName"] == "Count")
+            .map(|line| &line["line"])
+            .collect();
+        assert_eq!(counted, ["1", "2", "3", "4", "5"]);
+    }
+
+    #[tokio::test]
+    async fn test_transient_spawn_failure_runs_the_job_again() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("install.sh");
+        std::fs::write(&script, "#!/bin/sh\necho installed\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let document = serde_json::from_value::<JobDocument>(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Install",
+                "type": "runCommand",
+                "input": { "command": script.display().to_string() }
+            }}]
+        }))
+        .unwrap();
+
+        let mut config = Config::default();
+        config.execution.transient_error_retries = 2;
+        config.execution.transient_error_backoff_ms = 1000;
+        let (handler, fake) = handler_with(config).await;
+
+        // While the script is open for writing, running it fails with ETXTBSY
+        let writer = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&script)
+            .unwrap();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(writer);
+        });
+        let mut busy = job("job-1", 1);
+        busy.document = document.clone();
+        handler.handle_job(busy).await.1.unwrap();
+
+        let update = fake.published_to("$aws/things/test-thing/jobs/job-1/update");
+        let last = update.last().unwrap();
+        assert_eq!(last["status"], "SUCCEEDED", "{}", last);
+        assert_eq!(last["statusDetails"]["job_attempts"], "2");
+
+        // A terminal error fails at once
+        let mut missing = job("job-2", 2);
+        missing.document = document;
+        missing.document.steps[0].action.input.command = "/nonexistent/install.sh".to_string();
+        handler.handle_job(missing).await.1.unwrap();
+        let update = fake.published_to(UPDATE_TOPIC);
+        let last = update.last().unwrap();
+        assert_eq!(last["status"], "FAILED");
+        assert_eq!(last["statusDetails"]["job_attempts"], "1");
+        assert!(last["statusDetails"].as_object().unwrap().len() <= MAX_STATUS_DETAILS);
+    }
+
+    #[tokio::test]
+    async fn test_failed_step_keeps_its_stderr() {
+        let mut config = Config::default();
+        config.execution.transient_error_retries = 2;
+        let (handler, fake) = handler_with(config).await;
+
+        let mut locked = job("job-2", 2);
+        locked.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Install",
+                "type": "runCommand",
+                "input": {
+                    "command": "/bin/sh",
+                    "args": ["-c", "echo 'E: dpkg lock held' >&2; exit 100"]
+                }
+            }}]
+        }))
+        .unwrap();
+        handler.handle_job(locked).await.1.unwrap();
+
+        let update = fake.published_to(UPDATE_TOPIC);
+        let details = &update.last().unwrap()["statusDetails"];
+        assert!(details.as_object().unwrap().len() <= MAX_STATUS_DETAILS);
+        assert_eq!(details["stderr"], "E: dpkg lock held", "{}", details);
+        assert_eq!(details["fail_reason"], "exit_code=100");
+        for key in ["execution_id", "thing_name", "job_attempts"] {
+            assert!(details.get(key).is_some(), "{} dropped: {}", key, details);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_step_slower_than_its_history_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let mut config = Config::default();
+        config.execution.record_history = true;
+        config.execution.history_file = Some(path.clone());
+        config.execution.slow_step_factor = 3.0;
+
+        let mut sleep = job("job-2", 2);
+        sleep.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Sleep",
+                "type": "runCommand",
+                "input": { "command": "/bin/sleep", "args": ["1.2"] }
+            }}]
+        }))
+        .unwrap();
+        // Three earlier runs of the same document took 100ms
+        for n in 0..3 {
+            let record = HistoryRecord {
+                job_id: format!("old-{}", n),
+                thing_name: "test-thing".to_string(),
+                execution_id: format!("run-{}", n),
+                document_hash: document_hash(&sleep.document),
+                status: "SUCCEEDED".to_string(),
+                started_at: 1,
+                finished_at: 2,
+                steps: vec![StepRecord {
+                    name: "Sleep".to_string(),
+                    exit_code: 0,
+                    duration_ms: 100,
+                    timing: None,
+                }],
+                error: None,
+                device: None,
+            };
+            history::append(&path, &record).unwrap();
+        }
+        let (handler, fake) = handler_with(config).await;
+
+        handler.handle_job(sleep).await.1.unwrap();
+
+        let update = &fake.published_to(UPDATE_TOPIC)[0];
+        assert_eq!(update["status"], "SUCCEEDED");
+        let slow = update["statusDetails"]["slow_steps"].as_str().unwrap();
+        assert!(slow.starts_with("Sleep 1."), "{}", slow);
+        assert!(slow.ends_with("median 0.1s)"), "{}", slow);
+        assert_eq!(history::read(&path).unwrap().records.len(), 4);
+    }
+
+    /// Records the commands it is asked to run
+    #[derive(Default)]
+    struct RecordingRunner {
+        commands: Mutex<Vec<Command>>,
+    }
+
+    #[async_trait::async_trait]
+    impl CommandRunner for RecordingRunner {
+        async fn run(&self, command: &Command) -> Result<ExecutionOutput> {
+            self.commands.lock().unwrap().push(command.clone());
+            Ok(ExecutionOutput::default())
+        }
+    }
+
+    /// Hook runner that panics, standing in for a bug hit while handling a job
+    struct PanickingRunner;
+
+    #[async_trait::async_trait]
+    impl CommandRunner for PanickingRunner {
+        async fn run(&self, _command: &Command) -> Result<ExecutionOutput> {
+            panic!("formatting helper choked on a pathological string");
+        }
+    }
+
+    /// Battery probe that panics, for a bug hit while a job executes
+    struct PanickingBattery;
+
+    impl crate::executor::power::BatteryProbe for PanickingBattery {
+        fn battery_percent(&self, _command: Option<&str>) -> Result<Option<u8>> {
+            panic!("battery gauge returned garbage");
+        }
+    }
+
+    fn queued_notification(job_id: &str, document: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "execution": { "jobId": job_id, "status": "QUEUED", "jobDocument": document }
+        })
+    }
+
+    /// Updates published for `job_id` other than IN_PROGRESS
+    fn terminal_updates(fake: &FakeSdk, job_id: &str) -> Vec<serde_json::Value> {
+        fake.published_to(&format!("$aws/things/test-thing/jobs/{}/update", job_id))
+            .into_iter()
+            .filter(|update| update["status"] != "IN_PROGRESS")
+            .collect()
+    }
+
+    async fn wait_for_terminal(fake: &FakeSdk, job_id: &str) -> serde_json::Value {
+        let topic = format!("$aws/things/test-thing/jobs/{}/update", job_id);
+        fake.wait_for(Duration::from_secs(5), |m| {
+            m.topic == topic && m.json()["status"] != "IN_PROGRESS"
+        })
+        .await
+        .expect("terminal status update")
+        .json()
+    }
+
+    fn echo_steps() -> serde_json::Value {
+        serde_json::json!([{ "action": {
+            "name": "Echo",
+            "type": "runCommand",
+            "input": { "command": "/bin/echo", "args": ["hello"] }
+        }}])
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_fails_and_loop_survives() {
+        let (mut handler, fake) = handler_with(Config::default()).await;
+        handler.executor = handler.executor.with_battery_probe(PanickingBattery);
+        let notify = "$aws/things/test-thing/jobs/notify-next";
+
+        let scenario = async {
+            fake.wait_for(Duration::from_secs(5), |m| {
+                m.topic.ends_with("/jobs/$next/get")
+            })
+            .await
+            .expect("startup requests the next job");
+
+            // The battery check panics while the job executes, before any
+            // terminal status
+            let poison = serde_json::json!({
+                "version": "1.0",
+                "requireMinBatteryPercent": 20,
+                "steps": echo_steps()
+            });
+            fake.inject_json(notify, &queued_notification("poison", poison.clone()));
+            let update = wait_for_terminal(&fake, "poison").await;
+            assert_eq!(update["status"], "FAILED");
+            assert_eq!(update["statusDetails"]["reason_code"], "INTERNAL_PANIC");
+            assert!(update["statusDetails"]["reason"]
+                .as_str()
+                .unwrap()
+                .contains("battery gauge returned garbage"));
+
+            // Delivered again, it is not run into the same panic
+            fake.inject_json(notify, &queued_notification("poison", poison));
+
+            let healthy = serde_json::json!({ "version": "1.0", "steps": echo_steps() });
+            fake.inject_json(notify, &queued_notification("healthy", healthy));
+            assert_eq!(
+                wait_for_terminal(&fake, "healthy").await["status"],
+                "SUCCEEDED"
+            );
+            assert_eq!(terminal_updates(&fake, "poison").len(), 1);
+        };
+        tokio::select! {
+            biased;
+            result = handler.run() => panic!("job handler exited: {:?}", result),
+            () = scenario => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panic_after_final_status_is_not_reported_again() {
+        let mut config = Config::default();
+        config.execution.on_validation_failure_command =
+            Some("/opt/device-scripts/alert.sh".to_string());
+        let (handler, fake) = handler_with(config).await;
+        let mut handler = handler.with_hook_runner(Arc::new(PanickingRunner));
+        let notify = "$aws/things/test-thing/jobs/notify-next";
+
+        let scenario = async {
+            fake.wait_for(Duration::from_secs(5), |m| {
+                m.topic.ends_with("/jobs/$next/get")
+            })
+            .await
+            .expect("startup requests the next job");
+
+            // No steps: rejected, and the validation hook run after the
+            // rejection is reported panics
+            let empty = serde_json::json!({ "version": "1.0", "steps": [] });
+            fake.inject_json(notify, &queued_notification("poison", empty));
+            assert_eq!(wait_for_terminal(&fake, "poison").await["status"], "FAILED");
+
+            // The loop survives and the rejection stays the only final status
+            let healthy = serde_json::json!({ "version": "1.0", "steps": echo_steps() });
+            fake.inject_json(notify, &queued_notification("healthy", healthy));
+            assert_eq!(
+                wait_for_terminal(&fake, "healthy").await["status"],
+                "SUCCEEDED"
+            );
+            let updates = terminal_updates(&fake, "poison");
+            assert_eq!(updates.len(), 1);
+            assert_eq!(updates[0]["status"], "FAILED");
+            assert_ne!(updates[0]["statusDetails"]["reason_code"], "INTERNAL_PANIC");
+        };
+        tokio::select! {
+            biased;
+            result = handler.run() => panic!("job handler exited: {:?}", result),
+            () = scenario => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_document_reports_field_path() {
+        let (handler, fake) = handler_with(Config::default()).await;
+        let mut bad = job("job-2", 2);
+        bad.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [
+                { "action": { "name": "Echo", "type": "runCommand", "input": { "command": "/bin/echo" } } },
+                { "action": { "name": "Wait", "type": "runCommand", "input": { "command": "/bin/sleep", "timeout": 0 } } }
+            ]
+        }))
+        .unwrap();
+
+        handler.handle_job(bad).await.1.unwrap();
+
+        let update = &fake.published_to(UPDATE_TOPIC)[0];
+        assert_eq!(update["status"], "FAILED");
+        assert_eq!(
+            update["statusDetails"]["path"],
+            "steps[1].action.input.timeout"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_fields_rejected_when_policy_forbids_them() {
+        let mut config = Config::default();
+        config.document_policy.allow_unknown_fields = false;
+        let (handler, fake) = handler_with(config).await;
+        let mut strict = job("job-2", 2);
+        strict.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": { "name": "Echo", "type": "runCommand", "input": { "command": "/bin/echo" } } }]
+        }))
+        .unwrap();
+        strict.unknown_fields = vec!["steps[0].action.priority".to_string()];
+
+        handler.handle_job(strict).await.1.unwrap();
+
+        let update = &fake.published_to(UPDATE_TOPIC)[0];
+        assert_eq!(update["status"], "FAILED");
+        assert_eq!(update["statusDetails"]["path"], "steps[0].action.priority");
+    }
+
+    #[tokio::test]
+    async fn test_validation_failure_hook_runs_on_rejected_document() {
+        let mut config = Config::default();
+        config.execution.on_validation_failure_command =
+            Some("/opt/device-scripts/alert.sh".to_string());
+        let (handler, fake) = handler_with(config).await;
+        let runner = Arc::new(RecordingRunner::default());
+        let handler = handler.with_hook_runner(runner.clone());
+
+        // A document without steps is rejected
+        handler.handle_job(job("job-2", 2)).await.1.unwrap();
+
+        let commands = runner.commands.lock().unwrap().clone();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].script_path, "/opt/device-scripts/alert.sh");
+        let env: HashMap<&str, &str> = commands[0]
+            .env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(env["DEVICE_OPS_JOB_ID"], "job-2");
+        assert_eq!(env["DEVICE_OPS_THING_NAME"], "test-thing");
+        let update = &fake.published_to(UPDATE_TOPIC)[0];
+        assert_eq!(update["status"], "FAILED");
+        assert_eq!(
+            env["DEVICE_OPS_EXECUTION_ID"],
+            update["statusDetails"]["execution_id"]
+        );
+        assert!(
+            env["DEVICE_OPS_VALIDATION_ERROR"].contains("Invalid job document"),
+            "{}",
+            env["DEVICE_OPS_VALIDATION_ERROR"]
+        );
+
+        // Accepted documents do not run the hook
+        let mut echo = job("job-3", 3);
+        echo.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Echo",
+                "type": "runCommand",
+                "input": { "command": "/bin/echo", "args": ["hello"] }
+            }}]
+        }))
+        .unwrap();
+        handler.handle_job(echo).await.1.unwrap();
+        assert_eq!(runner.commands.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_job_outcomes_are_counted_in_metrics() {
+        let mut config = Config::default();
+        config.security.enabled = true;
+        config.security.command_allowlist = vec!["/opt/device-scripts/*".to_string()];
+        let (handler, _fake) = handler_with(config).await;
+        // Other tests record into the same process-wide metrics concurrently
+        let metrics = metrics::global();
+        let rejected = metrics.jobs(JobOutcome::Rejected);
+        let failed = metrics.jobs(JobOutcome::Failed);
+        let denials = metrics.security_denials(SecurityRule::CommandAllowlist);
+        let publishes = metrics.ipc_publishes();
+
+        handler.handle_job(job("job-2", 2)).await.1.unwrap();
+        let mut denied = job("job-3", 3);
+        denied.document = serde_json::from_value(serde_json::json!({
+            "version": "1.0",
+            "steps": [{ "action": {
+                "name": "Echo",
+                "type": "runCommand",
+                "input": { "command": "/bin/echo", "args": ["hello"] }
+            }}]
+        }))
+        .unwrap();
+        handler.handle_job(denied).await.1.unwrap();
+
+        assert!(metrics.jobs(JobOutcome::Rejected) > rejected);
+        assert!(metrics.jobs(JobOutcome::Failed) > failed);
+        assert!(metrics.security_denials(SecurityRule::CommandAllowlist) > denials);
+        assert!(metrics.ipc_publishes() > publishes);
+    }
+
+    #[tokio::test]
+    async fn test_telemetry_waits_for_the_session() {
+        let (handler, fake) = handler_with(Config::default()).await;
+        let topic = Config::default().telemetry.topic;
+        let mut emitter = TelemetryEmitter::new();
+
+        fake.set_offline(true);
+        handler.publish_telemetry(&mut emitter).await;
+        assert!(fake.published_to(&topic).is_empty());
+
+        // Counters recorded while offline are still pending
+        metrics::global().record_job(JobOutcome::TimedOut, Some(Duration::from_secs(1)));
+        let pending = emitter
+            .value(TelemetryMetric::JobsTimedOut, &metrics::global().snapshot())
+            .unwrap();
+        assert!(pending >= 1.0);
+
+        fake.set_offline(false);
+        handler.publish_telemetry(&mut emitter).await;
+        let requests = fake.published_to(&topic);
+        let names: Vec<_> = requests
+            .iter()
+            .map(|r| r["request"]["metricData"]["metricName"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "JobsSucceeded",
+                "JobsFailed",
+                "JobsTimedOut",
+                "JobDurationAverage",
+                "QueueDepth"
+            ]
+        );
+        assert_eq!(requests[0]["request"]["namespace"], "DeviceOps");
+        assert_eq!(
+            requests[2]["request"]["metricData"]["dimensions"][0]["value"],
+            "test-thing"
+        );
+    }
+}
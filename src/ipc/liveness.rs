@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Sequence numbers of loopback liveness probes: published vs. received back.
+/// The subscription callback only ever bumps `received`.
+#[derive(Debug, Default)]
+pub struct LivenessProbes {
+    sent: AtomicU64,
+    received: Arc<AtomicU64>,
+}
+
+impl LivenessProbes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number for the next probe
+    pub fn next_seq(&self) -> u64 {
+        self.sent.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Handle for the subscription callback to record echoed probes
+    pub fn receiver(&self) -> Arc<AtomicU64> {
+        self.received.clone()
+    }
+
+    /// Whether the most recent probe (if any) came back
+    pub fn last_answered(&self) -> bool {
+        self.received.load(Ordering::SeqCst) >= self.sent.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts consecutive unanswered probes and decides when the session is dead.
+/// State changes are logged once, not on every probe.
+#[derive(Debug)]
+pub struct LivenessMonitor {
+    failure_threshold: u32,
+    missed: u32,
+    live: bool,
+}
+
+impl LivenessMonitor {
+    pub fn new(failure_threshold: u32) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            missed: 0,
+            live: true,
+        }
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+
+    /// Record the outcome of the previous probe; returns true when the
+    /// session should be torn down and reconnected
+    pub fn record(&mut self, answered: bool) -> bool {
+        if answered {
+            self.missed = 0;
+            if !self.live {
+                self.live = true;
+                tracing::info!("IoT Core session is live again");
+            }
+            return false;
+        }
+
+        self.missed += 1;
+        if self.missed < self.failure_threshold {
+            return false;
+        }
+
+        if self.live {
+            self.live = false;
+            tracing::warn!(
+                missed_probes = self.missed,
+                "Liveness probes unanswered, IoT Core session considered dead"
+            );
+        }
+        true
+    }
+
+    /// Start counting again after a reconnect; the session stays "not live"
+    /// until a probe actually comes back
+    pub fn reconnected(&mut self) {
+        self.missed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes_answered() {
+        let probes = LivenessProbes::new();
+        assert!(probes.last_answered());
+
+        let seq = probes.next_seq();
+        assert!(!probes.last_answered());
+
+        probes.receiver().fetch_max(seq, Ordering::SeqCst);
+        assert!(probes.last_answered());
+    }
+
+    #[test]
+    fn test_monitor_trips_after_threshold() {
+        let mut monitor = LivenessMonitor::new(3);
+        assert!(!monitor.record(false));
+        assert!(!monitor.record(false));
+        assert!(monitor.record(false));
+        assert!(!monitor.is_live());
+
+        // Still dead after a reconnect until a probe comes back
+        monitor.reconnected();
+        assert!(!monitor.record(false));
+        assert!(!monitor.is_live());
+        assert!(!monitor.record(true));
+        assert!(monitor.is_live());
+    }
+
+    #[test]
+    fn test_answered_probe_resets_missed_count() {
+        let mut monitor = LivenessMonitor::new(2);
+        assert!(!monitor.record(false));
+        assert!(!monitor.record(true));
+        assert!(!monitor.record(false));
+        assert!(monitor.is_live());
+    }
+}
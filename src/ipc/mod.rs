@@ -1,5 +1,19 @@
 pub mod client;
+pub mod component_config;
+pub mod echo;
+pub mod events;
+#[cfg(any(test, feature = "fake-ipc"))]
+pub mod fake;
 pub mod jobs;
+pub mod liveness;
+pub mod queue;
+pub mod state;
+pub mod stats;
+pub mod telemetry;
+pub mod throttle;
+pub mod topics;
+pub mod transport;
 
 pub use client::IpcClient;
 pub use jobs::JobHandler;
+pub use topics::JobsTopics;
@@ -0,0 +1,191 @@
+use crate::models::Job;
+use std::collections::VecDeque;
+
+/// Bounded on-device job queue ordered by IoT Jobs `queuedAt`
+///
+/// Jobs without a `queuedAt` go to the back. When the queue is full the
+/// oldest job is evicted so the caller can fail it. Jobs for different things
+/// are popped round-robin, oldest first within each thing.
+#[derive(Debug, Clone)]
+pub struct JobQueue {
+    jobs: VecDeque<Job>,
+    capacity: usize,
+    /// Thing whose job was popped last
+    last_thing: Option<String>,
+}
+
+impl JobQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            jobs: VecDeque::new(),
+            capacity: capacity.max(1),
+            last_thing: None,
+        }
+    }
+
+    /// Restore a queue from persisted jobs, evicting any beyond capacity
+    pub fn from_jobs(jobs: Vec<Job>, capacity: usize) -> (Self, Vec<Job>) {
+        let mut queue = Self::new(capacity);
+        let mut evicted = Vec::new();
+        for job in jobs {
+            evicted.extend(queue.push(job));
+        }
+        (queue, evicted)
+    }
+
+    /// Change the capacity, returning the oldest jobs that no longer fit
+    pub fn set_capacity(&mut self, capacity: usize) -> Vec<Job> {
+        self.capacity = capacity.max(1);
+        let excess = self.jobs.len().saturating_sub(self.capacity);
+        self.jobs.drain(..excess).collect()
+    }
+
+    /// Enqueue a job, returning the oldest job if the queue overflowed
+    pub fn push(&mut self, job: Job) -> Option<Job> {
+        let position = match job.queued_at {
+            Some(queued_at) => self
+                .jobs
+                .iter()
+                .position(|queued| queued.queued_at.is_none_or(|other| other > queued_at))
+                .unwrap_or(self.jobs.len()),
+            None => self.jobs.len(),
+        };
+        self.jobs.insert(position, job);
+
+        if self.jobs.len() > self.capacity {
+            self.jobs.pop_front()
+        } else {
+            None
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Job> {
+        let index = self.next_index()?;
+        let job = self.jobs.remove(index)?;
+        self.last_thing = Some(job.thing_name.clone());
+        Some(job)
+    }
+
+    /// Index of the oldest job of the thing after the last one served, in name order
+    fn next_index(&self) -> Option<usize> {
+        let Some(last) = self.last_thing.as_deref() else {
+            // Nothing served yet: plain queuedAt order
+            return (!self.jobs.is_empty()).then_some(0);
+        };
+
+        let mut things: Vec<&str> = self.jobs.iter().map(|j| j.thing_name.as_str()).collect();
+        things.sort_unstable();
+        things.dedup();
+
+        let next = things
+            .iter()
+            .find(|thing| **thing > last)
+            .or(things.first())?;
+        self.jobs.iter().position(|job| job.thing_name == *next)
+    }
+
+    /// Number of queued jobs that will run before the given one
+    pub fn position(&self, thing_name: &str, job_id: &str) -> Option<usize> {
+        let mut remaining = self.clone();
+        std::iter::from_fn(|| remaining.pop())
+            .position(|job| job.thing_name == thing_name && job.job_id == job_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::JobDocument;
+
+    fn job(id: &str, queued_at: Option<i64>) -> Job {
+        thing_job("thing", id, queued_at)
+    }
+
+    fn thing_job(thing: &str, id: &str, queued_at: Option<i64>) -> Job {
+        Job {
+            job_id: id.to_string(),
+            thing_name: thing.to_string(),
+            queued_at,
+            document: JobDocument::default(),
+            unknown_fields: Vec::new(),
+        }
+    }
+
+    fn ids(queue: &JobQueue) -> Vec<String> {
+        queue.jobs().map(|j| j.job_id.clone()).collect()
+    }
+
+    #[test]
+    fn test_orders_by_queued_at() {
+        let mut queue = JobQueue::new(10);
+        queue.push(job("c", Some(300)));
+        queue.push(job("a", Some(100)));
+        queue.push(job("unknown", None));
+        queue.push(job("b", Some(200)));
+
+        assert_eq!(ids(&queue), vec!["a", "b", "c", "unknown"]);
+        assert_eq!(queue.position("thing", "c"), Some(2));
+        assert_eq!(queue.pop().unwrap().job_id, "a");
+    }
+
+    #[test]
+    fn test_overflow_evicts_oldest() {
+        let mut queue = JobQueue::new(2);
+        assert!(queue.push(job("a", Some(100))).is_none());
+        assert!(queue.push(job("b", Some(200))).is_none());
+
+        let evicted = queue.push(job("c", Some(300))).unwrap();
+        assert_eq!(evicted.job_id, "a");
+        assert_eq!(ids(&queue), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_restore_respects_capacity() {
+        let (queue, evicted) = JobQueue::from_jobs(
+            vec![job("a", Some(1)), job("b", Some(2)), job("c", Some(3))],
+            2,
+        );
+        assert_eq!(ids(&queue), vec!["b", "c"]);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].job_id, "a");
+    }
+
+    #[test]
+    fn test_things_are_served_round_robin() {
+        let mut queue = JobQueue::new(10);
+        queue.push(thing_job("leaf-a", "a1", Some(1)));
+        queue.push(thing_job("leaf-a", "a2", Some(2)));
+        queue.push(thing_job("leaf-a", "a3", Some(3)));
+        queue.push(thing_job("leaf-b", "b1", Some(4)));
+        queue.push(thing_job("core", "c1", Some(5)));
+
+        // b1 runs second even though a2 was queued earlier
+        assert_eq!(queue.position("leaf-b", "b1"), Some(1));
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop())
+            .map(|job| job.job_id)
+            .collect();
+        assert_eq!(order, vec!["a1", "b1", "c1", "a2", "a3"]);
+    }
+
+    #[test]
+    fn test_same_job_id_for_different_things() {
+        let mut queue = JobQueue::new(10);
+        queue.push(thing_job("leaf-a", "job", Some(1)));
+        queue.push(thing_job("leaf-b", "job", Some(1)));
+
+        assert_eq!(queue.position("leaf-a", "job"), Some(0));
+        assert_eq!(queue.position("leaf-b", "job"), Some(1));
+    }
+}
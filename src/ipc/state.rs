@@ -0,0 +1,158 @@
+use crate::error::{DeviceOpsError, Result};
+use crate::models::Job;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Leading bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Handler state persisted across restarts: dedup keys (`thing/job_id`) and the local job queue
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PersistedState {
+    #[serde(default)]
+    pub processed_jobs: Vec<String>,
+    #[serde(default)]
+    pub queued_jobs: Vec<Job>,
+}
+
+impl PersistedState {
+    /// Load state from disk, gzipped or plain JSON; a missing file is an empty state
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content =
+            std::fs::read(path).map_err(DeviceOpsError::config_io("Failed to read state file"))?;
+
+        let content = if content.starts_with(&GZIP_MAGIC) {
+            let mut json = Vec::new();
+            GzDecoder::new(content.as_slice())
+                .read_to_end(&mut json)
+                .map_err(DeviceOpsError::config_io("Failed to decompress state file"))?;
+            json
+        } else {
+            content
+        };
+
+        serde_json::from_slice(&content)
+            .map_err(DeviceOpsError::config_json("Failed to parse state file"))
+    }
+
+    /// Write state atomically (temp file + rename), gzipped if `compress` is set
+    pub fn save(&self, path: &Path, compress: bool) -> Result<()> {
+        let mut content = serde_json::to_vec(self)
+            .map_err(DeviceOpsError::config_json("Failed to serialize state"))?;
+
+        if compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            content = encoder
+                .write_all(&content)
+                .and_then(|_| encoder.finish())
+                .map_err(DeviceOpsError::config_io("Failed to compress state"))?;
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(DeviceOpsError::config_io(
+                "Failed to create state directory",
+            ))?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(DeviceOpsError::config_io("Failed to write state file"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::JobDocument;
+
+    #[test]
+    fn test_state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("handler-state.json");
+
+        let state = PersistedState {
+            processed_jobs: vec!["test-thing/job-1".to_string()],
+            queued_jobs: vec![Job {
+                job_id: "job-2".to_string(),
+                thing_name: "test-thing".to_string(),
+                queued_at: Some(1700000000),
+                document: JobDocument {
+                    version: "1.0".to_string(),
+                    ..Default::default()
+                },
+                unknown_fields: Vec::new(),
+            }],
+        };
+        state.save(&path, false).unwrap();
+
+        let loaded = PersistedState::load(&path).unwrap();
+        assert_eq!(loaded.processed_jobs, state.processed_jobs);
+        assert_eq!(loaded.queued_jobs.len(), 1);
+        assert_eq!(loaded.queued_jobs[0].queued_at, Some(1700000000));
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn test_compressed_state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("handler-state.json.gz");
+
+        let state = PersistedState {
+            processed_jobs: (0..100).map(|i| format!("test-thing/job-{}", i)).collect(),
+            queued_jobs: Vec::new(),
+        };
+        state.save(&path, true).unwrap();
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(raw.starts_with(&GZIP_MAGIC));
+        assert!(raw.len() < serde_json::to_vec(&state).unwrap().len());
+
+        let loaded = PersistedState::load(&path).unwrap();
+        assert_eq!(loaded.processed_jobs, state.processed_jobs);
+        assert!(!path.with_extension("tmp").exists());
+
+        // Turning compression off again still reads back the same state
+        loaded.save(&path, false).unwrap();
+        assert_eq!(
+            PersistedState::load(&path).unwrap().processed_jobs,
+            state.processed_jobs
+        );
+    }
+
+    #[test]
+    fn test_corrupt_state_file_keeps_parse_error() {
+        use std::error::Error;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "{\"processed_jobs\": [").unwrap();
+
+        let err = PersistedState::load(&path).unwrap_err();
+        let json = err
+            .source()
+            .and_then(|source| source.downcast_ref::<serde_json::Error>())
+            .unwrap();
+        assert!(json.is_eof());
+        assert_eq!(
+            err.to_string(),
+            format!("Configuration error: Failed to parse state file: {}", json)
+        );
+    }
+
+    #[test]
+    fn test_missing_state_file_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = PersistedState::load(&dir.path().join("missing.json")).unwrap();
+        assert!(state.processed_jobs.is_empty());
+        assert!(state.queued_jobs.is_empty());
+    }
+}
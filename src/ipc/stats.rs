@@ -0,0 +1,178 @@
+//! Runtime statistics on request
+//!
+//! Support publishes `{"requestId": "<id>"}` to the core's stats request
+//! topic (`device-ops/<thing>/stats/request` by default) and gets a snapshot
+//! back on `.../stats/response/<id>`: the counters of [`crate::metrics`],
+//! the on-device and AWS queues, uptime, the config hash, when the Jobs API
+//! was last heard from and the last few jobs of the history. Requests are
+//! answered from the job handler's loop, next to a running job and never
+//! waiting for it, and at most `stats.max_requests_per_minute` of them.
+
+use crate::history::HistoryRecord;
+use serde_json::{json, Value};
+
+/// Longest `requestId` accepted; it becomes a topic level of the response
+pub const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Requests waiting to be answered; more are dropped
+pub const STATS_QUEUE_CAPACITY: usize = 8;
+
+/// The `requestId` of a stats request, which must be 1 to 64 letters,
+/// digits, `-` or `_`
+pub fn request_id(payload: &[u8]) -> Result<String, String> {
+    let request: Value = serde_json::from_slice(payload).map_err(|e| format!("not JSON: {}", e))?;
+    let id = request["requestId"]
+        .as_str()
+        .ok_or("requestId missing or not a string")?;
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!(
+            "requestId {:?} must be 1 to {} letters, digits, '-' or '_'",
+            id, MAX_REQUEST_ID_LEN
+        ));
+    }
+    Ok(id.to_string())
+}
+
+/// What the job handler knows about itself when a request comes in
+#[derive(Debug, Clone, Default)]
+pub struct HandlerStats {
+    pub thing_name: String,
+    pub config_hash: String,
+    pub uptime_secs: u64,
+    pub system_uptime_secs: Option<u64>,
+    /// `(thing name, job ID)` of the running job
+    pub running_job: Option<(String, String)>,
+    /// Job IDs waiting in the on-device queue, next first
+    pub queued_on_device: Vec<String>,
+    /// Jobs AWS last reported as queued, over every served thing
+    pub queued_in_aws: usize,
+    /// Epoch millis of the last message on a Jobs topic
+    pub last_jobs_api_contact: Option<i64>,
+    /// Update responses seen for updates another client made
+    pub foreign_updates: u64,
+    /// Newest last
+    pub recent_jobs: Vec<HistoryRecord>,
+}
+
+/// The response to request `request_id`, with `metrics` as returned by
+/// [`crate::metrics::Metrics::to_json`]
+pub fn response(request_id: &str, stats: &HandlerStats, metrics: Value) -> Value {
+    let recent_jobs: Vec<Value> = stats
+        .recent_jobs
+        .iter()
+        .rev()
+        .map(|record| {
+            let mut summary = json!({
+                "jobId": record.job_id,
+                "thingName": record.thing_name,
+                "executionId": record.execution_id,
+                "status": record.status,
+                "startedAt": record.started_at,
+                "finishedAt": record.finished_at,
+                "steps": record.steps.len(),
+            });
+            if let Some(error) = &record.error {
+                summary["error"] = error.clone().into();
+            }
+            summary
+        })
+        .collect();
+    json!({
+        "requestId": request_id,
+        "thingName": stats.thing_name,
+        "timestamp": chrono::Utc::now().timestamp_millis(),
+        "componentVersion": env!("CARGO_PKG_VERSION"),
+        "configHash": stats.config_hash,
+        "uptimeSecs": stats.uptime_secs,
+        "systemUptimeSecs": stats.system_uptime_secs,
+        "runningJob": stats.running_job.as_ref().map(|(thing_name, job_id)| {
+            json!({ "thingName": thing_name, "jobId": job_id })
+        }),
+        "queuedOnDevice": stats.queued_on_device,
+        "queuedInAws": stats.queued_in_aws,
+        "lastJobsApiContact": stats.last_jobs_api_contact,
+        "foreignUpdates": stats.foreign_updates,
+        "metrics": metrics,
+        "recentJobs": recent_jobs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(
+            request_id(br#"{"requestId":"req-7_a"}"#).unwrap(),
+            "req-7_a"
+        );
+        for payload in [
+            &b"not json"[..],
+            br#"{}"#,
+            br#"{"requestId":7}"#,
+            br#"{"requestId":""}"#,
+            br#"{"requestId":"a/b"}"#,
+            br##"{"requestId":"#"}"##,
+        ] {
+            assert!(
+                request_id(payload).is_err(),
+                "{}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+        let long = format!(
+            r#"{{"requestId":"{}"}}"#,
+            "a".repeat(MAX_REQUEST_ID_LEN + 1)
+        );
+        assert!(request_id(long.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_response_lists_newest_job_first() {
+        let record = |job_id: &str, status: &str| HistoryRecord {
+            job_id: job_id.to_string(),
+            thing_name: "core-1".to_string(),
+            execution_id: format!("run-{}", job_id),
+            document_hash: "00".to_string(),
+            status: status.to_string(),
+            started_at: 1,
+            finished_at: 2,
+            steps: Vec::new(),
+            error: (status == "FAILED").then(|| "Step 'Install' failed".to_string()),
+            device: None,
+        };
+        let stats = HandlerStats {
+            thing_name: "core-1".to_string(),
+            config_hash: "abc".to_string(),
+            uptime_secs: 30,
+            running_job: Some(("core-1".to_string(), "job-3".to_string())),
+            queued_on_device: vec!["job-4".to_string()],
+            queued_in_aws: 2,
+            last_jobs_api_contact: Some(1_700_000_000_000),
+            foreign_updates: 3,
+            recent_jobs: vec![record("job-1", "SUCCEEDED"), record("job-2", "FAILED")],
+            ..Default::default()
+        };
+
+        let response = response("req-1", &stats, json!({ "queue_depth": 2 }));
+        assert_eq!(response["requestId"], "req-1");
+        assert_eq!(response["configHash"], "abc");
+        assert_eq!(response["runningJob"]["jobId"], "job-3");
+        assert_eq!(response["queuedOnDevice"], json!(["job-4"]));
+        assert_eq!(response["queuedInAws"], 2);
+        assert_eq!(response["lastJobsApiContact"], 1_700_000_000_000_i64);
+        assert_eq!(response["foreignUpdates"], 3);
+        assert_eq!(response["metrics"]["queue_depth"], 2);
+        assert_eq!(response["recentJobs"][0]["jobId"], "job-2");
+        assert_eq!(response["recentJobs"][0]["error"], "Step 'Install' failed");
+        assert_eq!(response["recentJobs"][1]["jobId"], "job-1");
+        assert!(response["recentJobs"][1].get("error").is_none());
+        assert!(response["systemUptimeSecs"].is_null());
+    }
+}
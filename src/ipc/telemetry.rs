@@ -0,0 +1,224 @@
+//! Greengrass telemetry: the job counters of [`crate::metrics`] published
+//! every `telemetry.interval_secs` in the request format of the CloudWatch
+//! metrics component (`aws.greengrass.Cloudwatch`), so they reach CloudWatch
+//! next to the nucleus metrics on fleets without Prometheus.
+//!
+//! Counters go out as the change since they were last published. A flush
+//! skipped while the session is down, or a publish that fails, leaves the
+//! change to be summed into the next flush.
+
+use crate::metrics::{JobOutcome, Snapshot};
+use serde_json::{json, Value};
+
+/// One value in a telemetry flush
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryMetric {
+    JobsSucceeded,
+    JobsFailed,
+    JobsTimedOut,
+    /// Mean time jobs finished since the last flush spent running their steps
+    JobDurationAverage,
+    /// Jobs AWS last reported as queued; a gauge, sent as is
+    QueueDepth,
+}
+
+impl TelemetryMetric {
+    pub const ALL: [TelemetryMetric; 5] = [
+        Self::JobsSucceeded,
+        Self::JobsFailed,
+        Self::JobsTimedOut,
+        Self::JobDurationAverage,
+        Self::QueueDepth,
+    ];
+
+    /// CloudWatch metric name
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::JobsSucceeded => "JobsSucceeded",
+            Self::JobsFailed => "JobsFailed",
+            Self::JobsTimedOut => "JobsTimedOut",
+            Self::JobDurationAverage => "JobDurationAverage",
+            Self::QueueDepth => "QueueDepth",
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Self::JobDurationAverage => "Seconds",
+            _ => "Count",
+        }
+    }
+
+    fn outcome(self) -> Option<JobOutcome> {
+        match self {
+            Self::JobsSucceeded => Some(JobOutcome::Succeeded),
+            Self::JobsFailed => Some(JobOutcome::Failed),
+            Self::JobsTimedOut => Some(JobOutcome::TimedOut),
+            Self::JobDurationAverage | Self::QueueDepth => None,
+        }
+    }
+}
+
+/// Tracks what each metric last published, so every flush reports the
+/// change since then
+#[derive(Debug, Default)]
+pub struct TelemetryEmitter {
+    /// Job counts as last published, indexed by the job metrics' position
+    sent_jobs: [u64; 3],
+    sent_duration_count: u64,
+    sent_duration_sum_secs: f64,
+}
+
+impl TelemetryEmitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of `metric` to publish for `current`; none for the average when
+    /// no job finished since it was last published
+    pub fn value(&self, metric: TelemetryMetric, current: &Snapshot) -> Option<f64> {
+        if let Some(outcome) = metric.outcome() {
+            return Some((current.jobs(outcome) - self.sent_jobs[metric as usize]) as f64);
+        }
+        match metric {
+            TelemetryMetric::JobDurationAverage => {
+                let count = current.job_duration_count - self.sent_duration_count;
+                let sum = current.job_duration_sum_secs - self.sent_duration_sum_secs;
+                (count > 0).then(|| sum / count as f64)
+            }
+            _ => Some(current.queue_depth as f64),
+        }
+    }
+
+    /// Record that `metric` was published with its value for `current`
+    pub fn sent(&mut self, metric: TelemetryMetric, current: &Snapshot) {
+        if let Some(outcome) = metric.outcome() {
+            self.sent_jobs[metric as usize] = current.jobs(outcome);
+        } else if metric == TelemetryMetric::JobDurationAverage {
+            self.sent_duration_count = current.job_duration_count;
+            self.sent_duration_sum_secs = current.job_duration_sum_secs;
+        }
+    }
+}
+
+/// A `cloudwatch/metric/put` request for one value, with the core device's
+/// thing name as the only dimension
+pub fn metric_request(
+    namespace: &str,
+    thing_name: &str,
+    metric: TelemetryMetric,
+    value: f64,
+    timestamp_secs: i64,
+) -> Value {
+    json!({
+        "request": {
+            "namespace": namespace,
+            "metricData": {
+                "metricName": metric.name(),
+                "dimensions": [{ "name": "ThingName", "value": thing_name }],
+                "timestamp": timestamp_secs,
+                "value": value,
+                "unit": metric.unit(),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn test_unpublished_changes_carry_over() {
+        let metrics = Metrics::new();
+        let mut emitter = TelemetryEmitter::new();
+        metrics.record_job(JobOutcome::Succeeded, Some(Duration::from_secs(2)));
+        metrics.record_job(JobOutcome::TimedOut, Some(Duration::from_secs(10)));
+        metrics.set_queue_depth(3);
+
+        let current = metrics.snapshot();
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobsSucceeded, &current),
+            Some(1.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobsFailed, &current),
+            Some(0.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobsTimedOut, &current),
+            Some(1.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobDurationAverage, &current),
+            Some(6.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::QueueDepth, &current),
+            Some(3.0)
+        );
+
+        // Only the succeeded count went out before the session dropped
+        emitter.sent(TelemetryMetric::JobsSucceeded, &current);
+        metrics.record_job(JobOutcome::Succeeded, Some(Duration::from_secs(4)));
+        metrics.record_job(JobOutcome::TimedOut, None);
+
+        let current = metrics.snapshot();
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobsSucceeded, &current),
+            Some(1.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobsTimedOut, &current),
+            Some(2.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobDurationAverage, &current),
+            Some(16.0 / 3.0)
+        );
+
+        for metric in TelemetryMetric::ALL {
+            emitter.sent(metric, &current);
+        }
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobsTimedOut, &current),
+            Some(0.0)
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::JobDurationAverage, &current),
+            None
+        );
+        assert_eq!(
+            emitter.value(TelemetryMetric::QueueDepth, &current),
+            Some(3.0)
+        );
+    }
+
+    #[test]
+    fn test_metric_request_format() {
+        let request = metric_request(
+            "DeviceOps",
+            "core-1",
+            TelemetryMetric::JobDurationAverage,
+            1.5,
+            1700000000,
+        );
+        assert_eq!(
+            request,
+            json!({
+                "request": {
+                    "namespace": "DeviceOps",
+                    "metricData": {
+                        "metricName": "JobDurationAverage",
+                        "dimensions": [{ "name": "ThingName", "value": "core-1" }],
+                        "timestamp": 1700000000,
+                        "value": 1.5,
+                        "unit": "Seconds",
+                    }
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use crate::clock::{Clock, SystemClock};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Enforces a minimum spacing between status updates for the same job, so a
+/// terminal update is never published right on the heels of a progress update
+pub struct StatusThrottle {
+    min_interval: Duration,
+    clock: Arc<dyn Clock>,
+    /// When the most recent update for each job was (or will be) sent
+    last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl StatusThrottle {
+    pub fn new(min_interval: Duration) -> Self {
+        Self::with_clock(min_interval, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(min_interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            min_interval,
+            clock,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserve the next send slot for a job and return how long to wait for it.
+    /// Slots are handed out in call order, so updates keep their order.
+    pub fn reserve(&self, job_id: &str, terminal: bool) -> Duration {
+        if self.min_interval.is_zero() {
+            return Duration::ZERO;
+        }
+
+        let now = self.clock.now();
+        let mut last_sent = self.last_sent.lock().unwrap();
+
+        // Forget jobs whose last update is far enough in the past
+        last_sent.retain(|_, sent| now.saturating_duration_since(*sent) < self.min_interval);
+
+        let slot = match last_sent.get(job_id) {
+            Some(previous) => (*previous + self.min_interval).max(now),
+            None => now,
+        };
+
+        if terminal {
+            last_sent.remove(job_id);
+        } else {
+            last_sent.insert(job_id.to_string(), slot);
+        }
+
+        slot - now
+    }
+
+    /// Wait until the job's next update may be sent
+    pub async fn wait(&self, job_id: &str, terminal: bool) {
+        let delay = self.reserve(job_id, terminal);
+        if !delay.is_zero() {
+            tracing::debug!(job_id = %job_id, delay_ms = delay.as_millis() as u64, "Spacing job status update");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+impl std::fmt::Debug for StatusThrottle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatusThrottle")
+            .field("min_interval", &self.min_interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Window over which `PerMinuteLimiter` counts
+const LIMITER_WINDOW: Duration = Duration::from_secs(60);
+
+/// Caps how many requests in a sliding minute are acted on: reconnect
+/// signals leading to job queries, so a reconnection storm cannot flood the
+/// broker with `$next/get` requests, and stats requests
+pub struct PerMinuteLimiter {
+    /// Requests allowed per minute (0 disables the cap)
+    max_per_minute: u32,
+    clock: Arc<dyn Clock>,
+    /// When each request of the last minute was allowed, oldest first
+    recent: VecDeque<Instant>,
+}
+
+impl PerMinuteLimiter {
+    pub fn new(max_per_minute: u32) -> Self {
+        Self::with_clock(max_per_minute, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(max_per_minute: u32, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            max_per_minute,
+            clock,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Whether a request may be acted on now; an allowed request is counted
+    pub fn allow(&mut self) -> bool {
+        if self.max_per_minute == 0 {
+            return true;
+        }
+
+        let now = self.clock.now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= LIMITER_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+
+        if self.recent.len() >= self.max_per_minute as usize {
+            return false;
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
+
+impl std::fmt::Debug for PerMinuteLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerMinuteLimiter")
+            .field("max_per_minute", &self.max_per_minute)
+            .field("recent", &self.recent.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const INTERVAL: Duration = Duration::from_millis(200);
+
+    fn throttle() -> (StatusThrottle, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new());
+        (StatusThrottle::with_clock(INTERVAL, clock.clone()), clock)
+    }
+
+    #[test]
+    fn test_first_update_is_immediate() {
+        let (throttle, _clock) = throttle();
+        assert_eq!(throttle.reserve("job-1", false), Duration::ZERO);
+        // Other jobs are spaced independently
+        assert_eq!(throttle.reserve("job-2", false), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_back_to_back_updates_are_spaced() {
+        let (throttle, clock) = throttle();
+        assert_eq!(throttle.reserve("job-1", false), Duration::ZERO);
+
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(throttle.reserve("job-1", true), Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_slots_queue_in_call_order() {
+        let (throttle, _clock) = throttle();
+        assert_eq!(throttle.reserve("job-1", false), Duration::ZERO);
+        assert_eq!(throttle.reserve("job-1", false), INTERVAL);
+        // The terminal update lands after both progress updates
+        assert_eq!(throttle.reserve("job-1", true), INTERVAL * 2);
+    }
+
+    #[test]
+    fn test_no_wait_after_interval_elapsed() {
+        let (throttle, clock) = throttle();
+        throttle.reserve("job-1", false);
+
+        clock.advance(INTERVAL);
+        assert_eq!(throttle.reserve("job-1", false), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_terminal_update_forgets_job() {
+        let (throttle, _clock) = throttle();
+        throttle.reserve("job-1", false);
+        throttle.reserve("job-1", true);
+        assert!(throttle.last_sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_zero_interval_disables_spacing() {
+        let clock = Arc::new(MockClock::new());
+        let throttle = StatusThrottle::with_clock(Duration::ZERO, clock);
+        throttle.reserve("job-1", false);
+        assert_eq!(throttle.reserve("job-1", true), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_reconnect_queries_capped_per_minute() {
+        let clock = Arc::new(MockClock::new());
+        let mut limiter = PerMinuteLimiter::with_clock(2, clock.clone());
+        assert!(limiter.allow());
+        clock.advance(Duration::from_secs(20));
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+
+        // The first query leaves the window a minute after it was allowed
+        clock.advance(Duration::from_secs(40));
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+
+        // Dropped signals do not count against the window
+        clock.advance(Duration::from_secs(20));
+        assert!(limiter.allow());
+    }
+
+    #[test]
+    fn test_zero_disables_reconnect_query_cap() {
+        let mut limiter = PerMinuteLimiter::with_clock(0, Arc::new(MockClock::new()));
+        assert!((0..100).all(|_| limiter.allow()));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::config::TopicTemplates;
+
+/// Placeholder for the thing name in topic templates
+pub const THING_NAME: &str = "{thingName}";
+/// Placeholder for the job ID in topic templates
+pub const JOB_ID: &str = "{jobId}";
+
+const DEFAULT_RECONNECT: &str = "reconnect/{thingName}";
+const DEFAULT_HEARTBEAT: &str = "device-ops/{thingName}/liveness";
+const DEFAULT_STATS: &str = "device-ops/{thingName}/stats";
+
+/// Fill in a topic template for a thing
+fn render(template: &str, thing_name: &str) -> String {
+    template.replace(THING_NAME, thing_name)
+}
+
+/// Topic the core listens on for reconnect signals
+pub fn reconnect_topic(templates: &TopicTemplates, thing_name: &str) -> String {
+    render(
+        templates.reconnect.as_deref().unwrap_or(DEFAULT_RECONNECT),
+        thing_name,
+    )
+}
+
+/// IoT Core topic of the heartbeats and the startup self-test report
+pub fn heartbeat_topic(templates: &TopicTemplates, thing_name: &str) -> String {
+    render(
+        templates.heartbeat.as_deref().unwrap_or(DEFAULT_HEARTBEAT),
+        thing_name,
+    )
+}
+
+fn stats_topic(templates: &TopicTemplates, thing_name: &str) -> String {
+    render(
+        templates.stats.as_deref().unwrap_or(DEFAULT_STATS),
+        thing_name,
+    )
+}
+
+/// Topic the core listens on for runtime statistics requests
+pub fn stats_request_topic(templates: &TopicTemplates, thing_name: &str) -> String {
+    format!("{}/request", stats_topic(templates, thing_name))
+}
+
+/// Topic the reply to stats request `request_id` goes to
+pub fn stats_response_topic(
+    templates: &TopicTemplates,
+    thing_name: &str,
+    request_id: &str,
+) -> String {
+    format!(
+        "{}/response/{}",
+        stats_topic(templates, thing_name),
+        request_id
+    )
+}
+
+/// Topic for the results of a job whose document names none, if configured
+pub fn results_topic(templates: &TopicTemplates, thing_name: &str, job_id: &str) -> Option<String> {
+    templates
+        .results
+        .as_deref()
+        .map(|template| render(template, thing_name).replace(JOB_ID, job_id))
+}
+
+/// Local pub/sub loopback topic of the IPC session's liveness probes
+pub fn liveness_probe_topic(thing_name: &str) -> String {
+    format!("device-ops/probe/{}", thing_name)
+}
+
+/// Local pub/sub topic a running job's output lines are published on
+/// (`execution.live_output`)
+pub fn live_output_topic(job_id: &str) -> String {
+    format!("device-ops/live/{}", job_id)
+}
+
+/// Builds the IoT Jobs MQTT topics for a thing, optionally under a jobs
+/// namespace, or from the configured `ipc.topics` templates
+///
+/// Default form: `$aws/things/{thing}/jobs/...`
+/// Namespaced form: `$aws/things/{thing}/jobs/{namespace}/...`
+#[derive(Debug, Clone)]
+pub struct JobsTopics {
+    notify_next: String,
+    next_get: String,
+    pending_get: String,
+    /// Still holding `{jobId}`
+    update: String,
+    /// Still holding `{jobId}`
+    describe: String,
+}
+
+impl JobsTopics {
+    /// The AWS IoT Jobs topics
+    pub fn new(thing_name: &str, namespace: Option<&str>) -> Self {
+        Self::from_templates(thing_name, namespace, &TopicTemplates::default())
+    }
+
+    /// Topics from `templates`; those left unset are the AWS IoT Jobs topics,
+    /// under `namespace` if one is given
+    pub fn from_templates(
+        thing_name: &str,
+        namespace: Option<&str>,
+        templates: &TopicTemplates,
+    ) -> Self {
+        let base = match namespace.map(|ns| ns.trim_matches('/')) {
+            Some(ns) if !ns.is_empty() => format!("$aws/things/{}/jobs/{}", THING_NAME, ns),
+            _ => format!("$aws/things/{}/jobs", THING_NAME),
+        };
+        let topic = |template: &Option<String>, default: &str| {
+            let topic = match template {
+                Some(template) => template.clone(),
+                None => format!("{}/{}", base, default),
+            };
+            render(&topic, thing_name)
+        };
+        Self {
+            notify_next: topic(&templates.notify, "notify-next"),
+            next_get: topic(&templates.next_get, "$next/get"),
+            pending_get: topic(&templates.pending_get, "get"),
+            update: topic(&templates.update, "{jobId}/update"),
+            describe: topic(&templates.describe, "{jobId}/get"),
+        }
+    }
+
+    pub fn notify_next(&self) -> String {
+        self.notify_next.clone()
+    }
+
+    /// StartNextPendingJobExecution-style `$next/get` request
+    pub fn next_get(&self) -> String {
+        self.next_get.clone()
+    }
+
+    pub fn next_get_accepted(&self) -> String {
+        format!("{}/accepted", self.next_get())
+    }
+
+    pub fn next_get_rejected(&self) -> String {
+        format!("{}/rejected", self.next_get())
+    }
+
+    /// GetPendingJobExecutions request
+    pub fn pending_get(&self) -> String {
+        self.pending_get.clone()
+    }
+
+    pub fn pending_get_accepted(&self) -> String {
+        format!("{}/accepted", self.pending_get())
+    }
+
+    pub fn pending_get_rejected(&self) -> String {
+        format!("{}/rejected", self.pending_get())
+    }
+
+    /// UpdateJobExecution request; pass `+` as the job ID for wildcard subscriptions
+    pub fn update(&self, job_id: &str) -> String {
+        self.update.replace(JOB_ID, job_id)
+    }
+
+    pub fn update_accepted(&self, job_id: &str) -> String {
+        format!("{}/accepted", self.update(job_id))
+    }
+
+    pub fn update_rejected(&self, job_id: &str) -> String {
+        format!("{}/rejected", self.update(job_id))
+    }
+
+    /// DescribeJobExecution request
+    pub fn describe(&self, job_id: &str) -> String {
+        self.describe.replace(JOB_ID, job_id)
+    }
+
+    pub fn describe_accepted(&self, job_id: &str) -> String {
+        format!("{}/accepted", self.describe(job_id))
+    }
+
+    pub fn describe_rejected(&self, job_id: &str) -> String {
+        format!("{}/rejected", self.describe(job_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_topics() {
+        let topics = JobsTopics::new("device-1", None);
+
+        assert_eq!(
+            topics.notify_next(),
+            "$aws/things/device-1/jobs/notify-next"
+        );
+        assert_eq!(topics.next_get(), "$aws/things/device-1/jobs/$next/get");
+        assert_eq!(
+            topics.next_get_accepted(),
+            "$aws/things/device-1/jobs/$next/get/accepted"
+        );
+        assert_eq!(
+            topics.next_get_rejected(),
+            "$aws/things/device-1/jobs/$next/get/rejected"
+        );
+        assert_eq!(topics.pending_get(), "$aws/things/device-1/jobs/get");
+        assert_eq!(
+            topics.pending_get_accepted(),
+            "$aws/things/device-1/jobs/get/accepted"
+        );
+        assert_eq!(
+            topics.pending_get_rejected(),
+            "$aws/things/device-1/jobs/get/rejected"
+        );
+        assert_eq!(
+            topics.update("job-1"),
+            "$aws/things/device-1/jobs/job-1/update"
+        );
+        assert_eq!(
+            topics.update_accepted("+"),
+            "$aws/things/device-1/jobs/+/update/accepted"
+        );
+        assert_eq!(
+            topics.update_rejected("+"),
+            "$aws/things/device-1/jobs/+/update/rejected"
+        );
+        assert_eq!(
+            topics.describe("job-1"),
+            "$aws/things/device-1/jobs/job-1/get"
+        );
+        assert_eq!(
+            topics.describe_accepted("job-1"),
+            "$aws/things/device-1/jobs/job-1/get/accepted"
+        );
+        assert_eq!(
+            topics.describe_rejected("job-1"),
+            "$aws/things/device-1/jobs/job-1/get/rejected"
+        );
+    }
+
+    #[test]
+    fn test_namespaced_topics() {
+        let topics = JobsTopics::new("device-1", Some("staging"));
+
+        assert_eq!(
+            topics.notify_next(),
+            "$aws/things/device-1/jobs/staging/notify-next"
+        );
+        assert_eq!(
+            topics.next_get(),
+            "$aws/things/device-1/jobs/staging/$next/get"
+        );
+        assert_eq!(
+            topics.next_get_accepted(),
+            "$aws/things/device-1/jobs/staging/$next/get/accepted"
+        );
+        assert_eq!(
+            topics.next_get_rejected(),
+            "$aws/things/device-1/jobs/staging/$next/get/rejected"
+        );
+        assert_eq!(
+            topics.pending_get(),
+            "$aws/things/device-1/jobs/staging/get"
+        );
+        assert_eq!(
+            topics.pending_get_accepted(),
+            "$aws/things/device-1/jobs/staging/get/accepted"
+        );
+        assert_eq!(
+            topics.update("job-1"),
+            "$aws/things/device-1/jobs/staging/job-1/update"
+        );
+        assert_eq!(
+            topics.update_accepted("+"),
+            "$aws/things/device-1/jobs/staging/+/update/accepted"
+        );
+        assert_eq!(
+            topics.update_rejected("+"),
+            "$aws/things/device-1/jobs/staging/+/update/rejected"
+        );
+        assert_eq!(
+            topics.describe("job-1"),
+            "$aws/things/device-1/jobs/staging/job-1/get"
+        );
+        assert_eq!(
+            topics.describe_accepted("job-1"),
+            "$aws/things/device-1/jobs/staging/job-1/get/accepted"
+        );
+        assert_eq!(
+            topics.describe_rejected("job-1"),
+            "$aws/things/device-1/jobs/staging/job-1/get/rejected"
+        );
+    }
+
+    #[test]
+    fn test_empty_namespace_uses_default_topics() {
+        let topics = JobsTopics::new("device-1", Some(""));
+        assert_eq!(
+            topics.notify_next(),
+            "$aws/things/device-1/jobs/notify-next"
+        );
+    }
+
+    #[test]
+    fn test_default_signal_topics() {
+        let templates = TopicTemplates::default();
+        assert_eq!(reconnect_topic(&templates, "core-1"), "reconnect/core-1");
+        assert_eq!(
+            heartbeat_topic(&templates, "core-1"),
+            "device-ops/core-1/liveness"
+        );
+        assert_eq!(results_topic(&templates, "core-1", "job-1"), None);
+        assert_eq!(
+            stats_request_topic(&templates, "core-1"),
+            "device-ops/core-1/stats/request"
+        );
+        assert_eq!(
+            stats_response_topic(&templates, "core-1", "req-7"),
+            "device-ops/core-1/stats/response/req-7"
+        );
+    }
+
+    #[test]
+    fn test_overridden_topics() {
+        let templates = TopicTemplates {
+            notify: Some("bridge/{thingName}/jobs/notify".to_string()),
+            next_get: Some("bridge/{thingName}/jobs/next".to_string()),
+            update: Some("bridge/{thingName}/jobs/{jobId}/status".to_string()),
+            reconnect: Some("bridge/{thingName}/reconnect".to_string()),
+            heartbeat: Some("bridge/{thingName}/heartbeat".to_string()),
+            results: Some("bridge/{thingName}/results/{jobId}".to_string()),
+            stats: Some("bridge/{thingName}/stats".to_string()),
+            ..Default::default()
+        };
+        let topics = JobsTopics::from_templates("device-1", Some("staging"), &templates);
+
+        assert_eq!(topics.notify_next(), "bridge/device-1/jobs/notify");
+        assert_eq!(
+            topics.next_get_accepted(),
+            "bridge/device-1/jobs/next/accepted"
+        );
+        assert_eq!(
+            topics.update_accepted("+"),
+            "bridge/device-1/jobs/+/status/accepted"
+        );
+        assert_eq!(topics.update("job-1"), "bridge/device-1/jobs/job-1/status");
+        // Templates left unset keep the AWS topics, with the namespace
+        assert_eq!(
+            topics.pending_get(),
+            "$aws/things/device-1/jobs/staging/get"
+        );
+        assert_eq!(
+            topics.describe("job-1"),
+            "$aws/things/device-1/jobs/staging/job-1/get"
+        );
+
+        assert_eq!(
+            reconnect_topic(&templates, "core-1"),
+            "bridge/core-1/reconnect"
+        );
+        assert_eq!(
+            heartbeat_topic(&templates, "core-1"),
+            "bridge/core-1/heartbeat"
+        );
+        assert_eq!(
+            results_topic(&templates, "device-1", "job-1").as_deref(),
+            Some("bridge/device-1/results/job-1")
+        );
+        assert_eq!(
+            stats_response_topic(&templates, "core-1", "req-7"),
+            "bridge/core-1/stats/response/req-7"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::ipc::component_config::{ComponentConfigSource, UpdateHandler};
+use gg_sdk::{Qos, Sdk};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+/// Handler invoked with `(topic, payload)` for every message on a subscription
+pub type MessageHandler = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+/// The slice of Greengrass IPC that `IpcClient` uses. Errors carry the
+/// underlying cause; callers add context.
+pub trait IotCoreTransport: Debug + Send + Sync {
+    fn connect(&self) -> Result<(), String>;
+    /// Drop the current session and open a new one. Subscriptions of the old
+    /// session are gone afterwards and have to be made again.
+    fn reconnect(&self) -> Result<(), String>;
+    /// Subscriptions stay active for the lifetime of the session
+    fn subscribe(&self, topic: &str, qos: Qos, handler: MessageHandler) -> Result<(), String>;
+    fn publish(&self, topic: &str, payload: &[u8], qos: Qos) -> Result<(), String>;
+    /// Publish on local pub/sub, to other components on the core
+    fn publish_local(&self, topic: &str, payload: &[u8]) -> Result<(), String>;
+    /// Subscribe on local pub/sub; like `subscribe`, for the session's lifetime
+    fn subscribe_local(&self, topic: &str, handler: MessageHandler) -> Result<(), String>;
+}
+
+/// Callback the SDK calls on component configuration updates
+type ConfigUpdateCallback = Box<dyn Fn(&str, &[&str]) + Send + Sync>;
+
+/// Callback the SDK calls with each message on a subscription
+type MessageCallback = Box<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+/// The SDK keeps subscription callbacks for the life of the program, so each
+/// topic's callback is leaked once and forwards to whichever handler
+/// subscribed to the topic last. Reconnects reuse it, which bounds the leak
+/// by the number of distinct topics.
+struct TopicCallback {
+    handler: Arc<Mutex<MessageHandler>>,
+    callback: &'static MessageCallback,
+}
+
+impl TopicCallback {
+    fn for_topic(
+        callbacks: &mut HashMap<String, TopicCallback>,
+        topic: &str,
+        handler: MessageHandler,
+    ) -> &'static MessageCallback {
+        if let Some(existing) = callbacks.get(topic) {
+            *existing.handler.lock().unwrap() = handler;
+            return existing.callback;
+        }
+        let slot = Arc::new(Mutex::new(handler));
+        let current = slot.clone();
+        let callback: MessageCallback = Box::new(move |topic: &str, payload: &[u8]| {
+            let handler = current.lock().unwrap().clone();
+            handler(topic, payload)
+        });
+        let callback: &'static MessageCallback = Box::leak(Box::new(callback));
+        callbacks.insert(
+            topic.to_string(),
+            TopicCallback {
+                handler: slot,
+                callback,
+            },
+        );
+        callback
+    }
+}
+
+/// Transport backed by the Greengrass component SDK. Clones share one IPC
+/// session, which also serves the component configuration
+/// ([`ComponentConfigSource`]).
+#[derive(Clone)]
+pub struct GreengrassTransport {
+    session: Arc<Mutex<Session>>,
+}
+
+struct Session {
+    sdk: Sdk,
+    connected: bool,
+    /// Subscribed again on every new session
+    config_updates: Option<&'static ConfigUpdateCallback>,
+    iot_core_callbacks: HashMap<String, TopicCallback>,
+    local_callbacks: HashMap<String, TopicCallback>,
+}
+
+impl GreengrassTransport {
+    pub fn new() -> Self {
+        Self {
+            session: Arc::new(Mutex::new(Session {
+                sdk: Sdk::init(),
+                connected: false,
+                config_updates: None,
+                iot_core_callbacks: HashMap::new(),
+                local_callbacks: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl Debug for GreengrassTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GreengrassTransport")
+            .field("connected", &self.session.lock().unwrap().connected)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for GreengrassTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IotCoreTransport for GreengrassTransport {
+    /// Connects once; later calls on any clone reuse the session
+    fn connect(&self) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        if !session.connected {
+            session.sdk.connect().map_err(|e| format!("{:?}", e))?;
+            session.connected = true;
+        }
+        Ok(())
+    }
+
+    fn reconnect(&self) -> Result<(), String> {
+        // Replacing the handle drops the dead session along with its socket
+        let mut session = self.session.lock().unwrap();
+        session.sdk = Sdk::init();
+        session.connected = false;
+        session.sdk.connect().map_err(|e| format!("{:?}", e))?;
+        session.connected = true;
+        if let Some(callback) = session.config_updates {
+            let subscription = session
+                .sdk
+                .subscribe_to_configuration_update(&[], None, callback)
+                .map_err(|e| format!("{:?}", e))?;
+            std::mem::forget(subscription);
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, topic: &str, qos: Qos, handler: MessageHandler) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        let callback = TopicCallback::for_topic(&mut session.iot_core_callbacks, topic, handler);
+        let subscription = session
+            .sdk
+            .subscribe_to_iot_core(topic, qos, callback)
+            .map_err(|e| format!("{:?}", e))?;
+
+        // Dropping the handle would unsubscribe; it goes with the session
+        std::mem::forget(subscription);
+        Ok(())
+    }
+
+    fn publish(&self, topic: &str, payload: &[u8], qos: Qos) -> Result<(), String> {
+        self.session
+            .lock()
+            .unwrap()
+            .sdk
+            .publish_to_iot_core(topic, payload, qos)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn publish_local(&self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        self.session
+            .lock()
+            .unwrap()
+            .sdk
+            .publish_to_topic_binary(topic, payload)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn subscribe_local(&self, topic: &str, handler: MessageHandler) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        let callback = TopicCallback::for_topic(&mut session.local_callbacks, topic, handler);
+        let subscription = session
+            .sdk
+            .subscribe_to_topic_binary(topic, callback)
+            .map_err(|e| format!("{:?}", e))?;
+        std::mem::forget(subscription);
+        Ok(())
+    }
+}
+
+impl ComponentConfigSource for GreengrassTransport {
+    fn get_configuration(&self) -> Result<Value, String> {
+        let json = self
+            .session
+            .lock()
+            .unwrap()
+            .sdk
+            .get_config(&[], None)
+            .map_err(|e| format!("{:?}", e))?;
+        serde_json::from_str(&json).map_err(|e| e.to_string())
+    }
+
+    fn subscribe_to_updates(&self, on_update: UpdateHandler) -> Result<(), String> {
+        // Leaked once and kept across reconnects, as the SDK needs a 'static callback
+        let callback: ConfigUpdateCallback =
+            Box::new(move |_component: &str, _key_path: &[&str]| on_update());
+        let callback: &'static ConfigUpdateCallback = Box::leak(Box::new(callback));
+
+        let mut session = self.session.lock().unwrap();
+        let subscription = session
+            .sdk
+            .subscribe_to_configuration_update(&[], None, callback)
+            .map_err(|e| format!("{:?}", e))?;
+        std::mem::forget(subscription);
+        session.config_updates = Some(callback);
+        Ok(())
+    }
+}
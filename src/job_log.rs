@@ -0,0 +1,253 @@
+//! Per-job log capture
+//!
+//! `JobLogLayer` tees every event emitted inside a `job` span carrying a
+//! `job_id` field into `<dir>/job-<job_id>.log`, so a single job's logs can be
+//! attached to a support bundle. Events inside a `step` span are prefixed
+//! with the step's fields.
+
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+const JOB_LOG_PREFIX: &str = "job-";
+const JOB_LOG_SUFFIX: &str = ".log";
+
+/// Job ID stored in the extensions of a span that declared a `job_id` field
+struct JobLogId(String);
+
+/// `step{name=... index=...}` stored in the extensions of a `step` span
+struct StepContext(String);
+
+pub struct JobLogLayer {
+    dir: PathBuf,
+}
+
+impl JobLogLayer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+/// Path of the log file for a job, with the job ID sanitized for use as a file name
+pub fn job_log_path(dir: &Path, job_id: &str) -> PathBuf {
+    let safe_id: String = job_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{}{}{}", JOB_LOG_PREFIX, safe_id, JOB_LOG_SUFFIX))
+}
+
+/// Remove the oldest job log files so that at most `keep` remain.
+/// Returns the number of files removed.
+pub fn cleanup_job_logs(dir: &Path, keep: usize) -> std::io::Result<usize> {
+    let mut logs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with(JOB_LOG_PREFIX) || !name.ends_with(JOB_LOG_SUFFIX) {
+            continue;
+        }
+        let modified = entry
+            .metadata()?
+            .modified()
+            .unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+        logs.push((modified, entry.path()));
+    }
+
+    if logs.len() <= keep {
+        return Ok(0);
+    }
+
+    // Oldest first
+    logs.sort();
+    let excess = logs.len() - keep;
+    let mut removed = 0;
+    for (_, path) in logs.into_iter().take(excess) {
+        std::fs::remove_file(&path)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// Collects the `job_id` field from span attributes
+struct JobIdVisitor(Option<String>);
+
+impl Visit for JobIdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "job_id" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "job_id" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Renders an event as `message key=value ...`
+struct LineVisitor(String);
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0.push_str(value);
+        } else {
+            let _ = write!(self.0, " {}={}", field.name(), value);
+        }
+    }
+}
+
+impl<S> Layer<S> for JobLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = JobIdVisitor(None);
+        attrs.record(&mut visitor);
+
+        if let (Some(job_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(JobLogId(job_id));
+        }
+
+        if attrs.metadata().name() == "step" {
+            let mut fields = LineVisitor(String::new());
+            attrs.record(&mut fields);
+            if let Some(span) = ctx.span(id) {
+                let context = format!("step{{{}}}: ", fields.0.trim_start());
+                span.extensions_mut().insert(StepContext(context));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+
+        let mut job_id = None;
+        let mut step = None;
+        for span in scope.from_root() {
+            let extensions = span.extensions();
+            if let (None, Some(id)) = (&job_id, extensions.get::<JobLogId>()) {
+                job_id = Some(id.0.clone());
+            }
+            if let Some(context) = extensions.get::<StepContext>() {
+                step = Some(context.0.clone());
+            }
+        }
+        let Some(job_id) = job_id else {
+            return;
+        };
+
+        let mut line = LineVisitor(String::new());
+        event.record(&mut line);
+
+        let metadata = event.metadata();
+        let entry = format!(
+            "{} {:>5} {}: {}{}\n",
+            chrono::Utc::now().to_rfc3339(),
+            metadata.level(),
+            metadata.target(),
+            step.unwrap_or_default(),
+            line.0
+        );
+
+        // Logging must never fail the job; drop the line if the file is unwritable
+        let path = job_log_path(&self.dir, &job_id);
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = file.write_all(entry.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_job_log_path_sanitizes_job_id() {
+        let path = job_log_path(Path::new("/var/logs"), "../etc/passwd");
+        assert_eq!(path, PathBuf::from("/var/logs/job-___etc_passwd.log"));
+    }
+
+    #[test]
+    fn test_cleanup_keeps_newest_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = std::time::SystemTime::now() - std::time::Duration::from_secs(100);
+
+        for i in 0..5u64 {
+            let path = job_log_path(dir.path(), &format!("job{}", i));
+            std::fs::write(&path, "log").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(base + std::time::Duration::from_secs(i))
+                .unwrap();
+        }
+        // Unrelated files are never touched
+        std::fs::write(dir.path().join("other.txt"), "keep").unwrap();
+
+        let removed = cleanup_job_logs(dir.path(), 2).unwrap();
+        assert_eq!(removed, 3);
+
+        assert!(!job_log_path(dir.path(), "job0").exists());
+        assert!(!job_log_path(dir.path(), "job2").exists());
+        assert!(job_log_path(dir.path(), "job3").exists());
+        assert!(job_log_path(dir.path(), "job4").exists());
+        assert!(dir.path().join("other.txt").exists());
+    }
+
+    #[test]
+    fn test_cleanup_under_limit_removes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(job_log_path(dir.path(), "job1"), "log").unwrap();
+
+        assert_eq!(cleanup_job_logs(dir.path(), 5).unwrap(), 0);
+        assert!(job_log_path(dir.path(), "job1").exists());
+    }
+
+    #[test]
+    fn test_layer_captures_only_job_scoped_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(JobLogLayer::new(dir.path().to_path_buf()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside any job");
+            let span = tracing::info_span!("job", job_id = "job-42");
+            let _guard = span.enter();
+            tracing::info!(attempt = 1, "Starting");
+            let step = tracing::info_span!("step", name = "Install", index = 0);
+            step.in_scope(|| tracing::info!("Executing step"));
+        });
+
+        let content = std::fs::read_to_string(job_log_path(dir.path(), "job-42")).unwrap();
+        assert!(content.contains(": Starting attempt=1\n"), "{}", content);
+        assert!(content.contains(": step{name=Install index=0}: Executing step\n"));
+        assert!(!content.contains("outside any job"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Last-run status files
+//!
+//! `last-run.json` describes the most recently finished job and
+//! `last-success.json` the most recent successful one, so scripts on the
+//! device can check how the last job went without MQTT access. Each file is
+//! replaced atomically (written next to it, then renamed over it), so a
+//! reader sees either the old or the new content. When jobs finish out of
+//! order, the one that finished last wins.
+
+use crate::error::{DeviceOpsError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Serializes the read-compare-replace of the files within the component
+static LAST_RUN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Tells apart the temporary files of writes in flight
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Contents of both files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    pub job_id: String,
+    pub thing_name: String,
+    pub execution_id: String,
+    /// Final job status, `SUCCEEDED` or `FAILED`
+    pub status: String,
+    /// Name of the step that failed the job
+    pub failed_step: Option<String>,
+    /// Job outcome as in `device_ops_jobs_total`: `succeeded`, `failed`,
+    /// `timed_out` or `rejected`
+    pub reason_code: String,
+    /// Epoch millis
+    pub started_at: i64,
+    /// Epoch millis
+    pub finished_at: i64,
+    pub component_version: String,
+}
+
+impl LastRun {
+    pub fn succeeded(&self) -> bool {
+        self.status == "SUCCEEDED"
+    }
+}
+
+/// Replace `last_run` with `record` and, if it succeeded, `last_success`
+/// too; a file describing a job that finished later is left as it is
+pub fn write(last_run: &Path, last_success: &Path, record: &LastRun) -> Result<()> {
+    let _guard = LAST_RUN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    replace_if_newer(last_run, record)?;
+    if record.succeeded() {
+        replace_if_newer(last_success, record)?;
+    }
+    Ok(())
+}
+
+/// Read one of the files; `None` if it is missing or unreadable
+pub fn read(path: &Path) -> Option<LastRun> {
+    let content = std::fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+fn replace_if_newer(path: &Path, record: &LastRun) -> Result<()> {
+    if read(path).is_some_and(|current| current.finished_at > record.finished_at) {
+        tracing::debug!(path = %path.display(), "Keeping status file of a later job");
+        return Ok(());
+    }
+
+    let content = serde_json::to_vec_pretty(record).map_err(DeviceOpsError::execution_json(
+        "Failed to serialize last run",
+    ))?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| last_run_error(path, e))?;
+    }
+    // Same directory, so the rename cannot cross filesystems
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = Path::new(&tmp_name);
+    let written = std::fs::File::create(tmp_path)
+        .and_then(|mut file| {
+            file.write_all(&content)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(tmp_path, path));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(tmp_path);
+        return Err(last_run_error(path, e));
+    }
+    Ok(())
+}
+
+fn last_run_error(path: &Path, e: std::io::Error) -> DeviceOpsError {
+    DeviceOpsError::execution_io(format!("Failed to write status file {}", path.display()))(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(job_id: &str, status: &str, finished_at: i64) -> LastRun {
+        LastRun {
+            job_id: job_id.to_string(),
+            thing_name: "core-1".to_string(),
+            execution_id: format!("run-{}", job_id),
+            status: status.to_string(),
+            failed_step: (status == "FAILED").then(|| "Install".to_string()),
+            reason_code: if status == "SUCCEEDED" {
+                "succeeded"
+            } else {
+                "failed"
+            }
+            .to_string(),
+            started_at: finished_at - 100,
+            finished_at,
+            component_version: "1.0.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_last_success_only_follows_successes() {
+        let dir = tempfile::tempdir().unwrap();
+        let last_run = dir.path().join("last-run.json");
+        let last_success = dir.path().join("last-success.json");
+
+        write(&last_run, &last_success, &record("job-1", "FAILED", 1_000)).unwrap();
+        assert_eq!(read(&last_run).unwrap().job_id, "job-1");
+        assert!(!last_success.exists());
+
+        write(
+            &last_run,
+            &last_success,
+            &record("job-2", "SUCCEEDED", 2_000),
+        )
+        .unwrap();
+        write(&last_run, &last_success, &record("job-3", "FAILED", 3_000)).unwrap();
+        let run = read(&last_run).unwrap();
+        assert_eq!(run.job_id, "job-3");
+        assert_eq!(run.failed_step.as_deref(), Some("Install"));
+        assert_eq!(read(&last_success).unwrap().job_id, "job-2");
+    }
+
+    #[test]
+    fn test_job_that_finished_later_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let last_run = dir.path().join("last-run.json");
+        let last_success = dir.path().join("last-success.json");
+
+        write(
+            &last_run,
+            &last_success,
+            &record("late", "SUCCEEDED", 5_000),
+        )
+        .unwrap();
+        // Reported after it, but finished earlier
+        write(
+            &last_run,
+            &last_success,
+            &record("early", "SUCCEEDED", 4_000),
+        )
+        .unwrap();
+        assert_eq!(read(&last_run).unwrap().job_id, "late");
+        assert_eq!(read(&last_success).unwrap().job_id, "late");
+    }
+
+    #[test]
+    fn test_readers_never_see_partial_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let last_run = dir.path().join("last-run.json");
+        let last_success = dir.path().join("last-success.json");
+        write(&last_run, &last_success, &record("job-0", "SUCCEEDED", 1)).unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let (last_run, last_success) = (last_run.clone(), last_success.clone());
+                std::thread::spawn(move || {
+                    for n in 0..50 {
+                        let finished_at = 2 + n * 4 + writer;
+                        let job = record(&format!("job-{}", finished_at), "SUCCEEDED", finished_at);
+                        write(&last_run, &last_success, &job).unwrap();
+                    }
+                })
+            })
+            .collect();
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            let content = std::fs::read(&last_run).unwrap();
+            serde_json::from_slice::<LastRun>(&content).unwrap();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(read(&last_run).unwrap().finished_at, 2 + 49 * 4 + 3);
+        // Only the two status files are left, no temporary ones
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
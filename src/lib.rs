@@ -1,9 +1,23 @@
+pub mod backoff;
+pub mod cli;
+pub mod clock;
 pub mod config;
+pub mod device_info;
 pub mod error;
 pub mod executor;
+pub mod history;
 pub mod ipc;
+pub mod job_log;
+pub mod last_run;
+pub mod logging;
+pub mod metrics;
 pub mod models;
+#[cfg(feature = "otlp")]
+pub mod otlp;
 pub mod security;
+pub mod selftest;
+pub mod slow_steps;
+pub mod storage;
 
 pub use config::Config;
 pub use error::{DeviceOpsError, Result};
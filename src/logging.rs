@@ -0,0 +1,348 @@
+//! Log output
+//!
+//! Events go to stdout (stderr for the CLI subcommands) and, with
+//! `logging.file_path`, to a file rotated by size. `logging.format` picks
+//! human-readable text or one JSON object per line; the JSON lines lift the
+//! `job` and `step` span fields (`job_id`, `step_name`, ...) to the top level
+//! next to the event's own fields such as `reason_code`. `logging.levels`
+//! adds per-module levels on top of `RUST_LOG`.
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::error::{DeviceOpsError, Result};
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+/// Filter used when `RUST_LOG` is unset or invalid
+const DEFAULT_FILTER: &str = "device_ops_component=info";
+
+/// The output layer, swapped in once the config is loaded
+pub type OutputLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// `RUST_LOG` (or the default) with `levels` added; a module listed in
+/// `levels` gets that level even if `RUST_LOG` names it too
+pub fn env_filter(config: &LoggingConfig) -> EnvFilter {
+    let mut filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    for (module, level) in &config.levels {
+        match format!("{}={}", module, level).parse() {
+            Ok(directive) => filter = filter.add_directive(directive),
+            Err(e) => {
+                tracing::warn!(module = %module, level = %level, error = %e, "Ignoring log level")
+            }
+        }
+    }
+    filter
+}
+
+/// Output for `config`: stdout or stderr, plus the rotated file when set
+pub fn output_layer(config: &LoggingConfig, to_stderr: bool) -> Result<OutputLayer> {
+    let console = move || -> Box<dyn Write> {
+        if to_stderr {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        }
+    };
+    let file = match &config.file_path {
+        Some(path) => Some(Arc::new(
+            RotatingFile::open(path, config.max_file_bytes, config.max_files).map_err(
+                DeviceOpsError::config_io(format!("Cannot open log file {}", path.display())),
+            )?,
+        )),
+        None => None,
+    };
+
+    Ok(match config.format {
+        // Colours for the console only
+        LogFormat::Text => Box::new(
+            tracing_subscriber::fmt::layer()
+                .with_writer(console)
+                .and_then(file.map(|file| {
+                    tracing_subscriber::fmt::layer()
+                        .with_ansi(false)
+                        .with_writer(file)
+                })),
+        ),
+        LogFormat::Json => match file {
+            Some(file) => Box::new(JsonLayer::new(console.and(file))),
+            None => Box::new(JsonLayer::new(console)),
+        },
+    })
+}
+
+/// Log file that is renamed to `<path>.1` (shifting older ones up to
+/// `<path>.<max_files>`) before a write would take it past `max_bytes`
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    /// The open file and its size
+    current: Mutex<(File, u64)>,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files,
+            current: Mutex::new((file, size)),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Write one whole line, rotating first if it would not fit
+    fn write_line(&self, line: &[u8]) -> std::io::Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if current.1 > 0 && current.1 + line.len() as u64 > self.max_bytes {
+            // The oldest file is overwritten by the rename below it
+            for n in (1..self.max_files).rev() {
+                match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            *current = (file, 0);
+        }
+        current.0.write_all(line)?;
+        current.1 += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Shared through an `Arc` as the writer of the layers; the formatters write
+/// each line in one call
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_line(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fields of a span, kept in its extensions for the JSON lines
+struct JsonFields(Map<String, Value>);
+
+/// Collects fields as JSON values; `message` stays a plain string
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// Writes each event as one JSON object per line: `timestamp`, `level`,
+/// `target`, `message`, the fields of the enclosing spans and the event's
+/// own fields. The `step` span's `name` and `index` become `step_name` and
+/// `step_index`.
+pub struct JsonLayer<W> {
+    make_writer: W,
+}
+
+impl<W> JsonLayer<W> {
+    pub fn new(make_writer: W) -> Self {
+        Self { make_writer }
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        if attrs.metadata().name() == "step" {
+            for (from, to) in [("name", "step_name"), ("index", "step_index")] {
+                if let Some(value) = fields.remove(from) {
+                    fields.insert(to.to_string(), value);
+                }
+            }
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().replace(JsonFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<JsonFields>() {
+                values.record(&mut JsonVisitor(&mut fields.0));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            chrono::Utc::now().to_rfc3339().into(),
+        );
+        line.insert("level".to_string(), metadata.level().as_str().into());
+        line.insert("target".to_string(), metadata.target().into());
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<JsonFields>() {
+                    line.extend(fields.0.clone());
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        let mut text = Value::Object(line).to_string();
+        text.push('\n');
+        // Logging must never fail the caller; a line that cannot be written is lost
+        let _ = self.make_writer.make_writer().write_all(text.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Collects written bytes for inspection
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Captured;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_json_lines_lift_span_fields() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(JsonLayer::new(captured.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside any job");
+            let job = tracing::info_span!("job", thing_name = "core-1", job_id = "job-42");
+            let _job = job.enter();
+            let step = tracing::info_span!("step", name = %"Install", index = 1);
+            let _step = step.enter();
+            tracing::error!(
+                reason_code = "timeout",
+                exit_code = -1,
+                "Step execution failed"
+            );
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "outside any job");
+        assert!(lines[0].get("job_id").is_none());
+
+        let line = &lines[1];
+        assert_eq!(line["job_id"], "job-42");
+        assert_eq!(line["thing_name"], "core-1");
+        assert_eq!(line["step_name"], "Install");
+        assert_eq!(line["step_index"], 1);
+        assert_eq!(line["reason_code"], "timeout");
+        assert_eq!(line["exit_code"], -1);
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["message"], "Step execution failed");
+        assert!(line.get("name").is_none());
+    }
+
+    #[test]
+    fn test_file_rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("component.log");
+        let file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["one 1234\n", "two 1234\n", "three 12\n", "four 123\n"] {
+            (&file).write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "four 123\n");
+        assert_eq!(read(&file.rotated(1)), "three 12\n");
+        assert_eq!(read(&file.rotated(2)), "two 1234\n");
+        // Only max_files rotated files are kept
+        assert!(!file.rotated(3).exists());
+    }
+
+    #[test]
+    fn test_file_size_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("component.log");
+        std::fs::write(&path, "earlier 1\n").unwrap();
+
+        let file = RotatingFile::open(&path, 15, 1).unwrap();
+        (&file).write_all(b"next 123\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(file.rotated(1)).unwrap(),
+            "earlier 1\n"
+        );
+    }
+}
@@ -1,49 +1,242 @@
-mod config;
-mod error;
-mod executor;
-mod ipc;
-mod models;
-mod security;
-
-use config::Config;
-use error::Result;
-use ipc::{IpcClient, JobHandler};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use device_ops_component::cli::Options;
+use device_ops_component::config::LoggingConfig;
+use device_ops_component::executor::command::{verify_default_run_as_user, SystemSudoVerifier};
+use device_ops_component::history;
+use device_ops_component::ipc::component_config::{
+    load_config, sighup_reload_requests, watch_config, ComponentConfigSource,
+};
+use device_ops_component::ipc::transport::{GreengrassTransport, IotCoreTransport};
+use device_ops_component::ipc::{IpcClient, JobHandler};
+use device_ops_component::job_log::JobLogLayer;
+use device_ops_component::logging;
+use device_ops_component::metrics;
+use device_ops_component::selftest::{IpcStatus, SelfTest, SelfTestContext, SystemProbes};
+use device_ops_component::storage::{run_periodic_cleanup, CLEANUP_INTERVAL};
+use device_ops_component::{Config, DeviceOpsError, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
+    let options = Options::parse(std::env::args().skip(1), |name| std::env::var(name).ok())
+        .and_then(|options| options.check_config_path().map(|()| options));
+    // `--print-config` and `history` keep stdout for their output
+    let log_to_stderr = options
+        .as_ref()
+        .is_ok_and(|options| options.print_config || options.history.is_some());
+
+    // Job and step spans go to an OTLP collector when OTEL_EXPORTER_OTLP_ENDPOINT is set
+    #[cfg(feature = "otlp")]
+    let (otlp_layer, _otlp_guard, otlp_error) =
+        match device_ops_component::otlp::init(|name| std::env::var(name).ok()) {
+            Ok(export) => {
+                let (layer, guard) = export.unzip();
+                (layer, guard, None)
+            }
+            Err(e) => (None, None, Some(e)),
+        };
+    #[cfg(not(feature = "otlp"))]
+    let otlp_layer = None::<tracing_subscriber::layer::Identity>;
+
+    // Initialize tracing with the default output and filter; the per-job log
+    // layer and the `logging` section are applied once config is loaded
+    let default_logging = LoggingConfig::default();
+    let (output_layer, output_handle) =
+        reload::Layer::new(logging::output_layer(&default_logging, log_to_stderr)?);
+    let (filter_layer, filter_handle) = reload::Layer::new(logging::env_filter(&default_logging));
+    let (job_log_layer, job_log_handle) = reload::Layer::new(None::<JobLogLayer>);
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "device_ops_component=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
+        .with(output_layer)
+        .with(otlp_layer)
+        .with(job_log_layer)
+        .with(filter_layer)
         .init();
 
     const VERSION: &str = env!("CARGO_PKG_VERSION");
     tracing::info!(version = %VERSION, "Device Operations Component starting");
+    #[cfg(feature = "otlp")]
+    if let Some(e) = otlp_error {
+        tracing::warn!(error = %e, "Spans will not be exported");
+    }
+
+    let options = options.map_err(|e| {
+        tracing::error!(error_kind = e.kind().label(), error = %e, "Invalid command line");
+        e
+    })?;
+    tracing::info!(
+        path = %options.effective_config_path().display(),
+        "Using config file"
+    );
+
+    if let Some(query) = &options.history {
+        // Run by an operator on the device: the config file gives the storage location
+        let config = Config::load_layered(
+            options.config_path.clone(),
+            None,
+            options.profile.as_deref(),
+        )?;
+        let history = history::read(&config.storage.history_file())?;
+        if history.corrupt_lines > 0 {
+            eprintln!("Skipped {} corrupt history lines", history.corrupt_lines);
+        }
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        print!("{}", query.render(&query.select(&history.records, now_ms)));
+        return Ok(());
+    }
 
-    // Load configuration
-    let config = Config::load(None)?;
+    // Load configuration: component configuration over the config file over defaults.
+    // The component configuration is read over the session the IPC client uses.
+    let transport = GreengrassTransport::new();
+    let config_source: Option<Arc<dyn ComponentConfigSource>> = match transport.connect() {
+        Ok(()) => Some(Arc::new(transport.clone())),
+        Err(e) => {
+            tracing::warn!(error = %e, "Component configuration unavailable, using config file only");
+            None
+        }
+    };
+    let profile = options.profile.as_deref();
+    let (config, load_report) = match &config_source {
+        Some(source) => load_config(source.as_ref(), options.config_path.clone(), profile),
+        None => Config::load_layered_with_report(options.config_path.clone(), None, profile),
+    }
+    .map_err(|e| {
+        tracing::error!(error_kind = e.kind().label(), error = %e, "Failed to load configuration");
+        e
+    })?;
     tracing::info!(
-        security_enabled = config.security.enabled,
-        default_timeout = config.execution.default_timeout,
+        config_source = %load_report.source,
+        path = %load_report.path.display(),
+        profile = load_report.profile.as_deref().unwrap_or("none"),
+        component_overrides = load_report.component_overrides,
+        env_overrides = %options.env_overrides.join(","),
         "Configuration loaded"
     );
+    // `--lenient-config` downgrades validation problems to a warning
+    let lenient_config = options.lenient_config;
+    let startup_check = match config.validate() {
+        Ok(()) => verify_default_run_as_user(&config.execution, &SystemSudoVerifier).await,
+        Err(e) => Err(e),
+    };
+    if options.print_config {
+        // For provisioning scripts: the effective config on stdout, problems
+        // on stderr and in the exit status
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config.redacted()).unwrap_or_default()
+        );
+        if let Err(e) = startup_check {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Err(e) = startup_check {
+        if !lenient_config {
+            tracing::error!(error_kind = e.kind().label(), error = %e, "Refusing to start with an invalid configuration");
+            return Err(e);
+        }
+        tracing::warn!(error = %e, "Starting despite configuration problems (--lenient-config)");
+    }
+
+    // Format, file and levels from config; a change takes effect on restart
+    match logging::output_layer(&config.logging, false) {
+        Ok(layer) => {
+            if let Err(e) = output_handle
+                .reload(layer)
+                .and_then(|()| filter_handle.reload(logging::env_filter(&config.logging)))
+            {
+                tracing::warn!(error = %e, "Failed to apply logging config");
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "Keeping default log output"),
+    }
+
+    config.log_effective("startup");
+
+    if let Some(dir) = &config.logging.job_log_dir {
+        match std::fs::create_dir_all(dir) {
+            Ok(()) => {
+                if let Err(e) = job_log_handle.reload(Some(JobLogLayer::new(dir.clone()))) {
+                    tracing::warn!(error = %e, "Failed to enable per-job log capture");
+                } else {
+                    tracing::info!(dir = %dir.display(), "Per-job log capture enabled");
+                }
+            }
+            Err(e) => {
+                tracing::warn!(dir = %dir.display(), error = %e, "Cannot create job log directory, per-job logs disabled");
+            }
+        }
+    }
+
+    // Retention and size caps on stored files, from startup on
+    tokio::spawn(run_periodic_cleanup(
+        config.storage.clone(),
+        CLEANUP_INTERVAL,
+    ));
+
+    // Prometheus metrics for the local scraper or node_exporter's textfile collector
+    if let Some(port) = config.metrics.listen_port {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics::global(), port).await {
+                tracing::warn!(error = %e, "Metrics endpoint unavailable");
+            }
+        });
+    }
+    if let Some(path) = config.metrics.textfile_path.clone() {
+        tokio::spawn(metrics::run_textfile_writer(
+            metrics::global(),
+            path,
+            Duration::from_secs(config.metrics.textfile_interval_secs),
+        ));
+    }
 
     // Create IPC client
-    let ipc_client = IpcClient::new().await?;
-    tracing::info!(thing_name = %ipc_client.thing_name(), "Connected to Greengrass IPC");
+    let ipc_client = IpcClient::with_transport(Box::new(transport), &config.ipc).await;
+    if config.selftest.enabled {
+        run_self_test(&config, &ipc_client).await?;
+    }
+    let ipc_client = ipc_client.map_err(|e| {
+        tracing::error!(error_kind = e.kind().label(), error = %e, "Failed to start IPC client");
+        e
+    })?;
+    tracing::info!(
+        thing_name = %ipc_client.thing_name(),
+        thing_names = ?ipc_client.thing_names(),
+        "Connected to Greengrass IPC"
+    );
 
     // Create and run job handler
-    let mut job_handler = JobHandler::new(ipc_client, config);
+    let mut job_handler = JobHandler::new(ipc_client, config.clone());
+    let file_poll = config
+        .reload
+        .watch_file
+        .then(|| Duration::from_secs(config.reload.poll_interval_secs));
+    // SIGHUP re-reads the config like a file change
+    let reload_requests = sighup_reload_requests()
+        .map_err(|e| tracing::warn!(error = %e, "SIGHUP will not reload the configuration"))
+        .ok();
+    if config_source.is_some() || file_poll.is_some() || reload_requests.is_some() {
+        match watch_config(
+            config_source,
+            options.config_path,
+            options.profile,
+            config,
+            file_poll,
+            reload_requests,
+        ) {
+            Ok(updates) => job_handler = job_handler.with_config_updates(updates),
+            Err(e) => {
+                tracing::warn!(error = %e, "Configuration changes will need a restart");
+            }
+        }
+    }
 
     // Handle graceful shutdown
     tokio::select! {
         result = job_handler.run() => {
             if let Err(e) = result {
-                tracing::error!(error = %e, "Job handler error");
+                tracing::error!(error_kind = e.kind().label(), error = %e, "Job handler error");
                 return Err(e);
             }
         }
@@ -55,3 +248,43 @@ async fn main() -> Result<()> {
     tracing::info!("Device Operations Component stopped");
     Ok(())
 }
+
+/// Check the device's environment, log and publish the report, and with
+/// `selftest.strict` refuse to start when a critical check failed
+async fn run_self_test(config: &Config, ipc_client: &Result<IpcClient>) -> Result<()> {
+    let ipc = match ipc_client {
+        Ok(client) => IpcStatus::Connected {
+            thing_name: client.thing_name().to_string(),
+        },
+        Err(e) => IpcStatus::Failed(e.to_string()),
+    };
+    let context = SelfTestContext {
+        config,
+        ipc: &ipc,
+        probes: &SystemProbes,
+    };
+    let report = SelfTest::standard().run(&context).await;
+    let failures = report.critical_failures();
+    tracing::info!(
+        passed = report.passed(),
+        critical_failures = %failures.join(","),
+        "Self-test finished"
+    );
+    if let Ok(client) = ipc_client {
+        if let Err(e) = client
+            .publish_self_test(&report.to_json(client.thing_name()))
+            .await
+        {
+            tracing::warn!(error = %e, "Failed to publish self-test report");
+        }
+    }
+
+    if config.selftest.strict && !failures.is_empty() {
+        tracing::error!("Refusing to start after failed self-test checks (selftest.strict)");
+        return Err(DeviceOpsError::ConfigError(format!(
+            "Self-test failed: {}",
+            failures.join(", ")
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,792 @@
+//! Prometheus metrics
+//!
+//! Counters and a job duration histogram kept for the whole process and
+//! rendered in the Prometheus text exposition format, either served on
+//! `127.0.0.1:<metrics.listen_port>/metrics` or written for node_exporter's
+//! textfile collector (`metrics.textfile_path`). The Greengrass telemetry
+//! emitter reads the same values through [`Metrics::snapshot`].
+
+use crate::error::{DeviceOpsError, ErrorKind, Result};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Upper bounds of the job duration histogram buckets, in seconds
+const JOB_DURATION_BUCKETS: [f64; 8] = [1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0];
+
+/// Largest HTTP request head read from a scraper
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+static METRICS: Metrics = Metrics::new();
+
+/// The process-wide metrics the component records into
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+/// Final outcome of a job, the `status` label of `device_ops_jobs_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOutcome {
+    Succeeded,
+    Failed,
+    /// Failed because a step was stopped by its timeout or idle timeout
+    TimedOut,
+    /// The document was rejected before any step ran
+    Rejected,
+}
+
+impl JobOutcome {
+    const ALL: [JobOutcome; 4] = [
+        Self::Succeeded,
+        Self::Failed,
+        Self::TimedOut,
+        Self::Rejected,
+    ];
+
+    /// Label value, also the `reason_code` of job failure events
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::TimedOut => "timed_out",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+/// Outcome of a step, the `result` label of `device_ops_steps_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Success,
+    /// Ran, but its exit code or stderr failed it
+    Failure,
+    /// Stopped after its timeout or idle timeout
+    Timeout,
+    /// Could not be run (security rejection, missing script)
+    Error,
+}
+
+impl StepResult {
+    const ALL: [StepResult; 4] = [Self::Success, Self::Failure, Self::Timeout, Self::Error];
+
+    /// Label value, also the `reason_code` of step failure events
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+            Self::Timeout => "timeout",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Security check that refused a step, the `rule` label of
+/// `device_ops_security_denials_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityRule {
+    PathTraversal,
+    CommandAllowlist,
+    PathAllowlist,
+    WorkingDirAllowlist,
+    RunAsUser,
+    ResultTopic,
+}
+
+impl SecurityRule {
+    const ALL: [SecurityRule; 6] = [
+        Self::PathTraversal,
+        Self::CommandAllowlist,
+        Self::PathAllowlist,
+        Self::WorkingDirAllowlist,
+        Self::RunAsUser,
+        Self::ResultTopic,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::PathTraversal => "path_traversal",
+            Self::CommandAllowlist => "command_allowlist",
+            Self::PathAllowlist => "path_allowlist",
+            Self::WorkingDirAllowlist => "working_dir_allowlist",
+            Self::RunAsUser => "run_as_user",
+            Self::ResultTopic => "result_topic",
+        }
+    }
+}
+
+/// What an IPC retry retried, the `kind` label of `device_ops_ipc_retries_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcRetry {
+    /// A throttled `$next/get` request
+    NextJob,
+    /// Reconnecting a dead session
+    Reconnect,
+}
+
+impl IpcRetry {
+    const ALL: [IpcRetry; 2] = [Self::NextJob, Self::Reconnect];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::NextJob => "next_job",
+            Self::Reconnect => "reconnect",
+        }
+    }
+}
+
+/// Which limit a slow step went past, the `limit` label of
+/// `device_ops_slow_steps_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowStepKind {
+    /// `execution.slow_step_warn_secs`
+    Absolute,
+    /// `execution.slow_step_factor` times the step's trailing median
+    Relative,
+}
+
+impl SlowStepKind {
+    const ALL: [SlowStepKind; 2] = [Self::Absolute, Self::Relative];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Absolute => "absolute",
+            Self::Relative => "relative",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// Cumulative counts per bucket of `JOB_DURATION_BUCKETS`
+    buckets: [u64; JOB_DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Point-in-time copy of the values reported through Greengrass telemetry
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Snapshot {
+    jobs: [u64; JobOutcome::ALL.len()],
+    /// `device_ops_job_duration_seconds_sum`
+    pub job_duration_sum_secs: f64,
+    /// `device_ops_job_duration_seconds_count`
+    pub job_duration_count: u64,
+    /// `device_ops_queue_depth`
+    pub queue_depth: u64,
+}
+
+impl Snapshot {
+    /// `device_ops_jobs_total{status=...}` at the time of the snapshot
+    pub fn jobs(&self, outcome: JobOutcome) -> u64 {
+        self.jobs[outcome as usize]
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    jobs: [AtomicU64; JobOutcome::ALL.len()],
+    steps: [AtomicU64; StepResult::ALL.len()],
+    security_denials: [AtomicU64; SecurityRule::ALL.len()],
+    ipc_publishes: AtomicU64,
+    ipc_publish_failures: AtomicU64,
+    ipc_retries: [AtomicU64; IpcRetry::ALL.len()],
+    slow_steps: [AtomicU64; SlowStepKind::ALL.len()],
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+    foreign_updates: AtomicU64,
+    queue_depth: AtomicU64,
+    job_duration: Mutex<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            jobs: [const { AtomicU64::new(0) }; JobOutcome::ALL.len()],
+            steps: [const { AtomicU64::new(0) }; StepResult::ALL.len()],
+            security_denials: [const { AtomicU64::new(0) }; SecurityRule::ALL.len()],
+            ipc_publishes: AtomicU64::new(0),
+            ipc_publish_failures: AtomicU64::new(0),
+            ipc_retries: [const { AtomicU64::new(0) }; IpcRetry::ALL.len()],
+            slow_steps: [const { AtomicU64::new(0) }; SlowStepKind::ALL.len()],
+            errors: [const { AtomicU64::new(0) }; ErrorKind::ALL.len()],
+            foreign_updates: AtomicU64::new(0),
+            queue_depth: AtomicU64::new(0),
+            job_duration: Mutex::new(Histogram {
+                buckets: [0; JOB_DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    /// Count a finished job; `duration` is how long its steps ran (none for
+    /// rejected documents)
+    pub fn record_job(&self, outcome: JobOutcome, duration: Option<Duration>) {
+        self.jobs[outcome as usize].fetch_add(1, Ordering::Relaxed);
+        let Some(duration) = duration else {
+            return;
+        };
+        let secs = duration.as_secs_f64();
+        let mut histogram = self.job_duration.lock().unwrap_or_else(|e| e.into_inner());
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(JOB_DURATION_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    pub fn record_step(&self, result: StepResult) {
+        self.steps[result as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_security_denial(&self, rule: SecurityRule) {
+        self.security_denials[rule as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_publish(&self, succeeded: bool) {
+        if succeeded {
+            self.ipc_publishes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.ipc_publish_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_ipc_retry(&self, retry: IpcRetry) {
+        self.ipc_retries[retry as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_slow_step(&self, kind: SlowStepKind) {
+        self.slow_steps[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, kind: ErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current value of `device_ops_errors_total{kind=...}`
+    pub fn errors(&self, kind: ErrorKind) -> u64 {
+        self.errors[kind as usize].load(Ordering::Relaxed)
+    }
+
+    /// Count a job status update response to a request this instance did not make
+    pub fn record_foreign_update(&self) {
+        self.foreign_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Jobs AWS last reported as queued, over every served thing
+    pub fn set_queue_depth(&self, depth: u64) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Current value of `device_ops_jobs_total{status=...}`
+    pub fn jobs(&self, outcome: JobOutcome) -> u64 {
+        self.jobs[outcome as usize].load(Ordering::Relaxed)
+    }
+
+    /// Current value of `device_ops_security_denials_total{rule=...}`
+    pub fn security_denials(&self, rule: SecurityRule) -> u64 {
+        self.security_denials[rule as usize].load(Ordering::Relaxed)
+    }
+
+    /// Current value of `device_ops_ipc_publishes_total{result="ok"}`
+    pub fn ipc_publishes(&self) -> u64 {
+        self.ipc_publishes.load(Ordering::Relaxed)
+    }
+
+    /// The job counters, job duration totals and queue depth as of now
+    pub fn snapshot(&self) -> Snapshot {
+        let histogram = self.job_duration.lock().unwrap_or_else(|e| e.into_inner());
+        Snapshot {
+            jobs: JobOutcome::ALL.map(|o| self.jobs(o)),
+            job_duration_sum_secs: histogram.sum,
+            job_duration_count: histogram.count,
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format (version 0.0.4).
+    /// Each label value is always present, so series do not appear out of
+    /// nowhere on the first event.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        labeled_counter(
+            &mut out,
+            "device_ops_jobs_total",
+            "Jobs finished, by final status",
+            "status",
+            JobOutcome::ALL.map(|o| (o.label(), self.jobs(o))),
+        );
+        labeled_counter(
+            &mut out,
+            "device_ops_steps_total",
+            "Steps run, by result",
+            "result",
+            StepResult::ALL.map(|r| (r.label(), self.steps[r as usize].load(Ordering::Relaxed))),
+        );
+        labeled_counter(
+            &mut out,
+            "device_ops_security_denials_total",
+            "Steps or results refused by a security check, by rule",
+            "rule",
+            SecurityRule::ALL.map(|r| (r.label(), self.security_denials(r))),
+        );
+        labeled_counter(
+            &mut out,
+            "device_ops_ipc_publishes_total",
+            "Messages published to IoT Core through IPC, by result",
+            "result",
+            [
+                ("ok", self.ipc_publishes()),
+                ("error", self.ipc_publish_failures.load(Ordering::Relaxed)),
+            ],
+        );
+        labeled_counter(
+            &mut out,
+            "device_ops_ipc_retries_total",
+            "IPC operations retried after throttling or a dead session, by kind",
+            "kind",
+            IpcRetry::ALL.map(|r| {
+                (
+                    r.label(),
+                    self.ipc_retries[r as usize].load(Ordering::Relaxed),
+                )
+            }),
+        );
+        labeled_counter(
+            &mut out,
+            "device_ops_slow_steps_total",
+            "Steps that ran past a slow-step limit, by limit",
+            "limit",
+            SlowStepKind::ALL.map(|k| {
+                (
+                    k.label(),
+                    self.slow_steps[k as usize].load(Ordering::Relaxed),
+                )
+            }),
+        );
+        labeled_counter(
+            &mut out,
+            "device_ops_errors_total",
+            "Errors that failed a step or job, by kind",
+            "kind",
+            ErrorKind::ALL.map(|k| (k.label(), self.errors(k))),
+        );
+
+        let name = "device_ops_foreign_updates_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Job status update responses to updates another client made",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(
+            out,
+            "{} {}",
+            name,
+            self.foreign_updates.load(Ordering::Relaxed)
+        );
+
+        let name = "device_ops_queue_depth";
+        let _ = writeln!(
+            out,
+            "# HELP {} Jobs AWS reports as queued for the device",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, self.queue_depth.load(Ordering::Relaxed));
+
+        let histogram = self.job_duration.lock().unwrap_or_else(|e| e.into_inner());
+        let name = "device_ops_job_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time jobs spent running their steps", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in JOB_DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(out, "{}_count {}", name, histogram.count);
+        out
+    }
+
+    /// Every metric as JSON, keyed by its name without the `device_ops_`
+    /// prefix; labeled counters become objects by label value
+    pub fn to_json(&self) -> serde_json::Value {
+        fn labeled<const N: usize>(values: [(&str, u64); N]) -> serde_json::Value {
+            values
+                .into_iter()
+                .map(|(label, count)| (label.to_string(), count.into()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        }
+        let histogram = self.job_duration.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::json!({
+            "jobs_total": labeled(JobOutcome::ALL.map(|o| (o.label(), self.jobs(o)))),
+            "steps_total": labeled(
+                StepResult::ALL.map(|r| (r.label(), self.steps[r as usize].load(Ordering::Relaxed)))
+            ),
+            "security_denials_total": labeled(
+                SecurityRule::ALL.map(|r| (r.label(), self.security_denials(r)))
+            ),
+            "ipc_publishes_total": labeled([
+                ("ok", self.ipc_publishes()),
+                ("error", self.ipc_publish_failures.load(Ordering::Relaxed)),
+            ]),
+            "ipc_retries_total": labeled(IpcRetry::ALL.map(|r| {
+                (r.label(), self.ipc_retries[r as usize].load(Ordering::Relaxed))
+            })),
+            "slow_steps_total": labeled(SlowStepKind::ALL.map(|k| {
+                (k.label(), self.slow_steps[k as usize].load(Ordering::Relaxed))
+            })),
+            "errors_total": labeled(ErrorKind::ALL.map(|k| (k.label(), self.errors(k)))),
+            "foreign_updates_total": self.foreign_updates.load(Ordering::Relaxed),
+            "queue_depth": self.queue_depth.load(Ordering::Relaxed),
+            "job_duration_seconds": { "sum": histogram.sum, "count": histogram.count },
+        })
+    }
+}
+
+fn labeled_counter<const N: usize>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: [(&str, u64); N],
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (value, count) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+    }
+}
+
+/// Serve `GET /metrics` on `127.0.0.1:<port>` until the process exits
+pub async fn serve(metrics: &'static Metrics, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.map_err(|e| {
+        DeviceOpsError::ConfigError(format!("Cannot listen on 127.0.0.1:{}: {}", port, e))
+    })?;
+    tracing::info!(port = port, "Serving metrics on 127.0.0.1");
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to accept metrics connection");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let response = match read_request_path(&mut stream).await {
+                Some(path) if path == "/metrics" => http_response("200 OK", &metrics.render()),
+                Some(_) => http_response("404 Not Found", "Not found\n"),
+                None => http_response("400 Bad Request", "Bad request\n"),
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!(error = %e, "Failed to answer metrics request");
+            }
+        });
+    }
+}
+
+/// Path of a `GET` request, once its head has been read
+async fn read_request_path(stream: &mut tokio::net::TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_BYTES {
+            return None;
+        }
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next()?.split_whitespace();
+    if request_line.next()? != "GET" {
+        return None;
+    }
+    let target = request_line.next()?;
+    Some(target.split('?').next().unwrap_or(target).to_string())
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Write the exposition to `path` for the textfile collector, atomically
+/// (temp file + rename) so node_exporter never reads half a file
+pub fn write_textfile(metrics: &Metrics, path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("prom.tmp");
+    std::fs::write(&tmp_path, metrics.render())
+        .and_then(|_| std::fs::rename(&tmp_path, path))
+        .map_err(|e| {
+            DeviceOpsError::ExecutionError(format!(
+                "Failed to write metrics to {}: {}",
+                path.display(),
+                e
+            ))
+        })
+}
+
+/// Rewrite the textfile every `interval` until the process exits
+pub async fn run_textfile_writer(metrics: &'static Metrics, path: PathBuf, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = write_textfile(metrics, &path) {
+            tracing::warn!(error = %e, "Failed to write metrics textfile");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check every line against the text exposition format: HELP and TYPE
+    /// comments, then `name{label="value",...} number` samples of a declared
+    /// metric. Returns the samples.
+    fn parse_exposition(text: &str) -> Vec<(String, f64)> {
+        let mut declared: Vec<(String, String)> = Vec::new();
+        let mut samples = Vec::new();
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                let (name, help) = rest.split_once(' ').expect("HELP without text");
+                assert!(!help.is_empty(), "{}", line);
+                assert!(is_metric_name(name), "{}", line);
+            } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').expect("TYPE without kind");
+                assert!(
+                    ["counter", "gauge", "histogram"].contains(&kind),
+                    "{}",
+                    line
+                );
+                declared.push((name.to_string(), kind.to_string()));
+            } else {
+                let (series, value) = line.rsplit_once(' ').expect("sample without value");
+                let value: f64 = value.parse().expect("sample value is not a number");
+                let name = match series.split_once('{') {
+                    Some((name, labels)) => {
+                        let labels = labels.strip_suffix('}').expect("unclosed labels");
+                        for pair in labels.split(',') {
+                            let (label, value) = pair.split_once('=').expect("label without =");
+                            assert!(is_metric_name(label), "{}", line);
+                            assert!(
+                                value.len() >= 2 && value.starts_with('"') && value.ends_with('"'),
+                                "{}",
+                                line
+                            );
+                        }
+                        name
+                    }
+                    None => series,
+                };
+                let (family, kind) = declared.last().expect("sample before TYPE");
+                let belongs = match kind.as_str() {
+                    "histogram" => ["_bucket", "_sum", "_count"]
+                        .iter()
+                        .any(|suffix| name == format!("{}{}", family, suffix)),
+                    _ => name == family,
+                };
+                assert!(belongs, "{} is not part of {}", name, family);
+                samples.push((series.to_string(), value));
+            }
+        }
+        samples
+    }
+
+    fn is_metric_name(name: &str) -> bool {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    fn sample(samples: &[(String, f64)], series: &str) -> f64 {
+        samples
+            .iter()
+            .find(|(name, _)| name == series)
+            .unwrap_or_else(|| panic!("no sample {}", series))
+            .1
+    }
+
+    #[test]
+    fn test_rendered_exposition_parses() {
+        let metrics = Metrics::new();
+        metrics.record_job(JobOutcome::Succeeded, Some(Duration::from_secs(3)));
+        metrics.record_job(JobOutcome::Failed, Some(Duration::from_secs(120)));
+        metrics.record_job(JobOutcome::Rejected, None);
+        metrics.set_queue_depth(4);
+        metrics.record_step(StepResult::Success);
+        metrics.record_step(StepResult::Timeout);
+        metrics.record_security_denial(SecurityRule::CommandAllowlist);
+        metrics.record_publish(true);
+        metrics.record_publish(false);
+        metrics.record_ipc_retry(IpcRetry::NextJob);
+        metrics.record_error(ErrorKind::Timeout);
+        metrics.record_foreign_update();
+
+        let samples = parse_exposition(&metrics.render());
+        assert_eq!(
+            sample(&samples, r#"device_ops_jobs_total{status="succeeded"}"#),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, r#"device_ops_jobs_total{status="rejected"}"#),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, r#"device_ops_jobs_total{status="timed_out"}"#),
+            0.0
+        );
+        assert_eq!(sample(&samples, "device_ops_queue_depth"), 4.0);
+        assert_eq!(sample(&samples, "device_ops_foreign_updates_total"), 1.0);
+        assert_eq!(
+            sample(&samples, r#"device_ops_steps_total{result="timeout"}"#),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, r#"device_ops_steps_total{result="failure"}"#),
+            0.0
+        );
+        assert_eq!(
+            sample(
+                &samples,
+                r#"device_ops_security_denials_total{rule="command_allowlist"}"#
+            ),
+            1.0
+        );
+        assert_eq!(
+            sample(
+                &samples,
+                r#"device_ops_ipc_publishes_total{result="error"}"#
+            ),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, r#"device_ops_ipc_retries_total{kind="next_job"}"#),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, r#"device_ops_errors_total{kind="timeout"}"#),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, r#"device_ops_errors_total{kind="ipc"}"#),
+            0.0
+        );
+
+        // Buckets are cumulative; rejected jobs are not timed
+        let duration = "device_ops_job_duration_seconds";
+        assert_eq!(
+            sample(&samples, &format!("{}_bucket{{le=\"1\"}}", duration)),
+            0.0
+        );
+        assert_eq!(
+            sample(&samples, &format!("{}_bucket{{le=\"5\"}}", duration)),
+            1.0
+        );
+        assert_eq!(
+            sample(&samples, &format!("{}_bucket{{le=\"300\"}}", duration)),
+            2.0
+        );
+        assert_eq!(
+            sample(&samples, &format!("{}_bucket{{le=\"+Inf\"}}", duration)),
+            2.0
+        );
+        assert_eq!(sample(&samples, &format!("{}_sum", duration)), 123.0);
+        assert_eq!(sample(&samples, &format!("{}_count", duration)), 2.0);
+    }
+
+    #[test]
+    fn test_json_matches_exposition() {
+        let metrics = Metrics::new();
+        metrics.record_job(JobOutcome::Succeeded, Some(Duration::from_secs(3)));
+        metrics.record_job(JobOutcome::Rejected, None);
+        metrics.record_step(StepResult::Timeout);
+        metrics.record_publish(false);
+        metrics.record_slow_step(SlowStepKind::Relative);
+        metrics.record_error(ErrorKind::Security);
+        metrics.set_queue_depth(2);
+
+        let json = metrics.to_json();
+        assert_eq!(json["jobs_total"]["succeeded"], 1);
+        assert_eq!(json["jobs_total"]["rejected"], 1);
+        assert_eq!(json["jobs_total"]["timed_out"], 0);
+        assert_eq!(json["steps_total"]["timeout"], 1);
+        assert_eq!(json["security_denials_total"]["run_as_user"], 0);
+        assert_eq!(json["ipc_publishes_total"]["error"], 1);
+        assert_eq!(json["ipc_retries_total"]["reconnect"], 0);
+        assert_eq!(json["slow_steps_total"]["relative"], 1);
+        assert_eq!(json["slow_steps_total"]["absolute"], 0);
+        assert_eq!(json["errors_total"]["security"], 1);
+        assert_eq!(json["errors_total"]["invalid_document"], 0);
+        assert_eq!(json["queue_depth"], 2);
+        assert_eq!(json["foreign_updates_total"], 0);
+        assert_eq!(json["job_duration_seconds"]["sum"], 3.0);
+        assert_eq!(json["job_duration_seconds"]["count"], 1);
+    }
+
+    #[test]
+    fn test_textfile_is_replaced_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("device_ops.prom");
+        let metrics = Metrics::new();
+        write_textfile(&metrics, &path).unwrap();
+        metrics.record_job(JobOutcome::Succeeded, Some(Duration::from_secs(1)));
+        write_textfile(&metrics, &path).unwrap();
+
+        let samples = parse_exposition(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            sample(&samples, r#"device_ops_jobs_total{status="succeeded"}"#),
+            1.0
+        );
+        let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().flatten().collect();
+        assert_eq!(names.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_served_over_http() {
+        // Port 0 picks a free port; bind it here to learn which
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        global().record_publish(true);
+        tokio::spawn(serve(global(), port));
+
+        let mut response = String::new();
+        for _ in 0..50 {
+            if let Ok(mut stream) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                stream
+                    .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .await
+                    .unwrap();
+                stream.read_to_string(&mut response).await.unwrap();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        let samples = parse_exposition(body);
+        assert!(sample(&samples, r#"device_ops_ipc_publishes_total{result="ok"}"#) >= 1.0);
+    }
+}
@@ -1,4 +1,7 @@
+use crate::config::{OutputLogConfig, SpawnMethod};
+use crate::executor::output_log::LiveOutput;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// IoT Jobs notification wrapper
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -19,31 +22,186 @@ pub struct JobExecution {
     pub job_document: JobDocument,
 }
 
+/// GetPendingJobExecutions response from IoT Jobs
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PendingJobExecutions {
+    #[serde(rename = "inProgressJobs", default)]
+    pub in_progress_jobs: Vec<JobExecutionSummary>,
+    #[serde(rename = "queuedJobs", default)]
+    pub queued_jobs: Vec<JobExecutionSummary>,
+}
+
+/// Summary entry in a GetPendingJobExecutions response
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobExecutionSummary {
+    #[serde(rename = "jobId")]
+    pub job_id: String,
+    #[serde(rename = "queuedAt")]
+    pub queued_at: Option<i64>,
+}
+
+/// Error response from an IoT Jobs MQTT request (`.../rejected` topics)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobsErrorResponse {
+    pub code: String,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(rename = "clientToken", default)]
+    pub client_token: Option<String>,
+}
+
+impl JobsErrorResponse {
+    /// Whether the request was rejected for exceeding the request rate
+    pub fn is_throttled(&self) -> bool {
+        matches!(
+            self.code.as_str(),
+            "RequestThrottled" | "ThrottlingException"
+        )
+    }
+}
+
 /// Internal job representation
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Job {
     #[serde(rename = "jobId")]
     pub job_id: String,
+    /// Thing whose job execution this is (set by `IpcClient` from the topic)
+    #[serde(rename = "thingName", default)]
+    pub thing_name: String,
+    #[serde(rename = "queuedAt", default)]
+    pub queued_at: Option<i64>,
     pub document: JobDocument,
+    /// Document fields this build does not know, by path (e.g. `steps[0].action.priority`);
+    /// ignored unless `document_policy.allow_unknown_fields` is off
+    #[serde(
+        rename = "unknownFields",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub unknown_fields: Vec<String>,
 }
 
 /// Job or parse error - used to handle malformed job notifications
 #[derive(Debug, Clone)]
 pub enum JobOrError {
     Valid(Job),
-    ParseError { job_id: String, error: String },
+    ParseError {
+        thing_name: String,
+        job_id: String,
+        error: String,
+        /// Path of the offending value in the job document, e.g. `steps[7].action.input`
+        path: Option<String>,
+    },
+}
+
+impl JobNotification {
+    /// Parse a notification payload. The error names the value that did not
+    /// fit by its path in the job document, e.g.
+    /// `steps[7].action.input: missing field `command``, and returns the path
+    /// separately (`None` at the top of the document or outside it).
+    ///
+    /// Fields the document structures do not know are skipped and their
+    /// paths returned along with the notification.
+    pub fn parse(
+        payload: &[u8],
+    ) -> std::result::Result<(Self, Vec<String>), (String, Option<String>)> {
+        let mut unknown = Vec::new();
+        let deserializer = &mut serde_json::Deserializer::from_slice(payload);
+        let mut record = |path: serde_ignored::Path| {
+            // Fields AWS adds around the document are not the author's concern
+            if let Some(path) = document_path(&path) {
+                unknown.push(path);
+            }
+        };
+        let deserializer = serde_ignored::Deserializer::new(deserializer, &mut record);
+        let error = match serde_path_to_error::deserialize(deserializer) {
+            Ok(notification) => return Ok((notification, unknown)),
+            Err(error) => error,
+        };
+
+        let path = error.path().to_string();
+        let inner = error.inner();
+        // The path says more than a line and column of a one-line payload
+        let mut message = inner.to_string();
+        if inner.is_data() {
+            let location = format!(" at line {} column {}", inner.line(), inner.column());
+            if let Some(stripped) = message.strip_suffix(&location) {
+                message = stripped.to_string();
+            }
+        }
+
+        let path = path
+            .strip_prefix("execution.jobDocument")
+            .map(|rest| rest.trim_start_matches('.').to_string())
+            .filter(|rest| !rest.is_empty());
+        match path {
+            Some(path) => Err((format!("{}: {}", path, message), Some(path))),
+            None => Err((message, None)),
+        }
+    }
+}
+
+/// Path of an ignored field inside `execution.jobDocument`, written like
+/// `steps[0].action.priority`
+fn document_path(path: &serde_ignored::Path) -> Option<String> {
+    use serde_ignored::Path;
+
+    fn segments(path: &Path, out: &mut Vec<String>) {
+        match path {
+            Path::Root => {}
+            Path::Seq { parent, index } => {
+                segments(parent, out);
+                if let Some(last) = out.last_mut() {
+                    last.push_str(&format!("[{}]", index));
+                }
+            }
+            Path::Map { parent, key } => {
+                segments(parent, out);
+                out.push(key.clone());
+            }
+            Path::Some { parent }
+            | Path::NewtypeStruct { parent }
+            | Path::NewtypeVariant { parent } => segments(parent, out),
+        }
+    }
+
+    let mut parts = Vec::new();
+    segments(path, &mut parts);
+    match parts.as_slice() {
+        [execution, document, rest @ ..]
+            if execution == "execution" && document == "jobDocument" && !rest.is_empty() =>
+        {
+            Some(rest.join("."))
+        }
+        _ => None,
+    }
+}
+
+impl Job {
+    /// Dedup key: the same job ID runs once per targeted thing
+    pub fn key(&self) -> String {
+        job_key(&self.thing_name, &self.job_id)
+    }
+}
+
+/// Identifies one job execution across all things this component serves
+pub fn job_key(thing_name: &str, job_id: &str) -> String {
+    format!("{}/{}", thing_name, job_id)
 }
 
 impl From<JobNotification> for Option<Job> {
     fn from(notification: JobNotification) -> Self {
         notification.execution.map(|exec| Job {
             job_id: exec.job_id,
+            thing_name: String::new(),
+            queued_at: exec.queued_at,
             document: exec.job_document,
+            unknown_fields: Vec::new(),
         })
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct JobDocument {
     pub version: String,
     pub steps: Vec<JobStep>,
@@ -51,6 +209,75 @@ pub struct JobDocument {
     pub final_step: Option<Box<JobStep>>,
     #[serde(rename = "includeStdOut", default)]
     pub include_std_out: Option<bool>,
+    /// Overrides `execution.status_details_numbers_as_strings` for this job
+    #[serde(rename = "statusDetailsNumbersAsStrings", default)]
+    pub status_details_numbers_as_strings: Option<bool>,
+    /// Extra topic the full result JSON is published to (must match `security.result_topic_prefixes`)
+    #[serde(rename = "resultTopic", default)]
+    pub result_topic: Option<String>,
+    /// Failure policy per step group (steps reference groups via `group`)
+    #[serde(rename = "groupPolicies", default)]
+    pub group_policies: Option<HashMap<String, GroupFailurePolicy>>,
+    /// Skip `execution.inter_job_delay_secs` after this job
+    #[serde(rename = "noDelayAfter", default)]
+    pub no_delay_after: Option<bool>,
+    /// Fail the job without running any step when the battery is below this level
+    #[serde(rename = "requireMinBatteryPercent", default)]
+    pub require_min_battery_percent: Option<u8>,
+    /// Report the device's uptime as `uptime_secs`, e.g. to confirm a reboot
+    #[serde(rename = "includeUptime", default)]
+    pub include_uptime: Option<bool>,
+    /// `runAsUser` for steps that set none; overrides `execution.default_run_as_user`
+    #[serde(rename = "runAsUser", default)]
+    pub run_as_user: Option<String>,
+    /// In-progress timeout of the IoT job, in seconds; steps that could use
+    /// up nearly all of it are flagged (see `execution.reporting_headroom_secs`)
+    #[serde(rename = "jobTimeoutSecs", default)]
+    pub job_timeout_secs: Option<u64>,
+    /// Shape of the final statusDetails (`json` unless set)
+    #[serde(rename = "statusDetailsFormat", default)]
+    pub status_details_format: Option<StatusDetailsFormat>,
+}
+
+impl JobDocument {
+    /// Failure policy for a step group; ungrouped steps and groups without a policy stop on failure
+    pub fn group_policy(&self, group: Option<&str>) -> GroupFailurePolicy {
+        group
+            .and_then(|name| self.group_policies.as_ref()?.get(name).copied())
+            .unwrap_or_default()
+    }
+
+    /// Whether the document or any of its steps sets `runAsUser`
+    pub fn sets_run_as_user(&self) -> bool {
+        self.run_as_user.is_some()
+            || self
+                .steps
+                .iter()
+                .chain(self.final_step.as_deref())
+                .any(|step| step.action.run_as_user.is_some())
+    }
+}
+
+/// How a job result is rendered into statusDetails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StatusDetailsFormat {
+    /// Separate string fields, with a JSON array of step summaries for multi-step jobs
+    #[default]
+    Json,
+    /// One `summary` field of `key=value` pairs separated by `|`
+    Compact,
+}
+
+/// What happens when a step in a group fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GroupFailurePolicy {
+    /// Fail the job (same as an ungrouped step)
+    #[default]
+    StopOnFailure,
+    /// Skip the rest of the group and continue with the next steps
+    IgnoreFailure,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -58,7 +285,18 @@ pub struct JobStep {
     pub action: JobAction,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Action `type`s this build can run
+pub const SUPPORTED_ACTION_TYPES: &[&str] = &["runCommand"];
+
+/// The supported spelling of an action `type`, ignoring case (`RUNCOMMAND` is `runCommand`)
+pub fn canonical_action_type(action_type: &str) -> Option<&'static str> {
+    SUPPORTED_ACTION_TYPES
+        .iter()
+        .copied()
+        .find(|supported| supported.eq_ignore_ascii_case(action_type))
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct JobAction {
     pub name: String,
     #[serde(rename = "type")]
@@ -70,17 +308,199 @@ pub struct JobAction {
     pub ignore_step_failure: Option<bool>,
     #[serde(rename = "allowStdErr", default)]
     pub allow_std_err: Option<i32>,
+    /// Expand `${VAR}` references in args from allowlisted environment variables
+    #[serde(rename = "expandEnvInArgs", default)]
+    pub expand_env_in_args: Option<bool>,
+    /// Step group name, used with the document's `groupPolicies`
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Extra attempts when the step fails (exit code or stderr); execution errors are not retried
+    #[serde(default)]
+    pub retries: Option<u32>,
+    /// Overrides `execution.run_as_user_fallback` for this step, except that
+    /// a configured `fail` cannot be loosened to `currentUser`
+    #[serde(rename = "runAsUserFallback", default)]
+    pub run_as_user_fallback: Option<RunAsUserFallback>,
+    /// Take the exit code from the last non-empty stdout line (for wrappers that always exit 0)
+    #[serde(rename = "exitCodeFromOutput", default)]
+    pub exit_code_from_output: Option<bool>,
+    /// systemd slice (e.g. `device-ops-updates.slice`) whose cgroup accounts and limits the step
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    /// Overrides `execution.termination.signal` for this step
+    #[serde(rename = "terminationSignal", default)]
+    pub termination_signal: Option<TerminationSignal>,
+    /// Wait for other steps running the same script (for non-idempotent commands)
+    #[serde(rename = "serializeByCommand", default)]
+    pub serialize_by_command: Option<bool>,
+    /// Wait this long before running the step, e.g. for a service started by the previous step
+    #[serde(rename = "delayBeforeMs", default)]
+    pub delay_before_ms: Option<u64>,
+    /// Stop the step when it writes no output for this many seconds, to
+    /// catch a hang before `timeout` does
+    #[serde(rename = "idleTimeout", default)]
+    pub idle_timeout: Option<u64>,
+    /// Conditions that must all hold for the step to succeed. Replaces the
+    /// zero exit code and `allowStdErr` checks when set.
+    #[serde(rename = "successCriteria", default)]
+    pub success_criteria: Option<Vec<Criterion>>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// One condition of a step's `successCriteria`, written as a single-key
+/// object such as `{"exitCode": 0}` or `{"stdoutContains": "installed"}`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Criterion {
+    ExitCode(i32),
+    /// The exit code is one of these
+    ExitCodeIn(Vec<i32>),
+    /// The kept stdout contains this text
+    StdoutContains(String),
+    StdoutNotContains(String),
+    StderrContains(String),
+    /// At most this many stderr lines
+    StderrLineMax(usize),
+}
+
+impl Criterion {
+    /// Whether the step's output meets the condition; `exit_code` is the one
+    /// the step is judged by (see `exitCodeFromOutput`)
+    pub fn holds(&self, exit_code: i32, output: &ExecutionOutput) -> bool {
+        match self {
+            Self::ExitCode(expected) => exit_code == *expected,
+            Self::ExitCodeIn(expected) => expected.contains(&exit_code),
+            Self::StdoutContains(text) => output.stdout.contains(text.as_str()),
+            Self::StdoutNotContains(text) => !output.stdout.contains(text.as_str()),
+            Self::StderrContains(text) => output.stderr.contains(text.as_str()),
+            Self::StderrLineMax(max) => output.stderr_line_count <= *max,
+        }
+    }
+}
+
+/// Why a step whose command ran was judged failed, reported as
+/// `fail_reason` in statusDetails
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(into = "String")]
+pub enum StepFailureReason {
+    /// The exit code the step is judged by was not 0
+    ExitCode(i32),
+    /// More stderr lines than `allowStdErr` (or `execution.default_allow_std_err`)
+    StderrLines { lines: usize, allowed: i32 },
+    /// The first `successCriteria` condition that did not hold
+    Criterion(Criterion),
+    /// `exitCodeFromOutput` is set but the last stdout line is not an exit code
+    NoExitCodeInOutput,
+}
+
+impl std::fmt::Display for StepFailureReason {
+    /// e.g. `exit_code=2`, `stderr_lines=3>1` or `criterion={"stdoutContains":"installed"}`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExitCode(code) => write!(f, "exit_code={}", code),
+            Self::StderrLines { lines, allowed } => write!(f, "stderr_lines={}>{}", lines, allowed),
+            Self::Criterion(criterion) => write!(
+                f,
+                "criterion={}",
+                serde_json::to_string(criterion).unwrap_or_default()
+            ),
+            Self::NoExitCodeInOutput => f.write_str("no_exit_code_in_output"),
+        }
+    }
+}
+
+impl From<StepFailureReason> for String {
+    fn from(reason: StepFailureReason) -> Self {
+        reason.to_string()
+    }
+}
+
+/// Signal sent to stop a step that ran past its timeout. Parsed from
+/// `TERM`, `INT`, `HUP` or `KILL`, case-insensitively and with or without
+/// the `SIG` prefix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TerminationSignal {
+    #[default]
+    Term,
+    Int,
+    Hup,
+    Kill,
+}
+
+impl TerminationSignal {
+    /// Name without the `SIG` prefix, as `kill -<name>` takes it
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Term => "TERM",
+            Self::Int => "INT",
+            Self::Hup => "HUP",
+            Self::Kill => "KILL",
+        }
+    }
+}
+
+impl std::fmt::Display for TerminationSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SIG{}", self.name())
+    }
+}
+
+impl std::str::FromStr for TerminationSignal {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let upper = s.trim().to_ascii_uppercase();
+        match upper.strip_prefix("SIG").unwrap_or(&upper) {
+            "TERM" => Ok(Self::Term),
+            "INT" => Ok(Self::Int),
+            "HUP" => Ok(Self::Hup),
+            "KILL" => Ok(Self::Kill),
+            _ => Err(format!(
+                "unsupported termination signal {:?} (expected TERM, INT, HUP or KILL)",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for TerminationSignal {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TerminationSignal> for String {
+    fn from(signal: TerminationSignal) -> Self {
+        signal.to_string()
+    }
+}
+
+/// What happens when a step's `runAsUser` cannot be used (no sudo, unknown
+/// user, or no passwordless sudo)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RunAsUserFallback {
+    /// Run the step as the component's own user
+    #[default]
+    CurrentUser,
+    /// Fail the step
+    Fail,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct JobInput {
     pub command: String,
     #[serde(default)]
     pub args: Option<Vec<String>>,
     pub timeout: Option<u64>,
+    /// Absolute directory to run the command in (checked against `security.working_dir_allowlist`)
+    #[serde(rename = "workingDirectory", default)]
+    pub working_directory: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct ExecutionOutput {
     pub stdout: String,
     pub stderr: String,
@@ -89,31 +509,212 @@ pub struct ExecutionOutput {
     pub stderr_line_count: usize,
     pub stdout_truncated: bool,
     pub stderr_truncated: bool,
+    /// Wall-clock start of the step (epoch millis)
+    pub started_at: i64,
+    /// Wall-clock end of the step (epoch millis)
+    pub finished_at: i64,
+    /// Every attempt of a step with `retries`, in order (empty otherwise)
+    pub attempt_history: Vec<AttemptRecord>,
+    /// Machine-readable error the script reported on stderr (see
+    /// `STRUCTURED_ERROR_PREFIX`)
+    pub structured_error: Option<serde_json::Value>,
+    /// Signal that stopped the step after it timed out
+    pub termination_signal: Option<TerminationSignal>,
+    /// Set when the step failed before its command ran
+    pub failure_class: Option<FailureClass>,
+    /// Why the step was judged failed after its command ran
+    pub fail_reason: Option<StepFailureReason>,
+    /// Allowlist rule the command broke, when it ran anyway
+    /// (`allowlist_violation_action: flag`)
+    pub policy_violation: Option<String>,
+    /// uid the command ran as: its runAsUser's, else the component's
+    pub ran_as_uid: Option<u32>,
+    /// Primary gid of that user
+    pub ran_as_gid: Option<u32>,
+    /// Where the step's time went
+    pub timing: StepTiming,
 }
 
-#[derive(Debug, Clone)]
+/// A step's time by phase, in milliseconds. `spawn_ms`, `run_ms` and
+/// `drain_ms` make up `execution_time_ms`; `queue_ms` and `verify_ms` come
+/// before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StepTiming {
+    /// Waiting for another run of the same command (`serializeByCommand`)
+    pub queue_ms: u64,
+    /// runAsUser (sudo) checks and security validation
+    pub verify_ms: u64,
+    /// Starting the process
+    pub spawn_ms: u64,
+    /// From start to exit of the process
+    pub run_ms: u64,
+    /// Reading the rest of its output after it exited
+    pub drain_ms: u64,
+}
+
+impl StepTiming {
+    pub fn total_ms(&self) -> u64 {
+        self.queue_ms + self.verify_ms + self.spawn_ms + self.run_ms + self.drain_ms
+    }
+
+    /// e.g. `queue=0 verify=12 spawn=3 run=45000 drain=1`
+    pub fn compact(&self) -> String {
+        format!(
+            "queue={} verify={} spawn={} run={} drain={}",
+            self.queue_ms, self.verify_ms, self.spawn_ms, self.run_ms, self.drain_ms
+        )
+    }
+}
+
+/// Why a step failed when the command itself never ran, reported as
+/// `failure_class` in statusDetails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureClass {
+    /// sudo refused to run the command as its `runAsUser`
+    Privilege,
+}
+
+impl FailureClass {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Privilege => "privilege",
+        }
+    }
+}
+
+/// One attempt of a retried step
+#[derive(Debug, Clone, Serialize)]
+pub struct AttemptRecord {
+    /// 1-based attempt number
+    pub attempt: u32,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+}
+
+/// Compact attempt summary for statusDetails, e.g. `exit 1 (120ms), exit 0 (95ms)`;
+/// `None` unless the step was attempted more than once
+fn attempt_summary(history: &[AttemptRecord]) -> Option<String> {
+    if history.len() < 2 {
+        return None;
+    }
+    let attempts: Vec<String> = history
+        .iter()
+        .map(|a| format!("exit {} ({}ms)", a.exit_code, a.duration_ms))
+        .collect();
+    Some(attempts.join(", "))
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Command {
     pub script_path: String,
     pub args: Vec<String>,
     pub run_as_user: Option<String>,
+    pub working_dir: Option<String>,
+    /// Signal that stops the command when it runs past its timeout
+    pub stop_signal: TerminationSignal,
+    /// Time the command gets to exit after `stop_signal` before it is killed
+    pub stop_timeout: std::time::Duration,
+    /// Send SIGKILL when the command outlives `stop_timeout`
+    pub escalate_to_kill: bool,
+    /// systemd slice to run the command in (via `systemd-run --scope`)
+    pub slice: Option<String>,
+    /// Program and arguments placed in front of the command (`execution.command_wrapper`)
+    pub wrapper: Vec<String>,
+    /// Longest stretch without stdout or stderr output before it is stopped
+    pub idle_timeout: Option<std::time::Duration>,
+    /// When the command is stopped as timed out, keeping the output it wrote so far
+    pub deadline: Option<tokio::time::Instant>,
+    /// Why the command runs as the current user instead of its `runAsUser`,
+    /// when that is worth recording in the step's stderr
+    pub run_as_note: Option<String>,
+    /// Extra environment variables, on top of the component's own
+    pub env: Vec<(String, String)>,
+    /// `PATH` the command gets instead of the inherited one, unless `env` sets it
+    pub sanitize_path: Option<String>,
+    /// How the command is started
+    pub spawn_method: SpawnMethod,
+    /// Log the command's output lines as they come (`execution.log_output`)
+    pub log_output: Option<OutputLogConfig>,
+    /// Pass the command's output lines on as they come (`execution.live_output`)
+    pub live_output: Option<LiveOutput>,
 }
 
 /// Aggregated result from executing all steps
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct JobExecutionResult {
     pub outputs: Vec<StepOutput>,
     pub overall_success: bool,
     pub failed_step: Option<String>,
+    /// Error message when the failed step could not be executed at all
+    pub failure_reason: Option<String>,
+    /// [`crate::error::DeviceOpsError::reason_code`] of that error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason_code: Option<&'static str>,
+    /// The failed step could not be executed for a transient reason, so
+    /// running the job again may succeed
+    #[serde(skip)]
+    pub retryable: bool,
+}
+
+impl JobExecutionResult {
+    /// Whether a step ran despite breaking an allowlist rule
+    pub fn policy_violation(&self) -> bool {
+        self.outputs
+            .iter()
+            .any(|step| step.output.policy_violation.is_some())
+    }
+
+    /// Whether the job failed because its failed step was stopped by its
+    /// timeout or idle timeout
+    pub fn timed_out(&self) -> bool {
+        let Some(failed_step) = &self.failed_step else {
+            return false;
+        };
+        self.outputs
+            .iter()
+            .rev()
+            .find(|step| &step.step_name == failed_step)
+            .is_some_and(|step| step.output.termination_signal.is_some())
+    }
 }
 
 /// Output from a single step execution
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct StepOutput {
     pub step_name: String,
     pub output: ExecutionOutput,
     pub ignored_failure: bool,
 }
 
+/// Per-job aggregates logged as the `Job metrics` record, for capacity planning
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobMetrics {
+    pub steps_executed: usize,
+    pub execution_time_ms: u64,
+    /// Captured stdout across all steps, after per-step truncation
+    pub total_stdout_bytes: usize,
+    /// Captured stderr across all steps, after per-step truncation
+    pub total_stderr_bytes: usize,
+    /// Whether any step's output hit the capture limit
+    pub output_truncated: bool,
+}
+
+impl JobMetrics {
+    pub fn from_result(result: &JobExecutionResult) -> Self {
+        let steps = result.outputs.iter().map(|step| &step.output);
+        Self {
+            steps_executed: result.outputs.len(),
+            execution_time_ms: steps.clone().map(|o| o.execution_time_ms).sum(),
+            total_stdout_bytes: steps.clone().map(|o| o.stdout.len()).sum(),
+            total_stderr_bytes: steps.clone().map(|o| o.stderr.len()).sum(),
+            output_truncated: steps
+                .clone()
+                .any(|o| o.stdout_truncated || o.stderr_truncated),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,20 +739,659 @@ mod tests {
         assert_eq!(doc.steps.len(), 1);
         assert_eq!(doc.steps[0].action.input.command, "/opt/test.sh");
     }
+
+    #[test]
+    fn test_unknown_fields_are_ignored_at_every_level() {
+        let payload = serde_json::json!({
+            "timestamp": 1700000000,
+            "clientToken": "abc",
+            "execution": {
+                "jobId": "job-1",
+                "status": "QUEUED",
+                "versionNumber": 1,
+                "jobDocument": {
+                    "version": "1.0",
+                    "owner": "fleet-team",
+                    "steps": [{
+                        "priority": 1,
+                        "action": {
+                            "name": "Update",
+                            "type": "runCommand",
+                            "retryPolicy": { "max": 3 },
+                            "input": {
+                                "command": "/opt/device-scripts/update.sh",
+                                "timeout": 60,
+                                "shell": "bash"
+                            }
+                        }
+                    }],
+                    "finalStep": {
+                        "action": {
+                            "name": "Cleanup",
+                            "type": "runCommand",
+                            "input": { "command": "/opt/device-scripts/cleanup.sh", "env": {} }
+                        }
+                    }
+                }
+            }
+        });
+
+        let (notification, unknown) =
+            JobNotification::parse(payload.to_string().as_bytes()).unwrap();
+        let job = Option::<Job>::from(notification).unwrap();
+        assert_eq!(job.document.steps[0].action.input.timeout, Some(60));
+        assert_eq!(
+            job.document.final_step.unwrap().action.input.command,
+            "/opt/device-scripts/cleanup.sh"
+        );
+
+        // Only fields inside the document are reported
+        let mut unknown = unknown;
+        unknown.sort();
+        assert_eq!(
+            unknown,
+            vec![
+                "finalStep.action.input.env",
+                "owner",
+                "steps[0].action.input.shell",
+                "steps[0].action.retryPolicy",
+                "steps[0].priority",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_notifications_name_the_offending_path() {
+        let cases = [
+            (
+                "missing-command.json",
+                "steps[2].action.input: missing field `command`",
+                Some("steps[2].action.input"),
+            ),
+            (
+                "timeout-as-string.json",
+                "steps[0].action.input.timeout: invalid type: string \"60\", expected u64",
+                Some("steps[0].action.input.timeout"),
+            ),
+            (
+                "steps-not-a-list.json",
+                "steps: invalid type: map, expected a sequence",
+                Some("steps"),
+            ),
+            (
+                "final-step-without-name.json",
+                "finalStep.action: missing field `name`",
+                Some("finalStep.action"),
+            ),
+            // At the top of the document there is no path to give
+            ("missing-steps.json", "missing field `steps`", None),
+        ];
+
+        for (file, message, path) in cases {
+            let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/malformed-jobs")
+                .join(file);
+            let payload = std::fs::read(fixture).unwrap();
+            let (error, error_path) = JobNotification::parse(&payload).unwrap_err();
+            assert_eq!(error, message, "{}", file);
+            assert_eq!(error_path.as_deref(), path, "{}", file);
+        }
+
+        // Syntax errors keep their position
+        let (error, _) = JobNotification::parse(b"{\"execution\": {").unwrap_err();
+        assert!(error.contains("line 1 column"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_pending_job_executions() {
+        let json = r#"{
+            "inProgressJobs": [],
+            "queuedJobs": [
+                {"jobId": "job-1", "queuedAt": 1700000000, "executionNumber": 1},
+                {"jobId": "job-2", "queuedAt": 1700000100, "executionNumber": 1}
+            ],
+            "timestamp": 1700000200
+        }"#;
+
+        let pending: PendingJobExecutions = serde_json::from_str(json).unwrap();
+        assert!(pending.in_progress_jobs.is_empty());
+        assert_eq!(pending.queued_jobs.len(), 2);
+        assert_eq!(pending.queued_jobs[1].job_id, "job-2");
+    }
+
+    #[test]
+    fn test_parse_success_criteria() {
+        let action: JobAction = serde_json::from_value(serde_json::json!({
+            "name": "Install",
+            "type": "runCommand",
+            "input": { "command": "/opt/install.sh" },
+            "successCriteria": [
+                { "exitCodeIn": [0, 3] },
+                { "stdoutContains": "installed" },
+                { "stderrLineMax": 5 }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            action.success_criteria,
+            Some(vec![
+                Criterion::ExitCodeIn(vec![0, 3]),
+                Criterion::StdoutContains("installed".to_string()),
+                Criterion::StderrLineMax(5),
+            ])
+        );
+
+        let unknown =
+            serde_json::from_value::<Criterion>(serde_json::json!({ "stdoutMatches": "x" }));
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_parse_termination_signal() {
+        for (text, expected) in [
+            ("TERM", TerminationSignal::Term),
+            ("sigint", TerminationSignal::Int),
+            ("SIGHUP", TerminationSignal::Hup),
+            (" kill ", TerminationSignal::Kill),
+        ] {
+            assert_eq!(text.parse::<TerminationSignal>().unwrap(), expected);
+        }
+        assert!("USR1".parse::<TerminationSignal>().is_err());
+        assert!("".parse::<TerminationSignal>().is_err());
+
+        let action: JobAction = serde_json::from_value(serde_json::json!({
+            "name": "Logger",
+            "type": "runCommand",
+            "input": { "command": "/opt/logger.sh" },
+            "terminationSignal": "INT"
+        }))
+        .unwrap();
+        assert_eq!(action.termination_signal, Some(TerminationSignal::Int));
+        assert_eq!(
+            serde_json::to_value(TerminationSignal::Int).unwrap(),
+            "SIGINT"
+        );
+    }
+
+    #[test]
+    fn test_with_detail_respects_field_limit() {
+        let mut status = JobStatus::failed("boom".to_string(), None, None);
+        for i in 0..MAX_STATUS_DETAILS {
+            status = status.with_detail(&format!("extra_{}", i), i.to_string());
+        }
+
+        let json = status.to_json();
+        let details = json["statusDetails"].as_object().unwrap();
+        assert_eq!(details.len(), MAX_STATUS_DETAILS);
+        assert_eq!(details["reason"], "boom");
+        assert!(!details.contains_key("extra_9"));
+    }
+
+    fn step(name: &str, exit_code: i32, time_ms: u64) -> StepOutput {
+        StepOutput {
+            step_name: name.to_string(),
+            output: ExecutionOutput {
+                stdout: String::new(),
+                stderr: String::new(),
+                exit_code,
+                execution_time_ms: time_ms,
+                stderr_line_count: 0,
+                stdout_truncated: false,
+                stderr_truncated: false,
+                ..Default::default()
+            },
+            ignored_failure: false,
+        }
+    }
+
+    fn result(outputs: Vec<StepOutput>) -> JobExecutionResult {
+        JobExecutionResult {
+            outputs,
+            overall_success: true,
+            failed_step: None,
+            failure_reason: None,
+            reason_code: None,
+            retryable: false,
+        }
+    }
+
+    #[test]
+    fn test_job_metrics_aggregate_output_bytes() {
+        let mut install = step("install", 0, 120);
+        install.output.stdout = "installed 3 packages\n".to_string();
+        install.output.stderr = "warning: cache miss\n".to_string();
+        let mut verify = step("verify", 0, 30);
+        verify.output.stdout = "ok".to_string();
+        verify.output.stdout_truncated = true;
+        let cleanup = step("cleanup", 0, 5);
+
+        let metrics = JobMetrics::from_result(&result(vec![install, verify, cleanup]));
+        assert_eq!(
+            metrics,
+            JobMetrics {
+                steps_executed: 3,
+                execution_time_ms: 155,
+                total_stdout_bytes: 23,
+                total_stderr_bytes: 20,
+                output_truncated: true,
+            }
+        );
+
+        let empty = JobMetrics::from_result(&result(vec![]));
+        assert_eq!(empty.total_stdout_bytes + empty.total_stderr_bytes, 0);
+        assert!(!empty.output_truncated);
+    }
+
+    #[test]
+    fn test_compact_status_details() {
+        let mut download = step("Download", 0, 812);
+        download.output.stdout = "fetching\nsaved fw.bin\n".to_string();
+        let mut install = step("Install", 2, 40);
+        install.output.stderr = "E: lock held | retry=later\n\n".to_string();
+        let mut failed = result(vec![download, install]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+
+        let options = StatusDetailsOptions {
+            format: StatusDetailsFormat::Compact,
+            ..Default::default()
+        };
+        let details = format_status_details(&failed, &options);
+        assert_eq!(details.as_object().unwrap().len(), 1);
+        assert_eq!(
+            details["summary"],
+            "steps=2|success=false|failed_step=Install|step1=Download|exit1=0|ms1=812|step2=Install|exit2=2|ms2=40|stderr2=E: lock held %7C retry%3Dlater"
+        );
+
+        let options = StatusDetailsOptions {
+            include_stdout: true,
+            uptime_secs: Some(42),
+            ..options
+        };
+        let summary = compact_summary(&failed, &options);
+        assert!(
+            summary.contains("|ms1=812|stdout1=saved fw.bin|step2="),
+            "{}",
+            summary
+        );
+        assert!(summary.ends_with("|uptime_secs=42"), "{}", summary);
+
+        // Every pair splits cleanly, and long output is cut
+        failed.outputs[1].output.stderr = "x".repeat(10_000);
+        let summary = compact_summary(&failed, &options);
+        assert!(summary.len() < 500, "{}", summary.len());
+        assert!(summary
+            .split('|')
+            .all(|pair| pair.matches('=').count() == 1));
+    }
+
+    #[test]
+    fn test_status_details_format_in_document() {
+        let doc: JobDocument = serde_json::from_str(
+            r#"{ "version": "1.0", "steps": [], "statusDetailsFormat": "compact" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            doc.status_details_format,
+            Some(StatusDetailsFormat::Compact)
+        );
+        assert!(serde_json::from_str::<JobDocument>(
+            r#"{ "version": "1.0", "steps": [], "statusDetailsFormat": "xml" }"#
+        )
+        .is_err());
+    }
+
+    fn step_summaries(details: &serde_json::Value) -> Vec<serde_json::Value> {
+        serde_json::from_str(details["steps"].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_uptime_only_when_requested() {
+        let details = format_status_details(
+            &result(vec![step("Reboot", 0, 12)]),
+            &StatusDetailsOptions::default(),
+        );
+        assert!(details.get("uptime_secs").is_none());
+
+        let options = StatusDetailsOptions {
+            uptime_secs: Some(42),
+            ..Default::default()
+        };
+        let details = format_status_details(&result(vec![step("Reboot", 0, 12)]), &options);
+        assert_eq!(details["uptime_secs"], "42");
+    }
+
+    #[test]
+    fn test_numbers_as_strings_in_both_paths() {
+        let options = StatusDetailsOptions {
+            numbers_as_strings: true,
+            ..Default::default()
+        };
+
+        let single = format_status_details(&result(vec![step("A", 0, 12)]), &options);
+        assert_eq!(single["exit_code"], serde_json::json!("0"));
+        assert_eq!(single["execution_time_ms"], serde_json::json!("12"));
+
+        let multi =
+            format_status_details(&result(vec![step("A", 0, 12), step("B", 3, 40)]), &options);
+        let steps = step_summaries(&multi);
+        assert_eq!(steps[1]["exit_code"], serde_json::json!("3"));
+        assert_eq!(steps[1]["time_ms"], serde_json::json!("40"));
+    }
+
+    #[test]
+    fn test_native_numbers_in_multi_step_summary() {
+        let options = StatusDetailsOptions::default();
+
+        // Top-level values stay strings: AWS rejects non-string statusDetails values
+        let single = format_status_details(&result(vec![step("A", 0, 12)]), &options);
+        assert_eq!(single["exit_code"], serde_json::json!("0"));
+
+        let multi =
+            format_status_details(&result(vec![step("A", 0, 12), step("B", 3, 40)]), &options);
+        let steps = step_summaries(&multi);
+        assert_eq!(steps[1]["exit_code"], serde_json::json!(3));
+        assert_eq!(steps[1]["time_ms"], serde_json::json!(40));
+    }
+
+    #[test]
+    fn test_human_summary_names_failed_step_and_error() {
+        let mut install = step("Install", 2, 9_300);
+        install.output.stderr =
+            "Reading package lists...\nE: Could not get lock /var/lib/dpkg/lock\n".to_string();
+        let mut failed = result(vec![step("Download", 0, 3_000), install]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+
+        let quiet = format_status_details(&failed, &StatusDetailsOptions::default());
+        assert!(quiet.get("human_summary").is_none());
+
+        let options = StatusDetailsOptions {
+            human_summary_job_id: Some("fw-update-42".to_string()),
+            ..Default::default()
+        };
+        let details = format_status_details(&failed, &options);
+        assert_eq!(
+            details["human_summary"],
+            "Job fw-update-42: FAILED\n\
+             Failed step: Install (exit 2)\n\
+             Error: E: Could not get lock /var/lib/dpkg/lock\n\
+             Total time: 12.3s"
+        );
+    }
+
+    #[test]
+    fn test_human_summary_without_step_output_uses_reason() {
+        let failed = JobExecutionResult {
+            outputs: Vec::new(),
+            overall_success: false,
+            failed_step: Some("Install".to_string()),
+            failure_reason: Some("command not found: /opt/install.sh".to_string()),
+            reason_code: None,
+            retryable: false,
+        };
+        let summary = human_summary("job-1", &failed);
+        assert!(summary.contains("Failed step: Install\n"), "{}", summary);
+        assert!(summary.contains("Error: command not found: /opt/install.sh"));
+
+        let ok = human_summary("job-2", &result(vec![step("A", 0, 50)]));
+        assert_eq!(ok, "Job job-2: SUCCEEDED\nTotal time: 0.1s");
+    }
+
+    #[test]
+    fn test_timing_only_for_the_failed_step() {
+        let timing = StepTiming {
+            queue_ms: 0,
+            verify_ms: 12,
+            spawn_ms: 3,
+            run_ms: 45_000,
+            drain_ms: 1,
+        };
+        assert_eq!(timing.total_ms(), 45_016);
+        let timed = |name: &str, exit_code: i32| {
+            let mut step = step(name, exit_code, 45_004);
+            step.output.timing = timing;
+            step
+        };
+
+        let ok = format_status_details(
+            &result(vec![timed("A", 0)]),
+            &StatusDetailsOptions::default(),
+        );
+        assert!(ok.get("timing").is_none());
+
+        let mut failed = result(vec![timed("Install", 1)]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+        let details = format_status_details(&failed, &StatusDetailsOptions::default());
+        assert_eq!(
+            details["timing"],
+            "queue=0 verify=12 spawn=3 run=45000 drain=1"
+        );
+
+        let mut failed = result(vec![timed("Download", 0), timed("Install", 1)]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+        let details = format_status_details(&failed, &StatusDetailsOptions::default());
+        let steps = step_summaries(&details);
+        assert!(steps[0].get("timing").is_none());
+        assert_eq!(steps[1]["timing"], timing.compact());
+    }
+
+    #[test]
+    fn test_fail_reason_in_status_details() {
+        let mut install = step("Install", 0, 120);
+        install.output.stderr_line_count = 3;
+        install.output.fail_reason = Some(StepFailureReason::StderrLines {
+            lines: 3,
+            allowed: 1,
+        });
+        let mut failed = result(vec![install.clone()]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+        let details = format_status_details(&failed, &StatusDetailsOptions::default());
+        assert_eq!(details["fail_reason"], "stderr_lines=3>1");
+
+        let mut failed = result(vec![step("Download", 0, 50), install]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+        let details = format_status_details(&failed, &StatusDetailsOptions::default());
+        let steps = step_summaries(&details);
+        assert!(steps[0].get("fail_reason").is_none());
+        assert_eq!(steps[1]["fail_reason"], "stderr_lines=3>1");
+    }
+
+    #[test]
+    fn test_worst_case_result_leaves_room_for_reserved_keys() {
+        let mut install = step("Install", 2, 40);
+        install.output.stdout = "out".to_string();
+        install.output.stderr = "err".to_string();
+        install.output.policy_violation = Some("Command not in allowlist".to_string());
+        install.output.structured_error = Some(serde_json::json!({"code": "E_LOCK"}));
+        install.output.termination_signal = Some(TerminationSignal::Term);
+        install.output.failure_class = Some(FailureClass::Privilege);
+        install.output.ran_as_uid = Some(0);
+        install.output.ran_as_gid = Some(0);
+        install.output.started_at = 1;
+        install.output.finished_at = 2;
+        let mut failed = result(vec![install]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+        failed.failure_reason = Some("Timeout: command exceeded 30 seconds".to_string());
+        failed.reason_code = Some("TIMEOUT");
+        let handler_keys = ["execution_id", "thing_name", "job_attempts", "slow_steps"];
+        let options = StatusDetailsOptions {
+            include_stdout: true,
+            include_timestamps: true,
+            human_summary_job_id: Some("job-1".to_string()),
+            uptime_secs: Some(60),
+            device: Some("v0.3.1 rpi4 debian-12".to_string()),
+            include_ran_as: true,
+            reserved_keys: handler_keys.len(),
+            ..Default::default()
+        };
+
+        for outputs in [failed.outputs.clone(), Vec::new()] {
+            let mut status = JobStatus::from_failure(
+                &JobExecutionResult {
+                    outputs,
+                    ..failed.clone()
+                },
+                &options,
+            );
+            for key in handler_keys {
+                status = status.with_detail(key, "1".to_string());
+            }
+            // Keys without a reservation only take what is left
+            for key in ["queued_behind", "config_hash"] {
+                status = status.with_detail(key, "1".to_string());
+            }
+            let details = status.to_json()["statusDetails"].clone();
+            let details = details.as_object().unwrap();
+            assert!(details.len() <= MAX_STATUS_DETAILS, "{:?}", details);
+            for key in handler_keys {
+                assert!(details.contains_key(key), "{} dropped: {:?}", key, details);
+            }
+            assert_eq!(details["failed_step"], "Install");
+            assert_eq!(details["reason"], "Timeout: command exceeded 30 seconds");
+        }
+    }
+
+    #[test]
+    fn test_device_yields_to_field_budget() {
+        let options = StatusDetailsOptions {
+            device: Some("v0.3.1 rpi4 debian-12".to_string()),
+            ..Default::default()
+        };
+        let details = format_status_details(&result(vec![step("A", 0, 50)]), &options);
+        assert_eq!(details["device"], "v0.3.1 rpi4 debian-12");
+
+        // A failed step's fields and its timing fill the budget
+        let mut install = step("Install", 2, 40);
+        install.output.stdout = "out".to_string();
+        install.output.stderr = "err".to_string();
+        let mut failed = result(vec![install]);
+        failed.overall_success = false;
+        failed.failed_step = Some("Install".to_string());
+        failed.failure_reason = Some("exit 2".to_string());
+        let full = StatusDetailsOptions {
+            include_stdout: true,
+            ..options.clone()
+        };
+        let details = format_status_details(&failed, &full);
+        assert_eq!(details.as_object().unwrap().len(), MAX_STATUS_DETAILS);
+        assert!(details.get("timing").is_some());
+        assert!(details.get("device").is_none());
+
+        // Fields added afterwards push it out too
+        let mut status = JobStatus::from_success(&result(vec![step("A", 0, 50)]), &options);
+        for n in 0..4 {
+            status = status.with_detail(&format!("extra_{}", n), n.to_string());
+        }
+        let details = status.to_json()["statusDetails"].clone();
+        assert_eq!(details.as_object().unwrap().len(), MAX_STATUS_DETAILS);
+        assert_eq!(details["device"], "v0.3.1 rpi4 debian-12");
+        let details =
+            status.with_detail("extra_4", "4".to_string()).to_json()["statusDetails"].clone();
+        assert_eq!(details["extra_4"], "4");
+        assert!(details.get("device").is_none());
+    }
+
+    #[test]
+    fn test_timestamps_only_when_verbose() {
+        let mut timed = step("A", 0, 12);
+        timed.output.started_at = 1_700_000_000_000;
+        timed.output.finished_at = 1_700_000_000_012;
+        let single = result(vec![timed.clone()]);
+        let multi = result(vec![timed.clone(), timed]);
+
+        let quiet = StatusDetailsOptions::default();
+        assert!(format_status_details(&single, &quiet)
+            .get("started_at")
+            .is_none());
+        assert!(step_summaries(&format_status_details(&multi, &quiet))[0]
+            .get("started_at")
+            .is_none());
+
+        let verbose = StatusDetailsOptions {
+            include_timestamps: true,
+            ..Default::default()
+        };
+        let details = format_status_details(&single, &verbose);
+        assert_eq!(details["started_at"], "1700000000000");
+        assert_eq!(details["finished_at"], "1700000000012");
+
+        let steps = step_summaries(&format_status_details(&multi, &verbose));
+        assert_eq!(
+            steps[1]["finished_at"],
+            serde_json::json!(1_700_000_000_012_i64)
+        );
+    }
 }
 
 // ============================================================================
 // Job Status & Formatting
 // ============================================================================
 
+/// AWS IoT Jobs limit on the number of key-value pairs in statusDetails
+pub const MAX_STATUS_DETAILS: usize = 10;
+
+/// Options controlling how a result is rendered into statusDetails
+#[derive(Debug, Clone, Default)]
+pub struct StatusDetailsOptions {
+    pub include_stdout: bool,
+    /// Encode numbers in the multi-step summary as strings, matching the
+    /// top-level fields (which AWS requires to be strings)
+    pub numbers_as_strings: bool,
+    /// Include each step's wall-clock `started_at`/`finished_at`
+    pub include_timestamps: bool,
+    /// Job ID to head a multi-line `human_summary` with; no summary when unset
+    pub human_summary_job_id: Option<String>,
+    /// Device uptime to report as `uptime_secs`
+    pub uptime_secs: Option<u64>,
+    /// The document's `statusDetailsFormat`
+    pub format: StatusDetailsFormat,
+    /// Compact [`crate::device_info::DeviceInfo`] to report as `device`,
+    /// the first field dropped when the budget runs out
+    pub device: Option<String>,
+    /// Report each step's `ran_as_uid`/`ran_as_gid`
+    pub include_ran_as: bool,
+    /// Keys the caller adds afterwards with [`JobStatus::with_detail`] that
+    /// must not be crowded out; the result's own fields leave room for them.
+    /// Keys added afterwards without a reservation only get what is left
+    pub reserved_keys: usize,
+}
+
+/// Longest value in a compact summary; longer output keeps its start
+const COMPACT_VALUE_MAX_CHARS: usize = 200;
+
+/// Encode a number as a JSON string or a native JSON number
+fn number_value<N: Into<serde_json::Number> + ToString>(
+    n: N,
+    as_string: bool,
+) -> serde_json::Value {
+    if as_string {
+        serde_json::Value::String(n.to_string())
+    } else {
+        serde_json::Value::Number(n.into())
+    }
+}
+
 /// Format job execution result into IoT Jobs statusDetails
 /// AWS IoT Jobs requires all values in statusDetails to be strings, not nested objects
 /// AWS IoT Jobs has a limit of 10 key-value pairs in statusDetails
 pub fn format_status_details(
     result: &JobExecutionResult,
-    include_stdout: bool,
+    options: &StatusDetailsOptions,
 ) -> serde_json::Value {
+    if options.format == StatusDetailsFormat::Compact {
+        return serde_json::json!({ "summary": compact_summary(result, options) });
+    }
+
+    let include_stdout = options.include_stdout;
     let mut details = serde_json::Map::new();
+    // Room left for the keys the caller adds afterwards
+    let limit = MAX_STATUS_DETAILS.saturating_sub(options.reserved_keys);
 
     // Summary fields (always included)
     details.insert(
@@ -163,11 +1403,29 @@ pub fn format_status_details(
         serde_json::Value::String(result.overall_success.to_string()),
     );
 
+    // Every further field only while it fits, in order of importance
+    let put = |details: &mut serde_json::Map<String, serde_json::Value>,
+               key: &str,
+               value: serde_json::Value| {
+        if details.len() < limit {
+            details.insert(key.to_string(), value);
+        }
+    };
+
     if let Some(failed_step) = &result.failed_step {
-        details.insert(
-            "failed_step".to_string(),
-            serde_json::Value::String(failed_step.clone()),
-        );
+        put(&mut details, "failed_step", failed_step.clone().into());
+    }
+
+    if let Some(reason) = &result.failure_reason {
+        put(&mut details, "reason", reason.clone().into());
+    }
+
+    if let Some(code) = result.reason_code {
+        put(&mut details, "reason_code", code.into());
+    }
+
+    if result.policy_violation() {
+        put(&mut details, "policy_violation", "true".into());
     }
 
     // For multi-step jobs, create compact JSON strings to stay under 10 field limit
@@ -184,11 +1442,11 @@ pub fn format_status_details(
                 );
                 summary.insert(
                     "exit_code".to_string(),
-                    serde_json::Value::Number(step.output.exit_code.into()),
+                    number_value(step.output.exit_code, options.numbers_as_strings),
                 );
                 summary.insert(
                     "time_ms".to_string(),
-                    serde_json::Value::Number(step.output.execution_time_ms.into()),
+                    number_value(step.output.execution_time_ms, options.numbers_as_strings),
                 );
 
                 if include_stdout && !step.output.stdout.is_empty() {
@@ -209,56 +1467,339 @@ pub fn format_status_details(
                     summary.insert("ignored_failure".to_string(), serde_json::Value::Bool(true));
                 }
 
+                if let Some(attempts) = attempt_summary(&step.output.attempt_history) {
+                    summary.insert("attempts".to_string(), serde_json::Value::String(attempts));
+                }
+
+                if let Some(error) = &step.output.structured_error {
+                    summary.insert("error".to_string(), error.clone());
+                }
+
+                if let Some(signal) = step.output.termination_signal {
+                    summary.insert("termination_signal".to_string(), signal.to_string().into());
+                }
+
+                if let Some(class) = step.output.failure_class {
+                    summary.insert("failure_class".to_string(), class.label().into());
+                }
+
+                if let Some(reason) = &step.output.fail_reason {
+                    summary.insert("fail_reason".to_string(), reason.to_string().into());
+                }
+
+                if step.output.policy_violation.is_some() {
+                    summary.insert(
+                        "policy_violation".to_string(),
+                        serde_json::Value::Bool(true),
+                    );
+                }
+
+                if options.include_ran_as {
+                    if let (Some(uid), Some(gid)) = (step.output.ran_as_uid, step.output.ran_as_gid)
+                    {
+                        summary.insert(
+                            "ran_as_uid".to_string(),
+                            number_value(uid, options.numbers_as_strings),
+                        );
+                        summary.insert(
+                            "ran_as_gid".to_string(),
+                            number_value(gid, options.numbers_as_strings),
+                        );
+                    }
+                }
+
+                if result.failed_step.as_deref() == Some(step.step_name.as_str()) {
+                    summary.insert("timing".to_string(), step.output.timing.compact().into());
+                }
+
+                if options.include_timestamps {
+                    summary.insert(
+                        "started_at".to_string(),
+                        number_value(step.output.started_at, options.numbers_as_strings),
+                    );
+                    summary.insert(
+                        "finished_at".to_string(),
+                        number_value(step.output.finished_at, options.numbers_as_strings),
+                    );
+                }
+
                 serde_json::Value::Object(summary)
             })
             .collect();
 
-        details.insert(
-            "steps".to_string(),
-            serde_json::Value::String(serde_json::to_string(&step_summaries).unwrap_or_default()),
+        put(
+            &mut details,
+            "steps",
+            serde_json::to_string(&step_summaries)
+                .unwrap_or_default()
+                .into(),
         );
     } else {
         // Single step: use individual fields for easier reading
         if let Some(step_output) = result.outputs.first() {
-            details.insert(
-                "step_name".to_string(),
-                serde_json::Value::String(step_output.step_name.clone()),
+            put(
+                &mut details,
+                "step_name",
+                step_output.step_name.clone().into(),
             );
-            details.insert(
-                "exit_code".to_string(),
-                serde_json::Value::String(step_output.output.exit_code.to_string()),
+            put(
+                &mut details,
+                "exit_code",
+                step_output.output.exit_code.to_string().into(),
             );
-            details.insert(
-                "execution_time_ms".to_string(),
-                serde_json::Value::String(step_output.output.execution_time_ms.to_string()),
+
+            // What the step said about its failure goes before its timing
+            if !step_output.output.stderr.is_empty() {
+                put(
+                    &mut details,
+                    "stderr",
+                    step_output.output.stderr.clone().into(),
+                );
+            }
+
+            if let Some(reason) = &step_output.output.fail_reason {
+                put(&mut details, "fail_reason", reason.to_string().into());
+            }
+
+            put(
+                &mut details,
+                "execution_time_ms",
+                step_output.output.execution_time_ms.to_string().into(),
             );
 
             if include_stdout && !step_output.output.stdout.is_empty() {
-                details.insert(
-                    "stdout".to_string(),
-                    serde_json::Value::String(step_output.output.stdout.clone()),
+                put(
+                    &mut details,
+                    "stdout",
+                    step_output.output.stdout.clone().into(),
                 );
             }
 
-            if !step_output.output.stderr.is_empty() {
-                details.insert(
-                    "stderr".to_string(),
-                    serde_json::Value::String(step_output.output.stderr.clone()),
+            if step_output.ignored_failure {
+                put(&mut details, "ignored_failure", "true".into());
+            }
+
+            if let Some(attempts) = attempt_summary(&step_output.output.attempt_history) {
+                put(&mut details, "attempts", attempts.into());
+            }
+
+            if let Some(signal) = step_output.output.termination_signal {
+                put(
+                    &mut details,
+                    "termination_signal",
+                    signal.to_string().into(),
                 );
             }
 
-            if step_output.ignored_failure {
+            if let Some(class) = step_output.output.failure_class {
+                put(&mut details, "failure_class", class.label().into());
+            }
+
+            if result.failed_step.as_deref() == Some(step_output.step_name.as_str()) {
+                put(
+                    &mut details,
+                    "timing",
+                    step_output.output.timing.compact().into(),
+                );
+            }
+
+            // Both ids or neither, within the statusDetails field limit
+            if let (true, Some(uid), Some(gid)) = (
+                options.include_ran_as,
+                step_output.output.ran_as_uid,
+                step_output.output.ran_as_gid,
+            ) {
+                if details.len() + 2 <= limit {
+                    details.insert("ran_as_uid".to_string(), uid.to_string().into());
+                    details.insert("ran_as_gid".to_string(), gid.to_string().into());
+                }
+            }
+
+            // statusDetails values are strings, so the object goes in as compact JSON
+            if let Some(error) = &step_output.output.structured_error {
+                put(&mut details, "error", error.to_string().into());
+            }
+
+            // Both timestamps or neither, within the statusDetails field limit
+            if options.include_timestamps && details.len() + 2 <= limit {
                 details.insert(
-                    "ignored_failure".to_string(),
-                    serde_json::Value::String("true".to_string()),
+                    "started_at".to_string(),
+                    serde_json::Value::String(step_output.output.started_at.to_string()),
+                );
+                details.insert(
+                    "finished_at".to_string(),
+                    serde_json::Value::String(step_output.output.finished_at.to_string()),
                 );
             }
         }
     }
 
+    if let Some(uptime) = options.uptime_secs {
+        put(&mut details, "uptime_secs", uptime.to_string().into());
+    }
+
+    if let Some(job_id) = &options.human_summary_job_id {
+        put(
+            &mut details,
+            "human_summary",
+            human_summary(job_id, result).into(),
+        );
+    }
+
+    if let Some(device) = &options.device {
+        put(&mut details, "device", device.clone().into());
+    }
+
     serde_json::Value::Object(details)
 }
 
+/// Flat `key=value|key=value` rendering of a result for systems that cannot
+/// take nested JSON, e.g.
+///
+/// ```text
+/// steps=2|success=false|failed_step=Install|step1=Download|exit1=0|ms1=812|step2=Install|exit2=2|ms2=40|stderr2=E: lock held
+/// ```
+///
+/// Output is reduced to the last non-empty line per stream, values are cut
+/// to `COMPACT_VALUE_MAX_CHARS`, and `%`, `|`, `=` and line breaks in values
+/// are percent-encoded so the pairs always split cleanly.
+pub fn compact_summary(result: &JobExecutionResult, options: &StatusDetailsOptions) -> String {
+    let mut pairs = vec![
+        ("steps".to_string(), result.outputs.len().to_string()),
+        ("success".to_string(), result.overall_success.to_string()),
+    ];
+    if let Some(failed_step) = &result.failed_step {
+        pairs.push(("failed_step".to_string(), failed_step.clone()));
+    }
+    if let Some(reason) = &result.failure_reason {
+        pairs.push(("reason".to_string(), reason.clone()));
+    }
+    if let Some(code) = result.reason_code {
+        pairs.push(("reason_code".to_string(), code.to_string()));
+    }
+    if result.policy_violation() {
+        pairs.push(("policy_violation".to_string(), "true".to_string()));
+    }
+
+    for (index, step) in result.outputs.iter().enumerate() {
+        let n = index + 1;
+        let output = &step.output;
+        pairs.push((format!("step{}", n), step.step_name.clone()));
+        pairs.push((format!("exit{}", n), output.exit_code.to_string()));
+        pairs.push((format!("ms{}", n), output.execution_time_ms.to_string()));
+        if options.include_stdout {
+            if let Some(line) = last_line(&output.stdout) {
+                pairs.push((format!("stdout{}", n), line.to_string()));
+            }
+        }
+        if let Some(line) = last_line(&output.stderr) {
+            pairs.push((format!("stderr{}", n), line.to_string()));
+        }
+        if step.ignored_failure {
+            pairs.push((format!("ignored{}", n), "true".to_string()));
+        }
+        if let Some(error) = &output.structured_error {
+            pairs.push((format!("error{}", n), error.to_string()));
+        }
+        if let Some(signal) = output.termination_signal {
+            pairs.push((format!("signal{}", n), signal.to_string()));
+        }
+        if let (true, Some(uid), Some(gid)) =
+            (options.include_ran_as, output.ran_as_uid, output.ran_as_gid)
+        {
+            pairs.push((format!("uid{}", n), uid.to_string()));
+            pairs.push((format!("gid{}", n), gid.to_string()));
+        }
+        if options.include_timestamps {
+            pairs.push((format!("start{}", n), output.started_at.to_string()));
+            pairs.push((format!("end{}", n), output.finished_at.to_string()));
+        }
+    }
+
+    if let Some(uptime) = options.uptime_secs {
+        pairs.push(("uptime_secs".to_string(), uptime.to_string()));
+    }
+
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, compact_value(value)))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn last_line(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+}
+
+fn compact_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for c in value.chars().take(COMPACT_VALUE_MAX_CHARS) {
+        match c {
+            '%' => encoded.push_str("%25"),
+            '|' => encoded.push_str("%7C"),
+            '=' => encoded.push_str("%3D"),
+            '\n' => encoded.push_str("%0A"),
+            '\r' => encoded.push_str("%0D"),
+            _ => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+/// Short plain-text summary for forwarding to chat or email, e.g.
+///
+/// ```text
+/// Job fw-update-42: FAILED
+/// Failed step: Install (exit 2)
+/// Error: E: Could not get lock /var/lib/dpkg/lock
+/// Total time: 12.3s
+/// ```
+pub fn human_summary(job_id: &str, result: &JobExecutionResult) -> String {
+    let outcome = if result.overall_success {
+        "SUCCEEDED"
+    } else {
+        "FAILED"
+    };
+    let mut lines = vec![format!("Job {}: {}", job_id, outcome)];
+
+    if let Some(name) = &result.failed_step {
+        let failed = result.outputs.iter().rev().find(|s| &s.step_name == name);
+        match failed {
+            Some(step) => lines.push(format!(
+                "Failed step: {} (exit {})",
+                name, step.output.exit_code
+            )),
+            None => lines.push(format!("Failed step: {}", name)),
+        }
+
+        let last_stderr = failed.and_then(|step| {
+            step.output
+                .stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+        });
+        if let Some(error) = last_stderr.or(result.failure_reason.as_deref()) {
+            lines.push(format!("Error: {}", error.trim()));
+        }
+    } else if let Some(reason) = &result.failure_reason {
+        lines.push(format!("Error: {}", reason));
+    }
+
+    let total_ms: u64 = result
+        .outputs
+        .iter()
+        .map(|step| step.output.execution_time_ms)
+        .sum();
+    lines.push(format!("Total time: {:.1}s", total_ms as f64 / 1000.0));
+
+    lines.join("\n")
+}
+
 /// Job status for IoT Jobs updates
 #[derive(Debug, Clone)]
 pub struct JobStatus {
@@ -267,7 +1808,7 @@ pub struct JobStatus {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "UPPERCASE")]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum JobStatusType {
     InProgress,
     Succeeded,
@@ -275,19 +1816,45 @@ pub enum JobStatusType {
 }
 
 impl JobStatus {
+    /// Whether this status ends the job execution
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self.status, JobStatusType::InProgress)
+    }
+
     /// Create a succeeded status from execution result
-    pub fn from_success(result: &JobExecutionResult, include_stdout: bool) -> Self {
+    pub fn from_success(result: &JobExecutionResult, options: &StatusDetailsOptions) -> Self {
         Self {
             status: JobStatusType::Succeeded,
-            status_details: format_status_details(result, include_stdout),
+            status_details: format_status_details(result, options),
         }
     }
 
     /// Create a failed status from execution result
-    pub fn from_failure(result: &JobExecutionResult, include_stdout: bool) -> Self {
+    pub fn from_failure(result: &JobExecutionResult, options: &StatusDetailsOptions) -> Self {
         Self {
             status: JobStatusType::Failed,
-            status_details: format_status_details(result, include_stdout),
+            status_details: format_status_details(result, options),
+        }
+    }
+
+    /// Create an IN_PROGRESS heartbeat for a job that is still running
+    pub fn running(elapsed: std::time::Duration) -> Self {
+        Self {
+            status: JobStatusType::InProgress,
+            status_details: serde_json::json!({
+                "running_secs": elapsed.as_secs().to_string(),
+            }),
+        }
+    }
+
+    /// Create an IN_PROGRESS status for a job waiting in the on-device queue
+    pub fn queued(behind: usize) -> Self {
+        Self {
+            status: JobStatusType::InProgress,
+            status_details: serde_json::json!({
+                "queued_on_device": "true",
+                "queued_behind": behind.to_string(),
+            }),
         }
     }
 
@@ -311,6 +1878,22 @@ impl JobStatus {
         }
     }
 
+    /// Add an extra string field to statusDetails if the 10-field budget
+    /// allows it, dropping `device` to make room
+    pub fn with_detail(mut self, key: &str, value: String) -> Self {
+        if let Some(details) = self.status_details.as_object_mut() {
+            if details.len() >= MAX_STATUS_DETAILS && !details.contains_key(key) {
+                details.remove("device");
+            }
+            if details.len() < MAX_STATUS_DETAILS || details.contains_key(key) {
+                details.insert(key.to_string(), serde_json::Value::String(value));
+            } else {
+                tracing::debug!(key = %key, "statusDetails field limit reached, dropping field");
+            }
+        }
+        self
+    }
+
     /// Convert to JSON for IoT Jobs API
     pub fn to_json(&self) -> serde_json::Value {
         serde_json::json!({
@@ -0,0 +1,85 @@
+//! OTLP export of the `job` and `step` spans (`otlp` feature)
+//!
+//! Off unless `OTEL_EXPORTER_OTLP_ENDPOINT` or
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The exporter reads the other
+//! standard variables itself (`OTEL_EXPORTER_OTLP_HEADERS`,
+//! `OTEL_EXPORTER_OTLP_TIMEOUT`, `OTEL_SERVICE_NAME`,
+//! `OTEL_RESOURCE_ATTRIBUTES`, ...) and sends spans over OTLP/HTTP from a
+//! background thread.
+
+use crate::error::{DeviceOpsError, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Variables that name a collector; without one nothing is exported
+const ENDPOINT_VARS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+
+/// `service.name` when `OTEL_SERVICE_NAME` is not set
+const DEFAULT_SERVICE_NAME: &str = "device-ops-component";
+
+/// Flushes and stops the exporter when dropped, so spans of the last job
+/// are not lost at shutdown
+pub struct OtlpGuard(SdkTracerProvider);
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("Failed to flush OTLP spans: {}", e);
+        }
+    }
+}
+
+/// The layer exporting spans, when an endpoint is configured in `env`
+pub fn init<S, F>(env: F) -> Result<Option<(OpenTelemetryLayer<S, Tracer>, OtlpGuard)>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    F: Fn(&str) -> Option<String>,
+{
+    let configured = |name: &str| env(name).is_some_and(|value| !value.is_empty());
+    if !ENDPOINT_VARS.iter().any(|name| configured(name)) {
+        return Ok(None);
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| DeviceOpsError::ConfigError(format!("OTLP exporter: {}", e)))?;
+    let mut resource = Resource::builder();
+    if !configured("OTEL_SERVICE_NAME") {
+        resource = resource.with_service_name(DEFAULT_SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    Ok(Some((
+        tracing_opentelemetry::layer().with_tracer(tracer),
+        OtlpGuard(provider),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::Registry;
+
+    #[test]
+    fn test_off_without_an_endpoint() {
+        let export = init::<Registry, _>(|name| {
+            (name == "OTEL_SERVICE_NAME").then(|| "edge-fleet".to_string())
+        });
+        assert!(export.unwrap().is_none());
+
+        let export =
+            init::<Registry, _>(|name| (name == "OTEL_EXPORTER_OTLP_ENDPOINT").then(String::new));
+        assert!(export.unwrap().is_none());
+    }
+}
@@ -1,3 +1,3 @@
 mod validation;
 
-pub use validation::{validate_job_document, SecurityValidator};
+pub use validation::{check_unknown_fields, validate_job_document, SecurityValidator};
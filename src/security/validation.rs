@@ -1,13 +1,23 @@
-use crate::config::SecurityConfig;
+use crate::config::{AllowlistViolationAction, DocumentPolicyConfig, SecurityConfig};
 use crate::error::{DeviceOpsError, Result};
-use crate::models::{Command, JobDocument};
+use crate::metrics::{self, SecurityRule};
+use crate::models::{
+    canonical_action_type, Command, Criterion, JobAction, JobDocument, SUPPORTED_ACTION_TYPES,
+};
 use std::path::Path;
 
+/// Longest `delayBeforeMs` a step may ask for (10 minutes)
+const MAX_DELAY_BEFORE_MS: u64 = 10 * 60 * 1000;
+
 // ============================================================================
 // Job Document Validation
 // ============================================================================
 
-pub fn validate_job_document(document: &JobDocument) -> Result<()> {
+pub fn validate_job_document(
+    document: &mut JobDocument,
+    config: &SecurityConfig,
+    policy: &DocumentPolicyConfig,
+) -> Result<()> {
     // Validate version
     if document.version != "1.0" {
         return Err(DeviceOpsError::InvalidJobDocument(format!(
@@ -23,49 +33,280 @@ pub fn validate_job_document(document: &JobDocument) -> Result<()> {
         ));
     }
 
-    // Validate all steps and final step
-    let all_steps: Vec<&crate::models::JobStep> = document
+    if document.run_as_user.is_some() && !policy.allow_run_as_user {
+        return Err(DeviceOpsError::InvalidJobDocument(
+            "The document uses runAsUser, which document_policy.allow_run_as_user forbids"
+                .to_string(),
+        ));
+    }
+
+    if let Some(topic) = &document.result_topic {
+        validate_result_topic(topic, &config.result_topic_prefixes)?;
+    }
+
+    // Every group policy must name a group used by some step (catches typos)
+    if let Some(policies) = &document.group_policies {
+        for group in policies.keys() {
+            let used = document
+                .steps
+                .iter()
+                .any(|step| step.action.group.as_deref() == Some(group.as_str()));
+            if !used {
+                return Err(DeviceOpsError::InvalidJobDocument(format!(
+                    "groupPolicies references unknown group: {}",
+                    group
+                )));
+            }
+        }
+    }
+
+    // Accept casing mistakes in action types and store the supported spelling
+    let steps = document.steps.iter_mut();
+    for step in steps.chain(document.final_step.as_deref_mut()) {
+        if let Some(canonical) = canonical_action_type(&step.action.action_type) {
+            if step.action.action_type != canonical {
+                tracing::debug!(
+                    step = %step.action.name,
+                    action_type = %step.action.action_type,
+                    "Normalized action type to {}",
+                    canonical
+                );
+                step.action.action_type = canonical.to_string();
+            }
+        }
+    }
+
+    // Validate all steps and final step; errors name the offending field
+    let steps = document
         .steps
         .iter()
-        .chain(document.final_step.as_ref().map(|s| s.as_ref()))
-        .collect();
+        .enumerate()
+        .map(|(index, step)| (format!("steps[{}]", index), step));
+    let final_step = document
+        .final_step
+        .as_deref()
+        .map(|step| ("finalStep".to_string(), step));
+
+    for (location, step) in steps.chain(final_step) {
+        let field = |name: &str| format!("{}.action.{}", location, name);
 
-    for step in all_steps {
         // Validate action type
-        if step.action.action_type != "runCommand" {
-            return Err(DeviceOpsError::InvalidJobDocument(format!(
-                "Unsupported action type: {}. Only 'runCommand' is supported",
-                step.action.action_type
-            )));
+        if !SUPPORTED_ACTION_TYPES.contains(&step.action.action_type.as_str()) {
+            return Err(DeviceOpsError::InvalidDocumentField {
+                path: field("type"),
+                message: format!(
+                    "Unsupported action type: {}. Only 'runCommand' is supported",
+                    step.action.action_type
+                ),
+            });
+        }
+        if let Some(allowed) = &policy.allowed_action_types {
+            if !allowed.contains(&step.action.action_type) {
+                return Err(DeviceOpsError::InvalidDocumentField {
+                    path: field("type"),
+                    message: format!(
+                        "Step '{}' uses action type {}, which document_policy.allowed_action_types does not list",
+                        step.action.name, step.action.action_type
+                    ),
+                });
+            }
         }
 
         // Validate command length
         if step.action.input.command.len() > 4096 {
-            return Err(DeviceOpsError::InvalidJobDocument(
-                "Command too long (max 4096 characters)".to_string(),
-            ));
+            return Err(DeviceOpsError::InvalidDocumentField {
+                path: field("input.command"),
+                message: "Command too long (max 4096 characters)".to_string(),
+            });
         }
 
         // Validate command is not empty
         if step.action.input.command.trim().is_empty() {
-            return Err(DeviceOpsError::InvalidJobDocument(
-                "Command cannot be empty".to_string(),
-            ));
+            return Err(DeviceOpsError::InvalidDocumentField {
+                path: field("input.command"),
+                message: "Command cannot be empty".to_string(),
+            });
+        }
+
+        if let Some((name, message)) = check_document_policy(&step.action, policy) {
+            return Err(DeviceOpsError::InvalidDocumentField {
+                path: field(name),
+                message,
+            });
+        }
+
+        if let Some(slice) = &step.action.cgroup {
+            if !is_valid_slice_name(slice) {
+                return Err(DeviceOpsError::InvalidDocumentField {
+                    path: field("cgroup"),
+                    message: format!("Invalid cgroup slice name: {}", slice),
+                });
+            }
         }
 
         // Validate timeout is reasonable
         if let Some(timeout) = step.action.input.timeout {
             if timeout == 0 || timeout > 86400 {
-                return Err(DeviceOpsError::InvalidJobDocument(
-                    "Timeout must be between 1 and 86400 seconds (24 hours)".to_string(),
-                ));
+                return Err(DeviceOpsError::InvalidDocumentField {
+                    path: field("input.timeout"),
+                    message: "Timeout must be between 1 and 86400 seconds (24 hours)".to_string(),
+                });
             }
         }
+
+        if let Some(idle) = step.action.idle_timeout {
+            if idle == 0 || idle > 86400 {
+                return Err(DeviceOpsError::InvalidDocumentField {
+                    path: field("idleTimeout"),
+                    message: "idleTimeout must be between 1 and 86400 seconds (24 hours)"
+                        .to_string(),
+                });
+            }
+        }
+
+        if let Some(criteria) = &step.action.success_criteria {
+            let empty = criteria.iter().any(
+                |criterion| matches!(criterion, Criterion::ExitCodeIn(codes) if codes.is_empty()),
+            );
+            if criteria.is_empty() || empty {
+                return Err(DeviceOpsError::InvalidDocumentField {
+                    path: field("successCriteria"),
+                    message: "successCriteria and exitCodeIn must not be empty".to_string(),
+                });
+            }
+        }
+
+        if let Some(delay) = step.action.delay_before_ms {
+            if delay > MAX_DELAY_BEFORE_MS {
+                return Err(DeviceOpsError::InvalidDocumentField {
+                    path: field("delayBeforeMs"),
+                    message: format!(
+                        "delayBeforeMs must be at most {} (10 minutes)",
+                        MAX_DELAY_BEFORE_MS
+                    ),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the fields a document carried that this build does not know (see
+/// `Job::unknown_fields`): ignored, unless `document_policy.allow_unknown_fields`
+/// is off
+pub fn check_unknown_fields(fields: &[String], policy: &DocumentPolicyConfig) -> Result<()> {
+    let Some(first) = fields.first() else {
+        return Ok(());
+    };
+    if !policy.allow_unknown_fields {
+        return Err(DeviceOpsError::InvalidDocumentField {
+            path: first.clone(),
+            message: "unknown field, which document_policy.allow_unknown_fields forbids"
+                .to_string(),
+        });
+    }
+    tracing::debug!(fields = ?fields, "Ignoring job document fields this build does not know");
+    Ok(())
+}
+
+/// Step settings the fleet's `document_policy` forbids: the first one the
+/// step uses, as its field path under `action` and the reason
+fn check_document_policy(
+    action: &JobAction,
+    policy: &DocumentPolicyConfig,
+) -> Option<(&'static str, String)> {
+    let features = [
+        (
+            "runAsUser",
+            action.run_as_user.is_some(),
+            policy.allow_run_as_user,
+            "allow_run_as_user",
+        ),
+        (
+            "expandEnvInArgs",
+            action.expand_env_in_args.unwrap_or(false),
+            policy.allow_expand_env_in_args,
+            "allow_expand_env_in_args",
+        ),
+        (
+            "cgroup",
+            action.cgroup.is_some(),
+            policy.allow_cgroup,
+            "allow_cgroup",
+        ),
+        (
+            "input.workingDirectory",
+            action.input.working_directory.is_some(),
+            policy.allow_working_directory,
+            "allow_working_directory",
+        ),
+    ];
+
+    features
+        .into_iter()
+        .find(|(_, used, allowed, _)| *used && !allowed)
+        .map(|(field, _, _, key)| {
+            let name = field.rsplit('.').next().unwrap_or(field);
+            (
+                field,
+                format!(
+                    "Step '{}' uses {}, which document_policy.{} forbids",
+                    action.name, name, key
+                ),
+            )
+        })
+}
+
+/// A systemd slice unit name: unit name characters only, optionally ending
+/// in `.slice` (systemd-run adds it otherwise)
+fn is_valid_slice_name(slice: &str) -> bool {
+    let name = slice.strip_suffix(".slice").unwrap_or(slice);
+    !name.is_empty()
+        && slice.len() <= 255
+        && !name.starts_with(['-', '.'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '.' | '-'))
+}
+
+/// Check a document-supplied result topic against the configured prefix allowlist
+fn validate_result_topic(topic: &str, allowed_prefixes: &[String]) -> Result<()> {
+    if topic.contains('+') || topic.contains('#') {
+        return Err(DeviceOpsError::InvalidJobDocument(format!(
+            "resultTopic must not contain MQTT wildcards: {}",
+            topic
+        )));
+    }
+
+    if !allowed_prefixes
+        .iter()
+        .any(|prefix| under_topic_prefix(topic, prefix))
+    {
+        metrics::global().record_security_denial(SecurityRule::ResultTopic);
+        let allowed = if allowed_prefixes.is_empty() {
+            "none configured".to_string()
+        } else {
+            allowed_prefixes.join(", ")
+        };
+        return Err(DeviceOpsError::InvalidJobDocument(format!(
+            "resultTopic '{}' is not under an allowed prefix (allowed: {})",
+            topic, allowed
+        )));
     }
 
     Ok(())
 }
 
+/// Whether `topic` is `prefix` or lies below it; a prefix only matches whole
+/// topic levels, so `results/team-a` does not admit `results/team-ab`
+fn under_topic_prefix(topic: &str, prefix: &str) -> bool {
+    match topic.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
 // ============================================================================
 // Security Validation (Command Allowlist & Path Traversal)
 // ============================================================================
@@ -73,6 +314,8 @@ pub fn validate_job_document(document: &JobDocument) -> Result<()> {
 pub struct SecurityValidator {
     command_allowlist: Vec<String>,
     path_allowlist: Vec<String>,
+    working_dir_allowlist: Vec<String>,
+    violation_action: AllowlistViolationAction,
 }
 
 impl SecurityValidator {
@@ -80,41 +323,101 @@ impl SecurityValidator {
         Self {
             command_allowlist: config.command_allowlist,
             path_allowlist: config.path_allowlist,
+            working_dir_allowlist: config.working_dir_allowlist,
+            violation_action: config.allowlist_violation_action,
         }
     }
 
-    pub fn validate(&self, command: &Command) -> Result<()> {
+    /// Check a command before it runs. With `allowlist_violation_action:
+    /// flag` a command outside the command or path allowlist passes, and the
+    /// broken rule is returned for the step's result to carry.
+    pub fn validate(&self, command: &Command) -> Result<Option<String>> {
         // Check for path traversal
         if self.has_path_traversal(&command.script_path) {
+            metrics::global().record_security_denial(SecurityRule::PathTraversal);
             return Err(DeviceOpsError::SecurityError(format!(
                 "Path traversal detected: {}",
                 command.script_path
             )));
         }
 
-        // Check if command is in allowlist
-        if !self.command_allowlist.is_empty() && !self.is_command_allowed(&command.script_path) {
+        let violation = if !self.command_allowlist.is_empty()
+            && !self.is_command_allowed(&command.script_path)
+        {
+            Some((
+                SecurityRule::CommandAllowlist,
+                format!("Command not in allowlist: {}", command.script_path),
+            ))
+        } else if !self.path_allowlist.is_empty() && !self.is_path_allowed(&command.script_path) {
+            Some((
+                SecurityRule::PathAllowlist,
+                format!("Path not in allowlist: {}", command.script_path),
+            ))
+        } else {
+            None
+        };
+
+        let flagged = match violation {
+            Some((rule, message)) if self.violation_action == AllowlistViolationAction::Reject => {
+                metrics::global().record_security_denial(rule);
+                return Err(DeviceOpsError::SecurityError(message));
+            }
+            Some((_, message)) => Some(message),
+            None => None,
+        };
+
+        if let Some(dir) = &command.working_dir {
+            self.validate_working_dir(dir)?;
+        }
+
+        if let Some(message) = &flagged {
+            tracing::warn!(
+                script = %command.script_path,
+                violation = %message,
+                "Running command despite an allowlist violation (allowlist_violation_action: flag)"
+            );
+        }
+        Ok(flagged)
+    }
+
+    fn validate_working_dir(&self, dir: &str) -> Result<()> {
+        if self.has_path_traversal(dir) {
+            metrics::global().record_security_denial(SecurityRule::PathTraversal);
             return Err(DeviceOpsError::SecurityError(format!(
-                "Command not in allowlist: {}",
-                command.script_path
+                "Path traversal detected in working directory: {}",
+                dir
             )));
         }
 
-        // Check if path is in allowed paths
-        if !self.path_allowlist.is_empty() && !self.is_path_allowed(&command.script_path) {
+        let path = Path::new(dir);
+        if !self.working_dir_allowlist.is_empty()
+            && !self
+                .working_dir_allowlist
+                .iter()
+                .any(|allowed| path.starts_with(allowed))
+        {
+            metrics::global().record_security_denial(SecurityRule::WorkingDirAllowlist);
             return Err(DeviceOpsError::SecurityError(format!(
-                "Path not in allowlist: {}",
-                command.script_path
+                "Working directory not in allowlist: {}",
+                dir
             )));
         }
 
         Ok(())
     }
 
+    /// Entries match exactly, except `dir/*` which allows any direct child of
+    /// `dir` (not recursive). No other wildcards are interpreted.
     fn is_command_allowed(&self, script_path: &str) -> bool {
         self.command_allowlist
             .iter()
-            .any(|allowed| script_path == allowed)
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(dir) => {
+                    let dir = if dir.is_empty() { "/" } else { dir };
+                    Path::new(script_path).parent() == Some(Path::new(dir))
+                }
+                None => script_path == allowed,
+            })
     }
 
     fn is_path_allowed(&self, script_path: &str) -> bool {
@@ -148,7 +451,7 @@ impl SecurityValidator {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::{JobAction, JobInput, JobStep};
+    use crate::models::{JobInput, JobStep};
 
     // ========================================================================
     // Job Document Validation Tests
@@ -156,7 +459,7 @@ mod tests {
 
     #[test]
     fn test_validate_valid_document() {
-        let doc = JobDocument {
+        let mut doc = JobDocument {
             version: "1.0".to_string(),
             steps: vec![JobStep {
                 action: JobAction {
@@ -166,22 +469,30 @@ mod tests {
                         command: "/opt/test.sh".to_string(),
                         args: None,
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
-        assert!(validate_job_document(&doc).is_ok());
+        assert!(validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default()
+        )
+        .is_ok());
     }
 
     #[test]
     fn test_validate_invalid_version() {
-        let doc = JobDocument {
+        let mut doc = JobDocument {
             version: "2.0".to_string(),
             steps: vec![JobStep {
                 action: JobAction {
@@ -191,22 +502,30 @@ mod tests {
                         command: "/opt/test.sh".to_string(),
                         args: None,
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
-        assert!(validate_job_document(&doc).is_err());
+        assert!(validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default()
+        )
+        .is_err());
     }
 
     #[test]
     fn test_validate_invalid_action_type() {
-        let doc = JobDocument {
+        let mut doc = JobDocument {
             version: "1.0".to_string(),
             steps: vec![JobStep {
                 action: JobAction {
@@ -216,22 +535,30 @@ mod tests {
                         command: "/opt/test.sh".to_string(),
                         args: None,
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
-        assert!(validate_job_document(&doc).is_err());
+        assert!(validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default()
+        )
+        .is_err());
     }
 
     #[test]
     fn test_validate_empty_command() {
-        let doc = JobDocument {
+        let mut doc = JobDocument {
             version: "1.0".to_string(),
             steps: vec![JobStep {
                 action: JobAction {
@@ -241,29 +568,49 @@ mod tests {
                         command: "   ".to_string(),
                         args: None,
                         timeout: None,
+                        ..Default::default()
                     },
                     run_as_user: None,
                     ignore_step_failure: None,
                     allow_std_err: None,
+                    ..Default::default()
                 },
             }],
             final_step: None,
             include_std_out: None,
+            ..Default::default()
         };
 
-        assert!(validate_job_document(&doc).is_err());
+        assert!(validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default()
+        )
+        .is_err());
     }
 
     // ========================================================================
     // Security Validation Tests
     // ========================================================================
 
+    #[test]
+    fn test_validate_slice_name() {
+        assert!(is_valid_slice_name("device-ops.slice"));
+        assert!(is_valid_slice_name("device-ops-updates"));
+        assert!(is_valid_slice_name("system-device_ops:1.slice"));
+
+        for bad in ["", ".slice", "-x.slice", "../x.slice", "a b.slice", "a/b"] {
+            assert!(!is_valid_slice_name(bad), "{:?}", bad);
+        }
+    }
+
     #[test]
     fn test_path_traversal_detection() {
         let config = SecurityConfig {
             enabled: true,
             command_allowlist: vec![],
             path_allowlist: vec![],
+            ..Default::default()
         };
         let validator = SecurityValidator::new(config);
 
@@ -272,6 +619,8 @@ mod tests {
             script_path: "../etc/passwd".to_string(),
             args: vec![],
             run_as_user: None,
+            working_dir: None,
+            ..Default::default()
         };
         assert!(validator.validate(&command).is_err());
 
@@ -280,6 +629,8 @@ mod tests {
             script_path: "/opt/%2e%2e/etc/passwd".to_string(),
             args: vec![],
             run_as_user: None,
+            working_dir: None,
+            ..Default::default()
         };
         assert!(validator.validate(&command2).is_err());
 
@@ -288,6 +639,8 @@ mod tests {
             script_path: "relative/path.sh".to_string(),
             args: vec![],
             run_as_user: None,
+            working_dir: None,
+            ..Default::default()
         };
         assert!(validator.validate(&command3).is_err());
     }
@@ -298,6 +651,7 @@ mod tests {
             enabled: true,
             command_allowlist: vec!["/opt/device-scripts/test.sh".to_string()],
             path_allowlist: vec![],
+            ..Default::default()
         };
         let validator = SecurityValidator::new(config);
 
@@ -305,6 +659,8 @@ mod tests {
             script_path: "/opt/device-scripts/test.sh".to_string(),
             args: vec![],
             run_as_user: None,
+            working_dir: None,
+            ..Default::default()
         };
 
         assert!(validator.validate(&allowed_command).is_ok());
@@ -313,8 +669,455 @@ mod tests {
             script_path: "/tmp/malicious.sh".to_string(),
             args: vec![],
             run_as_user: None,
+            working_dir: None,
+            ..Default::default()
         };
 
         assert!(validator.validate(&disallowed_command).is_err());
     }
+
+    #[test]
+    fn test_allowlist_violation_flag_mode() {
+        let validator = SecurityValidator::new(SecurityConfig {
+            enabled: true,
+            command_allowlist: vec!["/opt/device-scripts/test.sh".to_string()],
+            path_allowlist: vec!["/opt/".to_string()],
+            allowlist_violation_action: AllowlistViolationAction::Flag,
+            ..Default::default()
+        });
+        let command = |path: &str| Command {
+            script_path: path.to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            validator
+                .validate(&command("/opt/device-scripts/test.sh"))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            validator
+                .validate(&command("/tmp/other.sh"))
+                .unwrap()
+                .as_deref(),
+            Some("Command not in allowlist: /tmp/other.sh")
+        );
+        // Traversal and working directory checks still reject
+        assert!(validator.validate(&command("/opt/../tmp/x.sh")).is_err());
+        let mut outside = command("/opt/device-scripts/test.sh");
+        outside.working_dir = Some("relative".to_string());
+        assert!(validator.validate(&outside).is_err());
+    }
+
+    #[test]
+    fn test_command_allowlist_directory_entry() {
+        let validator = SecurityValidator::new(SecurityConfig {
+            enabled: true,
+            command_allowlist: vec!["/opt/scripts/*".to_string()],
+            ..Default::default()
+        });
+
+        assert!(validator.is_command_allowed("/opt/scripts/a.sh"));
+        // Not recursive
+        assert!(!validator.is_command_allowed("/opt/scripts/sub/b.sh"));
+        assert!(!validator.is_command_allowed("/opt/scripts-other/a.sh"));
+        assert!(!validator.is_command_allowed("/opt/scripts"));
+    }
+
+    #[test]
+    fn test_command_allowlist_other_wildcards_are_literal() {
+        let validator = SecurityValidator::new(SecurityConfig {
+            enabled: true,
+            command_allowlist: vec!["/opt/scripts/*.sh".to_string()],
+            ..Default::default()
+        });
+
+        assert!(!validator.is_command_allowed("/opt/scripts/a.sh"));
+        assert!(validator.is_command_allowed("/opt/scripts/*.sh"));
+    }
+
+    fn command_in_dir(dir: &str) -> Command {
+        Command {
+            script_path: "/opt/device-scripts/test.sh".to_string(),
+            args: vec![],
+            run_as_user: None,
+            working_dir: Some(dir.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_working_dir_allowlist() {
+        let config = SecurityConfig {
+            enabled: true,
+            working_dir_allowlist: vec!["/var/lib/device-ops".to_string()],
+            ..Default::default()
+        };
+        let validator = SecurityValidator::new(config);
+
+        assert!(validator
+            .validate(&command_in_dir("/var/lib/device-ops"))
+            .is_ok());
+        assert!(validator
+            .validate(&command_in_dir("/var/lib/device-ops/cache"))
+            .is_ok());
+
+        let err = validator.validate(&command_in_dir("/etc")).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Working directory not in allowlist"));
+        // Prefix match is per path component
+        assert!(validator
+            .validate(&command_in_dir("/var/lib/device-ops-other"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_working_dir_without_allowlist_still_checks_traversal() {
+        let validator = SecurityValidator::new(SecurityConfig {
+            enabled: true,
+            ..Default::default()
+        });
+
+        assert!(validator.validate(&command_in_dir("/tmp")).is_ok());
+        assert!(validator.validate(&command_in_dir("/tmp/../etc")).is_err());
+        assert!(validator.validate(&command_in_dir("relative/dir")).is_err());
+    }
+
+    fn document_with_result_topic(topic: &str) -> JobDocument {
+        JobDocument {
+            version: "1.0".to_string(),
+            steps: vec![JobStep {
+                action: JobAction {
+                    name: "Test".to_string(),
+                    action_type: "runCommand".to_string(),
+                    input: JobInput {
+                        command: "/opt/test.sh".to_string(),
+                        args: None,
+                        timeout: None,
+                        ..Default::default()
+                    },
+                    run_as_user: None,
+                    ignore_step_failure: None,
+                    allow_std_err: None,
+                    ..Default::default()
+                },
+            }],
+            result_topic: Some(topic.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_result_topic_under_allowed_prefix() {
+        let config = SecurityConfig {
+            result_topic_prefixes: vec!["results/team-a/".to_string()],
+            ..Default::default()
+        };
+
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        assert!(validate_job_document(&mut doc, &config, &DocumentPolicyConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn test_result_topic_prefix_matches_whole_levels() {
+        let config = SecurityConfig {
+            result_topic_prefixes: vec!["results/team-a".to_string()],
+            ..Default::default()
+        };
+        let check = |topic: &str| {
+            let mut doc = document_with_result_topic(topic);
+            validate_job_document(&mut doc, &config, &DocumentPolicyConfig::default()).is_ok()
+        };
+
+        assert!(check("results/team-a"));
+        assert!(check("results/team-a/device-1"));
+        assert!(!check("results/team-ab"));
+        assert!(!check("results/team-a-other/device-1"));
+
+        assert!(under_topic_prefix(
+            "results/team-a/device-1",
+            "results/team-a/"
+        ));
+        assert!(!under_topic_prefix("results/team-a", "results/team-a/"));
+    }
+
+    #[test]
+    fn test_result_topic_rejected_names_allowed_prefixes() {
+        let config = SecurityConfig {
+            result_topic_prefixes: vec!["results/team-a/".to_string(), "results/ops/".to_string()],
+            ..Default::default()
+        };
+
+        let mut doc = document_with_result_topic("$aws/things/other/shadow/update");
+        let err = validate_job_document(&mut doc, &config, &DocumentPolicyConfig::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("results/team-a/, results/ops/"));
+
+        let mut wildcard = document_with_result_topic("results/team-a/#");
+        assert!(
+            validate_job_document(&mut wildcard, &config, &DocumentPolicyConfig::default())
+                .is_err()
+        );
+
+        // No prefixes configured means no result topics are allowed
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        assert!(validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_step_errors_name_the_field() {
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        doc.result_topic = None;
+        doc.steps.push(doc.steps[0].clone());
+        doc.steps[1].action.input.timeout = Some(0);
+
+        let err = validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default(),
+        )
+        .unwrap_err();
+        match &err {
+            DeviceOpsError::InvalidDocumentField { path, .. } => {
+                assert_eq!(path, "steps[1].action.input.timeout")
+            }
+            other => panic!("expected a field error, got {:?}", other),
+        }
+        assert_eq!(
+            err.to_string(),
+            "Invalid job document: steps[1].action.input.timeout: Timeout must be between 1 and 86400 seconds (24 hours)"
+        );
+
+        doc.steps[1].action.input.timeout = None;
+        let mut final_step = doc.steps[0].clone();
+        final_step.action.input.working_directory = Some("/var/lib/device-ops".to_string());
+        doc.final_step = Some(Box::new(final_step));
+        let policy = DocumentPolicyConfig {
+            allow_working_directory: false,
+            ..Default::default()
+        };
+        let err = validate_job_document(&mut doc, &SecurityConfig::default(), &policy)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "finalStep.action.input.workingDirectory: Step 'Test' uses workingDirectory"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_policy() {
+        let fields = vec!["steps[0].action.priority".to_string()];
+        let allowed = DocumentPolicyConfig::default();
+        assert!(check_unknown_fields(&fields, &allowed).is_ok());
+
+        let strict = DocumentPolicyConfig {
+            allow_unknown_fields: false,
+            ..Default::default()
+        };
+        assert!(check_unknown_fields(&[], &strict).is_ok());
+        let err = check_unknown_fields(&fields, &strict).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid job document: steps[0].action.priority: unknown field, which document_policy.allow_unknown_fields forbids"
+        );
+    }
+
+    #[test]
+    fn test_delay_before_limit() {
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        doc.result_topic = None;
+
+        doc.steps[0].action.delay_before_ms = Some(MAX_DELAY_BEFORE_MS);
+        assert!(validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default()
+        )
+        .is_ok());
+
+        doc.steps[0].action.delay_before_ms = Some(MAX_DELAY_BEFORE_MS + 1);
+        let err = validate_job_document(
+            &mut doc,
+            &SecurityConfig::default(),
+            &DocumentPolicyConfig::default(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("delayBeforeMs"));
+    }
+
+    #[test]
+    fn test_idle_timeout_range() {
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        doc.result_topic = None;
+        let validate = |doc: &mut JobDocument| {
+            validate_job_document(
+                doc,
+                &SecurityConfig::default(),
+                &DocumentPolicyConfig::default(),
+            )
+        };
+
+        doc.steps[0].action.idle_timeout = Some(60);
+        assert!(validate(&mut doc).is_ok());
+        for idle in [0, 86401] {
+            doc.steps[0].action.idle_timeout = Some(idle);
+            assert!(validate(&mut doc)
+                .unwrap_err()
+                .to_string()
+                .contains("idleTimeout"));
+        }
+    }
+
+    #[test]
+    fn test_success_criteria_must_not_be_empty() {
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        doc.result_topic = None;
+        let validate = |doc: &mut JobDocument| {
+            validate_job_document(
+                doc,
+                &SecurityConfig::default(),
+                &DocumentPolicyConfig::default(),
+            )
+        };
+
+        doc.steps[0].action.success_criteria = Some(vec![Criterion::ExitCodeIn(vec![0, 2])]);
+        assert!(validate(&mut doc).is_ok());
+        for criteria in [vec![], vec![Criterion::ExitCodeIn(vec![])]] {
+            doc.steps[0].action.success_criteria = Some(criteria);
+            assert!(validate(&mut doc)
+                .unwrap_err()
+                .to_string()
+                .contains("successCriteria"));
+        }
+    }
+
+    /// A document policy key, how a step uses the feature, and how to forbid it
+    type PolicyCase = (
+        &'static str,
+        fn(&mut JobAction),
+        fn(&mut DocumentPolicyConfig),
+    );
+
+    #[test]
+    fn test_document_policy_rejects_forbidden_features() {
+        let allowed = DocumentPolicyConfig::default();
+        let cases: [PolicyCase; 4] = [
+            (
+                "allow_run_as_user",
+                |action| action.run_as_user = Some("backupuser".to_string()),
+                |policy| policy.allow_run_as_user = false,
+            ),
+            (
+                "allow_expand_env_in_args",
+                |action| action.expand_env_in_args = Some(true),
+                |policy| policy.allow_expand_env_in_args = false,
+            ),
+            (
+                "allow_cgroup",
+                |action| action.cgroup = Some("device-ops.slice".to_string()),
+                |policy| policy.allow_cgroup = false,
+            ),
+            (
+                "allow_working_directory",
+                |action| action.input.working_directory = Some("/var/lib/device-ops".to_string()),
+                |policy| policy.allow_working_directory = false,
+            ),
+        ];
+
+        for (key, use_feature, forbid) in cases {
+            let mut doc = document_with_result_topic("results/team-a/device-1");
+            doc.result_topic = None;
+            use_feature(&mut doc.steps[0].action);
+            assert!(
+                validate_job_document(&mut doc, &SecurityConfig::default(), &allowed).is_ok(),
+                "{}",
+                key
+            );
+
+            let mut policy = DocumentPolicyConfig::default();
+            forbid(&mut policy);
+            let err = validate_job_document(&mut doc, &SecurityConfig::default(), &policy)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(&format!("document_policy.{}", key)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_action_type_casing_is_normalized() {
+        let policy = DocumentPolicyConfig {
+            allowed_action_types: Some(vec!["runCommand".to_string()]),
+            ..Default::default()
+        };
+        for action_type in ["RUNCOMMAND", "RunCommand", "runcommand"] {
+            let mut doc = document_with_result_topic("results/team-a/device-1");
+            doc.result_topic = None;
+            doc.steps[0].action.action_type = action_type.to_string();
+            let mut final_step = doc.steps[0].clone();
+            final_step.action.name = "Cleanup".to_string();
+            doc.final_step = Some(Box::new(final_step));
+
+            assert!(
+                validate_job_document(&mut doc, &SecurityConfig::default(), &policy).is_ok(),
+                "{}",
+                action_type
+            );
+            assert_eq!(doc.steps[0].action.action_type, "runCommand");
+            assert_eq!(
+                doc.final_step.as_ref().unwrap().action.action_type,
+                "runCommand"
+            );
+        }
+
+        // Unknown types are still rejected, with the type as written
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        doc.result_topic = None;
+        doc.steps[0].action.action_type = "RunScript".to_string();
+        let err = validate_job_document(&mut doc, &SecurityConfig::default(), &policy)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Unsupported action type: RunScript"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_document_policy_rejects_disallowed_action_type() {
+        let mut doc = document_with_result_topic("results/team-a/device-1");
+        doc.result_topic = None;
+        let listed = DocumentPolicyConfig {
+            allowed_action_types: Some(vec!["runCommand".to_string()]),
+            ..Default::default()
+        };
+        assert!(validate_job_document(&mut doc, &SecurityConfig::default(), &listed).is_ok());
+
+        // Config validation reports such a list, but --lenient-config starts anyway
+        let disallowed = DocumentPolicyConfig {
+            allowed_action_types: Some(vec!["downloadFile".to_string()]),
+            ..Default::default()
+        };
+        let err = validate_job_document(&mut doc, &SecurityConfig::default(), &disallowed)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("uses action type runCommand, which document_policy.allowed_action_types"),
+            "{}",
+            err
+        );
+    }
 }
@@ -0,0 +1,683 @@
+//! Startup self-test
+//!
+//! Most field problems are environmental: sudo missing, an allowlisted
+//! directory that was never created, a clock years off, a storage directory
+//! that cannot be written. With `selftest.enabled`, `main` runs a list of
+//! checks once the config is loaded and the IPC client is up, logs a line per
+//! check and publishes the report on the heartbeat topic. With
+//! `selftest.strict` a failed critical check stops the component.
+//!
+//! A check is a [`Check`]; what it looks at goes through [`SelfTestProbes`],
+//! so each check can be tested against a made-up device.
+
+use crate::config::Config;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+/// Earliest plausible wall-clock time (2024-01-01T00:00:00Z); a clock before
+/// it has not been set since the device booted
+pub const MIN_PLAUSIBLE_EPOCH_MILLIS: i64 = 1_704_067_200_000;
+
+/// Latest plausible wall-clock time (2100-01-01T00:00:00Z)
+pub const MAX_PLAUSIBLE_EPOCH_MILLIS: i64 = 4_102_444_800_000;
+
+/// Longest thing name IoT Core accepts
+const MAX_THING_NAME_LEN: usize = 128;
+
+/// What the checks look at on the device, swappable in tests
+pub trait SelfTestProbes: Send + Sync {
+    /// Whether `path` is an existing directory
+    fn is_dir(&self, path: &Path) -> bool;
+    /// Create `dir` if needed, then write and remove a file in it
+    fn check_writable(&self, dir: &Path) -> std::result::Result<(), String>;
+    /// Where `program` is found on `PATH`, if anywhere
+    fn find_program(&self, program: &str) -> Option<PathBuf>;
+    /// Wall-clock time as milliseconds since the Unix epoch
+    fn epoch_millis(&self) -> i64;
+}
+
+/// Probes of the real device
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemProbes;
+
+impl SelfTestProbes for SystemProbes {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn check_writable(&self, dir: &Path) -> std::result::Result<(), String> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("cannot create: {}", e))?;
+        let probe = dir.join(format!(".selftest.{}", std::process::id()));
+        let written = std::fs::File::create(&probe).and_then(|mut file| {
+            file.write_all(b"selftest")?;
+            file.sync_all()
+        });
+        let _ = std::fs::remove_file(&probe);
+        written.map_err(|e| format!("cannot write: {}", e))
+    }
+
+    fn find_program(&self, program: &str) -> Option<PathBuf> {
+        let path = std::env::var_os("PATH")?;
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn epoch_millis(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+}
+
+/// How the IPC client came up
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcStatus {
+    /// Connected, serving `thing_name`
+    Connected { thing_name: String },
+    /// Connecting failed with this error
+    Failed(String),
+}
+
+/// What every check gets to look at
+pub struct SelfTestContext<'a> {
+    pub config: &'a Config,
+    pub ipc: &'a IpcStatus,
+    pub probes: &'a dyn SelfTestProbes,
+}
+
+/// Result of one check
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckOutcome {
+    Pass(String),
+    Fail(String),
+    /// Does not apply with this config
+    Skip(String),
+}
+
+impl CheckOutcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Pass(_) => "pass",
+            Self::Fail(_) => "fail",
+            Self::Skip(_) => "skip",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            Self::Pass(detail) | Self::Fail(detail) | Self::Skip(detail) => detail,
+        }
+    }
+}
+
+/// One self-test check
+#[async_trait]
+pub trait Check: Send + Sync {
+    /// Name in the log and the report, e.g. `sudo`
+    fn name(&self) -> &'static str;
+    /// Whether a failure stops the component under `selftest.strict`
+    fn critical(&self, config: &Config) -> bool;
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome;
+}
+
+/// The IPC client connected
+pub struct IpcCheck;
+
+#[async_trait]
+impl Check for IpcCheck {
+    fn name(&self) -> &'static str {
+        "ipc"
+    }
+
+    fn critical(&self, _config: &Config) -> bool {
+        true
+    }
+
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome {
+        match context.ipc {
+            IpcStatus::Connected { .. } => CheckOutcome::Pass("connected".to_string()),
+            IpcStatus::Failed(e) => CheckOutcome::Fail(e.clone()),
+        }
+    }
+}
+
+/// The core's thing name was resolved and IoT Core would accept it
+pub struct ThingNameCheck;
+
+#[async_trait]
+impl Check for ThingNameCheck {
+    fn name(&self) -> &'static str {
+        "thing_name"
+    }
+
+    fn critical(&self, _config: &Config) -> bool {
+        true
+    }
+
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome {
+        let IpcStatus::Connected { thing_name } = context.ipc else {
+            return CheckOutcome::Fail("not resolved, IPC is down".to_string());
+        };
+        let valid = !thing_name.is_empty()
+            && thing_name.len() <= MAX_THING_NAME_LEN
+            && thing_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '-'));
+        if valid {
+            CheckOutcome::Pass(thing_name.clone())
+        } else {
+            CheckOutcome::Fail(format!("{:?} is not a valid thing name", thing_name))
+        }
+    }
+}
+
+/// sudo is installed, for steps with `runAsUser`
+pub struct SudoCheck;
+
+#[async_trait]
+impl Check for SudoCheck {
+    fn name(&self) -> &'static str {
+        "sudo"
+    }
+
+    /// Critical when every step runs through sudo
+    fn critical(&self, config: &Config) -> bool {
+        config
+            .execution
+            .default_run_as_user
+            .as_ref()
+            .is_some_and(|user| !user.is_empty())
+    }
+
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome {
+        match context.probes.find_program("sudo") {
+            Some(path) => CheckOutcome::Pass(path.display().to_string()),
+            None => CheckOutcome::Fail(
+                "sudo not found on PATH; steps with runAsUser will fail".to_string(),
+            ),
+        }
+    }
+}
+
+/// The directories the security allowlists name exist
+pub struct AllowlistDirsCheck;
+
+impl AllowlistDirsCheck {
+    /// Directories named by the allowlists: `path_allowlist` and
+    /// `working_dir_allowlist` entries ending in `/`, and the directories of
+    /// `command_allowlist` entries ending in `/*`
+    fn directories(config: &Config) -> Vec<&str> {
+        let security = &config.security;
+        let prefixes = security
+            .path_allowlist
+            .iter()
+            .chain(&security.working_dir_allowlist)
+            .filter(|entry| entry.len() > 1 && entry.ends_with('/'))
+            .map(|entry| entry.trim_end_matches('/'));
+        let wildcards = security
+            .command_allowlist
+            .iter()
+            .filter_map(|entry| entry.strip_suffix("/*"));
+        let mut dirs: Vec<&str> = prefixes.chain(wildcards).collect();
+        dirs.sort_unstable();
+        dirs.dedup();
+        dirs
+    }
+}
+
+#[async_trait]
+impl Check for AllowlistDirsCheck {
+    fn name(&self) -> &'static str {
+        "allowlist_dirs"
+    }
+
+    fn critical(&self, config: &Config) -> bool {
+        config.security.enabled
+    }
+
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome {
+        if !context.config.security.enabled {
+            return CheckOutcome::Skip("security is disabled".to_string());
+        }
+        let dirs = Self::directories(context.config);
+        if dirs.is_empty() {
+            return CheckOutcome::Skip("no allowlisted directories".to_string());
+        }
+        let missing: Vec<&str> = dirs
+            .iter()
+            .copied()
+            .filter(|dir| !context.probes.is_dir(Path::new(dir)))
+            .collect();
+        if missing.is_empty() {
+            CheckOutcome::Pass(format!("{} directories exist", dirs.len()))
+        } else {
+            CheckOutcome::Fail(format!("missing: {}", missing.join(", ")))
+        }
+    }
+}
+
+/// The directories of the enabled storage subsystems can be written
+pub struct StorageCheck;
+
+impl StorageCheck {
+    fn directories(config: &Config) -> Vec<PathBuf> {
+        let execution = &config.execution;
+        let files = [
+            &execution.state_file,
+            &execution.history_file,
+            &execution.last_run_file,
+            &execution.last_success_file,
+        ];
+        let mut dirs: Vec<PathBuf> = files
+            .into_iter()
+            .flatten()
+            .filter_map(|file| file.parent().map(Path::to_path_buf))
+            .chain(execution.output_spool_dir.clone())
+            .chain(config.logging.job_log_dir.clone())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+}
+
+#[async_trait]
+impl Check for StorageCheck {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    fn critical(&self, _config: &Config) -> bool {
+        true
+    }
+
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome {
+        let dirs = Self::directories(context.config);
+        if dirs.is_empty() {
+            return CheckOutcome::Skip("no storage subsystem enabled".to_string());
+        }
+        let problems: Vec<String> = dirs
+            .iter()
+            .filter_map(|dir| {
+                context
+                    .probes
+                    .check_writable(dir)
+                    .err()
+                    .map(|e| format!("{}: {}", dir.display(), e))
+            })
+            .collect();
+        if problems.is_empty() {
+            CheckOutcome::Pass(format!("{} directories writable", dirs.len()))
+        } else {
+            CheckOutcome::Fail(problems.join("; "))
+        }
+    }
+}
+
+/// The wall clock is set to a plausible time
+pub struct ClockCheck;
+
+#[async_trait]
+impl Check for ClockCheck {
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    /// Jobs still run with a wrong clock; only their timestamps are off
+    fn critical(&self, _config: &Config) -> bool {
+        false
+    }
+
+    async fn run(&self, context: &SelfTestContext<'_>) -> CheckOutcome {
+        let now = context.probes.epoch_millis();
+        let shown = chrono::DateTime::from_timestamp_millis(now)
+            .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|| now.to_string());
+        if (MIN_PLAUSIBLE_EPOCH_MILLIS..MAX_PLAUSIBLE_EPOCH_MILLIS).contains(&now) {
+            CheckOutcome::Pass(shown)
+        } else {
+            CheckOutcome::Fail(format!("{} is not a plausible time", shown))
+        }
+    }
+}
+
+/// An ordered list of checks
+pub struct SelfTest {
+    checks: Vec<Box<dyn Check>>,
+}
+
+impl Default for SelfTest {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl SelfTest {
+    /// The checks `main` runs
+    pub fn standard() -> Self {
+        Self {
+            checks: vec![
+                Box::new(IpcCheck),
+                Box::new(ThingNameCheck),
+                Box::new(SudoCheck),
+                Box::new(AllowlistDirsCheck),
+                Box::new(StorageCheck),
+                Box::new(ClockCheck),
+            ],
+        }
+    }
+
+    /// Add a check after the others
+    pub fn with_check(mut self, check: impl Check + 'static) -> Self {
+        self.checks.push(Box::new(check));
+        self
+    }
+
+    /// Run every check in order, logging a line for each
+    pub async fn run(&self, context: &SelfTestContext<'_>) -> SelfTestReport {
+        let mut results = Vec::with_capacity(self.checks.len());
+        for check in &self.checks {
+            let outcome = check.run(context).await;
+            let critical = check.critical(context.config);
+            match &outcome {
+                CheckOutcome::Fail(detail) if critical => {
+                    tracing::error!(check = check.name(), detail = %detail, "Self-test check failed")
+                }
+                CheckOutcome::Fail(detail) => {
+                    tracing::warn!(check = check.name(), detail = %detail, "Self-test check failed")
+                }
+                outcome => tracing::info!(
+                    check = check.name(),
+                    result = outcome.label(),
+                    detail = %outcome.detail(),
+                    "Self-test check"
+                ),
+            }
+            results.push(CheckResult {
+                name: check.name(),
+                critical,
+                outcome,
+            });
+        }
+        SelfTestReport { results }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub critical: bool,
+    pub outcome: CheckOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    /// Names of the critical checks that failed
+    pub fn critical_failures(&self) -> Vec<&'static str> {
+        self.results
+            .iter()
+            .filter(|result| result.critical && matches!(result.outcome, CheckOutcome::Fail(_)))
+            .map(|result| result.name)
+            .collect()
+    }
+
+    pub fn passed(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|result| matches!(result.outcome, CheckOutcome::Fail(_)))
+    }
+
+    /// The report published on the heartbeat topic
+    pub fn to_json(&self, thing_name: &str) -> Value {
+        let checks: Vec<Value> = self
+            .results
+            .iter()
+            .map(|result| {
+                json!({
+                    "name": result.name,
+                    "result": result.outcome.label(),
+                    "critical": result.critical,
+                    "detail": result.outcome.detail(),
+                })
+            })
+            .collect();
+        json!({
+            "type": "selfTest",
+            "thingName": thing_name,
+            "timestamp": chrono::Utc::now().timestamp_millis(),
+            "componentVersion": env!("CARGO_PKG_VERSION"),
+            "passed": self.passed(),
+            "criticalFailures": self.critical_failures(),
+            "checks": checks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// A made-up device
+    #[derive(Default)]
+    struct FakeProbes {
+        dirs: HashSet<PathBuf>,
+        read_only: HashSet<PathBuf>,
+        programs: Vec<(String, PathBuf)>,
+        now: i64,
+    }
+
+    impl SelfTestProbes for FakeProbes {
+        fn is_dir(&self, path: &Path) -> bool {
+            self.dirs.contains(path)
+        }
+
+        fn check_writable(&self, dir: &Path) -> std::result::Result<(), String> {
+            if self.read_only.contains(dir) {
+                Err("cannot write: Read-only file system".to_string())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn find_program(&self, program: &str) -> Option<PathBuf> {
+            self.programs
+                .iter()
+                .find(|(name, _)| name == program)
+                .map(|(_, path)| path.clone())
+        }
+
+        fn epoch_millis(&self) -> i64 {
+            self.now
+        }
+    }
+
+    fn healthy() -> FakeProbes {
+        FakeProbes {
+            programs: vec![("sudo".to_string(), PathBuf::from("/usr/bin/sudo"))],
+            now: 1_760_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    fn connected() -> IpcStatus {
+        IpcStatus::Connected {
+            thing_name: "core-1".to_string(),
+        }
+    }
+
+    async fn outcome(check: &dyn Check, config: &Config, probes: &FakeProbes) -> CheckOutcome {
+        let ipc = connected();
+        let context = SelfTestContext {
+            config,
+            ipc: &ipc,
+            probes,
+        };
+        check.run(&context).await
+    }
+
+    #[tokio::test]
+    async fn test_healthy_device_passes() {
+        let config = Config::default();
+        let ipc = connected();
+        let probes = healthy();
+        let context = SelfTestContext {
+            config: &config,
+            ipc: &ipc,
+            probes: &probes,
+        };
+        let report = SelfTest::standard().run(&context).await;
+        assert!(report.passed(), "{:?}", report);
+        let json = report.to_json("core-1");
+        assert_eq!(json["passed"], true);
+        assert_eq!(json["checks"][0]["name"], "ipc");
+        assert_eq!(json["checks"][0]["result"], "pass");
+        // Nothing to check with security off and no storage enabled
+        let skipped: Vec<&str> = report
+            .results
+            .iter()
+            .filter(|result| matches!(result.outcome, CheckOutcome::Skip(_)))
+            .map(|result| result.name)
+            .collect();
+        assert_eq!(skipped, ["allowlist_dirs", "storage"]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_sudo_is_critical_only_with_default_run_as_user() {
+        let probes = FakeProbes {
+            programs: Vec::new(),
+            ..healthy()
+        };
+        let mut config = Config::default();
+        assert!(matches!(
+            outcome(&SudoCheck, &config, &probes).await,
+            CheckOutcome::Fail(_)
+        ));
+        assert!(!SudoCheck.critical(&config));
+        config.execution.default_run_as_user = Some("ops".to_string());
+        assert!(SudoCheck.critical(&config));
+    }
+
+    #[tokio::test]
+    async fn test_missing_allowlist_directory() {
+        let mut config = Config::default();
+        config.security.enabled = true;
+        config.security.path_allowlist =
+            vec!["/opt/scripts/".to_string(), "/usr/bin/id".to_string()];
+        config.security.command_allowlist = vec!["/opt/tools/*".to_string()];
+        let mut probes = healthy();
+        probes.dirs.insert(PathBuf::from("/opt/scripts"));
+
+        assert_eq!(
+            outcome(&AllowlistDirsCheck, &config, &probes).await,
+            CheckOutcome::Fail("missing: /opt/tools".to_string())
+        );
+        probes.dirs.insert(PathBuf::from("/opt/tools"));
+        assert_eq!(
+            outcome(&AllowlistDirsCheck, &config, &probes).await,
+            CheckOutcome::Pass("2 directories exist".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_storage_directory() {
+        let mut config = Config::default();
+        config.execution.history_file = Some(PathBuf::from("/data/history/history.jsonl"));
+        config.execution.output_spool_dir = Some(PathBuf::from("/data/spool"));
+        let mut probes = healthy();
+        probes.read_only.insert(PathBuf::from("/data/spool"));
+
+        let CheckOutcome::Fail(detail) = outcome(&StorageCheck, &config, &probes).await else {
+            panic!("storage check passed");
+        };
+        assert_eq!(detail, "/data/spool: cannot write: Read-only file system");
+    }
+
+    #[tokio::test]
+    async fn test_clock_before_plausible_time() {
+        let config = Config::default();
+        let probes = FakeProbes {
+            now: 86_400_000,
+            ..healthy()
+        };
+        assert_eq!(
+            outcome(&ClockCheck, &config, &probes).await,
+            CheckOutcome::Fail("1970-01-02T00:00:00Z is not a plausible time".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ipc_failure_fails_critical_checks() {
+        let config = Config::default();
+        let ipc = IpcStatus::Failed("connection refused".to_string());
+        let probes = healthy();
+        let context = SelfTestContext {
+            config: &config,
+            ipc: &ipc,
+            probes: &probes,
+        };
+        let report = SelfTest::standard().run(&context).await;
+        assert_eq!(report.critical_failures(), ["ipc", "thing_name"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_thing_name() {
+        let config = Config::default();
+        let ipc = IpcStatus::Connected {
+            thing_name: "core 1/a".to_string(),
+        };
+        let probes = healthy();
+        let context = SelfTestContext {
+            config: &config,
+            ipc: &ipc,
+            probes: &probes,
+        };
+        assert!(matches!(
+            ThingNameCheck.run(&context).await,
+            CheckOutcome::Fail(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_extra_checks_run_after_the_standard_ones() {
+        struct AlwaysFails;
+
+        #[async_trait]
+        impl Check for AlwaysFails {
+            fn name(&self) -> &'static str {
+                "custom"
+            }
+
+            fn critical(&self, _config: &Config) -> bool {
+                false
+            }
+
+            async fn run(&self, _context: &SelfTestContext<'_>) -> CheckOutcome {
+                CheckOutcome::Fail("always".to_string())
+            }
+        }
+
+        let config = Config::default();
+        let ipc = connected();
+        let probes = healthy();
+        let context = SelfTestContext {
+            config: &config,
+            ipc: &ipc,
+            probes: &probes,
+        };
+        let report = SelfTest::standard()
+            .with_check(AlwaysFails)
+            .run(&context)
+            .await;
+        assert_eq!(report.results.last().unwrap().name, "custom");
+        assert!(!report.passed());
+        // A failed non-critical check does not stop a strict start
+        assert!(report.critical_failures().is_empty());
+    }
+}
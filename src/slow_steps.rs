@@ -0,0 +1,277 @@
+//! Slow-step detection
+//!
+//! A step is slow when it ran longer than `execution.slow_step_warn_secs`, or
+//! longer than `execution.slow_step_factor` times its trailing median: the
+//! median duration of its last successful runs in the same document (same
+//! document hash and step name), taken from the newest `HISTORY_WINDOW`
+//! records of the execution history. The
+//! median needs `execution.slow_step_min_runs` earlier runs, so the first
+//! runs of a new document are never compared, and steps that took under a
+//! second are left out of the comparison, where scheduling noise alone can
+//! triple the time.
+
+use crate::history::{HistoryRecord, StepRecord};
+use crate::metrics::SlowStepKind;
+
+/// Earlier successful runs the trailing median is taken over
+pub const MEDIAN_WINDOW: usize = 10;
+
+/// Newest history records searched for earlier runs, so the check reads a
+/// bounded tail of the history file however long it has grown
+pub const HISTORY_WINDOW: usize = 1000;
+
+/// Steps quicker than this are never slow relative to their median
+pub const MIN_RELATIVE_MS: u64 = 1000;
+
+/// What makes a step slow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlowStepRules {
+    /// Absolute limit in seconds; 0 disables it
+    pub warn_secs: u64,
+    /// Multiple of the trailing median; 0 disables the relative check
+    pub factor: f64,
+    /// Earlier runs needed before the relative check applies
+    pub min_runs: usize,
+}
+
+impl SlowStepRules {
+    pub fn enabled(&self) -> bool {
+        self.warn_secs > 0 || self.factor > 0.0
+    }
+
+    pub fn relative(&self) -> bool {
+        self.factor > 0.0
+    }
+}
+
+/// Which limit a slow step went past
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowLimit {
+    /// `execution.slow_step_warn_secs`
+    Absolute { limit_secs: u64 },
+    /// `execution.slow_step_factor` times this median
+    Relative { median_ms: u64 },
+}
+
+impl SlowLimit {
+    pub fn kind(self) -> SlowStepKind {
+        match self {
+            Self::Absolute { .. } => SlowStepKind::Absolute,
+            Self::Relative { .. } => SlowStepKind::Relative,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowStep {
+    pub name: String,
+    pub duration_ms: u64,
+    pub limit: SlowLimit,
+}
+
+impl SlowStep {
+    /// e.g. `Install 95.0s (over 60s)` or `Install 30.2s (3.4x median 9.0s)`
+    pub fn describe(&self) -> String {
+        let secs = |ms: u64| format!("{:.1}s", ms as f64 / 1000.0);
+        match self.limit {
+            SlowLimit::Absolute { limit_secs } => format!(
+                "{} {} (over {}s)",
+                self.name,
+                secs(self.duration_ms),
+                limit_secs
+            ),
+            SlowLimit::Relative { median_ms } => format!(
+                "{} {} ({:.1}x median {})",
+                self.name,
+                secs(self.duration_ms),
+                self.duration_ms as f64 / median_ms.max(1) as f64,
+                secs(median_ms)
+            ),
+        }
+    }
+}
+
+/// The steps of a finished run of document `document_hash` that were slow,
+/// compared with the earlier runs in `history`. The absolute limit is
+/// checked first.
+pub fn find(
+    steps: &[StepRecord],
+    document_hash: &str,
+    history: &[HistoryRecord],
+    rules: &SlowStepRules,
+) -> Vec<SlowStep> {
+    steps
+        .iter()
+        .filter_map(|step| {
+            let limit =
+                if rules.warn_secs > 0 && step.duration_ms > rules.warn_secs.saturating_mul(1000) {
+                    SlowLimit::Absolute {
+                        limit_secs: rules.warn_secs,
+                    }
+                } else if rules.relative() && step.duration_ms >= MIN_RELATIVE_MS {
+                    let median_ms =
+                        trailing_median(history, document_hash, &step.name, rules.min_runs)?;
+                    if step.duration_ms as f64 <= median_ms as f64 * rules.factor {
+                        return None;
+                    }
+                    SlowLimit::Relative { median_ms }
+                } else {
+                    return None;
+                };
+            Some(SlowStep {
+                name: step.name.clone(),
+                duration_ms: step.duration_ms,
+                limit,
+            })
+        })
+        .collect()
+}
+
+/// Median duration of the last `MEDIAN_WINDOW` successful runs of step
+/// `step_name` in document `document_hash`; `None` with fewer than
+/// `min_runs` of them. `history` is oldest first.
+pub fn trailing_median(
+    history: &[HistoryRecord],
+    document_hash: &str,
+    step_name: &str,
+    min_runs: usize,
+) -> Option<u64> {
+    let mut durations: Vec<u64> = history
+        .iter()
+        .rev()
+        .filter(|record| record.document_hash == document_hash)
+        .flat_map(|record| record.steps.iter())
+        .filter(|step| step.name == step_name && step.exit_code == 0)
+        .map(|step| step.duration_ms)
+        .take(MEDIAN_WINDOW)
+        .collect();
+    if durations.is_empty() || durations.len() < min_runs {
+        return None;
+    }
+    durations.sort_unstable();
+    let mid = durations.len() / 2;
+    Some(if durations.len().is_multiple_of(2) {
+        (durations[mid - 1] + durations[mid]) / 2
+    } else {
+        durations[mid]
+    })
+}
+
+/// The `slow_steps` statusDetails value
+pub fn summary(slow_steps: &[SlowStep]) -> String {
+    slow_steps
+        .iter()
+        .map(SlowStep::describe)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(name: &str, duration_ms: u64) -> StepRecord {
+        StepRecord {
+            name: name.to_string(),
+            exit_code: 0,
+            duration_ms,
+            timing: None,
+        }
+    }
+
+    fn run(document_hash: &str, steps: Vec<StepRecord>) -> HistoryRecord {
+        HistoryRecord {
+            job_id: "job".to_string(),
+            thing_name: "core-1".to_string(),
+            execution_id: "run".to_string(),
+            document_hash: document_hash.to_string(),
+            status: "SUCCEEDED".to_string(),
+            started_at: 1,
+            finished_at: 2,
+            steps,
+            error: None,
+            device: None,
+        }
+    }
+
+    const RELATIVE: SlowStepRules = SlowStepRules {
+        warn_secs: 0,
+        factor: 3.0,
+        min_runs: 3,
+    };
+
+    #[test]
+    fn test_absolute_limit() {
+        let rules = SlowStepRules {
+            warn_secs: 60,
+            ..RELATIVE
+        };
+        let slow = find(
+            &[step("Fetch", 5_000), step("Install", 95_000)],
+            "doc",
+            &[],
+            &rules,
+        );
+        assert_eq!(
+            slow,
+            vec![SlowStep {
+                name: "Install".to_string(),
+                duration_ms: 95_000,
+                limit: SlowLimit::Absolute { limit_secs: 60 },
+            }]
+        );
+        assert_eq!(summary(&slow), "Install 95.0s (over 60s)");
+    }
+
+    #[test]
+    fn test_relative_to_trailing_median_of_same_document() {
+        let mut history: Vec<HistoryRecord> = [5_000, 6_000, 4_000]
+            .into_iter()
+            .map(|ms| run("doc", vec![step("Install", ms)]))
+            .collect();
+        // Other documents and failed runs do not count
+        history.push(run("other", vec![step("Install", 90_000)]));
+        let mut failed = run("doc", vec![step("Install", 80_000)]);
+        failed.steps[0].exit_code = 1;
+        history.push(failed);
+
+        assert_eq!(trailing_median(&history, "doc", "Install", 3), Some(5_000));
+        let slow = find(&[step("Install", 16_000)], "doc", &history, &RELATIVE);
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].limit, SlowLimit::Relative { median_ms: 5_000 });
+        assert_eq!(summary(&slow), "Install 16.0s (3.2x median 5.0s)");
+        assert!(find(&[step("Install", 15_000)], "doc", &history, &RELATIVE).is_empty());
+    }
+
+    #[test]
+    fn test_first_runs_and_quick_steps_are_not_compared() {
+        let history: Vec<HistoryRecord> = [100, 100]
+            .into_iter()
+            .map(|ms| run("doc", vec![step("Check", ms)]))
+            .collect();
+        // Two earlier runs are not enough
+        assert_eq!(trailing_median(&history, "doc", "Check", 3), None);
+        assert!(find(&[step("Check", 60_000)], "doc", &history, &RELATIVE).is_empty());
+
+        let mut history = history;
+        history.push(run("doc", vec![step("Check", 100)]));
+        // 9x the median, but under a second
+        assert!(find(&[step("Check", 900)], "doc", &history, &RELATIVE).is_empty());
+        assert_eq!(
+            find(&[step("Check", 1_500)], "doc", &history, &RELATIVE).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_median_uses_only_the_latest_runs() {
+        let history: Vec<HistoryRecord> = (0..MEDIAN_WINDOW as u64 + 5)
+            .map(|n| {
+                // Old runs were slow; the last MEDIAN_WINDOW took 2s
+                let ms = if n < 5 { 60_000 } else { 2_000 };
+                run("doc", vec![step("Install", ms)])
+            })
+            .collect();
+        assert_eq!(trailing_median(&history, "doc", "Install", 3), Some(2_000));
+    }
+}
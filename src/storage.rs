@@ -0,0 +1,244 @@
+use crate::config::StorageConfig;
+use crate::history;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often `run_periodic_cleanup` enforces the storage caps
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Files one cleanup pass removed
+#[derive(Debug, Default, PartialEq)]
+pub struct CleanupSummary {
+    /// Past `retention_days`
+    pub expired: Vec<PathBuf>,
+    /// Over `max_total_bytes`, oldest first
+    pub evicted: Vec<PathBuf>,
+    pub bytes_freed: u64,
+    /// History records past `retention_days`
+    pub history_pruned: usize,
+}
+
+/// Enforce `retention_days` and then `max_total_bytes` on the files directly
+/// under the managed directories (spooled output and job logs), oldest
+/// first, and `retention_days` on the records of the history file. The state
+/// directory is never touched. Missing directories are skipped; files that
+/// cannot be removed are logged and left in place.
+pub fn cleanup(config: &StorageConfig, now: SystemTime) -> CleanupSummary {
+    let mut files = Vec::new();
+    for dir in config.managed_dirs() {
+        collect_files(&dir, &mut files);
+    }
+    // Oldest first
+    files.sort();
+
+    let mut summary = CleanupSummary::default();
+    let max_age = Duration::from_secs(config.retention_days * 24 * 60 * 60);
+    let mut kept = Vec::new();
+    for (modified, size, path) in files {
+        let expired =
+            config.retention_days > 0 && now.duration_since(modified).unwrap_or_default() > max_age;
+        if expired && remove(&path, "retention_days") {
+            summary.bytes_freed += size;
+            summary.expired.push(path);
+        } else {
+            kept.push((size, path));
+        }
+    }
+
+    let mut total: u64 = kept.iter().map(|(size, _)| size).sum();
+    if config.max_total_bytes > 0 {
+        for (size, path) in kept {
+            if total <= config.max_total_bytes {
+                break;
+            }
+            if remove(&path, "max_total_bytes") {
+                total -= size;
+                summary.bytes_freed += size;
+                summary.evicted.push(path);
+            }
+        }
+    }
+
+    let now_ms = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64);
+    match history::prune(&config.history_file(), config.retention_days, now_ms) {
+        Ok(pruned) => summary.history_pruned = pruned,
+        Err(e) => tracing::warn!(error = %e, "Cannot prune job history"),
+    }
+
+    if !summary.expired.is_empty() || !summary.evicted.is_empty() || summary.history_pruned > 0 {
+        tracing::info!(
+            expired = summary.expired.len(),
+            evicted = summary.evicted.len(),
+            history_pruned = summary.history_pruned,
+            bytes_freed = summary.bytes_freed,
+            remaining_bytes = total,
+            "Storage cleanup removed files"
+        );
+    }
+    summary
+}
+
+/// Run `cleanup` now and then every `interval`
+pub async fn run_periodic_cleanup(config: StorageConfig, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        cleanup(&config, SystemTime::now());
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<(SystemTime, u64, PathBuf)>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!(dir = %dir.display(), error = %e, "Cannot list storage directory");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, metadata.len(), entry.path()));
+        }
+    }
+}
+
+/// Remove a file for the cap named by `limit`, logging the outcome
+fn remove(path: &Path, limit: &str) -> bool {
+    match std::fs::remove_file(path) {
+        Ok(()) => {
+            tracing::info!(path = %path.display(), limit = limit, "Removed stored file");
+            true
+        }
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Cannot remove stored file");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn storage(dir: &Path) -> StorageConfig {
+        StorageConfig {
+            data_dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Write `size` bytes to `path`, last modified `age` before `now`
+    fn write_file(path: &Path, size: usize, now: SystemTime, age: Duration) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, vec![b'x'; size]).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(now - age).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_removes_expired_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let old_log = dir.path().join("job-logs/job-a.log");
+        let new_log = dir.path().join("job-logs/job-b.log");
+        let old_state = dir.path().join("state/state.json");
+        write_file(&old_log, 10, now, 31 * DAY);
+        write_file(&new_log, 10, now, DAY);
+        write_file(&old_state, 10, now, 90 * DAY);
+
+        let summary = cleanup(&storage(dir.path()), now);
+        assert_eq!(summary.expired, vec![old_log.clone()]);
+        assert_eq!(summary.bytes_freed, 10);
+        assert!(!old_log.exists());
+        assert!(new_log.exists());
+        // The state is not subject to retention
+        assert!(old_state.exists());
+
+        let keep_forever = StorageConfig {
+            retention_days: 0,
+            ..storage(dir.path())
+        };
+        write_file(&old_log, 10, now, 365 * DAY);
+        assert_eq!(cleanup(&keep_forever, now), CleanupSummary::default());
+    }
+
+    #[test]
+    fn test_cleanup_prunes_history_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = storage(dir.path());
+        let now = SystemTime::now();
+        let millis_ago = |age: Duration| {
+            (now - age)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64
+        };
+        let record = |job_id: &str, age: Duration| history::HistoryRecord {
+            job_id: job_id.to_string(),
+            thing_name: "device-1".to_string(),
+            execution_id: "run-1".to_string(),
+            document_hash: "0123456789abcdef".to_string(),
+            status: "SUCCEEDED".to_string(),
+            started_at: millis_ago(age),
+            finished_at: millis_ago(age),
+            steps: vec![],
+            error: None,
+            device: None,
+        };
+        let path = config.history_file();
+        history::append(&path, &record("old", 40 * DAY)).unwrap();
+        history::append(&path, &record("recent", DAY)).unwrap();
+        // However old the file itself, it is never removed as a whole
+        write_file(&dir.path().join("job-logs/job-a.log"), 10, now, DAY);
+
+        let summary = cleanup(&config, now);
+        assert_eq!(summary.history_pruned, 1);
+        assert!(summary.expired.is_empty());
+        let records = history::read(&path).unwrap().records;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].job_id, "recent");
+    }
+
+    #[test]
+    fn test_cleanup_enforces_size_cap_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let oldest = dir.path().join("spool/1-step.stdout");
+        let middle = dir.path().join("job-logs/job-a.log");
+        let newest = dir.path().join("spool/3-step.stdout");
+        write_file(&oldest, 400, now, 3 * DAY);
+        write_file(&middle, 400, now, 2 * DAY);
+        write_file(&newest, 400, now, DAY);
+
+        let config = StorageConfig {
+            max_total_bytes: 1000,
+            ..storage(dir.path())
+        };
+        let summary = cleanup(&config, now);
+        assert_eq!(summary.evicted, vec![oldest.clone()]);
+        assert!(middle.exists() && newest.exists());
+
+        let config = StorageConfig {
+            max_total_bytes: 400,
+            ..config
+        };
+        assert_eq!(cleanup(&config, now).evicted, vec![middle]);
+        assert!(newest.exists());
+    }
+
+    #[test]
+    fn test_cleanup_without_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let summary = cleanup(&storage(&dir.path().join("missing")), SystemTime::now());
+        assert_eq!(summary, CleanupSummary::default());
+    }
+}
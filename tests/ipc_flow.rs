@@ -0,0 +1,528 @@
+//! End-to-end job handling against the in-memory `FakeSdk`.
+//! Run with `cargo test --features fake-ipc`.
+
+use device_ops_component::config::IpcConfig;
+use device_ops_component::ipc::fake::FakeSdk;
+use device_ops_component::ipc::{IpcClient, JobHandler};
+use device_ops_component::models::JobStatus;
+use device_ops_component::{Config, DeviceOpsError};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+
+const THING: &str = "test-thing";
+const NOTIFY_NEXT: &str = "$aws/things/test-thing/jobs/notify-next";
+const NEXT_GET: &str = "$aws/things/test-thing/jobs/$next/get";
+const NEXT_GET_ACCEPTED: &str = "$aws/things/test-thing/jobs/$next/get/accepted";
+const NEXT_GET_REJECTED: &str = "$aws/things/test-thing/jobs/$next/get/rejected";
+const RECONNECT: &str = "reconnect/test-thing";
+const WAIT: Duration = Duration::from_secs(5);
+
+fn update_topic(job_id: &str) -> String {
+    format!("$aws/things/{}/jobs/{}/update", THING, job_id)
+}
+
+fn test_config() -> Config {
+    Config {
+        ipc: IpcConfig {
+            thing_name: Some(THING.to_string()),
+            min_status_update_interval_ms: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn job_notification(job_id: &str, command: &str, args: &[&str]) -> Value {
+    json!({
+        "timestamp": 1700000000,
+        "execution": {
+            "jobId": job_id,
+            "status": "QUEUED",
+            "queuedAt": 1700000000,
+            "jobDocument": {
+                "version": "1.0",
+                "includeStdOut": true,
+                "steps": [{
+                    "action": {
+                        "name": "Step",
+                        "type": "runCommand",
+                        "input": { "command": command, "args": args }
+                    }
+                }]
+            }
+        }
+    })
+}
+
+/// Run the same flow as `main` against `fake`, concurrently with `scenario`
+async fn run_with_fake<F, Fut>(fake: &FakeSdk, config: Config, scenario: F)
+where
+    F: FnOnce(FakeSdk) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let client = IpcClient::with_transport(Box::new(fake.clone()), &config.ipc)
+        .await
+        .expect("fake IPC connects");
+    let mut handler = JobHandler::new(client, config);
+
+    // Startup subscribes, then requests the next job of each thing; the
+    // scenario starts once the first request is out
+    let started = async {
+        fake.wait_for(WAIT, |m| m.topic.ends_with("/jobs/$next/get"))
+            .await
+            .expect("startup requests the next job");
+        scenario(fake.clone()).await
+    };
+    tokio::select! {
+        biased;
+        result = handler.run() => panic!("job handler exited: {:?}", result),
+        _ = started => {}
+    }
+}
+
+/// Wait for the first terminal status update of a job
+async fn terminal_update(fake: &FakeSdk, job_id: &str) -> Value {
+    let topic = update_topic(job_id);
+    fake.wait_for(WAIT, |m| {
+        m.topic == topic && m.json()["status"] != "IN_PROGRESS"
+    })
+    .await
+    .unwrap_or_else(|| panic!("no terminal update for {}", job_id))
+    .json()
+}
+
+#[tokio::test]
+async fn test_startup_subscribes_and_requests_next_job() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        let subscriptions = fake.subscriptions();
+        assert!(subscriptions.contains(&NOTIFY_NEXT.to_string()));
+        assert!(subscriptions.contains(&NEXT_GET_ACCEPTED.to_string()));
+        assert!(subscriptions.contains(&NEXT_GET_REJECTED.to_string()));
+        assert!(subscriptions.contains(&RECONNECT.to_string()));
+        assert_eq!(fake.published_to(NEXT_GET).len(), 1);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_happy_path() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        fake.inject_json(
+            NOTIFY_NEXT,
+            &job_notification("job-1", "/bin/echo", &["hello"]),
+        );
+
+        let update = terminal_update(&fake, "job-1").await;
+        assert_eq!(update["status"], "SUCCEEDED");
+        assert_eq!(update["statusDetails"]["stdout"], "hello");
+
+        // The handler asks for the next job once this one is reported
+        assert!(fake
+            .wait_for(WAIT, |m| m.topic == NEXT_GET
+                && fake.published_to(NEXT_GET).len() > 1)
+            .await
+            .is_some());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_failing_command_reports_failed() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        fake.inject_json(NOTIFY_NEXT, &job_notification("job-1", "/bin/false", &[]));
+
+        let update = terminal_update(&fake, "job-1").await;
+        assert_eq!(update["status"], "FAILED");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_parse_error_fails_job() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        // jobDocument is missing the required `steps`
+        let notification = json!({
+            "execution": {
+                "jobId": "bad-job",
+                "status": "QUEUED",
+                "jobDocument": { "version": "1.0" }
+            }
+        });
+        fake.inject_json(NOTIFY_NEXT, &notification);
+
+        let update = terminal_update(&fake, "bad-job").await;
+        assert_eq!(update["status"], "FAILED");
+        assert!(update["statusDetails"]["reason"]
+            .as_str()
+            .unwrap_or_default()
+            .contains("parsing failed"));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_parse_error_names_the_offending_field() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        let mut notification = job_notification("bad-step", "/bin/echo", &["hi"]);
+        notification["execution"]["jobDocument"]["steps"][0]["action"]["input"]["timeout"] =
+            json!("60");
+        fake.inject_json(NOTIFY_NEXT, &notification);
+
+        let update = terminal_update(&fake, "bad-step").await;
+        assert_eq!(update["status"], "FAILED");
+        assert_eq!(
+            update["statusDetails"]["path"],
+            "steps[0].action.input.timeout"
+        );
+        assert_eq!(
+            update["statusDetails"]["reason"],
+            "Job document parsing failed: steps[0].action.input.timeout: invalid type: string \"60\", expected u64"
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_duplicate_delivery_runs_once() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        let notification = job_notification("job-1", "/bin/echo", &["once"]);
+        // The same execution arrives via notify-next and the $next/get response
+        fake.inject_json(NOTIFY_NEXT, &notification);
+        fake.inject_json(NEXT_GET_ACCEPTED, &notification);
+
+        terminal_update(&fake, "job-1").await;
+        fake.inject_json(NOTIFY_NEXT, &notification);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let terminal: Vec<Value> = fake
+            .published_to(&update_topic("job-1"))
+            .into_iter()
+            .filter(|u| u["status"] != "IN_PROGRESS")
+            .collect();
+        assert_eq!(terminal.len(), 1);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_offline_update_recovers_on_reconnect() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        fake.set_offline(true);
+        fake.inject_json(NOTIFY_NEXT, &job_notification("job-1", "/bin/echo", &["x"]));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // The status update could not go out while offline
+        assert!(fake.published_to(&update_topic("job-1")).is_empty());
+
+        // Once back online the reconnect signal makes the handler ask for work again
+        fake.set_offline(false);
+        let before = fake.published_to(NEXT_GET).len();
+        fake.inject(RECONNECT, b"{}");
+        assert!(fake
+            .wait_for(WAIT, |_| fake.published_to(NEXT_GET).len() > before)
+            .await
+            .is_some());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_rejected_update_does_not_stall_handler() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        fake.reject_updates(true);
+        fake.inject_json(NOTIFY_NEXT, &job_notification("job-1", "/bin/echo", &["a"]));
+        terminal_update(&fake, "job-1").await;
+
+        fake.reject_updates(false);
+        fake.inject_json(NOTIFY_NEXT, &job_notification("job-2", "/bin/echo", &["b"]));
+        let update = terminal_update(&fake, "job-2").await;
+        assert_eq!(update["status"], "SUCCEEDED");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_throttled_next_job_request_is_retried_after_backoff() {
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, test_config(), |fake| async move {
+        let before = fake.published_to(NEXT_GET).len();
+        let token = fake.published_to(NEXT_GET)[0]["clientToken"].clone();
+        fake.inject_json(
+            NEXT_GET_REJECTED,
+            &json!({ "code": "RequestThrottled", "message": "Rate exceeded", "clientToken": token }),
+        );
+
+        // No immediate retry
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(fake.published_to(NEXT_GET).len(), before);
+
+        assert!(fake
+            .wait_for(WAIT, |_| fake.published_to(NEXT_GET).len() > before)
+            .await
+            .is_some());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_jobs_of_each_thing_run_and_report_to_their_own_topics() {
+    let mut config = test_config();
+    config.ipc.thing_names = vec!["leaf-a".to_string(), "leaf-b".to_string()];
+    let config_hash = config.fingerprint();
+
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, config, |fake| async move {
+        // The same job ID on two things is two separate executions
+        for thing in ["leaf-a", "leaf-b"] {
+            fake.inject_json(
+                &format!("$aws/things/{}/jobs/notify-next", thing),
+                &job_notification("job-1", "/bin/echo", &[thing]),
+            );
+        }
+
+        for thing in ["leaf-a", "leaf-b"] {
+            let topic = format!("$aws/things/{}/jobs/job-1/update", thing);
+            let update = fake
+                .wait_for(WAIT, |m| {
+                    m.topic == topic && m.json()["status"] == "SUCCEEDED"
+                })
+                .await
+                .unwrap_or_else(|| panic!("no terminal update for {}", thing))
+                .json();
+            assert_eq!(update["statusDetails"]["stdout"], thing);
+            assert_eq!(update["statusDetails"]["thing_name"], thing);
+            assert_eq!(update["statusDetails"]["config_hash"], config_hash.as_str());
+        }
+
+        // Only the configured things are served
+        assert!(!fake.subscriptions().contains(&NOTIFY_NEXT.to_string()));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_dead_session_is_detected_and_reconnected() {
+    let mut config = test_config();
+    config.ipc.liveness_probe_interval_ms = 50;
+    config.ipc.liveness_failure_threshold = 2;
+
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, config, |fake| async move {
+        let before = fake.published_to(NEXT_GET).len();
+        fake.kill_session();
+
+        assert!(fake
+            .wait_for(WAIT, |_| fake.published_to(NEXT_GET).len() > before)
+            .await
+            .is_some());
+        assert_eq!(fake.reconnects(), 1);
+        // Subscriptions are back, so jobs flow again
+        fake.inject_json(
+            NOTIFY_NEXT,
+            &job_notification("job-1", "/bin/echo", &["back"]),
+        );
+        let update = terminal_update(&fake, "job-1").await;
+        assert_eq!(update["status"], "SUCCEEDED");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_iot_core_outage_does_not_reconnect() {
+    const PROBE: &str = "device-ops/probe/test-thing";
+    const HEARTBEAT: &str = "device-ops/test-thing/liveness";
+    let mut config = test_config();
+    config.ipc.liveness_probe_interval_ms = 50;
+    config.ipc.liveness_failure_threshold = 2;
+
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, config, |fake| async move {
+        fake.set_offline(true);
+        let heartbeats = fake.published_to(HEARTBEAT).len();
+        let probes = fake.published_local_to(PROBE).len();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        // Probes stay on the core and keep coming back; only heartbeats fail
+        assert!(fake.published_local_to(PROBE).len() >= probes + 4);
+        assert_eq!(fake.published_to(HEARTBEAT).len(), heartbeats);
+        assert_eq!(fake.reconnects(), 0);
+
+        fake.set_offline(false);
+        assert!(fake
+            .wait_for(WAIT, |_| fake.published_to(HEARTBEAT).len() > heartbeats)
+            .await
+            .is_some());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_lifecycle_events_go_to_local_pubsub() {
+    const EVENTS: &str = "device-ops/events";
+    let mut config = test_config();
+    config.events.enabled = true;
+    config.security.enabled = true;
+    config.security.command_allowlist = vec!["/bin/echo".to_string()];
+    config.security.path_allowlist = vec!["/bin/".to_string()];
+
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, config, |fake| async move {
+        fake.inject_json(
+            NOTIFY_NEXT,
+            &job_notification("job-1", "/bin/echo", &["hi"]),
+        );
+        assert_eq!(terminal_update(&fake, "job-1").await["status"], "SUCCEEDED");
+        fake.inject_json(NOTIFY_NEXT, &job_notification("job-2", "/bin/false", &[]));
+        assert_eq!(terminal_update(&fake, "job-2").await["status"], "FAILED");
+
+        assert!(fake
+            .wait_for(WAIT, |_| fake.published_local_to(EVENTS).len() >= 7)
+            .await
+            .is_some());
+        let events = fake.published_local_to(EVENTS);
+        let names: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "jobStarted",
+                "stepCompleted",
+                "jobCompleted",
+                "jobStarted",
+                "stepCompleted",
+                "securityDenied",
+                "jobCompleted"
+            ]
+        );
+        assert_eq!(events[1]["jobId"], "job-1");
+        assert_eq!(events[1]["stepName"], "Step");
+        assert_eq!(events[1]["stepIndex"], 0);
+        assert_eq!(events[1]["status"], "success");
+        assert_eq!(events[1]["exitCode"], 0);
+        assert_eq!(events[2]["status"], "SUCCEEDED");
+        // The allowlist refuses the second job's step
+        assert_eq!(events[4]["jobId"], "job-2");
+        assert_eq!(events[4]["status"], "error");
+        assert_eq!(events[5]["stepName"], "Step");
+        assert_eq!(events[6]["status"], "FAILED");
+        assert_eq!(events[6]["failedStep"], "Step");
+        // Events stay local; nothing extra goes to IoT Core
+        assert!(fake.published_to(EVENTS).is_empty());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_stats_requests_are_answered_and_rate_limited() {
+    const REQUEST: &str = "device-ops/test-thing/stats/request";
+    let mut config = test_config();
+    config.stats.enabled = true;
+    config.stats.max_requests_per_minute = 2;
+
+    let fake = FakeSdk::new();
+    run_with_fake(&fake, config, |fake| async move {
+        assert!(fake.subscriptions().contains(&REQUEST.to_string()));
+        fake.inject_json(
+            NOTIFY_NEXT,
+            &job_notification("job-1", "/bin/echo", &["hi"]),
+        );
+        assert_eq!(terminal_update(&fake, "job-1").await["status"], "SUCCEEDED");
+
+        // Not answered and not counted against the limit
+        fake.inject(REQUEST, b"not json");
+        fake.inject_json(REQUEST, &json!({ "requestId": "a/b" }));
+        for id in ["req-1", "req-2", "req-3"] {
+            fake.inject_json(REQUEST, &json!({ "requestId": id }));
+        }
+        let response = fake
+            .wait_for(WAIT, |m| {
+                m.topic == "device-ops/test-thing/stats/response/req-1"
+            })
+            .await
+            .expect("stats response")
+            .json();
+        assert_eq!(response["requestId"], "req-1");
+        assert_eq!(response["thingName"], THING);
+        // Metrics are process-wide, shared with the other tests
+        assert!(
+            response["metrics"]["jobs_total"]["succeeded"]
+                .as_u64()
+                .unwrap()
+                >= 1
+        );
+        assert!(response["runningJob"].is_null());
+        assert_eq!(response["queuedOnDevice"], json!([]));
+        assert!(response["lastJobsApiContact"].is_i64());
+        assert_eq!(response["foreignUpdates"], 0);
+        assert!(fake
+            .wait_for(WAIT, |m| m.topic
+                == "device-ops/test-thing/stats/response/req-2")
+            .await
+            .is_some());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let responses: Vec<String> = fake
+            .published()
+            .into_iter()
+            .map(|m| m.topic)
+            .filter(|topic| topic.contains("/stats/response/"))
+            .collect();
+        assert_eq!(responses.len(), 2, "{:?}", responses);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_connect_failure_is_ipc_error() {
+    let fake = FakeSdk::new();
+    fake.refuse_connect();
+
+    let err = IpcClient::with_transport(Box::new(fake), &test_config().ipc)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DeviceOpsError::IpcError(_)));
+}
+
+#[tokio::test]
+async fn test_unacknowledged_publish_times_out() {
+    let fake = FakeSdk::new();
+    let mut config = test_config();
+    config.ipc.publish_timeout_secs = 1;
+    let client = IpcClient::with_transport(Box::new(fake.clone()), &config.ipc)
+        .await
+        .unwrap();
+
+    fake.stall_publishes(true);
+    let started = std::time::Instant::now();
+    let err = client.request_next_job(THING).await.unwrap_err();
+    assert!(matches!(err, DeviceOpsError::IpcError(_)));
+    assert!(
+        err.to_string().contains("not acknowledged within 1s"),
+        "{}",
+        err
+    );
+    let status = JobStatus::failed("boom".to_string(), None, None);
+    let err = client
+        .update_job_status(THING, "job-1", status)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("not acknowledged within 1s"));
+    assert!(started.elapsed() < WAIT);
+
+    // Once the broker answers again publishes go through, the stalled ones included
+    fake.stall_publishes(false);
+    client.request_next_job(THING).await.unwrap();
+    let update = update_topic("job-1");
+    assert!(fake.wait_for(WAIT, |m| m.topic == update).await.is_some());
+}